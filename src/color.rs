@@ -382,6 +382,29 @@ impl<T: Primitive + 'static> FromColor<Luma<T>> for Rgb<T> {
     }
 }
 
+/// FromColor for 16 bit colors
+
+// Narrows a color with 16 bit samples to 8 bits by keeping the high byte of
+// each sample, then converts it into the color space of the target.
+macro_rules! narrowing_from_color {
+    ($($from: ident => $($to: ident),+;)+) => {$($(
+        impl FromColor<$from<u16>> for $to<u8> {
+            fn from_color(&mut self, other: &$from<u16>) {
+                let (a, b, c, d) = other.channels4();
+                let narrow: $from<u8> = Pixel::from_channels(
+                    (a >> 8) as u8, (b >> 8) as u8, (c >> 8) as u8, (d >> 8) as u8
+                );
+                self.from_color(&narrow)
+            }
+        }
+    )+)+}
+}
+
+narrowing_from_color!(
+    Luma => Luma, LumaA, Rgb, Rgba;
+    Rgb => Luma, LumaA, Rgb, Rgba;
+);
+
 /// Blends a color inter another one
 pub trait Blend {
     /// Blends a color in-place.
@@ -391,6 +414,16 @@ pub trait Blend {
 impl<T: Primitive> Blend for LumaA<T> {
     fn blend(&mut self, other: &LumaA<T>) {
         let max_t: T = Primitive::max_value();
+
+        // Opaque colors replace the background exactly and transparent colors leave it unchanged,
+        // instead of being rounded or divided by an alpha of zero
+        if other[1] == max_t {
+            *self = *other;
+            return
+        } else if other[1] == Zero::zero() {
+            return
+        }
+
         let max_t = max_t.to_f32().unwrap();
         let &mut LumaA([bg_luma, bg_a]) = self;
         let &LumaA([fg_luma, fg_a]) = other;
@@ -421,8 +454,18 @@ impl<T: Primitive> Blend for Rgba<T> {
     fn blend(&mut self, other: &Rgba<T>) {
         // http://stackoverflow.com/questions/7438263/alpha-compositing-algorithm-blend-modes#answer-11163848
 
-        // First, as we don't know what type our pixel is, we have to convert to floats between 0.0 and 1.0
         let max_t: T = Primitive::max_value();
+
+        // Opaque colors replace the background exactly and transparent colors leave it unchanged,
+        // instead of being rounded or divided by an alpha of zero
+        if other[3] == max_t {
+            *self = *other;
+            return
+        } else if other[3] == Zero::zero() {
+            return
+        }
+
+        // First, as we don't know what type our pixel is, we have to convert to floats between 0.0 and 1.0
         let max_t = max_t.to_f32().unwrap();
         let &mut Rgba([bg_r, bg_g, bg_b, bg_a]) = self;
         let &Rgba([fg_r, fg_g, fg_b, fg_a]) = other;
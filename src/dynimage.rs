@@ -21,8 +21,10 @@ use tiff;
 use tga;
 
 use color;
+use color::FromColor;
 use buffer::{ImageBuffer, ConvertBuffer, Pixel, GrayImage, GrayAlphaImage, RgbImage, RgbaImage};
 use imageops;
+use imageops::Orientation;
 use image;
 use image:: {
     GenericImage,
@@ -31,7 +33,7 @@ use image:: {
     ImageFormat,
 };
 
-use image::DecodingResult::{U8};
+use image::DecodingResult::{U8, U16};
 
 /// A Dynamic Image
 pub enum DynamicImage {
@@ -46,6 +48,12 @@ pub enum DynamicImage {
 
     /// Each pixel in this image is 8-bit Rgb with alpha
     ImageRgba8(RgbaImage),
+
+    /// Each pixel in this image is 16-bit Luma
+    ImageLuma16(ImageBuffer<color::Luma<u16>, Vec<u16>>),
+
+    /// Each pixel in this image is 16-bit Rgb
+    ImageRgb16(ImageBuffer<color::Rgb<u16>, Vec<u16>>),
 }
 
 macro_rules! dynamic_map(
@@ -55,6 +63,8 @@ macro_rules! dynamic_map(
                         DynamicImage::ImageLumaA8(ref $image) => DynamicImage::ImageLumaA8($action),
                         DynamicImage::ImageRgb8(ref $image) => DynamicImage::ImageRgb8($action),
                         DynamicImage::ImageRgba8(ref $image) => DynamicImage::ImageRgba8($action),
+                        DynamicImage::ImageLuma16(ref $image) => DynamicImage::ImageLuma16($action),
+                        DynamicImage::ImageRgb16(ref $image) => DynamicImage::ImageRgb16($action),
                 }
         );
 
@@ -64,6 +74,8 @@ macro_rules! dynamic_map(
                         DynamicImage::ImageLumaA8(ref mut $image) => DynamicImage::ImageLumaA8($action),
                         DynamicImage::ImageRgb8(ref mut $image) => DynamicImage::ImageRgb8($action),
                         DynamicImage::ImageRgba8(ref mut $image) => DynamicImage::ImageRgba8($action),
+                        DynamicImage::ImageLuma16(ref mut $image) => DynamicImage::ImageLuma16($action),
+                        DynamicImage::ImageRgb16(ref mut $image) => DynamicImage::ImageRgb16($action),
                 }
         );
 
@@ -73,6 +85,8 @@ macro_rules! dynamic_map(
                         DynamicImage::ImageLumaA8(ref $image) => $action,
                         DynamicImage::ImageRgb8(ref $image) => $action,
                         DynamicImage::ImageRgba8(ref $image) => $action,
+                        DynamicImage::ImageLuma16(ref $image) => $action,
+                        DynamicImage::ImageRgb16(ref $image) => $action,
                 }
         );
 
@@ -82,6 +96,8 @@ macro_rules! dynamic_map(
                         DynamicImage::ImageLumaA8(ref mut $image) => $action,
                         DynamicImage::ImageRgb8(ref mut $image) => $action,
                         DynamicImage::ImageRgba8(ref mut $image) => $action,
+                        DynamicImage::ImageLuma16(ref mut $image) => $action,
+                        DynamicImage::ImageRgb16(ref mut $image) => $action,
                 }
         );
 );
@@ -108,6 +124,16 @@ impl DynamicImage {
         DynamicImage::ImageRgba8(ImageBuffer::new(w, h))
     }
 
+    /// Creates a dynamic image backed by a buffer of 16 bit grey pixels.
+    pub fn new_luma16(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageLuma16(ImageBuffer::new(w, h))
+    }
+
+    /// Creates a dynamic image backed by a buffer of 16 bit RGB pixels.
+    pub fn new_rgb16(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageRgb16(ImageBuffer::new(w, h))
+    }
+
     /// Returns a copy of this image as an RGB image.
     pub fn to_rgb(&self) -> RgbImage {
         dynamic_map!(*self, ref p -> {
@@ -210,7 +236,40 @@ impl DynamicImage {
         }
     }
 
+    /// Return a reference to a 16bit Grayscale image
+    pub fn as_luma16(&self) -> Option<&ImageBuffer<color::Luma<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageLuma16(ref p) => Some(p),
+            _                                => None
+        }
+    }
+
+    /// Return a mutable reference to a 16bit Grayscale image
+    pub fn as_mut_luma16(&mut self) -> Option<&mut ImageBuffer<color::Luma<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageLuma16(ref mut p) => Some(p),
+            _                                    => None
+        }
+    }
+
+    /// Return a reference to a 16bit RGB image
+    pub fn as_rgb16(&self) -> Option<&ImageBuffer<color::Rgb<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageRgb16(ref p) => Some(p),
+            _                               => None
+        }
+    }
+
+    /// Return a mutable reference to a 16bit RGB image
+    pub fn as_mut_rgb16(&mut self) -> Option<&mut ImageBuffer<color::Rgb<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageRgb16(ref mut p) => Some(p),
+            _                                   => None
+        }
+    }
+
     /// Return this image's pixels as a byte vector.
+    /// The samples of 16 bit images are stored in big endian byte order.
     pub fn raw_pixels(&self) -> Vec<u8> {
         image_to_bytes(self)
     }
@@ -222,6 +281,8 @@ impl DynamicImage {
             DynamicImage::ImageLumaA8(_) => color::ColorType::GrayA(8),
             DynamicImage::ImageRgb8(_) => color::ColorType::RGB(8),
             DynamicImage::ImageRgba8(_) => color::ColorType::RGBA(8),
+            DynamicImage::ImageLuma16(_) => color::ColorType::Gray(16),
+            DynamicImage::ImageRgb16(_) => color::ColorType::RGB(16),
         }
     }
 
@@ -232,6 +293,8 @@ impl DynamicImage {
             DynamicImage::ImageLumaA8(ref p) => DynamicImage::ImageLuma8(imageops::grayscale(p)),
             DynamicImage::ImageRgb8(ref p) => DynamicImage::ImageLuma8(imageops::grayscale(p)),
             DynamicImage::ImageRgba8(ref p) => DynamicImage::ImageLuma8(imageops::grayscale(p)),
+            DynamicImage::ImageLuma16(ref p) => DynamicImage::ImageLuma16(p.clone()),
+            DynamicImage::ImageRgb16(ref p) => DynamicImage::ImageLuma16(imageops::grayscale(p)),
         }
     }
 
@@ -339,11 +402,52 @@ impl DynamicImage {
         dynamic_map!(*self, ref p => imageops::rotate270(p))
     }
 
+    /// Turn this image upright, when it is stored with the orientation ```orientation```.
+    pub fn orient(self, orientation: Orientation) -> DynamicImage {
+        match orientation {
+            Orientation::Normal         => self,
+            Orientation::FlipHorizontal => self.fliph(),
+            Orientation::Rotate180      => self.rotate180(),
+            Orientation::FlipVertical   => self.flipv(),
+            Orientation::Transpose      => self.rotate90().fliph(),
+            Orientation::Rotate90       => self.rotate90(),
+            Orientation::Transverse     => self.rotate270().fliph(),
+            Orientation::Rotate270      => self.rotate270(),
+        }
+    }
+
     /// Encode this image and write it to ```w```
     pub fn save<W: Write>(&self, w: &mut W, format: ImageFormat) -> io::Result<ImageResult<()>> {
-        let bytes = self.raw_pixels();
-        let (width, height) = self.dimensions();
-        let color = self.color();
+        self.encode(w, format, None)
+    }
+
+    /// Encode this image with the quality ```quality``` and write it to ```w```.
+    /// The quality ranges from 1 to 100 and is used by the lossy JPEG and WebP encoders,
+    /// other formats are encoded like ```save``` does.
+    pub fn save_with_quality<W: Write>(&self,
+                                       w: &mut W,
+                                       format: ImageFormat,
+                                       quality: u8) -> io::Result<ImageResult<()>> {
+        self.encode(w, format, Some(quality))
+    }
+
+    fn encode<W: Write>(&self,
+                        w: &mut W,
+                        format: ImageFormat,
+                        quality: Option<u8>) -> io::Result<ImageResult<()>> {
+
+        // Only the PNG encoder writes 16 bit samples of both color types
+        let narrowed = match (format, self) {
+            (image::ImageFormat::PNG, _) => None,
+            (_, &DynamicImage::ImageLuma16(_)) => Some(DynamicImage::ImageLuma8(self.to_luma())),
+            (_, &DynamicImage::ImageRgb16(_)) => Some(DynamicImage::ImageRgb8(self.to_rgb())),
+            _ => None
+        };
+        let image = narrowed.as_ref().unwrap_or(self);
+
+        let bytes = image.raw_pixels();
+        let (width, height) = image.dimensions();
+        let color = image.color();
 
         let r = match format {
             #[cfg(feature = "png")]
@@ -363,12 +467,26 @@ impl DynamicImage {
 
             #[cfg(feature = "jpeg")]
             image::ImageFormat::JPEG => {
-                let mut j = jpeg::JPEGEncoder::new(w);
+                let mut j = match quality {
+                    Some(quality) => jpeg::JPEGEncoder::new_with_quality(w, quality),
+                    None => jpeg::JPEGEncoder::new(w),
+                };
 
                 try!(j.encode(&bytes, width, height, color));
                 Ok(())
             }
 
+            #[cfg(feature = "webp")]
+            image::ImageFormat::WEBP => {
+                let mut p = match quality {
+                    Some(quality) => webp::WebpEncoder::new_with_quality(w, quality),
+                    None => webp::WebpEncoder::new(w),
+                };
+
+                try!(p.encode(&bytes, width, height, color));
+                Ok(())
+            }
+
             #[cfg(feature = "gif")]
             image::ImageFormat::GIF => {
                 let mut g = gif::GIFEncoder::new(
@@ -401,7 +519,11 @@ impl GenericImage for DynamicImage {
     }
 
     fn get_pixel(&self, x: u32, y: u32) -> color::Rgba<u8> {
-        dynamic_map!(*self, ref p -> p.get_pixel(x, y).to_rgba())
+        dynamic_map!(*self, ref p -> {
+            let mut pixel = color::Rgba([0, 0, 0, 0]);
+            pixel.from_color(&p.get_pixel(x, y));
+            pixel
+        })
     }
 
     fn put_pixel(&mut self, x: u32, y: u32, pixel: color::Rgba<u8>) {
@@ -410,6 +532,8 @@ impl GenericImage for DynamicImage {
             DynamicImage::ImageLumaA8(ref mut p) => p.put_pixel(x, y, pixel.to_luma_alpha()),
             DynamicImage::ImageRgb8(ref mut p) => p.put_pixel(x, y, pixel.to_rgb()),
             DynamicImage::ImageRgba8(ref mut p) => p.put_pixel(x, y, pixel),
+            DynamicImage::ImageLuma16(ref mut p) => p.put_pixel(x, y, widen(pixel).to_luma()),
+            DynamicImage::ImageRgb16(ref mut p) => p.put_pixel(x, y, widen(pixel).to_rgb()),
         }
     }
     #[deprecated = "Use iterator `pixels_mut` to blend the pixels directly. "]
@@ -419,6 +543,8 @@ impl GenericImage for DynamicImage {
             DynamicImage::ImageLumaA8(ref mut p) => p.blend_pixel(x, y, pixel.to_luma_alpha()),
             DynamicImage::ImageRgb8(ref mut p) => p.blend_pixel(x, y, pixel.to_rgb()),
             DynamicImage::ImageRgba8(ref mut p) => p.blend_pixel(x, y, pixel),
+            DynamicImage::ImageLuma16(ref mut p) => p.blend_pixel(x, y, widen(pixel).to_luma()),
+            DynamicImage::ImageRgb16(ref mut p) => p.blend_pixel(x, y, widen(pixel).to_rgb()),
         }
     }
 
@@ -428,6 +554,23 @@ impl GenericImage for DynamicImage {
     }
 }
 
+// Widens a color with 8 bit samples to 16 bits, mapping 0xFF to 0xFFFF
+fn widen(pixel: color::Rgba<u8>) -> color::Rgba<u16> {
+    let (r, g, b, a) = pixel.channels4();
+    color::Rgba([r as u16 * 257, g as u16 * 257, b as u16 * 257, a as u16 * 257])
+}
+
+// Scales samples with a bit depth between 9 and 16 to the full 16 bit range
+fn scale_to_16bit(buf: Vec<u16>, bit_depth: u8) -> Vec<u16> {
+    if bit_depth == 16 {
+        return buf
+    }
+
+    let shift = 16 - bit_depth as usize;
+    // Repeating the high bits in the low bits maps the maximum value to 0xFFFF
+    buf.into_iter().map(|v| v << shift | v >> (bit_depth as usize - shift)).collect()
+}
+
 
 /// Decodes an image and stores it into a dynamic image
 pub fn decoder_to_image<I: ImageDecoder>(codec: I) -> ImageResult<DynamicImage> {
@@ -477,6 +620,19 @@ pub fn decoder_to_image<I: ImageDecoder>(codec: I) -> ImageResult<DynamicImage>
                        .collect();
             ImageBuffer::from_raw(w, h, p).map(|buf| DynamicImage::ImageLuma8(buf))
         },
+
+        // Samples of more than 8 bits, like those of 12 bit JPEG images,
+        // are scaled to the range of 16 bit images
+        (color::ColorType::Gray(bit_depth), U16(buf)) if bit_depth > 8 && bit_depth <= 16 => {
+            let buf = scale_to_16bit(buf, bit_depth);
+            ImageBuffer::from_raw(w, h, buf).map(|v| DynamicImage::ImageLuma16(v))
+        }
+
+        (color::ColorType::RGB(bit_depth), U16(buf)) if bit_depth > 8 && bit_depth <= 16 => {
+            let buf = scale_to_16bit(buf, bit_depth);
+            ImageBuffer::from_raw(w, h, buf).map(|v| DynamicImage::ImageRgb16(v))
+        }
+
         _ => return Err(image::ImageError::UnsupportedColor(color))
     };
     match image {
@@ -504,6 +660,14 @@ fn image_to_bytes(image: &DynamicImage) -> Vec<u8> {
         DynamicImage::ImageRgba8(ref a) => {
             a.as_slice().iter().map(|v| *v).collect()
         }
+
+        DynamicImage::ImageLuma16(ref a) => {
+            a.as_slice().iter().flat_map(|&v| vec![(v >> 8) as u8, v as u8].into_iter()).collect()
+        }
+
+        DynamicImage::ImageRgb16(ref a) => {
+            a.as_slice().iter().flat_map(|&v| vec![(v >> 8) as u8, v as u8].into_iter()).collect()
+        }
     }
 }
 
@@ -517,25 +681,63 @@ pub fn open<P>(path: P) -> ImageResult<DynamicImage> where P: AsRef<Path> {
         Err(err) => return Err(image::ImageError::IoError(err))
     };
 
+    let format = try!(format_from_path(path));
+
+    load(fin, format)
+}
+
+/// Open the image located at the path specified, like `open`,
+/// and turn it upright as the orientation tag of its EXIF metadata describes.
+/// Only JPEG images are read with their EXIF metadata.
+pub fn open_upright<P>(path: P) -> ImageResult<DynamicImage> where P: AsRef<Path> {
+    let path = path.as_ref();
+
+    let fin = match File::open(path) {
+        Ok(f)  => f,
+        Err(err) => return Err(image::ImageError::IoError(err))
+    };
+
+    match try!(format_from_path(path)) {
+        #[cfg(feature = "jpeg")]
+        image::ImageFormat::JPEG => {
+            let mut decoder = jpeg::JPEGDecoder::new(BufReader::new(fin));
+            let _ = try!(decoder.dimensions());
+
+            // Malformed EXIF metadata does not keep the image from being opened
+            let orientation = decoder.exif().ok()
+                                     .and_then(|exif| exif)
+                                     .and_then(|exif| exif.orientation);
+
+            let image = try!(decoder_to_image(decoder));
+
+            Ok(match orientation {
+                Some(orientation) => image.orient(orientation),
+                None => image
+            })
+        }
+        format => load(fin, format)
+    }
+}
+
+// Determines the image format from the file extension of the path
+fn format_from_path(path: &Path) -> ImageResult<ImageFormat> {
     let ext = path.extension().and_then(|s| s.to_str())
                   .map_or("".to_string(), |s| s.to_string().into_ascii_lowercase());
 
-    let format = match &ext[..] {
+    match &ext[..] {
         "jpg" |
-        "jpeg" => image::ImageFormat::JPEG,
-        "png"  => image::ImageFormat::PNG,
-        "gif"  => image::ImageFormat::GIF,
-        "webp" => image::ImageFormat::WEBP,
+        "jpeg" => Ok(image::ImageFormat::JPEG),
+        "png"  => Ok(image::ImageFormat::PNG),
+        "gif"  => Ok(image::ImageFormat::GIF),
+        "webp" => Ok(image::ImageFormat::WEBP),
         "tif" |
-        "tiff" => image::ImageFormat::TIFF,
-        "tga" => image::ImageFormat::TGA,
-        format => return Err(image::ImageError::UnsupportedError(format!(
+        "tiff" => Ok(image::ImageFormat::TIFF),
+        "tga" => Ok(image::ImageFormat::TGA),
+        format => Err(image::ImageError::UnsupportedError(format!(
             "Image format image/{:?} is not supported.",
             format
         )))
-    };
-
-    load(fin, format)
+    }
 }
 
 /// Saves the supplied buffer to a file at the path specified.
@@ -544,7 +746,7 @@ pub fn open<P>(path: P) -> ImageResult<DynamicImage> where P: AsRef<Path> {
 /// the correct format according to the specified color type.
 
 /// This will lead to corrupted files if the buffer contains malformed data. Currently only
-/// jpeg and png files are supported.
+/// jpeg, png, ppm and webp files are supported.
 pub fn save_buffer<P>(path: P, buf: &[u8], width: u32, height: u32, color: color::ColorType)
                       -> io::Result<()> where P: AsRef<Path> {
     let path = path.as_ref();
@@ -560,6 +762,8 @@ pub fn save_buffer<P>(path: P, buf: &[u8], width: u32, height: u32, color: color
         "png"  => png::PNGEncoder::new(fout).encode(buf, width, height, color),
         #[cfg(feature = "ppm")]
         "ppm"  => ppm::PPMEncoder::new(fout).encode(buf, width, height, color),
+        #[cfg(feature = "webp")]
+        "webp" => webp::WebpEncoder::new(fout).encode(buf, width, height, color),
         format => Err(io::Error::new(
             io::ErrorKind::InvalidInput,
             "Unsupported image format.",
@@ -637,3 +841,83 @@ mod bench {
         b.bytes = 1000*1000*3
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use color;
+    use buffer::ImageBuffer;
+    use imageops::Orientation;
+    use image::GenericImage;
+
+    #[cfg(feature = "jpeg")]
+    #[test]
+    /// Test that a 12 bit lossless JPEG image is opened as a 16 bit image
+    fn test_16bit_decoding() {
+        // A 4 x 2 gray image with the 12 bit samples 100, 110, 90, 4095, 100, 0, 50, 60
+        let path: PathBuf = [".", "src", "jpeg", "testdata", "lossless_ra.jpg"].iter().collect();
+        let image = super::open(&path).unwrap();
+        assert_eq!(image.color(), color::ColorType::Gray(16));
+        assert_eq!(image.dimensions(), (4, 2));
+
+        let samples = image.as_luma16().unwrap().clone().into_raw();
+        assert_eq!(samples, vec![1600, 1760, 1440, 65535, 1600, 0, 800, 960]);
+
+        assert_eq!(&image.raw_pixels()[..4], &[0x06, 0x40, 0x06, 0xE0][..]);
+        assert_eq!(image.get_pixel(3, 0), color::Rgba([255, 255, 255, 255]));
+        assert_eq!(image.to_luma().into_raw(), vec![6, 6, 5, 255, 6, 0, 3, 3]);
+    }
+
+    #[test]
+    /// Test that every orientation turns an asymmetric image, stored as the EXIF tag describes, upright
+    fn test_orient() {
+        let (w, h) = (3u32, 2u32);
+        let upright: Vec<u8> = (0..w * h).map(|i| i as u8 + 1).collect();
+
+        for tag in (1u32..9) {
+            let (sw, sh) = if tag < 5 { (w, h) } else { (h, w) };
+
+            // The upright pixel that is stored at (a, b)
+            let stored = ImageBuffer::from_fn(sw, sh, |a, b| {
+                let (x, y) = match tag {
+                    1 => (a, b),
+                    2 => (w - 1 - a, b),
+                    3 => (w - 1 - a, h - 1 - b),
+                    4 => (a, h - 1 - b),
+                    5 => (b, a),
+                    6 => (w - 1 - b, a),
+                    7 => (w - 1 - b, h - 1 - a),
+                    _ => (b, h - 1 - a),
+                };
+
+                color::Luma([upright[(y * w + x) as usize]])
+            });
+
+            let image = super::DynamicImage::ImageLuma8(stored).orient(Orientation::from_tag(tag).unwrap());
+
+            assert_eq!(image.dimensions(), (w, h));
+            assert_eq!(image.raw_pixels(), upright);
+        }
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[test]
+    /// Test that a JPEG image is opened upright as the orientation in its APP1 Exif segment describes.
+    /// The image was written by libjpeg, rotated 90 degrees counterclockwise with the orientation 6.
+    fn test_open_upright() {
+        let path: PathBuf = [".", "src", "jpeg", "testdata", "exif_orientation.jpg"].iter().collect();
+
+        assert_eq!(super::open(&path).unwrap().dimensions(), (24, 16));
+
+        let image = super::open_upright(&path).unwrap();
+        assert_eq!(image.dimensions(), (16, 24));
+
+        // The blocks of 8 x 8 pixels have the levels 0, 50 / 100, 150 / 200, 250
+        let pixels = image.to_luma();
+        for (x, y, p) in pixels.enumerate_pixels() {
+            let expected = (y / 8) * 100 + (x / 8) * 50;
+            assert!((p[0] as i32 - expected as i32).abs() <= 2, "{} {}: {}", x, y, p[0]);
+        }
+    }
+}
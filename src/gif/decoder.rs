@@ -14,6 +14,8 @@ use imageops::overlay;
 
 use color;
 use animation::Frame;
+use indexed::IndexedImage;
+use color::Rgba;
 use image::{ImageError, ImageResult, DecodingResult, ImageDecoder};
 use buffer::{ImageBuffer, GrayImage, RgbaImage};
 
@@ -175,8 +177,9 @@ impl<R: Read> GIFDecoder<R> {
         Ok(data)
     }
 
-    #[allow(unused_variables)]
-    fn read_frame(&mut self) -> ImageResult<Frame> {
+    /// Reads the image descriptor and the indices of a frame.
+    /// Returns the offset of the frame, its indices and the local color table
+    fn read_frame_indices(&mut self) -> ImageResult<(u16, u16, GrayImage, Option<Vec<(u8, u8, u8)>>)> {
         let image_left   = try!(self.r.read_u16::<LittleEndian>());
         let image_top    = try!(self.r.read_u16::<LittleEndian>());
         let image_width  = try!(self.r.read_u16::<LittleEndian>());
@@ -221,25 +224,13 @@ impl<R: Read> GIFDecoder<R> {
             code_size
         ));
 
-        let table = if let Some(ref table) = local_table {
-            table
-        } else {
-            &self.global_table
-        };
-
         let image: Option<GrayImage> = ImageBuffer::from_vec(
             image_width as u32,
             image_height as u32,
             indices
         );
         if let Some(image) = image {
-            let image = image.expand_palette(table, self.local_transparent_index);
-            Ok(Frame::from_parts(
-                image,
-                image_left as u32,
-                image_top as u32,
-                Ratio::new(self.delay, 100)
-            ))
+            Ok((image_left, image_top, image, local_table))
         } else {
             Err(ImageError::FormatError(
                 "Image data has not the expected size.".to_string()
@@ -247,21 +238,75 @@ impl<R: Read> GIFDecoder<R> {
         }
     }
 
-    fn next_frame(&mut self) -> ImageResult<Option<Frame>> {
+    fn read_frame(&mut self) -> ImageResult<Frame> {
+        let (left, top, indices, local_table) = try!(self.read_frame_indices());
+
+        let image = {
+            let table = if let Some(ref table) = local_table {
+                table
+            } else {
+                &self.global_table
+            };
+
+            indices.expand_palette(table, self.local_transparent_index)
+        };
+
+        Ok(Frame::from_parts(
+            image,
+            left as u32,
+            top as u32,
+            Ratio::new(self.delay, 100)
+        ))
+    }
+
+    /// Reads blocks until the next image descriptor.
+    /// Returns false if the trailer is reached
+    fn find_image(&mut self) -> ImageResult<bool> {
         use super::Block::{Image, Extension, Trailer};
 
         try!(self.read_logical_screen_descriptor());
         loop {
             match FromPrimitive::from_u8(try!(self.r.read_u8())) {
                 Some(Extension) => try!(self.read_extension()),
-                Some(Image) => return self.read_frame().map(|v| Some(v)),
-                Some(Trailer) => return Ok(None),
+                Some(Image) => return Ok(true),
+                Some(Trailer) => return Ok(false),
                 None => return Err(ImageError::UnsupportedError(
                     "Unknown block encountered".to_string()
                 ))
             }
         }
     }
+
+    fn next_frame(&mut self) -> ImageResult<Option<Frame>> {
+        if try!(self.find_image()) {
+            self.read_frame().map(|v| Some(v))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Decodes the first frame to the palette indices of its pixels, without expanding them
+    /// to colors. The palette is the local color table of the frame or the global color table,
+    /// the transparent color has an alpha of 0.
+    pub fn read_indexed(&mut self) -> ImageResult<IndexedImage> {
+        if !try!(self.find_image()) {
+            return Err(ImageError::ImageEnd)
+        }
+
+        let (_, _, indices, local_table) = try!(self.read_frame_indices());
+        let (width, height) = indices.dimensions();
+        let table = local_table.unwrap_or_else(|| self.global_table.clone());
+
+        let palette = table.iter().enumerate().map(|(i, &(r, g, b))| {
+            let alpha = if self.local_transparent_index == Some(i as u8) {0} else {255};
+
+            Rgba([r, g, b, alpha])
+        }).collect();
+
+        IndexedImage::from_parts(width, height, indices.into_raw(), palette).ok_or(
+            ImageError::FormatError("Color index out of range.".to_string())
+        )
+    }
 }
 
 impl<R: Read> ImageDecoder for GIFDecoder<R> {
@@ -8,6 +8,7 @@ use byteorder::{WriteBytesExt, LittleEndian};
 use std::num::Int;
 
 use buffer::{ImageBuffer, Pixel};
+use indexed::IndexedImage;
 use color::{Rgb, Rgba};
 use utils::lzw;
 use utils::bitstream::LsbWriter;
@@ -241,15 +242,7 @@ where Container: Deref<Target=[u8]> + DerefMut {
             1 => 2,
             n => n
         };
-        let mut encoded_data = Vec::new();
-        try!(lzw::encode(indices, LsbWriter::new(&mut encoded_data), code_size));
-        try!(w.write_all(&[code_size]));
-        for chunk in encoded_data.chunks(255) {
-            try!(w.write_all(&[(chunk.len()) as u8]));
-            try!(w.write_all(chunk));
-        }
-        w.write_all(&[0]) // block terminator
-
+        write_image_data(w, indices, code_size)
     }
 
     /// Writes the image to the file assuming that every pixel is in the color table
@@ -369,6 +362,90 @@ where Container: Deref<Target=[u8]> + DerefMut {
     }
 }
 
+impl Encoder<IndexedImage> {
+    /// Creates a GIF encoder that writes the indices and the palette of ```image``` unchanged.
+    /// The palette is padded to a power of two. GIF images have at most one transparent color,
+    /// the first palette entry with an alpha of 0 is used, the alpha of all other entries is ignored.
+    pub fn new_indexed(image: IndexedImage) -> Encoder<IndexedImage> {
+        Encoder {
+            image: image,
+            bg_color: None,
+            color_mode: TrueColor
+        }
+    }
+
+    /// Encodes the image
+    pub fn encode<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        let (width, height) = self.image.dimensions();
+        if width > <u16 as Int>::max_value() as u32 ||
+           height > <u16 as Int>::max_value() as u32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Image dimensions are to large for the gif format.",
+                None
+            ))
+        }
+
+        let palette = self.image.palette();
+        let n = flag_n(palette.len());
+
+        // Header and logical screen descriptor with the global color table
+        try!(w.write_all(b"GIF89a"));
+        try!(w.write_u16::<LittleEndian>(width as u16));
+        try!(w.write_u16::<LittleEndian>(height as u16));
+        try!(w.write_all(&[0x80 | n << 4 | n]));
+        try!(w.write_all(&[0])); // background index
+        try!(w.write_all(&[0])); // aspect ratio, disregard
+
+        for entry in palette.iter() {
+            try!(w.write_all(&entry.channels()[..3]));
+        }
+        // waste some space as of gif spec
+        for _ in 0..((2 << n) - palette.len()) {
+            try!(w.write_all(&[0, 0, 0]));
+        }
+
+        // Graphics control extension with the transparent index
+        let transparent = palette.iter().position(|entry| entry[3] == 0);
+        try!(w.write_all(&[Block::Extension as u8]));
+        try!(w.write_all(&[Extension::Control as u8]));
+        try!(w.write_all(&[4])); // size
+        try!(w.write_all(&[(DisposalMethod::None as u8) << 2 | transparent.is_some() as u8]));
+        try!(w.write_u16::<LittleEndian>(0)); // delay
+        try!(w.write_all(&[transparent.unwrap_or(0) as u8]));
+        try!(w.write_all(&[0]));
+
+        // Image descriptor without a local color table
+        try!(w.write_all(&[Block::Image as u8]));
+        try!(w.write_u16::<LittleEndian>(0)); // left
+        try!(w.write_u16::<LittleEndian>(0)); // top
+        try!(w.write_u16::<LittleEndian>(width as u16));
+        try!(w.write_u16::<LittleEndian>(height as u16));
+        try!(w.write_all(&[0]));
+
+        // The minimum code size has to be at least 2
+        let code_size = match n + 1 {
+            1 => 2,
+            size => size
+        };
+        try!(write_image_data(w, self.image.indices(), code_size));
+
+        w.write_all(&[Block::Trailer as u8])
+    }
+}
+
+/// Writes and compresses the indexed data with the initial code size ```code_size```
+fn write_image_data<W: Write>(w: &mut W, indices: &[u8], code_size: u8) -> io::Result<()> {
+    let mut encoded_data = Vec::new();
+    try!(lzw::encode(indices, LsbWriter::new(&mut encoded_data), code_size));
+    try!(w.write_all(&[code_size]));
+    for chunk in encoded_data.chunks(255) {
+        try!(w.write_all(&[(chunk.len()) as u8]));
+        try!(w.write_all(chunk));
+    }
+    w.write_all(&[0]) // block terminator
+}
+
 // Color table len converted to flag bits
 fn flag_n(size: usize) -> u8 {
     match size {
@@ -383,3 +460,33 @@ fn flag_n(size: usize) -> u8 {
         _ => 7
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use std::io;
+
+    use color::Rgba;
+    use indexed::IndexedImage;
+    use super::Encoder;
+    use super::super::GIFDecoder;
+
+    #[test]
+    /// Test that the indices and the palette of an image are encoded unchanged
+    fn test_indexed() {
+        let palette = vec![
+            Rgba([0, 0, 0, 0]),
+            Rgba([255, 0, 0, 255]),
+            Rgba([0, 255, 0, 255]),
+            Rgba([0, 0, 255, 255]),
+        ];
+        let indices = (0..48).map(|i| (i % 7 % 4) as u8).collect();
+        let image = IndexedImage::from_parts(8, 6, indices, palette).unwrap();
+
+        let mut encoded = Vec::new();
+        Encoder::new_indexed(image.clone()).encode(&mut encoded).unwrap();
+
+        let decoded = GIFDecoder::new(io::Cursor::new(encoded)).read_indexed().unwrap();
+        assert_eq!(decoded, image);
+    }
+}
@@ -3,6 +3,55 @@
 use buffer::{ImageBuffer, Pixel};
 use image::GenericImage;
 
+/// The orientation of a stored image, as the orientation tag of TIFF and EXIF metadata describes it.
+/// Each variant names the transformation that turns the stored image upright.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Orientation {
+    /// The image is stored upright
+    Normal,
+
+    /// The image has to be flipped horizontally
+    FlipHorizontal,
+
+    /// The image has to be rotated 180 degrees
+    Rotate180,
+
+    /// The image has to be flipped vertically
+    FlipVertical,
+
+    /// The image has to be mirrored along its main diagonal,
+    /// by a rotation of 90 degrees clockwise and a horizontal flip
+    Transpose,
+
+    /// The image has to be rotated 90 degrees clockwise
+    Rotate90,
+
+    /// The image has to be mirrored along its other diagonal,
+    /// by a rotation of 270 degrees clockwise and a horizontal flip
+    Transverse,
+
+    /// The image has to be rotated 270 degrees clockwise
+    Rotate270,
+}
+
+impl Orientation {
+    /// Returns the orientation of the value of an orientation tag,
+    /// or ```None``` for values outside of 1 to 8
+    pub fn from_tag(value: u32) -> Option<Orientation> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::FlipHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::FlipVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None
+        }
+    }
+}
+
 /// Rotate an image 90 degrees clockwise.
 // TODO: Is the 'static bound on `I` really required? Can we avoid it?
 pub fn rotate90<I: GenericImage + 'static>(image:  &I)
@@ -67,7 +116,7 @@ pub fn flip_horizontal<I: GenericImage + 'static>(image:  &I)
     where I::Pixel: 'static,
           <I::Pixel as Pixel>::Subpixel: 'static {
     let (width, height) = image.dimensions();
-    let mut out = ImageBuffer::new(height, width);
+    let mut out = ImageBuffer::new(width, height);
 
     for y in (0..height) {
         for x in (0..width) {
@@ -97,3 +146,26 @@ pub fn flip_vertical<I: GenericImage + 'static>(image:  &I)
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+
+    use image::GenericImage;
+    use buffer::ImageBuffer;
+    use color::Luma;
+    use super::{flip_horizontal, rotate90};
+
+    #[test]
+    /// Test that transforming an image that is not square moves its pixels correctly
+    fn test_non_square_transforms() {
+        let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::from_raw(3, 2, vec![0, 1, 2, 3, 4, 5]).unwrap();
+
+        let flipped = flip_horizontal(&image);
+        assert_eq!(flipped.dimensions(), (3, 2));
+        assert_eq!(flipped.into_raw(), vec![2, 1, 0, 5, 4, 3]);
+
+        let rotated = rotate90(&image);
+        assert_eq!(rotated.dimensions(), (2, 3));
+        assert_eq!(rotated.into_raw(), vec![3, 0, 4, 1, 5, 2]);
+    }
+}
@@ -20,6 +20,7 @@ pub use self::sample::FilterType:: {
 
 /// Affine transformations
 pub use self::affine:: {
+    Orientation,
     rotate90,
     rotate180,
     rotate270,
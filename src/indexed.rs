@@ -0,0 +1,77 @@
+use buffer::{ImageBuffer, RgbaImage};
+use color::Rgba;
+
+/// An image of palette indices with the palette they index.
+///
+/// Decoders of paletted formats can return the indices unchanged,
+/// so that they can be encoded again without quantizing the colors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexedImage {
+    width: u32,
+    height: u32,
+    indices: Vec<u8>,
+    palette: Vec<Rgba<u8>>,
+}
+
+impl IndexedImage {
+    /// Contructs an image from ```width * height``` indices in row major order
+    /// and a palette of at most 256 colors.
+    /// Returns ```None``` if the number of indices does not match the dimensions
+    /// or an index is not in the palette.
+    pub fn from_parts(width: u32, height: u32, indices: Vec<u8>, palette: Vec<Rgba<u8>>)
+        -> Option<IndexedImage> {
+
+        if indices.len() != width as usize * height as usize || palette.len() > 256 ||
+           indices.iter().any(|&i| i as usize >= palette.len()) {
+            return None
+        }
+
+        Some(IndexedImage {
+            width: width,
+            height: height,
+            indices: indices,
+            palette: palette
+        })
+    }
+
+    /// The width of the image
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// The height of the image
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The width and height of the image
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// The palette index of each pixel in row major order
+    pub fn indices(&self) -> &[u8] {
+        &self.indices
+    }
+
+    /// The colors of the palette
+    pub fn palette(&self) -> &[Rgba<u8>] {
+        &self.palette
+    }
+
+    /// Returns the indices and the palette
+    pub fn into_parts(self) -> (Vec<u8>, Vec<Rgba<u8>>) {
+        (self.indices, self.palette)
+    }
+
+    /// Replaces every index with the color of its palette entry
+    pub fn to_rgba(&self) -> RgbaImage {
+        let mut data = Vec::with_capacity(4 * self.indices.len());
+
+        for &i in self.indices.iter() {
+            data.push_all(&self.palette[i as usize].0);
+        }
+
+        ImageBuffer::from_vec(self.width, self.height, data).unwrap()
+    }
+}
@@ -0,0 +1,392 @@
+//! Lossless transformations of JPEG images
+//!
+//! The quantized DCT coefficients of an image can be rotated, flipped and cropped
+//! without decoding them to pixels, so that the image loses no quality when it is encoded again.
+//! Only whole MCUs can be moved, transformations that would move a partial MCU to the
+//! top or left of the image drop the partial MCUs at its edge, like ```jpegtran -trim``` does.
+
+use std::cmp;
+use std::iter::repeat;
+
+use image;
+use image::ImageResult;
+use imageops::Orientation;
+
+/// The quantized DCT coefficients of a component of a JPEG image
+#[derive(Clone, Debug)]
+pub struct ComponentCoefficients {
+    /// The component's identifier
+    pub id: u8,
+
+    /// Horizontal sampling factor
+    pub h: u8,
+
+    /// Vertical sampling factor
+    pub v: u8,
+
+    /// The quantization table of the component in row major order
+    pub qtable: Vec<u8>,
+
+    /// The number of blocks in a row, the blocks cover whole MCUs of the image
+    pub blocks_per_row: usize,
+
+    /// The coefficients of every block in row major order, the blocks are stored row by row
+    pub blocks: Vec<i32>,
+}
+
+/// The quantized DCT coefficients of a JPEG image
+#[derive(Clone, Debug)]
+pub struct Coefficients {
+    /// The width of the image
+    pub width: u32,
+
+    /// The height of the image
+    pub height: u32,
+
+    /// The components in the order of the frame header
+    pub components: Vec<ComponentCoefficients>,
+
+    /// The transform flag of the Adobe APP14 segment, which tells the color space of the components
+    pub adobe_transform: Option<u8>,
+}
+
+impl ComponentCoefficients {
+    // The number of block rows of the component
+    fn blocks_per_column(&self) -> usize {
+        self.blocks.len() / (64 * self.blocks_per_row)
+    }
+
+    fn block(&self, bx: usize, by: usize) -> &[i32] {
+        let offset = (by * self.blocks_per_row + bx) * 64;
+        &self.blocks[offset..offset + 64]
+    }
+
+    // Returns a copy of the component with blocks_per_row x rows blocks, where the block at (bx, by)
+    // is the block at source(bx, by) with every coefficient at (u, v) moved to position(u, v)
+    // and negated if negate(u, v) is true
+    fn map_blocks<S, P, N>(&self, blocks_per_row: usize, rows: usize, source: S, position: P, negate: N)
+        -> ComponentCoefficients where S: Fn(usize, usize) -> (usize, usize),
+                                       P: Fn(usize, usize) -> (usize, usize),
+                                       N: Fn(usize, usize) -> bool {
+
+        let mut blocks = repeat(0i32).take(blocks_per_row * rows * 64).collect::<Vec<i32>>();
+
+        for by in (0..rows) {
+            for bx in (0..blocks_per_row) {
+                let (sx, sy) = source(bx, by);
+                let block = self.block(sx, sy);
+                let out = &mut blocks[(by * blocks_per_row + bx) * 64..][..64];
+
+                for v in (0..8) {
+                    for u in (0..8) {
+                        let (x, y) = position(u, v);
+                        let coefficient = block[v * 8 + u];
+
+                        out[y * 8 + x] = if negate(x, y) { -coefficient } else { coefficient };
+                    }
+                }
+            }
+        }
+
+        ComponentCoefficients {
+            blocks_per_row: blocks_per_row,
+            blocks: blocks,
+            ..self.clone()
+        }
+    }
+}
+
+impl Coefficients {
+    /// Transforms the image to the orientation it has to be turned to,
+    /// see ```DynamicImage::orient```.
+    pub fn orient(&self, orientation: Orientation) -> ImageResult<Coefficients> {
+        match orientation {
+            Orientation::Normal         => Ok(self.clone()),
+            Orientation::FlipHorizontal => self.fliph(),
+            Orientation::Rotate180      => self.rotate180(),
+            Orientation::FlipVertical   => self.flipv(),
+            Orientation::Transpose      => Ok(self.transpose()),
+            Orientation::Rotate90       => self.rotate90(),
+            Orientation::Transverse     => try!(self.transpose().fliph()).flipv(),
+            Orientation::Rotate270      => self.rotate270(),
+        }
+    }
+
+    /// Rotates the image 90 degrees clockwise, dropping a partial MCU row at the bottom.
+    pub fn rotate90(&self) -> ImageResult<Coefficients> {
+        self.transpose().fliph()
+    }
+
+    /// Rotates the image 180 degrees, dropping partial MCUs at the right and the bottom.
+    pub fn rotate180(&self) -> ImageResult<Coefficients> {
+        try!(self.fliph()).flipv()
+    }
+
+    /// Rotates the image 270 degrees clockwise, dropping a partial MCU column at the right.
+    pub fn rotate270(&self) -> ImageResult<Coefficients> {
+        self.transpose().flipv()
+    }
+
+    /// Mirrors the image along its main diagonal, the sampling factors of the components are swapped.
+    pub fn transpose(&self) -> Coefficients {
+        let components = self.components.iter().map(|c| {
+            let mut t = c.map_blocks(c.blocks_per_column(), c.blocks_per_row,
+                                     |bx, by| (by, bx),
+                                     |u, v| (v, u),
+                                     |_, _| false);
+
+            t.h = c.v;
+            t.v = c.h;
+            t.qtable = (0..64).map(|i| c.qtable[(i % 8) * 8 + i / 8]).collect();
+            t
+        }).collect();
+
+        Coefficients {
+            width: self.height,
+            height: self.width,
+            components: components,
+            adobe_transform: self.adobe_transform,
+        }
+    }
+
+    /// Flips the image horizontally, dropping a partial MCU column at the right.
+    pub fn fliph(&self) -> ImageResult<Coefficients> {
+        let (mcu_width, _) = self.mcu_size();
+        let mcus = self.width / mcu_width;
+
+        if mcus == 0 {
+            return Err(image::ImageError::DimensionError)
+        }
+
+        let components = self.components.iter().map(|c| {
+            let blocks_per_row = mcus as usize * c.h as usize;
+
+            // Odd horizontal frequencies change their sign
+            c.map_blocks(blocks_per_row, c.blocks_per_column(),
+                         |bx, by| (blocks_per_row - 1 - bx, by),
+                         |u, v| (u, v),
+                         |u, _| u % 2 == 1)
+        }).collect();
+
+        Ok(Coefficients {
+            width: mcus * mcu_width,
+            height: self.height,
+            components: components,
+            adobe_transform: self.adobe_transform,
+        })
+    }
+
+    /// Flips the image vertically, dropping a partial MCU row at the bottom.
+    pub fn flipv(&self) -> ImageResult<Coefficients> {
+        let (_, mcu_height) = self.mcu_size();
+        let mcus = self.height / mcu_height;
+
+        if mcus == 0 {
+            return Err(image::ImageError::DimensionError)
+        }
+
+        let components = self.components.iter().map(|c| {
+            let rows = mcus as usize * c.v as usize;
+
+            // Odd vertical frequencies change their sign
+            c.map_blocks(c.blocks_per_row, rows,
+                         |bx, by| (bx, rows - 1 - by),
+                         |u, v| (u, v),
+                         |_, v| v % 2 == 1)
+        }).collect();
+
+        Ok(Coefficients {
+            width: self.width,
+            height: mcus * mcu_height,
+            components: components,
+            adobe_transform: self.adobe_transform,
+        })
+    }
+
+    /// Crops the rectangle of the image at (```x```, ```y```) with dimensions ```width``` x ```height```.
+    /// The top left corner is moved up and left to the nearest MCU boundary,
+    /// and the rectangle is clipped to the image.
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> ImageResult<Coefficients> {
+        let (mcu_width, mcu_height) = self.mcu_size();
+
+        if x >= self.width || y >= self.height || width == 0 || height == 0 {
+            return Err(image::ImageError::DimensionError)
+        }
+
+        let (mcux, mcuy) = (x / mcu_width, y / mcu_height);
+
+        let width  = cmp::min(width + x % mcu_width, self.width - mcux * mcu_width);
+        let height = cmp::min(height + y % mcu_height, self.height - mcuy * mcu_height);
+
+        let mcus_per_row    = (width + mcu_width - 1) / mcu_width;
+        let mcus_per_column = (height + mcu_height - 1) / mcu_height;
+
+        let components = self.components.iter().map(|c| {
+            let (h, v) = (c.h as usize, c.v as usize);
+            let (x0, y0) = (mcux as usize * h, mcuy as usize * v);
+
+            c.map_blocks(mcus_per_row as usize * h, mcus_per_column as usize * v,
+                         |bx, by| (x0 + bx, y0 + by),
+                         |u, v| (u, v),
+                         |_, _| false)
+        }).collect();
+
+        Ok(Coefficients {
+            width: width,
+            height: height,
+            components: components,
+            adobe_transform: self.adobe_transform,
+        })
+    }
+
+    // The width and height of an MCU in pixels
+    fn mcu_size(&self) -> (u32, u32) {
+        let (hmax, vmax) = self.components.iter().fold((1, 1), |(h, v), c| {
+            (cmp::max(h, c.h), cmp::max(v, c.v))
+        });
+
+        (8 * hmax as u32, 8 * vmax as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io;
+    use std::iter::repeat;
+    use color;
+    use image::{ImageDecoder, DecodingResult};
+    use super::{Coefficients, ComponentCoefficients};
+    use super::super::{JPEGEncoder, JPEGDecoder};
+
+    // A gray image of 3 x 2 blocks, where the DC coefficient of each block is its index
+    // and the coefficient at (1, 0) is 1
+    fn test_coefficients() -> Coefficients {
+        let mut blocks: Vec<i32> = repeat(0).take(6 * 64).collect();
+
+        for i in (0..6) {
+            blocks[i * 64] = i as i32;
+            blocks[i * 64 + 1] = 1;
+        }
+
+        Coefficients {
+            width: 20,
+            height: 16,
+            components: vec![ComponentCoefficients {
+                id: 1,
+                h: 1,
+                v: 1,
+                qtable: (0..64).map(|i| i as u8 + 1).collect(),
+                blocks_per_row: 3,
+                blocks: blocks,
+            }],
+            adobe_transform: None,
+        }
+    }
+
+    #[test]
+    /// Test that the blocks are moved and their coefficients are transformed
+    fn test_lossless_transforms() {
+        let c = test_coefficients();
+
+        // The partial MCU column is dropped
+        let flipped = c.fliph().unwrap();
+        assert_eq!((flipped.width, flipped.height), (16, 16));
+        let blocks = &flipped.components[0].blocks;
+        assert_eq!((blocks[0], blocks[1]), (1, -1));
+        assert_eq!((blocks[64], blocks[65]), (0, -1));
+        assert_eq!(blocks[2 * 64], 4);
+
+        let rotated = c.rotate90().unwrap();
+        assert_eq!((rotated.width, rotated.height), (16, 20));
+        let component = &rotated.components[0];
+        assert_eq!(component.blocks_per_row, 2);
+        // The top left block was the bottom left block, (1, 0) was moved to (0, 1)
+        assert_eq!((component.blocks[0], component.blocks[8]), (3, 1));
+        assert_eq!((component.blocks[64], component.blocks[72]), (0, 1));
+        assert_eq!(component.qtable[1], 9);
+
+        let cropped = c.crop(10, 9, 4, 4).unwrap();
+        assert_eq!((cropped.width, cropped.height), (6, 5));
+        assert_eq!(cropped.components[0].blocks_per_row, 1);
+        assert_eq!(cropped.components[0].blocks[0], 4);
+
+        assert!(c.crop(20, 0, 1, 1).is_err());
+    }
+
+    fn decode(data: Vec<u8>) -> Vec<u8> {
+        match JPEGDecoder::new(io::Cursor::new(data)).read_image().unwrap() {
+            DecodingResult::U8(decoded) => decoded,
+            _ => panic!("unexpected bit depth")
+        }
+    }
+
+    // Encodes the coefficients and returns the coefficients and the pixels that are decoded again
+    fn roundtrip(coefficients: &Coefficients) -> (Coefficients, Vec<u8>) {
+        let mut encoded = Vec::new();
+        JPEGEncoder::new(&mut encoded).encode_coefficients(coefficients).unwrap();
+
+        let decoded = JPEGDecoder::new(io::Cursor::new(encoded.clone())).read_coefficients().unwrap();
+        (decoded, decode(encoded))
+    }
+
+    #[test]
+    /// Test that cropped, flipped and transposed images are encoded without loss
+    /// and decode to the transformed pixels of the original image
+    fn test_lossless_roundtrips() {
+        let (width, height) = (32u32, 16u32);
+        let image: Vec<u8> = (0..width * height * 3).map(|i| {
+            let p = i / 3;
+            match i % 3 {
+                0 => (p % width * 6) as u8,
+                1 => (p / width * 10) as u8,
+                _ => 128,
+            }
+        }).collect();
+
+        // The MCUs of the 4:2:0 image are 16 x 16 pixels
+        let mut encoded = Vec::new();
+        JPEGEncoder::new_with_quality(&mut encoded, 90).encode(&image, width, height, color::ColorType::RGB(8)).unwrap();
+
+        let original = decode(encoded.clone());
+        let c = JPEGDecoder::new(io::Cursor::new(encoded)).read_coefficients().unwrap();
+
+        // The transformed image and its dimensions, the offset of the crop,
+        // whether it is flipped horizontally and vertically and whether it is transposed
+        let transforms = [
+            (c.crop(16, 0, 16, 16).unwrap(), (16, 16), (16, 0), false, false, false),
+            // The corner is moved left to (16, 0), the rectangle grows by the distance
+            (c.crop(20, 3, 9, 10).unwrap(), (13, 13), (16, 0), false, false, false),
+            (c.crop(4, 5, 100, 100).unwrap(), (32, 16), (0, 0), false, false, false),
+            (c.fliph().unwrap(), (32, 16), (0, 0), true, false, false),
+            (c.flipv().unwrap(), (32, 16), (0, 0), false, true, false),
+            (c.transpose(), (16, 32), (0, 0), false, false, true),
+        ];
+
+        for &(ref transformed, (w, h), (x0, y0), mirror_h, mirror_v, swap) in transforms.iter() {
+            let (coefficients, decoded) = roundtrip(transformed);
+
+            assert_eq!((coefficients.width, coefficients.height), (w, h));
+            for (a, b) in coefficients.components.iter().zip(transformed.components.iter()) {
+                assert_eq!((a.h, a.v, &a.qtable, &a.blocks), (b.h, b.v, &b.qtable, &b.blocks));
+            }
+
+            assert_eq!(decoded.len(), (w * h * 3) as usize);
+            for y in (0..h) {
+                for x in (0..w) {
+                    // The position of the pixel in the original image
+                    let (tx, ty) = if swap { (y, x) } else { (x, y) };
+                    let sx = if mirror_h { width - 1 - tx } else { x0 + tx };
+                    let sy = if mirror_v { height - 1 - ty } else { y0 + ty };
+
+                    let i = ((y * w + x) * 3) as usize;
+                    let j = ((sy * width + sx) * 3) as usize;
+
+                    for k in (0..3) {
+                        assert!((decoded[i + k] as i32 - original[j + k] as i32).abs() <= 4);
+                    }
+                }
+            }
+        }
+    }
+}
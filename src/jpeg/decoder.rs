@@ -1,19 +1,21 @@
 use std::cmp;
+use std::mem;
 use std::slice;
 use std::io::Read;
 use std::default::Default;
 use std::collections::vec_map::VecMap;
-use std::num::Float;
 use std::iter::repeat;
 use std::num::wrapping::WrappingOps;
 use byteorder::{ReadBytesExt, BigEndian};
 
 use color;
 use super::transform;
+use super::coefficients::{Coefficients, ComponentCoefficients};
 
 use super::entropy:: {
     HuffTable,
     HuffDecoder,
+    ArithDecoder,
     derive_tables,
 };
 
@@ -21,6 +23,7 @@ use image;
 use image::ImageResult;
 use image::ImageDecoder;
 use math::utils::clamp;
+use tiff::Exif;
 
 /// The permutation of dct coefficients.
 pub static UNZIGZAG: [u8; 64] = [
@@ -62,10 +65,20 @@ pub struct Component {
 // Markers
 // Baseline DCT
 const SOF0: u8 = 0xC0;
+// Extended sequential DCT
+const SOF1: u8 = 0xC1;
 // Progressive DCT
 const SOF2: u8 = 0xC2;
+// Lossless (sequential)
+const SOF3: u8 = 0xC3;
 // Huffman Tables
 const DHT: u8 = 0xC4;
+
+// Arithmetic coding
+const SOF9: u8 = 0xC9;
+const SOF10: u8 = 0xCA;
+const DAC: u8 = 0xCC;
+
 // Restart Interval start and End (standalone)
 const RST0: u8 = 0xD0;
 const RST7: u8 = 0xD7;
@@ -83,53 +96,113 @@ const DNL: u8 = 0xDC;
 const DRI: u8 = 0xDD;
 // Application segments start and end
 const APP0: u8 = 0xE0;
+const APP1: u8 = 0xE1;
+const APP14: u8 = 0xEE;
 const APPF: u8 = 0xEF;
 // Comment
 const COM: u8 = 0xFE;
 // Reserved
 const TEM: u8 = 0x01;
 
+// The color spaces of the decoded components
+#[derive(Copy, PartialEq)]
+enum ColorTransform {
+    Gray,
+    RGB,
+    YCbCr,
+    CMYK,
+    // CMYK written by Adobe applications, where 0 is the full amount of ink
+    InvertedCMYK,
+    // Adobe CMYK with the inverted C, M and Y components converted to YCbCr
+    YCCK,
+}
+
 #[derive(PartialEq)]
 enum JPEGState {
     Start,
     HaveSOI,
     HaveFirstFrame,
     HaveFirstScan,
-    #[allow(dead_code)]
     End
 }
 
 /// The representation of a JPEG decoder
 ///
-/// Does not support decoding progressive JPEG images
+/// Decodes baseline, extended, progressive and lossless JPEG images,
+/// sequential and progressive images may be arithmetic coded.
+/// Images with samples of more than 8 bits are decoded to ```DecodingResult::U16```
+/// with samples in their original range, the color type tells their precision.
 pub struct JPEGDecoder<R> {
     r: R,
 
-    qtables: [u8; 64 * 4],
-    dctables: [HuffTable; 2],
-    actables: [HuffTable; 2],
+    qtables: [u16; 64 * 4],
+    dctables: [HuffTable; 4],
+    actables: [HuffTable; 4],
 
     h: HuffDecoder,
 
+    // Whether the scans are arithmetic coded, instead of huffman coded
+    arithmetic: bool,
+    arith: ArithDecoder,
+
     height: u16,
     width: u16,
+    precision: u8,
+
+    // Baseline frames only have two huffman tables of each class
+    baseline: bool,
 
     num_components: u8,
+    frame_components: Vec<u8>,
+
+    // The transform flag of the Adobe APP14 segment
+    adobe_transform: Option<u8>,
+
+    // Whether four component images are returned as CMYK instead of RGB
+    raw_cmyk: bool,
+
+    // The TIFF structure of the first EXIF APP1 segment
+    exif: Option<Vec<u8>>,
+
     scan_components: Vec<u8>,
     components: VecMap<Component>,
 
+    // The spectral selection and successive approximation of the current scan
+    spectral_start: u8,
+    spectral_end: u8,
+    approx_high: u8,
+    approx_low: u8,
+
+    // The quantized coefficients of each component of a progressive or arithmetic coded image
+    // or an image whose coefficients were read, they are only transformed once all scans are decoded
+    progressive: bool,
+    buffered: bool,
+    coefficients: VecMap<Vec<i32>>,
+    eobrun: u32,
+
+    // The coefficients of a lossless image are the samples of its components
+    lossless: bool,
+
+    // The pixels of an image that is decoded at once, because its samples
+    // have more than 8 bits or it is lossless
+    samples: Vec<u16>,
+
     mcu_row: Vec<u8>,
     mcu: Vec<u8>,
     hmax: u8,
     vmax: u8,
 
     interval: u16,
-    mcucount: u16,
+    mcucount: u32,
     expected_rst: u8,
 
     row_count: u8,
     decoded_rows: u32,
     padded_width: usize,
+
+    // The number of samples in a row and a column of a decoded block,
+    // smaller than 8 when the image is scaled down
+    block_size: usize,
     state: JPEGState,
 }
 
@@ -141,19 +214,44 @@ impl<R: Read>JPEGDecoder<R> {
         JPEGDecoder {
             r: r,
 
-            qtables: [0u8; 64 * 4],
-            dctables: [h.clone(), h.clone()],
-            actables: [h.clone(), h.clone()],
+            qtables: [0u16; 64 * 4],
+            dctables: [h.clone(), h.clone(), h.clone(), h.clone()],
+            actables: [h.clone(), h.clone(), h.clone(), h.clone()],
 
             h: HuffDecoder::new(),
 
+            arithmetic: false,
+            arith: ArithDecoder::new(),
+
             height: 0,
             width: 0,
+            precision: 8,
+
+            baseline: false,
 
             num_components: 0,
+            frame_components: Vec::new(),
+
+            adobe_transform: None,
+            raw_cmyk: false,
+            exif: None,
+
             scan_components: Vec::new(),
             components: VecMap::new(),
 
+            spectral_start: 0,
+            spectral_end: 63,
+            approx_high: 0,
+            approx_low: 0,
+
+            progressive: false,
+            buffered: false,
+            coefficients: VecMap::new(),
+            eobrun: 0,
+
+            lossless: false,
+            samples: Vec::new(),
+
             mcu_row: Vec::new(),
             mcu: Vec::new(),
             hmax: 0,
@@ -166,16 +264,170 @@ impl<R: Read>JPEGDecoder<R> {
             row_count: 0,
             decoded_rows: 0,
             state: JPEGState::Start,
-            padded_width: 0
+            padded_width: 0,
+            block_size: 8,
+        }
+    }
+
+    /// Decodes the image at the smallest of the scales 1/8, 1/4, 1/2 and 1 where it is at least
+    /// ```min_width``` x ```min_height``` pixels large, using reduced inverse DCTs.
+    /// Returns the dimensions of the scaled image.
+    /// The scale can only be changed before the first scanline is read.
+    pub fn scale_to(&mut self, min_width: u32, min_height: u32) -> ImageResult<(u32, u32)> {
+        if self.state == JPEGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        if self.decoded_rows > 0 {
+            return Err(image::ImageError::UnsupportedError(
+                "The scale can not be changed while an image is decoded.".to_string()
+            ))
+        }
+
+        if self.high_precision() {
+            return Err(image::ImageError::UnsupportedError(
+                "Only 8 bit DCT images can be scaled.".to_string()
+            ))
+        }
+
+        let (width, height) = (self.width as u32, self.height as u32);
+
+        self.block_size = [1, 2, 4].iter().map(|&size| size).find(|&size| {
+            (width * size + 7) / 8 >= min_width && (height * size + 7) / 8 >= min_height
+        }).unwrap_or(8) as usize;
+
+        self.allocate_mcu_row();
+        self.dimensions()
+    }
+
+    /// Decodes the quantized DCT coefficients of the image, without transforming them to pixels.
+    /// The coefficients have to be read before any scanline.
+    pub fn read_coefficients(&mut self) -> ImageResult<Coefficients> {
+        if self.state == JPEGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        if self.decoded_rows > 0 {
+            return Err(image::ImageError::UnsupportedError(
+                "The coefficients can not be read while an image is decoded.".to_string()
+            ))
+        }
+
+        if self.high_precision() || self.qtables.iter().any(|&q| q > 255) {
+            return Err(image::ImageError::UnsupportedError(
+                "Only the coefficients of 8 bit DCT images can be read.".to_string()
+            ))
+        }
+
+        if self.state == JPEGState::HaveFirstScan {
+            let _ = try!(self.read_coefficient_scans());
+        }
+
+        // Scanlines are reconstructed from the coefficients from now on
+        self.buffered = true;
+
+        let (mcus_per_row, _) = self.mcus();
+
+        let components = self.frame_components.iter().map(|id| {
+            let c = self.components.get(&(*id as usize)).unwrap();
+            let qtable = &self.qtables[64 * c.tq as usize..64 * c.tq as usize + 64];
+
+            let mut natural = repeat(0u8).take(64).collect::<Vec<u8>>();
+            for k in (0usize..64) {
+                natural[UNZIGZAG[k] as usize] = qtable[k] as u8;
+            }
+
+            ComponentCoefficients {
+                id: c.id,
+                h: c.h,
+                v: c.v,
+                qtable: natural,
+                blocks_per_row: mcus_per_row * c.h as usize,
+                blocks: self.coefficients.get(&(*id as usize)).unwrap().clone(),
+            }
+        }).collect();
+
+        Ok(Coefficients {
+            width: self.width as u32,
+            height: self.height as u32,
+            components: components,
+            adobe_transform: self.adobe_transform,
+        })
+    }
+
+    /// Returns the EXIF metadata of the image, if it has any
+    pub fn exif(&mut self) -> ImageResult<Option<Exif>> {
+        if self.state == JPEGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        match self.exif {
+            Some(ref data) => Ok(Some(try!(Exif::from_bytes(data)))),
+            None => Ok(None)
+        }
+    }
+
+    /// Decodes a four component image to CMYK, without converting it to RGB.
+    /// The samples are the amount of ink, where 0 is no ink.
+    pub fn read_cmyk(&mut self) -> ImageResult<Vec<u8>> {
+        if self.state == JPEGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        if self.num_components != 4 {
+            return Err(image::ImageError::UnsupportedError(format!(
+                "An image with {} components is not a CMYK image",
+                self.num_components
+            )))
+        }
+
+        self.raw_cmyk = true;
+
+        match try!(self.read_image()) {
+            image::DecodingResult::U8(buf) => Ok(buf),
+            _ => unreachable!()
+        }
+    }
+
+    fn color_transform(&self) -> ColorTransform {
+        match (self.num_components, self.adobe_transform) {
+            (1, _)       => ColorTransform::Gray,
+            (3, Some(0)) => ColorTransform::RGB,
+            // Lossless images are rarely converted to YCbCr, which would not be lossless
+            (3, None) if self.lossless => ColorTransform::RGB,
+            (3, _)       => ColorTransform::YCbCr,
+            (_, Some(2)) => ColorTransform::YCCK,
+            (_, Some(_)) => ColorTransform::InvertedCMYK,
+            (_, None)    => ColorTransform::CMYK,
+        }
+    }
+
+    // The number of samples of a decoded pixel
+    fn output_components(&self) -> usize {
+        match self.num_components {
+            4 if !self.raw_cmyk => 3,
+            n => n as usize
         }
     }
 
     fn decode_mcu_row(&mut self) -> ImageResult<()> {
-        let bytesperpixel = self.num_components as usize;
+        let bytesperpixel = self.output_components();
+        let mcu_width = bytesperpixel * self.block_size * self.hmax as usize;
+        let mcuy = self.decoded_rows as usize / (self.block_size * self.vmax as usize);
+
+        let transform = self.color_transform();
+        let sampling: Vec<(u8, u8)> = self.frame_components.iter().map(|id| {
+            let c = self.components.get(&(*id as usize)).unwrap();
+            (c.h, c.v)
+        }).collect();
 
-        for x0 in (0 .. self.padded_width * bytesperpixel).step_by(bytesperpixel * 8 * self.hmax as usize) {
+        for x0 in (0 .. self.padded_width * bytesperpixel).step_by(mcu_width) {
 
-            let _ = try!(self.decode_mcu());
+            if self.buffered {
+                self.reconstruct_mcu(x0 / mcu_width, mcuy);
+            } else {
+                let _ = try!(self.decode_mcu());
+            }
 
             upsample_mcu (
                 &mut self.mcu_row,
@@ -183,8 +435,11 @@ impl<R: Read>JPEGDecoder<R> {
                 self.padded_width,
                 bytesperpixel,
                 &self.mcu,
+                self.block_size,
+                &sampling,
                 self.hmax,
-                self.vmax
+                self.vmax,
+                transform
             );
         }
 
@@ -208,7 +463,9 @@ impl<R: Read>JPEGDecoder<R> {
         }
 
         self.mcucount += 1;
-        self.read_restart()
+
+        let (mcus_per_row, mcus_per_column) = self.mcus();
+        self.read_restart((mcus_per_row * mcus_per_column) as u32)
     }
 
     fn decode_block(&mut self, i: usize, dc: u8, pred: i32, ac: u8, q: u8) -> ImageResult<i32> {
@@ -256,7 +513,7 @@ impl<R: Read>JPEGDecoder<R> {
             }
         }
 
-        transform::idct(&tmp, zz);
+        idct(&tmp, zz, self.block_size);
 
         Ok(dc)
     }
@@ -275,7 +532,15 @@ impl<R: Read>JPEGDecoder<R> {
                 SOI => self.state = JPEGState::HaveSOI,
                 DHT => try!(self.read_huffman_tables()),
                 DQT => try!(self.read_quantization_tables()),
-                SOF0 => {
+                APP1 => try!(self.read_exif_segment()),
+                APP14 => try!(self.read_adobe_segment()),
+                SOF0 ... SOF3 | SOF9 | SOF10 => {
+                    self.baseline = marker == SOF0;
+                    self.progressive = marker == SOF2 || marker == SOF10;
+                    self.lossless = marker == SOF3;
+                    self.arithmetic = marker == SOF9 || marker == SOF10;
+                    self.buffered = self.progressive || self.arithmetic;
+
                     let _ = try!(self.read_frame_header());
                     self.state = JPEGState::HaveFirstFrame;
                 }
@@ -284,13 +549,9 @@ impl<R: Read>JPEGDecoder<R> {
                     self.state = JPEGState::HaveFirstScan;
                 }
                 DRI => try!(self.read_restart_interval()),
-                APP0 ... APPF | COM => {
-                    let length = try!(self.r.read_u16::<BigEndian>());
-                    let mut buf = Vec::with_capacity((length - 2) as usize);
-                    try!(self.r.by_ref().take((length - 2) as u64).read_to_end(&mut buf));
-                }
+                DAC => try!(self.read_arithmetic_conditioning()),
+                APP0 ... APPF | COM => try!(self.skip_segment()),
                 TEM  => continue,
-                SOF2 => return Err(image::ImageError::UnsupportedError("Marker SOF2 ist not supported.".to_string())),
                 DNL  => return Err(image::ImageError::UnsupportedError("Marker DNL ist not supported.".to_string())),
                 marker => return Err(image::ImageError::FormatError(format!("Unkown marker {} encountered.", marker))),
             }
@@ -301,12 +562,19 @@ impl<R: Read>JPEGDecoder<R> {
 
     fn read_frame_header(&mut self) -> ImageResult<()> {
         let _frame_length = try!(self.r.read_u16::<BigEndian>());
-        let sample_precision = try!(self.r.read_u8());
+        self.precision = try!(self.r.read_u8());
+
+        // Section B.2.2
+        let supported = if self.lossless {
+            self.precision >= 2 && self.precision <= 16
+        } else {
+            self.precision == 8 || self.precision == 12
+        };
 
-        if sample_precision != 8 {
+        if !supported {
             return Err(image::ImageError::UnsupportedError(format!(
                 "A sample precision of {} is not supported",
-                sample_precision
+                self.precision
             )))
         }
 
@@ -318,15 +586,13 @@ impl<R: Read>JPEGDecoder<R> {
             return Err(image::ImageError::DimensionError)
         }
 
-        if self.num_components != 1 && self.num_components != 3 {
+        if self.num_components != 1 && self.num_components != 3 && self.num_components != 4 {
             return Err(image::ImageError::UnsupportedError(format!(
                 "Frames with {} components are not supported",
                 self.num_components
             )))
         }
 
-        self.padded_width = 8 * ((self.width as usize + 7) / 8);
-
         let num_components = self.num_components;
         self.read_frame_components(num_components)
     }
@@ -351,6 +617,7 @@ impl<R: Read>JPEGDecoder<R> {
 
             blocks_per_mcu += (hv >> 4) * (hv & 0x0F);
             self.components.insert(id as usize, c);
+            self.frame_components.push(id);
         }
 
         let (hmax, vmax) = self.components.iter().fold((0, 0), | (h, v), (_, c) | {
@@ -373,13 +640,26 @@ impl<R: Read>JPEGDecoder<R> {
         }
 
         self.mcu = repeat(0u8).take(blocks_per_mcu as usize * 64).collect::<Vec<u8>>();
+        self.allocate_mcu_row();
+
+        Ok(())
+    }
+
+    // Allocates the decoded rows of an MCU, which are padded to whole MCUs
+    fn allocate_mcu_row(&mut self) {
+        let (mcus_per_row, _) = self.mcus();
+        self.padded_width = mcus_per_row * self.block_size * self.hmax as usize;
 
-        let mcus_per_row = (self.width as f32 / (8 * hmax) as f32).ceil() as usize;
-        let mcu_row_len = (hmax as usize * vmax as usize) * self.mcu.len() * mcus_per_row;
+        let mcu_row_len = self.padded_width * self.num_components as usize * self.block_size * self.vmax as usize;
 
         self.mcu_row = repeat(0u8).take(mcu_row_len).collect::<Vec<u8>>();
+    }
 
-        Ok(())
+    // The dimensions of the decoded image, which are rounded up when it is scaled
+    fn output_size(&self) -> (u32, u32) {
+        let size = self.block_size as u32;
+
+        ((self.width as u32 * size + 7) / 8, (self.height as u32 * size + 7) / 8)
     }
 
     fn read_scan_header(&mut self) -> ImageResult<()> {
@@ -401,13 +681,35 @@ impl<R: Read>JPEGDecoder<R> {
             self.scan_components.push(id);
         }
 
-        let _spectral_end   = try!(self.r.read_u8());
-        let _spectral_start = try!(self.r.read_u8());
+        self.spectral_start = try!(self.r.read_u8());
+        self.spectral_end   = try!(self.r.read_u8());
 
         let approx = try!(self.r.read_u8());
 
-        let _approx_high = approx >> 4;
-        let _approx_low  = approx & 0x0F;
+        self.approx_high = approx >> 4;
+        self.approx_low  = approx & 0x0F;
+
+        if self.progressive && (self.spectral_end > 63 || self.spectral_start > self.spectral_end ||
+                                (self.spectral_start == 0 && self.spectral_end != 0) ||
+                                (self.spectral_start > 0 && num_scan_components != 1)) {
+            return Err(image::ImageError::FormatError("Invalid progressive scan.".to_string()))
+        }
+
+        if self.arithmetic && self.scan_components.iter().any(|id| {
+            let c = self.components.get(&(*id as usize)).unwrap();
+            c.dc_table > 3 || c.ac_table > 3
+        }) {
+            return Err(image::ImageError::FormatError("Invalid conditioning table.".to_string()))
+        }
+
+        let max_table = if self.baseline { 1 } else { 3 };
+
+        if !self.arithmetic && self.scan_components.iter().any(|id| {
+            let c = self.components.get(&(*id as usize)).unwrap();
+            c.dc_table > max_table || c.ac_table > max_table
+        }) {
+            return Err(image::ImageError::FormatError("Invalid huffman table.".to_string()))
+        }
 
         Ok(())
     }
@@ -421,17 +723,22 @@ impl<R: Read>JPEGDecoder<R> {
             let pq = pqtq >> 4;
             let tq = pqtq & 0x0F;
 
-            if pq != 0 || tq > 3 {
+            if pq > 1 || tq > 3 {
                 return Err(image::ImageError::FormatError("Quantization table malformed.".to_string()))
             }
 
             let slice = &mut self.qtables[64 * tq as usize..64 * tq as usize + 64];
 
+            // Images with 12 bit samples may have tables of 16 bit values
             for i in (0usize..64) {
-                slice[i] = try!(self.r.read_u8());
+                slice[i] = if pq == 0 {
+                    try!(self.r.read_u8()) as u16
+                } else {
+                    try!(self.r.read_u16::<BigEndian>())
+                };
             }
 
-            table_length -= 1 + 64;
+            table_length -= 1 + 64 * (pq as i32 + 1);
         }
 
         Ok(())
@@ -452,6 +759,12 @@ impl<R: Read>JPEGDecoder<R> {
                 )))
             }
 
+            if th > 3 || (self.baseline && th > 1) {
+                return Err(image::ImageError::FormatError(format!(
+                    "Invalid huffman table destination {}", th
+                )))
+            }
+
             let mut bits = Vec::with_capacity(16);
             try!(self.r.by_ref().take(16).read_to_end(&mut bits));
             let len = bits.len();
@@ -473,6 +786,32 @@ impl<R: Read>JPEGDecoder<R> {
     }
 
 
+    // The Adobe APP14 segment tells whether the components are RGB, YCbCr, CMYK or YCCK
+    fn read_adobe_segment(&mut self) -> ImageResult<()> {
+        let length = try!(self.read_segment_length());
+        let mut buf = Vec::with_capacity(length as usize);
+        try!(self.r.by_ref().take(length as u64).read_to_end(&mut buf));
+
+        if buf.len() >= 12 && &buf[..5] == b"Adobe" {
+            self.adobe_transform = Some(buf[11]);
+        }
+
+        Ok(())
+    }
+
+    fn read_exif_segment(&mut self) -> ImageResult<()> {
+        let length = try!(self.read_segment_length());
+        let mut buf = Vec::with_capacity(length as usize);
+        try!(self.r.by_ref().take(length as u64).read_to_end(&mut buf));
+
+        // APP1 is also used for XMP metadata
+        if self.exif.is_none() && buf.len() >= 6 && &buf[..6] == b"Exif\0\0" {
+            self.exif = Some(buf[6..].to_vec());
+        }
+
+        Ok(())
+    }
+
     fn read_restart_interval(&mut self) -> ImageResult<()> {
         let _length = try!(self.r.read_u16::<BigEndian>());
         self.interval = try!(self.r.read_u16::<BigEndian>());
@@ -480,13 +819,46 @@ impl<R: Read>JPEGDecoder<R> {
         Ok(())
     }
 
-    fn read_restart(&mut self) -> ImageResult<()> {
-        let w = (self.width + 7) / (self.hmax * 8) as u16;
-        let h = (self.height + 7) / (self.vmax * 8) as u16;
+    // Section B.2.4.3
+    // Reads the conditioning of the DC and AC statistics of arithmetic coded scans
+    fn read_arithmetic_conditioning(&mut self) -> ImageResult<()> {
+        let length = try!(self.read_segment_length());
+
+        for _ in (0..length / 2) {
+            let tables = try!(self.r.read_u8());
+            let value  = try!(self.r.read_u8());
+
+            let (class, table) = ((tables >> 4) as usize, (tables & 0x0F) as usize);
+
+            match class {
+                0 if table < 4 && value & 0x0F <= value >> 4 => {
+                    self.arith.dc_lower[table] = value & 0x0F;
+                    self.arith.dc_upper[table] = value >> 4;
+                }
+                1 if table < 4 && value >= 1 && value <= 63 => self.arith.ac_kx[table] = value,
+                _ => return Err(image::ImageError::FormatError(
+                    "Invalid arithmetic conditioning table.".to_string()
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    // The number of MCUs in a row and in a column of an interleaved scan,
+    // the MCUs of a lossless image consist of samples instead of blocks
+    fn mcus(&self) -> (usize, usize) {
+        let unit = if self.lossless {1} else {8};
+        let w = unit * self.hmax as usize;
+        let h = unit * self.vmax as usize;
+
+        ((self.width as usize + w - 1) / w, (self.height as usize + h - 1) / h)
+    }
 
+    fn read_restart(&mut self, num_mcus: u32) -> ImageResult<()> {
         if self.interval != 0  &&
-           self.mcucount % self.interval == 0 &&
-           self.mcucount < w * h {
+           self.mcucount % self.interval as u32 == 0 &&
+           self.mcucount < num_mcus {
 
             let rst = try!(self.find_restart_marker());
 
@@ -508,10 +880,7 @@ impl<R: Read>JPEGDecoder<R> {
     }
 
     fn find_restart_marker(&mut self) -> ImageResult<u8> {
-        if self.h.marker != 0 {
-            let m = self.h.marker;
-            self.h.marker = 0;
-
+        if let Some(m) = self.pending_marker() {
             return Ok(m);
         }
 
@@ -537,11 +906,630 @@ impl<R: Read>JPEGDecoder<R> {
         self.h.num_bits = 0;
         self.h.end = false;
         self.h.marker = 0;
+        self.arith.reset();
+        self.eobrun = 0;
 
         for (_, c) in self.components.iter_mut() {
             c.dc_pred = 0;
         }
     }
+
+    // Returns the length of the segment without its two length bytes
+    fn read_segment_length(&mut self) -> ImageResult<u16> {
+        let length = try!(self.r.read_u16::<BigEndian>());
+
+        if length < 2 {
+            return Err(image::ImageError::FormatError(format!(
+                "Invalid segment length {}", length
+            )))
+        }
+
+        Ok(length - 2)
+    }
+
+    fn skip_segment(&mut self) -> ImageResult<()> {
+        let length = try!(self.read_segment_length());
+        let mut buf = Vec::with_capacity(length as usize);
+        try!(self.r.by_ref().take(length as u64).read_to_end(&mut buf));
+
+        Ok(())
+    }
+
+    // Returns the marker that the entropy decoder read at the end of the scan, if any
+    fn pending_marker(&mut self) -> Option<u8> {
+        let marker = if self.arithmetic {
+            &mut self.arith.marker
+        } else {
+            &mut self.h.marker
+        };
+
+        match mem::replace(marker, 0) {
+            0 => None,
+            m => Some(m)
+        }
+    }
+
+    // Returns the next marker, which may have been read by the entropy decoder at the end of a scan
+    fn read_marker(&mut self) -> ImageResult<u8> {
+        if let Some(m) = self.pending_marker() {
+            return Ok(m)
+        }
+
+        loop {
+            if try!(self.r.read_u8()) != 0xFF {
+                continue
+            }
+
+            let mut marker = try!(self.r.read_u8());
+
+            // Markers may be preceded by any number of fill bytes
+            while marker == 0xFF {
+                marker = try!(self.r.read_u8());
+            }
+
+            if marker != 0 {
+                return Ok(marker)
+            }
+        }
+    }
+
+    // Decodes all scans of the image into the coefficients of its components,
+    // the tables can be redefined between the scans.
+    fn read_coefficient_scans(&mut self) -> ImageResult<()> {
+        let (mcus_per_row, mcus_per_column) = self.mcus();
+        let unit = if self.lossless {1} else {64};
+
+        for (id, c) in self.components.iter() {
+            let blocks = mcus_per_row * c.h as usize * mcus_per_column * c.v as usize;
+            self.coefficients.insert(id, repeat(0i32).take(blocks * unit).collect());
+        }
+
+        loop {
+            if self.lossless {
+                try!(self.decode_lossless_scan());
+            } else {
+                try!(self.decode_progressive_scan());
+            }
+
+            loop {
+                match try!(self.read_marker()) {
+                    DHT => try!(self.read_huffman_tables()),
+                    DQT => try!(self.read_quantization_tables()),
+                    DRI => try!(self.read_restart_interval()),
+                    DAC => try!(self.read_arithmetic_conditioning()),
+                    SOS => {
+                        try!(self.read_scan_header());
+                        break
+                    }
+                    EOI => {
+                        self.state = JPEGState::End;
+                        return Ok(())
+                    }
+                    APP0 ... APPF | COM => try!(self.skip_segment()),
+                    RST0 ... RST7 | TEM => continue,
+                    marker => return Err(image::ImageError::FormatError(format!(
+                        "Unkown marker {} encountered.", marker
+                    ))),
+                }
+            }
+        }
+    }
+
+    // Section G.1.2
+    // Sequential scans are decoded like progressive scans that code all coefficients at once.
+    fn decode_progressive_scan(&mut self) -> ImageResult<()> {
+        self.reset();
+        self.mcucount = 0;
+        self.expected_rst = RST0;
+
+        let (mcus_per_row, mcus_per_column) = self.mcus();
+        let ids = self.scan_components.clone();
+
+        if ids.len() == 1 {
+            // A scan of a single component is not interleaved,
+            // its MCUs are the blocks that cover the component.
+            let id = ids[0] as usize;
+            let (h, v) = {
+                let c = self.components.get(&id).unwrap();
+                (c.h as usize, c.v as usize)
+            };
+
+            let width  = (self.width as usize * h + self.hmax as usize - 1) / self.hmax as usize;
+            let height = (self.height as usize * v + self.vmax as usize - 1) / self.vmax as usize;
+            let blocks_per_row = (width + 7) / 8;
+            let blocks_per_column = (height + 7) / 8;
+            let line = mcus_per_row * h;
+
+            for by in (0..blocks_per_column) {
+                for bx in (0..blocks_per_row) {
+                    let mut c = *self.components.get(&id).unwrap();
+                    try!(self.decode_progressive_block(&mut c, (by * line + bx) * 64));
+                    self.components.insert(id, c);
+
+                    self.mcucount += 1;
+                    try!(self.read_restart((blocks_per_row * blocks_per_column) as u32));
+                }
+            }
+        } else {
+            for mcuy in (0..mcus_per_column) {
+                for mcux in (0..mcus_per_row) {
+                    for id in ids.iter() {
+                        let mut c = *self.components.get(&(*id as usize)).unwrap();
+                        let line = mcus_per_row * c.h as usize;
+
+                        for v in (0..c.v as usize) {
+                            for h in (0..c.h as usize) {
+                                let offset = ((mcuy * c.v as usize + v) * line + mcux * c.h as usize + h) * 64;
+                                try!(self.decode_progressive_block(&mut c, offset));
+                            }
+                        }
+
+                        self.components.insert(*id as usize, c);
+                    }
+
+                    self.mcucount += 1;
+                    try!(self.read_restart((mcus_per_row * mcus_per_column) as u32));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Decodes the part of the block at offset in the coefficients of c that is coded in the current scan
+    fn decode_progressive_block(&mut self, c: &mut Component, offset: usize) -> ImageResult<()> {
+        if self.arithmetic {
+            return self.decode_arithmetic_block(c, offset)
+        }
+
+        let coefficients = &mut self.coefficients.get_mut(&(c.id as usize)).unwrap()[offset..offset + 64];
+
+        let start = self.spectral_start as usize;
+        let end   = self.spectral_end as usize;
+        let low   = self.approx_low as usize;
+
+        // Section G.1.2.1
+        if start == 0 {
+            if self.approx_high == 0 {
+                let dctable = &self.dctables[c.dc_table as usize];
+                let t = try!(self.h.decode_symbol(&mut self.r, dctable));
+
+                let diff = if t > 0 {
+                    try!(self.h.receive(&mut self.r, t))
+                } else {
+                    0
+                };
+
+                c.dc_pred += extend(diff, t);
+                coefficients[0] = c.dc_pred << low;
+            } else if try!(self.h.read_bit(&mut self.r)) == 1 {
+                coefficients[0] |= 1 << low;
+            }
+
+            // The block of a sequential scan continues with its AC coefficients
+            if end == 0 {
+                return Ok(())
+            }
+        }
+
+        let actable = &self.actables[c.ac_table as usize];
+        let mut k = cmp::max(start, 1);
+
+        // Section G.1.2.2
+        if self.approx_high == 0 {
+            if self.eobrun > 0 {
+                self.eobrun -= 1;
+                return Ok(())
+            }
+
+            while k <= end {
+                let rs = try!(self.h.decode_symbol(&mut self.r, actable));
+
+                let ssss = rs & 0x0F;
+                let rrrr = rs >> 4;
+
+                if ssss == 0 {
+                    if rrrr < 15 {
+                        // The end of band run includes this block
+                        self.eobrun = (1 << rrrr as usize) - 1;
+
+                        if rrrr > 0 {
+                            self.eobrun += try!(self.h.receive(&mut self.r, rrrr)) as u32;
+                        }
+
+                        break
+                    }
+
+                    k += 16;
+                } else {
+                    k += rrrr as usize;
+
+                    if k > end {
+                        return Err(image::ImageError::FormatError("Invalid run length.".to_string()))
+                    }
+
+                    let t = try!(self.h.receive(&mut self.r, ssss));
+                    coefficients[UNZIGZAG[k] as usize] = extend(t, ssss) << low;
+                    k += 1;
+                }
+            }
+
+            return Ok(())
+        }
+
+        // Section G.1.2.3
+        // Coefficients that are already non-zero get a correction bit,
+        // new coefficients are 1 or -1 at this bit position.
+        let p1 = 1i32 << low;
+        let m1 = -1i32 << low;
+
+        if self.eobrun == 0 {
+            while k <= end {
+                let rs = try!(self.h.decode_symbol(&mut self.r, actable));
+
+                let ssss = rs & 0x0F;
+                let mut rrrr = rs as i32 >> 4;
+                let mut value = 0;
+
+                if ssss == 0 {
+                    if rrrr < 15 {
+                        self.eobrun = 1 << rrrr as usize;
+
+                        if rrrr > 0 {
+                            self.eobrun += try!(self.h.receive(&mut self.r, rrrr as u8)) as u32;
+                        }
+
+                        break
+                    }
+                } else {
+                    value = if try!(self.h.read_bit(&mut self.r)) == 1 { p1 } else { m1 };
+                }
+
+                // Skip rrrr zero coefficients, refining the non-zero ones on the way
+                while k <= end {
+                    let z = UNZIGZAG[k] as usize;
+
+                    if coefficients[z] != 0 {
+                        try!(refine(&mut self.h, &mut self.r, &mut coefficients[z], p1, m1));
+                    } else {
+                        if rrrr == 0 {
+                            break
+                        }
+
+                        rrrr -= 1;
+                    }
+
+                    k += 1;
+                }
+
+                if value != 0 {
+                    if k > end {
+                        return Err(image::ImageError::FormatError("Invalid run length.".to_string()))
+                    }
+
+                    coefficients[UNZIGZAG[k] as usize] = value;
+                }
+
+                k += 1;
+            }
+        }
+
+        if self.eobrun > 0 {
+            while k <= end {
+                let z = UNZIGZAG[k] as usize;
+
+                if coefficients[z] != 0 {
+                    try!(refine(&mut self.h, &mut self.r, &mut coefficients[z], p1, m1));
+                }
+
+                k += 1;
+            }
+
+            self.eobrun -= 1;
+        }
+
+        Ok(())
+    }
+
+    // Section G.1.3
+    // Decodes the part of an arithmetic coded block that is coded in the current scan,
+    // sequential scans code all coefficients at once.
+    fn decode_arithmetic_block(&mut self, c: &mut Component, offset: usize) -> ImageResult<()> {
+        let coefficients = &mut self.coefficients.get_mut(&(c.id as usize)).unwrap()[offset..offset + 64];
+
+        let start = self.spectral_start as usize;
+        let end   = self.spectral_end as usize;
+        let low   = self.approx_low as usize;
+
+        // Section G.1.3.1
+        if start == 0 {
+            if self.approx_high == 0 {
+                c.dc_pred += try!(self.arith.decode_dc_diff(&mut self.r, c.id, c.dc_table));
+                coefficients[0] = c.dc_pred << low;
+            } else if try!(self.arith.decode_fixed(&mut self.r)) {
+                coefficients[0] |= 1 << low;
+            }
+
+            if end == 0 {
+                return Ok(())
+            }
+        }
+
+        let table = c.ac_table;
+        let mut k = cmp::max(start, 1);
+
+        // Section G.1.3.2, Figure F.20
+        // Each position has an end of block decision, a zero decision and the bins of its value
+        if self.approx_high == 0 {
+            while k <= end {
+                if try!(self.arith.decode_ac(&mut self.r, table, 3 * (k - 1))) {
+                    break
+                }
+
+                while !try!(self.arith.decode_ac(&mut self.r, table, 3 * (k - 1) + 1)) {
+                    k += 1;
+
+                    if k > end {
+                        return Err(image::ImageError::FormatError("Invalid run length.".to_string()))
+                    }
+                }
+
+                let v = try!(self.arith.decode_ac_value(&mut self.r, table, k));
+                coefficients[UNZIGZAG[k] as usize] = v << low;
+                k += 1;
+            }
+
+            return Ok(())
+        }
+
+        // Section G.1.3.3
+        // There is no end of block decision before the end of block of the previous scans
+        let p1 = 1i32 << low;
+        let m1 = -1i32 << low;
+
+        let eob = (1..end + 1).rev().find(|&k| coefficients[UNZIGZAG[k] as usize] != 0).unwrap_or(0);
+
+        while k <= end {
+            if k > eob && try!(self.arith.decode_ac(&mut self.r, table, 3 * (k - 1))) {
+                break
+            }
+
+            loop {
+                let z = UNZIGZAG[k] as usize;
+
+                if coefficients[z] != 0 {
+                    if try!(self.arith.decode_ac(&mut self.r, table, 3 * (k - 1) + 2)) {
+                        coefficients[z] += if coefficients[z] < 0 { m1 } else { p1 };
+                    }
+
+                    break
+                }
+
+                if try!(self.arith.decode_ac(&mut self.r, table, 3 * (k - 1) + 1)) {
+                    coefficients[z] = if try!(self.arith.decode_fixed(&mut self.r)) { m1 } else { p1 };
+                    break
+                }
+
+                k += 1;
+
+                if k > end {
+                    return Err(image::ImageError::FormatError("Invalid run length.".to_string()))
+                }
+            }
+
+            k += 1;
+        }
+
+        Ok(())
+    }
+
+    // Section H.1.2
+    // Every sample of a lossless scan is coded as the difference to a prediction
+    // from its left, upper and upper left neighbours.
+    fn decode_lossless_scan(&mut self) -> ImageResult<()> {
+        self.reset();
+        self.mcucount = 0;
+        self.expected_rst = RST0;
+
+        let predictor = self.spectral_start;
+        let point_transform = self.approx_low as usize;
+
+        if predictor < 1 || predictor > 7 || point_transform >= self.precision as usize {
+            return Err(image::ImageError::FormatError("Invalid lossless scan.".to_string()))
+        }
+
+        // The first sample of a line without neighbours is predicted as the center of the range
+        let initial = 1i32 << (self.precision as usize - point_transform - 1);
+
+        let (mcus_per_row, mcus_per_column) = self.mcus();
+        let ids = self.scan_components.clone();
+
+        // A scan of a single component is not interleaved, it codes the samples of the component
+        // line by line. The sampling factors of the component are ignored then.
+        let (num_mcus, mcus_per_line) = if ids.len() == 1 {
+            let c = *self.components.get(&(ids[0] as usize)).unwrap();
+            let width  = (self.width as usize * c.h as usize + self.hmax as usize - 1) / self.hmax as usize;
+            let height = (self.height as usize * c.v as usize + self.vmax as usize - 1) / self.vmax as usize;
+
+            (width * height, width)
+        } else {
+            (mcus_per_row * mcus_per_column, mcus_per_row)
+        };
+
+        // The MCU row that starts the current restart interval
+        let mut first_row = 0;
+
+        for mcu in (0..num_mcus) {
+            let (mcux, mcuy) = (mcu % mcus_per_line, mcu / mcus_per_line);
+
+            if self.interval != 0 && mcu % self.interval as usize == 0 {
+                first_row = mcuy;
+            }
+
+            for id in ids.iter() {
+                let c = *self.components.get(&(*id as usize)).unwrap();
+                let (h, v) = if ids.len() == 1 { (1, 1) } else { (c.h as usize, c.v as usize) };
+                let line = mcus_per_row * c.h as usize;
+
+                for y in (mcuy * v..mcuy * v + v) {
+                    for x in (mcux * h..mcux * h + h) {
+                        let t = try!(self.h.decode_symbol(&mut self.r, &self.dctables[c.dc_table as usize]));
+
+                        let diff = match t {
+                            0  => 0,
+                            1 ... 15 => extend(try!(self.h.receive(&mut self.r, t)), t),
+                            16 => 32768,
+                            _  => return Err(image::ImageError::FormatError(
+                                "Invalid difference category.".to_string()
+                            ))
+                        };
+
+                        let samples = self.coefficients.get_mut(&(*id as usize)).unwrap();
+                        let prediction = predict(&samples[..], line, x, y, first_row * v, predictor, initial);
+
+                        // The samples are computed modulo 2^16
+                        samples[y * line + x] = (prediction + diff) & 0xFFFF;
+                    }
+                }
+            }
+
+            self.mcucount += 1;
+            try!(self.read_restart(num_mcus as u32));
+        }
+
+        for id in ids.iter() {
+            for sample in self.coefficients.get_mut(&(*id as usize)).unwrap().iter_mut() {
+                *sample <<= point_transform;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Whether the image is decoded at once into samples of up to 16 bits
+    fn high_precision(&self) -> bool {
+        self.lossless || self.precision != 8
+    }
+
+    // Decodes all scans of the image and converts the samples of its components to pixels
+    fn decode_high_precision(&mut self) -> ImageResult<()> {
+        if self.state == JPEGState::HaveFirstScan {
+            let _ = try!(self.read_coefficient_scans());
+        }
+
+        let transform = self.color_transform();
+
+        if transform != ColorTransform::Gray && transform != ColorTransform::RGB &&
+           transform != ColorTransform::YCbCr {
+            return Err(image::ImageError::UnsupportedError(
+                "Four component images are only supported with 8 bit DCT coding.".to_string()
+            ))
+        }
+
+        let (mcus_per_row, _) = self.mcus();
+
+        // The samples of every component and the number of samples in their rows
+        let planes: Vec<(Vec<i32>, usize)> = self.frame_components.iter().map(|id| {
+            let c = self.components.get(&(*id as usize)).unwrap();
+            let coefficients = self.coefficients.get(&(*id as usize)).unwrap();
+
+            if self.lossless {
+                (coefficients.clone(), mcus_per_row * c.h as usize)
+            } else {
+                let blocks_per_row = mcus_per_row * c.h as usize;
+                (self.reconstruct_plane(c, coefficients, blocks_per_row), blocks_per_row * 8)
+            }
+        }).collect();
+
+        let sampling: Vec<(usize, usize)> = self.frame_components.iter().map(|id| {
+            let c = self.components.get(&(*id as usize)).unwrap();
+            (c.h as usize, c.v as usize)
+        }).collect();
+
+        let (hmax, vmax) = (self.hmax as usize, self.vmax as usize);
+        let max = (1i32 << self.precision as usize) - 1;
+
+        self.samples.clear();
+
+        for y in (0..self.height as usize) {
+            for x in (0..self.width as usize) {
+                let mut pixel = [0i32; 3];
+
+                for (i, (&(ref plane, line), &(h, v))) in planes.iter().zip(sampling.iter()).enumerate() {
+                    pixel[i] = plane[(y * v / vmax) * line + x * h / hmax];
+                }
+
+                match transform {
+                    ColorTransform::Gray => self.samples.push(pixel[0] as u16),
+                    ColorTransform::YCbCr => {
+                        let (r, g, b) = ycbcr_to_rgb_samples(pixel[0], pixel[1], pixel[2], max);
+                        self.samples.push_all(&[r as u16, g as u16, b as u16]);
+                    }
+                    _ => self.samples.push_all(&[pixel[0] as u16, pixel[1] as u16, pixel[2] as u16]),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Dequantizes and transforms the blocks of a component with samples of more than 8 bits
+    fn reconstruct_plane(&self, c: &Component, coefficients: &[i32], blocks_per_row: usize) -> Vec<i32> {
+        let qtable = &self.qtables[64 * c.tq as usize..64 * c.tq as usize + 64];
+        let line = blocks_per_row * 8;
+
+        let mut plane = repeat(0i32).take(coefficients.len()).collect::<Vec<i32>>();
+        let mut samples = [0u16; 64];
+
+        for (i, block) in coefficients.chunks(64).enumerate() {
+            let mut tmp = [0i32; 64];
+
+            for k in (0usize..64) {
+                let z = UNZIGZAG[k] as usize;
+                tmp[z] = block[z] * qtable[k] as i32;
+            }
+
+            transform::idct_high_precision(&tmp, &mut samples, self.precision);
+
+            let (x0, y0) = ((i % blocks_per_row) * 8, (i / blocks_per_row) * 8);
+
+            for y in (0usize..8) {
+                for x in (0usize..8) {
+                    plane[(y0 + y) * line + x0 + x] = samples[y * 8 + x] as i32;
+                }
+            }
+        }
+
+        plane
+    }
+
+    // Dequantizes and transforms the blocks of the MCU at (mcux, mcuy) of a progressive image
+    fn reconstruct_mcu(&mut self, mcux: usize, mcuy: usize) {
+        let (mcus_per_row, _) = self.mcus();
+        let mut i = 0;
+
+        for id in self.frame_components.iter() {
+            let c = self.components.get(&(*id as usize)).unwrap();
+            let coefficients = self.coefficients.get(&(*id as usize)).unwrap();
+            let qtable = &self.qtables[64 * c.tq as usize..64 * c.tq as usize + 64];
+            let line = mcus_per_row * c.h as usize;
+
+            for v in (0..c.v as usize) {
+                for h in (0..c.h as usize) {
+                    let offset = ((mcuy * c.v as usize + v) * line + mcux * c.h as usize + h) * 64;
+                    let mut tmp = [0i32; 64];
+
+                    for k in (0usize..64) {
+                        let z = UNZIGZAG[k] as usize;
+                        tmp[z] = coefficients[offset + z] * qtable[k] as i32;
+                    }
+
+                    idct(&tmp, &mut self.mcu[i * 64..i * 64 + 64], self.block_size);
+                    i += 1;
+                }
+            }
+        }
+    }
 }
 
 impl<R: Read> ImageDecoder for JPEGDecoder<R> {
@@ -550,7 +1538,7 @@ impl<R: Read> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
-        Ok((self.width as u32, self.height as u32))
+        Ok(self.output_size())
     }
 
     fn colortype(&mut self) -> ImageResult<color::ColorType> {
@@ -558,10 +1546,12 @@ impl<R: Read> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
+        let bits = cmp::max(self.precision, 8);
+
         let ctype = if self.num_components == 1 {
-            color::ColorType::Gray(8)
+            color::ColorType::Gray(bits)
         } else {
-            color::ColorType::RGB(8)
+            color::ColorType::RGB(bits)
         };
 
         Ok(ctype)
@@ -572,9 +1562,13 @@ impl<R: Read> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
-        let len = self.width as usize * self.num_components as usize;
+        let len = self.output_size().0 as usize * self.output_components();
 
-        Ok(len)
+        if self.precision > 8 {
+            Ok(len * 2)
+        } else {
+            Ok(len)
+        }
     }
 
     fn read_scanline(&mut self, buf: &mut [u8]) -> ImageResult<u32> {
@@ -582,17 +1576,43 @@ impl<R: Read> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
+        if self.high_precision() {
+            if self.state != JPEGState::End {
+                let _ = try!(self.decode_high_precision());
+            }
+
+            // Samples of more than 8 bits are stored in big endian order
+            let len = self.width as usize * self.output_components();
+            let row = &self.samples[self.decoded_rows as usize * len..][..len];
+
+            for (i, &sample) in row.iter().enumerate() {
+                if self.precision > 8 {
+                    buf[2 * i] = (sample >> 8) as u8;
+                    buf[2 * i + 1] = sample as u8;
+                } else {
+                    buf[i] = (sample << (8 - self.precision) as usize) as u8;
+                }
+            }
+
+            self.decoded_rows += 1;
+            return Ok(self.decoded_rows)
+        }
+
+        if self.buffered && self.state == JPEGState::HaveFirstScan {
+            let _ = try!(self.read_coefficient_scans());
+        }
+
         if self.row_count == 0 {
             let _ = try!(self.decode_mcu_row());
         }
 
-        let len   = self.padded_width * self.num_components as usize;
+        let len   = self.padded_width * self.output_components();
         let slice = &self.mcu_row[self.row_count as usize * len..
         self.row_count as usize * len + buf.len()];
 
         slice::bytes::copy_memory(buf, slice);
 
-        self.row_count = (self.row_count + 1) % (self.vmax * 8);
+        self.row_count = (self.row_count + 1) % (self.vmax * self.block_size as u8);
         self.decoded_rows += 1;
 
         Ok(self.decoded_rows)
@@ -603,8 +1623,17 @@ impl<R: Read> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
+        if self.precision > 8 {
+            if self.state != JPEGState::End {
+                let _ = try!(self.decode_high_precision());
+            }
+
+            return Ok(image::DecodingResult::U16(self.samples.clone()))
+        }
+
         let row = try!(self.row_len());
-        let mut buf = repeat(0u8).take(row * self.height as usize).collect::<Vec<u8>>();
+        let height = self.output_size().1 as usize;
+        let mut buf = repeat(0u8).take(row * height).collect::<Vec<u8>>();
 
         for chunk in buf.chunks_mut(row) {
             let _len = try!(self.read_scanline(chunk));
@@ -614,62 +1643,162 @@ impl<R: Read> ImageDecoder for JPEGDecoder<R> {
     }
 }
 
-fn upsample_mcu(out: &mut [u8], xoffset: usize, width: usize, bpp: usize, mcu: &[u8], h: u8, v: u8) {
-    if mcu.len() == 64 {
-        for y in (0usize..8) {
-            for x in (0usize..8) {
-                out[xoffset + x + (y * width)] = mcu[x + y * 8]
+// Writes the pixels of an MCU to out, where the components are
+// upsampled to the largest sampling factors and converted to RGB.
+// Each block of the MCU holds size x size samples.
+fn upsample_mcu(out: &mut [u8],
+                xoffset: usize,
+                width: usize,
+                bpp: usize,
+                mcu: &[u8],
+                size: usize,
+                sampling: &[(u8, u8)],
+                hmax: u8,
+                vmax: u8,
+                transform: ColorTransform) {
+
+    let (hmax, vmax) = (hmax as usize, vmax as usize);
+
+    for y in (0..size * vmax) {
+        for x in (0..size * hmax) {
+            let mut samples = [0u8; 4];
+            let mut start = 0;
+
+            for (i, &(h, v)) in sampling.iter().enumerate() {
+                let (h, v) = (h as usize, v as usize);
+                let sx = x * h / hmax;
+                let sy = y * v / vmax;
+
+                samples[i] = mcu[start + ((sy / size) * h + sx / size) * 64 + (sy % size) * size + sx % size];
+                start += h * v * 64;
             }
+
+            let offset = y * width * bpp + xoffset + x * bpp;
+            convert_pixel(transform, &samples, &mut out[offset..offset + bpp]);
         }
-    } else {
-        let y_blocks = h * v;
+    }
+}
 
-        let y_blocks = &mcu[..y_blocks as usize * 64];
-        let cb = &mcu[y_blocks.len()..y_blocks.len() + 64];
-        let cr = &mcu[y_blocks.len() + cb.len()..];
+// Transforms the dequantized coefficients to a block of size x size samples
+fn idct(coefficients: &[i32], samples: &mut [u8], size: usize) {
+    match size {
+        8 => transform::idct(coefficients, samples),
+        4 => transform::idct4x4(coefficients, samples),
+        2 => transform::idct2x2(coefficients, samples),
+        _ => transform::idct1x1(coefficients, samples),
+    }
+}
 
-        let mut k = 0;
+// Converts the samples of a pixel to gray, RGB, or CMYK when out has room for four samples
+fn convert_pixel(transform: ColorTransform, samples: &[u8; 4], out: &mut [u8]) {
+    let cmyk = match transform {
+        ColorTransform::Gray => {
+            out[0] = samples[0];
+            return
+        }
 
-        for by in (0..v as usize) {
-            let y0 = by * 8;
+        ColorTransform::RGB => {
+            out[0] = samples[0];
+            out[1] = samples[1];
+            out[2] = samples[2];
+            return
+        }
 
-            for bx in (0..h as usize) {
-                let x0 = xoffset + bx * 8 * bpp;
+        ColorTransform::YCbCr => {
+            let (r, g, b) = ycbcr_to_rgb(samples[0], samples[1], samples[2]);
+            out[0] = r;
+            out[1] = g;
+            out[2] = b;
+            return
+        }
 
-                for y in (0usize..8) {
-                    for x in (0usize..8) {
-                        let (a, b, c) = (y_blocks[k * 64 + x + y * 8], cb[x + y * 8], cr[x + y * 8]);
-                        let (r, g, b) = ycbcr_to_rgb(a , b , c );
+        ColorTransform::CMYK => *samples,
 
-                        let offset = (y0 + y) * (width * bpp) + x0 + x * bpp;
-                        out[offset + 0] = r;
-                        out[offset + 1] = g;
-                        out[offset + 2] = b;
-                    }
-                }
+        ColorTransform::InvertedCMYK => [255 - samples[0], 255 - samples[1], 255 - samples[2], 255 - samples[3]],
 
-                k += 1;
-            }
+        // The converted components are the inverted C, M and Y of Adobe CMYK
+        ColorTransform::YCCK => {
+            let (r, g, b) = ycbcr_to_rgb(samples[0], samples[1], samples[2]);
+            [r, g, b, 255 - samples[3]]
         }
+    };
+
+    if out.len() == 4 {
+        out[0] = cmyk[0];
+        out[1] = cmyk[1];
+        out[2] = cmyk[2];
+        out[3] = cmyk[3];
+    } else {
+        let k = 255 - cmyk[3] as u32;
+
+        out[0] = (((255 - cmyk[0] as u32) * k + 127) / 255) as u8;
+        out[1] = (((255 - cmyk[1] as u32) * k + 127) / 255) as u8;
+        out[2] = (((255 - cmyk[2] as u32) * k + 127) / 255) as u8;
     }
 }
 
 fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> (u8, u8, u8) {
+    let (r, g, b) = ycbcr_to_rgb_samples(y as i32, cb as i32, cr as i32, 255);
+
+    (r as u8, g as u8, b as u8)
+}
+
+// Converts samples in the range [0, max], where the chroma samples are centered at (max + 1) / 2
+fn ycbcr_to_rgb_samples(y: i32, cb: i32, cr: i32, max: i32) -> (i32, i32, i32) {
+    let center = ((max + 1) / 2) as f32;
+
     let y = y as f32;
     let cr = cr as f32;
     let cb = cb as f32;
 
-    let r1 = y + 1.402f32 * (cr - 128f32) ;
-    let g1 = y - 0.34414f32 * (cb - 128f32) - 0.71414f32 * (cr - 128f32);
-    let b1 = y + 1.772f32 * (cb - 128f32);
+    let r1 = y + 1.402f32 * (cr - center) ;
+    let g1 = y - 0.34414f32 * (cb - center) - 0.71414f32 * (cr - center);
+    let b1 = y + 1.772f32 * (cb - center);
 
-    let r = clamp(r1 as i32, 0, 255) as u8;
-    let g = clamp(g1 as i32, 0, 255) as u8;
-    let b = clamp(b1 as i32, 0, 255) as u8;
+    let r = clamp(r1 as i32, 0, max);
+    let g = clamp(g1 as i32, 0, max);
+    let b = clamp(b1 as i32, 0, max);
 
     (r, g, b)
 }
 
+// Section H.1.2.1
+// Predicts the sample at (x, y) of a plane of samples with line samples per row. The first row
+// after the start of a scan or a restart has only left neighbours, the first column only upper ones.
+fn predict(samples: &[i32], line: usize, x: usize, y: usize, first_row: usize, predictor: u8, initial: i32) -> i32 {
+    if y == first_row {
+        return if x == 0 { initial } else { samples[y * line + x - 1] }
+    }
+
+    if x == 0 {
+        return samples[(y - 1) * line]
+    }
+
+    let ra = samples[y * line + x - 1];
+    let rb = samples[(y - 1) * line + x];
+    let rc = samples[(y - 1) * line + x - 1];
+
+    match predictor {
+        1 => ra,
+        2 => rb,
+        3 => rc,
+        4 => ra + rb - rc,
+        5 => ra + ((rb - rc) >> 1),
+        6 => rb + ((ra - rc) >> 1),
+        _ => (ra + rb) >> 1,
+    }
+}
+
+// Section G.1.2.3
+// Adds the correction bit of a non-zero coefficient in a refinement scan
+fn refine<R: Read>(h: &mut HuffDecoder, r: &mut R, coefficient: &mut i32, p1: i32, m1: i32) -> ImageResult<()> {
+    if try!(h.read_bit(r)) == 1 && *coefficient & p1 == 0 {
+        *coefficient += if *coefficient >= 0 { p1 } else { m1 };
+    }
+
+    Ok(())
+}
+
 // Section F.2.2.1
 // Figure F.12
 fn extend(v: i32, t: u8) -> i32 {
@@ -684,3 +1813,278 @@ let vt:
         v
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use std::cmp;
+    use std::io;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use color;
+    use image::{ImageDecoder, ImageError, DecodingResult};
+    use super::{JPEGDecoder, ColorTransform, convert_pixel};
+    use super::super::JPEGEncoder;
+
+    #[test]
+    /// Test that lossless images with 12 bit samples are decoded with their predictions.
+    /// The scans predict the samples from the left neighbour Ra and from the mean of Ra and the upper neighbour Rb.
+    fn test_lossless_decoding() {
+        let images = [
+            ("lossless_ra.jpg", (4u32, 2u32), vec![100u16, 110, 90, 4095, 100, 0, 50, 60]),
+            ("lossless_average.jpg", (5, 3), vec![100, 110, 90, 4095, 7, 100, 0, 50, 60, 3000, 1, 2049, 333, 4000, 0]),
+        ];
+
+        for &(name, dimensions, ref expected) in images.iter() {
+            let path: PathBuf = [".", "src", "jpeg", "testdata", name].iter().collect();
+            let mut decoder = JPEGDecoder::new(File::open(&path).unwrap());
+
+            assert_eq!(decoder.dimensions().unwrap(), dimensions);
+            assert_eq!(decoder.colortype().unwrap(), color::ColorType::Gray(12));
+
+            match decoder.read_image().unwrap() {
+                DecodingResult::U16(samples) => assert_eq!(&samples, expected),
+                _ => panic!("unexpected bit depth")
+            }
+        }
+    }
+
+    #[test]
+    /// Test that an extended image with 12 bit samples and a quantization table of 16 bit values
+    /// is transformed with enough precision. The 16 x 8 image has a horizontal cosine in its left block,
+    /// whose brightest samples are clamped, and a vertical cosine in its right block.
+    fn test_12bit_decoding() {
+        let path: PathBuf = [".", "src", "jpeg", "testdata", "dct_12bit.jpg"].iter().collect();
+        let mut decoder = JPEGDecoder::new(File::open(&path).unwrap());
+
+        assert_eq!(decoder.dimensions().unwrap(), (16, 8));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::Gray(12));
+
+        let samples = match decoder.read_image().unwrap() {
+            DecodingResult::U16(samples) => samples,
+            _ => panic!("unexpected bit depth")
+        };
+
+        let left: [u16; 8] = [4095, 4095, 3941, 3686, 3410, 3155, 2960, 2854];
+        let right: [u16; 8] = [691, 784, 954, 1177, 1419, 1642, 1812, 1905];
+
+        for y in (0usize..8) {
+            assert_eq!(&samples[y * 16..y * 16 + 8], &left[..]);
+            assert!(samples[y * 16 + 8..y * 16 + 16].iter().all(|&s| s == right[y]));
+        }
+    }
+
+    #[test]
+    /// Test that huffman tables beyond the four destinations, or the two of baseline images, are rejected
+    fn test_huffman_table_destinations() {
+        // A DHT segment with a single DC code for the destination
+        let dht = |th: u8| vec![
+            0xFF, 0xC4, 0x00, 0x14, th, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        for &(sof, th, valid) in [(0xC0u8, 1u8, true), (0xC0, 2, false), (0xC1, 3, true), (0xC1, 4, false)].iter() {
+            // SOI and the frame header of an 8 x 8 gray image
+            let mut data = vec![0xFF, 0xD8, 0xFF, sof, 0x00, 0x0B, 0x08, 0x00, 0x08, 0x00, 0x08, 0x01, 0x01, 0x11, 0x00];
+            data.push_all(&dht(th));
+            // A scan that uses the tables
+            data.push_all(&[0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, th << 4 | th, 0x00, 0x3F, 0x00]);
+
+            match JPEGDecoder::new(io::Cursor::new(data)).dimensions() {
+                Ok(_) if valid => (),
+                Err(ImageError::FormatError(_)) if !valid => (),
+                r => panic!("SOF {:X} with table {}: {:?}", sof, th, r)
+            }
+        }
+    }
+
+    #[test]
+    /// Test that sequential and progressive arithmetic coded images decode to the same gradient
+    fn test_arithmetic_decoding() {
+        let header = [
+            // SOI and a quantization table
+            0xFF, 0xD8, 0xFF, 0xDB, 0x00, 0x43, 0x00, 0x03, 0x02, 0x02, 0x03, 0x02, 0x02, 0x03, 0x03,
+            0x03, 0x03, 0x04, 0x03, 0x03, 0x04, 0x05, 0x08, 0x05, 0x05, 0x04, 0x04, 0x05, 0x0A, 0x07,
+            0x07, 0x06, 0x08, 0x0C, 0x0A, 0x0C, 0x0C, 0x0B, 0x0A, 0x0B, 0x0B, 0x0D, 0x0E, 0x12, 0x10,
+            0x0D, 0x0E, 0x11, 0x0E, 0x0B, 0x0B, 0x10, 0x16, 0x10, 0x11, 0x13, 0x14, 0x15, 0x15, 0x15,
+            0x0C, 0x0F, 0x17, 0x18, 0x16, 0x14, 0x18, 0x12, 0x14, 0x15, 0x14,
+        ];
+
+        let sequential = [
+            // SOF9 of a 16 x 8 gray image, the default conditioning and a scan of all coefficients
+            0xFF, 0xC9, 0x00, 0x0B, 0x08, 0x00, 0x08, 0x00, 0x10, 0x01, 0x01, 0x11, 0x00, 0xFF, 0xCC,
+            0x00, 0x06, 0x00, 0x10, 0x10, 0x05, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F,
+            0x00, 0xFF, 0x00, 0xDA, 0x44, 0x51, 0x57, 0xD2, 0x60, 0x8A, 0x8F, 0x0B, 0x8A, 0x0A, 0x38,
+            0xD2, 0x58, 0xF3, 0x46, 0x22, 0xFF, 0xD9,
+        ];
+
+        let progressive = [
+            // SOF10, the scans of a progression with conditioning between them
+            0xFF, 0xCA, 0x00, 0x0B, 0x08, 0x00, 0x08, 0x00, 0x10, 0x01, 0x01, 0x11, 0x00, 0xFF, 0xCC,
+            0x00, 0x04, 0x00, 0x10, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00, 0x01, 0xFF,
+            0x00, 0xB4, 0x80, 0x40, 0xFF, 0xCC, 0x00, 0x04, 0x10, 0x05, 0xFF, 0xDA, 0x00, 0x08, 0x01,
+            0x01, 0x00, 0x01, 0x05, 0x02, 0x16, 0x61, 0x5E, 0x42, 0xFE, 0xC0, 0xFF, 0xCC, 0x00, 0x04,
+            0x10, 0x05, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x06, 0x3F, 0x02, 0x1D, 0x98, 0xA4,
+            0xFF, 0xCC, 0x00, 0x04, 0x10, 0x05, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x01, 0x3F,
+            0x21, 0x4C, 0x24, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00, 0x10, 0xE0, 0xFF,
+            0xCC, 0x00, 0x04, 0x10, 0x05, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x01, 0x3F, 0x10,
+            0xD3, 0x4C, 0xAD, 0xCE, 0xB0, 0xFF, 0xD9,
+        ];
+
+        for data in [&sequential[..], &progressive[..]].iter() {
+            let mut image = header.to_vec();
+            image.push_all(*data);
+
+            let mut decoder = JPEGDecoder::new(io::Cursor::new(image));
+            assert_eq!(decoder.dimensions().unwrap(), (16, 8));
+
+            let pixels = match decoder.read_image().unwrap() {
+                DecodingResult::U8(pixels) => pixels,
+                _ => panic!("unexpected bit depth")
+            };
+
+            for (i, &p) in pixels.iter().enumerate() {
+                let expected = (i % 16) * 12 + (i / 16) * 8;
+                assert!((p as i32 - expected as i32).abs() <= 4);
+            }
+        }
+    }
+
+    #[test]
+    /// Test that a progressive image decodes exactly like the baseline image it was transcoded from.
+    /// The images were written by libjpeg, the progressive one with the default script of
+    /// spectral selection and successive approximation.
+    fn test_progressive_decoding() {
+        let mut images = Vec::new();
+
+        for &name in ["baseline.jpg", "progressive.jpg"].iter() {
+            let path: PathBuf = [".", "src", "jpeg", "testdata", name].iter().collect();
+            let mut decoder = JPEGDecoder::new(File::open(&path).unwrap());
+
+            assert_eq!(decoder.dimensions().unwrap(), (45, 29));
+            assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGB(8));
+
+            match decoder.read_image().unwrap() {
+                DecodingResult::U8(pixels) => images.push(pixels),
+                _ => panic!("unexpected bit depth")
+            }
+        }
+
+        assert_eq!(images[0].len(), 45 * 29 * 3);
+        assert!(images[0] == images[1]);
+    }
+
+    #[test]
+    /// Test that segments too short to hold their own length are rejected
+    fn test_segment_length() {
+        // APP0, APP1, APP14, COM and DAC
+        for &marker in [0xE0u8, 0xE1, 0xEE, 0xFE, 0xCC].iter() {
+            for length in (0u8..2) {
+                let data = vec![0xFF, 0xD8, 0xFF, marker, 0x00, length, 0x00, 0x00];
+
+                match JPEGDecoder::new(io::Cursor::new(data)).dimensions() {
+                    Err(ImageError::FormatError(_)) => (),
+                    r => panic!("marker {:X} with length {}: {:?}", marker, length, r)
+                }
+            }
+        }
+    }
+
+    #[test]
+    /// Test the conversion of four component pixels to CMYK and RGB
+    fn test_cmyk_conversion() {
+        let pixels = [
+            (ColorTransform::CMYK, [0, 255, 128, 0], [0, 255, 128, 0], [255, 0, 127]),
+            (ColorTransform::CMYK, [0, 0, 0, 128], [0, 0, 0, 128], [127, 127, 127]),
+
+            // Adobe applications store 255 for no ink
+            (ColorTransform::InvertedCMYK, [255, 0, 127, 255], [0, 255, 128, 0], [255, 0, 127]),
+
+            // YCbCr of the C, M and Y ink amounts and the inverted K
+            (ColorTransform::YCCK, [76, 85, 255, 255], [254, 0, 0, 0], [1, 255, 255]),
+            (ColorTransform::YCCK, [128, 128, 128, 155], [128, 128, 128, 100], [77, 77, 77]),
+        ];
+
+        for &(transform, samples, cmyk, rgb) in pixels.iter() {
+            let mut out = [0u8; 4];
+            convert_pixel(transform, &samples, &mut out);
+            assert_eq!(out, cmyk);
+
+            let mut out = [0u8; 3];
+            convert_pixel(transform, &samples, &mut out);
+            assert_eq!(out, rgb);
+        }
+    }
+
+    #[test]
+    /// Test CMYK images written by libjpeg without an Adobe segment, with an Adobe segment
+    /// and inverted samples, and converted to YCCK. The left half of every image has the
+    /// ink amounts 0, 255, 128, 0 and the right half 64, 0, 200, 100.
+    fn test_cmyk_decoding() {
+        let colors = [([0, 255, 128, 0], [255, 0, 127]), ([64, 0, 200, 100], [116, 155, 33])];
+
+        // The YCbCr conversion of YCCK images is not exact
+        for &(name, tolerance) in [("cmyk.jpg", 0), ("cmyk_adobe.jpg", 0), ("ycck.jpg", 3)].iter() {
+            let path: PathBuf = [".", "src", "jpeg", "testdata", name].iter().collect();
+
+            let mut decoder = JPEGDecoder::new(File::open(&path).unwrap());
+            assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGB(8));
+
+            let rgb = match decoder.read_image().unwrap() {
+                DecodingResult::U8(pixels) => pixels,
+                _ => panic!("unexpected bit depth")
+            };
+
+            let cmyk = JPEGDecoder::new(File::open(&path).unwrap()).read_cmyk().unwrap();
+
+            assert_eq!(rgb.len(), 32 * 16 * 3);
+            assert_eq!(cmyk.len(), 32 * 16 * 4);
+
+            // The chroma of YCCK images is subsampled, the pixels next to the edge are skipped
+            for i in (0usize..32 * 16).filter(|i| i % 32 < 8 || i % 32 >= 24) {
+                let (ref ink, ref color) = colors[if i % 32 < 16 { 0 } else { 1 }];
+
+                for (&a, &b) in cmyk[4 * i..4 * i + 4].iter().zip(ink.iter()) {
+                    assert!((a as i32 - b as i32).abs() <= tolerance);
+                }
+
+                for (&a, &b) in rgb[3 * i..3 * i + 3].iter().zip(color.iter()) {
+                    assert!((a as i32 - b as i32).abs() <= tolerance);
+                }
+            }
+        }
+    }
+
+    #[test]
+    /// Test that scaled decoding picks the smallest scale that fits and averages the pixels
+    fn test_scaled_decoding() {
+        let (width, height) = (37u32, 21u32);
+        let image: Vec<u8> = (0..width * height).map(|i| (i % width * 6) as u8).collect();
+
+        let mut encoded = Vec::new();
+        JPEGEncoder::new_with_quality(&mut encoded, 90).encode(&image, width, height, color::ColorType::Gray(8)).unwrap();
+
+        for &((min_width, min_height), (w, h)) in [((1, 1), (5, 3)), ((6, 1), (10, 6)),
+                                                   ((1, 11), (19, 11)), ((19, 12), (37, 21))].iter() {
+            let mut decoder = JPEGDecoder::new(io::Cursor::new(encoded.clone()));
+            assert_eq!(decoder.scale_to(min_width, min_height).unwrap(), (w, h));
+            assert_eq!(decoder.dimensions().unwrap(), (w, h));
+
+            let decoded = match decoder.read_image().unwrap() {
+                DecodingResult::U8(decoded) => decoded,
+                _ => panic!("unexpected bit depth")
+            };
+            assert_eq!(decoded.len(), (w * h) as usize);
+
+            // Every pixel of the first row is the mean of the columns it covers,
+            // where the last column is repeated past the edge
+            let factor = (width + w - 1) / w;
+            for x in (0..w) {
+                let sum = (x * factor..(x + 1) * factor).fold(0, |sum, i| {
+                    sum + image[cmp::min(i, width - 1) as usize] as u32
+                });
+                assert!((decoded[x as usize] as i32 - (sum / factor) as i32).abs() < 8);
+            }
+        }
+    }
+}
@@ -1,4 +1,7 @@
 use std::io::{self, Write};
+use std::cmp;
+use std::mem;
+use std::iter::repeat;
 use byteorder::{WriteBytesExt, BigEndian};
 
 use std::num::{ Float, SignedInt };
@@ -6,6 +9,7 @@ use std::num::{ Float, SignedInt };
 use color;
 
 use super::transform;
+use super::coefficients::Coefficients;
 use super::decoder::Component;
 use super::decoder::UNZIGZAG;
 use super::entropy::build_huff_lut;
@@ -13,8 +17,12 @@ use super::entropy::build_huff_lut;
 // Markers
 // Baseline DCT
 static SOF0: u8 = 0xC0;
+// Progressive DCT
+static SOF2: u8 = 0xC2;
 // Huffman Tables
 static DHT: u8 = 0xC4;
+// Restart Interval start (standalone)
+static RST0: u8 = 0xD0;
 // Start of Image (standalone)
 static SOI: u8 = 0xD8;
 // End of image (standalone)
@@ -23,20 +31,32 @@ static EOI: u8 = 0xD9;
 static SOS: u8 = 0xDA;
 // Quantization Tables
 static DQT: u8 = 0xDB;
+// Define Restart Interval
+static DRI: u8 = 0xDD;
 // Application segments start and end
 static APP0: u8 = 0xE0;
+static APP2: u8 = 0xE2;
+static APP14: u8 = 0xEE;
+// Comment
+static COM: u8 = 0xFE;
+
+// The largest amount of data in a segment, the length field counts itself
+static MAX_SEGMENT_DATA: usize = 65535 - 2;
+// The identifier and the sequence number and count of every chunk of an ICC profile
+static ICC_HEADER: &'static [u8] = b"ICC_PROFILE\0";
+static ICC_CHUNK_DATA: usize = 65535 - 2 - 12 - 2;
 
 // section K.1
 // table K.1
 static STD_LUMA_QTABLE: [u8; 64] = [
-    16, 11, 10, 16, 124, 140, 151, 161,
-    12, 12, 14, 19, 126, 158, 160, 155,
-    14, 13, 16, 24, 140, 157, 169, 156,
-    14, 17, 22, 29, 151, 187, 180, 162,
-    18, 22, 37, 56, 168, 109, 103, 177,
-    24, 35, 55, 64, 181, 104, 113, 192,
+    16, 11, 10, 16,  24,  40,  51,  61,
+    12, 12, 14, 19,  26,  58,  60,  55,
+    14, 13, 16, 24,  40,  57,  69,  56,
+    14, 17, 22, 29,  51,  87,  80,  62,
+    18, 22, 37, 56,  68, 109, 103,  77,
+    24, 35, 55, 64,  81, 104, 113,  92,
     49, 64, 78, 87, 103, 121, 120, 101,
-    72, 92, 95, 98, 112, 100, 103, 199
+    72, 92, 95, 98, 112, 100, 103,  99
  ];
 
 // table K.2
@@ -123,82 +143,349 @@ static LUMAID: u8 = 1;
 static CHROMABLUEID: u8 = 2;
 static CHROMAREDID: u8 = 3;
 
+// The indices of the components, the spectral selection
+// and the successive approximation of a scan
+type Scan = (&'static [usize], u8, u8, u8, u8);
+
+static BASELINE_GRAY_SCRIPT: [Scan; 1] = [(&[0], 0, 63, 0, 0)];
+static BASELINE_COLOR_SCRIPT: [Scan; 1] = [(&[0, 1, 2], 0, 63, 0, 0)];
+static BASELINE_CMYK_SCRIPT: [Scan; 1] = [(&[0, 1, 2, 3], 0, 63, 0, 0)];
+
+// The default progressive scripts of libjpeg
+static PROGRESSIVE_GRAY_SCRIPT: [Scan; 6] = [
+    (&[0], 0, 0, 0, 1),
+    (&[0], 1, 5, 0, 2),
+    (&[0], 6, 63, 0, 2),
+    (&[0], 1, 63, 2, 1),
+    (&[0], 0, 0, 1, 0),
+    (&[0], 1, 63, 1, 0),
+];
+
+static PROGRESSIVE_COLOR_SCRIPT: [Scan; 10] = [
+    (&[0, 1, 2], 0, 0, 0, 1),
+    (&[0], 1, 5, 0, 2),
+    (&[2], 1, 63, 0, 1),
+    (&[1], 1, 63, 0, 1),
+    (&[0], 6, 63, 0, 2),
+    (&[0], 1, 63, 2, 1),
+    (&[0, 1, 2], 0, 0, 1, 0),
+    (&[2], 1, 63, 1, 0),
+    (&[1], 1, 63, 1, 0),
+    (&[0], 1, 63, 1, 0),
+];
+
+// Four component images send the DC coefficients first and each component in full afterwards
+static PROGRESSIVE_CMYK_SCRIPT: [Scan; 5] = [
+    (&[0, 1, 2, 3], 0, 0, 0, 0),
+    (&[0], 1, 63, 0, 0),
+    (&[1], 1, 63, 0, 0),
+    (&[2], 1, 63, 0, 0),
+    (&[3], 1, 63, 0, 0),
+];
+
+// The longest end of band run of a progressive AC scan
+static MAX_EOBRUN: u32 = 0x7FFF;
+
+// The number of correction bits after which a pending end of band run is written
+static MAX_CORRECTION_BITS: usize = 1000 - 64;
+
+/// The sampling of the chroma components relative to the luma component
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Subsampling {
+    /// Chroma at full resolution
+    Ratio444,
+
+    /// Chroma at half the horizontal resolution
+    Ratio422,
+
+    /// Chroma at half the horizontal and half the vertical resolution
+    Ratio420,
+}
+
 /// The representation of a JPEG encoder
 pub struct JPEGEncoder<'a, W: 'a> {
     w: &'a mut W,
 
     components: Vec<Component>,
     tables: Vec<u8>,
+    subsampling: Subsampling,
+    optimize_huffman: bool,
+    progressive: bool,
+
+    // The number of MCUs between restart markers, 0 if there are none
+    restart_interval: u16,
+    icc_profile: Option<Vec<u8>>,
+    comment: Option<String>,
 
     accumulator: u32,
     nbits: u8,
 
-    luma_dctable: Vec<(u8, u16)>,
-    luma_actable: Vec<(u8, u16)>,
-    chroma_dctable: Vec<(u8, u16)>,
-    chroma_actable: Vec<(u8, u16)>,
+    // The code of every symbol of the huffman tables, indexed by class * 2 + destination
+    huffman_tables: Vec<Vec<(u8, u16)>>,
+
+    // The symbols of every huffman table are counted, instead of written,
+    // while gathering the statistics of a scan
+    symbol_counts: Option<Vec<Vec<u32>>>,
+
+    // The end of band run of a progressive AC scan and the correction bits of its blocks
+    eobrun: u32,
+    correction_bits: Vec<u8>,
 }
 
 impl<'a, W: Write> JPEGEncoder<'a, W> {
     /// Create a new encoder that writes its output to ```w```
     pub fn new(w: &mut W) -> JPEGEncoder<W> {
-        let ld = build_huff_lut(&STD_LUMA_DC_CODE_LENGTHS, &STD_LUMA_DC_VALUES);
-        let la = build_huff_lut(&STD_LUMA_AC_CODE_LENGTHS, &STD_LUMA_AC_VALUES);
-
-        let cd = build_huff_lut(&STD_CHROMA_DC_CODE_LENGTHS, &STD_CHROMA_DC_VALUES);
-        let ca = build_huff_lut(&STD_CHROMA_AC_CODE_LENGTHS, &STD_CHROMA_AC_VALUES);
+        JPEGEncoder::new_with_quality(w, 50)
+    }
 
+    /// Create a new encoder that writes its output to ```w```, and has
+    /// the quality parameter ```quality``` with a value in the range 1-100
+    /// where 1 is the worst and 100 is the best.
+    /// The standard quantization tables are scaled like those of libjpeg.
+    pub fn new_with_quality(w: &mut W, quality: u8) -> JPEGEncoder<W> {
         let components = vec![
             Component {id: LUMAID, h: 1, v: 1, tq: LUMADESTINATION, dc_table: LUMADESTINATION, ac_table: LUMADESTINATION, dc_pred: 0},
             Component {id: CHROMABLUEID, h: 1, v: 1, tq: CHROMADESTINATION, dc_table: CHROMADESTINATION, ac_table: CHROMADESTINATION, dc_pred: 0},
             Component {id: CHROMAREDID, h: 1, v: 1, tq: CHROMADESTINATION, dc_table: CHROMADESTINATION, ac_table: CHROMADESTINATION, dc_pred: 0}
         ];
 
+        // Section K.1
+        // The tables give a good quality at 50, other qualities scale them
+        let quality = cmp::min(cmp::max(quality, 1), 100) as u32;
+        let scale = if quality < 50 { 5000 / quality }
+                    else { 200 - quality * 2 };
+
         let mut tables = Vec::new();
-        tables.extend(STD_LUMA_QTABLE.iter().map(|&v| v));
-        tables.extend(STD_CHROMA_QTABLE.iter().map(|&v| v));
+        tables.extend(STD_LUMA_QTABLE.iter().map(|&v| scale_quantizer(v, scale)));
+        tables.extend(STD_CHROMA_QTABLE.iter().map(|&v| scale_quantizer(v, scale)));
 
         JPEGEncoder {
             w: w,
 
             components: components,
             tables: tables,
+            subsampling: Subsampling::Ratio420,
+            optimize_huffman: false,
+            progressive: false,
 
-            luma_dctable: ld,
-            luma_actable: la,
-            chroma_dctable: cd,
-            chroma_actable: ca,
+            restart_interval: 0,
+            icc_profile: None,
+            comment: None,
+
+            huffman_tables: std_huffman_tables(),
+            symbol_counts: None,
+
+            eobrun: 0,
+            correction_bits: Vec::new(),
 
             accumulator: 0,
             nbits: 0,
         }
     }
 
+    /// Replaces the quantization tables of the luma and chroma components.
+    /// The tables are in row major order, not zigzag order,
+    /// and values of 0 are raised to 1.
+    pub fn set_quantization_tables(&mut self, luma: &[u8; 64], chroma: &[u8; 64]) {
+        self.tables.clear();
+        self.tables.extend(luma.iter().map(|&v| cmp::max(v, 1)));
+        self.tables.extend(chroma.iter().map(|&v| cmp::max(v, 1)));
+    }
+
+    /// Sets the chroma subsampling of color images, the default is 4:2:0
+    pub fn set_subsampling(&mut self, subsampling: Subsampling) {
+        self.subsampling = subsampling;
+    }
+
+    /// Generates huffman tables that are optimal for the image, instead of using
+    /// the standard tables. The coefficients of every scan are coded twice.
+    pub fn set_optimized_huffman_tables(&mut self, optimize: bool) {
+        self.optimize_huffman = optimize;
+    }
+
+    /// Writes a progressive image, its scans follow the default script of libjpeg.
+    /// Progressive images always use optimized huffman tables.
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.progressive = progressive;
+    }
+
+    /// Writes a restart marker after every ```interval``` MCUs of a scan, so that a decoder
+    /// can resynchronize after corrupted data. The default of 0 writes no restart markers.
+    pub fn set_restart_interval(&mut self, interval: u16) {
+        self.restart_interval = interval;
+    }
+
+    /// Embeds the ICC color profile ```profile``` in APP2 segments,
+    /// which hold up to 65519 bytes of it each.
+    pub fn set_icc_profile(&mut self, profile: &[u8]) {
+        self.icc_profile = Some(profile.to_vec());
+    }
+
+    /// Writes ```comment``` into a COM segment, which holds up to 65533 bytes.
+    pub fn set_comment(&mut self, comment: &str) {
+        self.comment = Some(comment.to_string());
+    }
+
     /// Encodes the image ```image```
     /// that has dimensions ```width``` and ```height```
     /// and ```ColorType``` ```c```
-    /// The Image in encoded with subsampling ratio 4:2:2
     pub fn encode(&mut self,
                   image: &[u8],
                   width: u32,
                   height: u32,
                   c: color::ColorType) -> io::Result<()> {
 
+        // The last column and row are repeated to fill the MCUs, an empty image has none
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid image dimensions.",
+                Some(format!("A JPEG image can not be {} x {} pixels.", width, height))
+            ))
+        }
+
         let n = color::num_components(c);
         let num_components = if n == 1 || n == 2 {1}
                              else {3};
 
+        // The luma component of a color image covers the chroma samples
+        let (h, v) = match self.subsampling {
+            _ if num_components == 1 => (1, 1),
+            Subsampling::Ratio444 => (1, 1),
+            Subsampling::Ratio422 => (2, 1),
+            Subsampling::Ratio420 => (2, 2),
+        };
+
+        self.components[0].h = h;
+        self.components[0].v = v;
+
+        let coefficients = match c {
+            color::ColorType::RGB(8)   => self.quantized_coefficients(image, width as usize, height as usize, 3),
+            color::ColorType::RGBA(8)  => self.quantized_coefficients(image, width as usize, height as usize, 4),
+            color::ColorType::Gray(8)  => self.quantized_coefficients(image, width as usize, height as usize, 1),
+            color::ColorType::GrayA(8) => self.quantized_coefficients(image, width as usize, height as usize, 2),
+            _  => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unsupported color type. Use 8 bit per channel RGB(A) or Gray(A) instead.",
+                Some(format!(
+                    "Color type {:?} is not suppored by this JPEG encoder.",
+                    c
+                ))
+            ))
+        };
+
+        self.write_frame(&coefficients, width, height, num_components, None)
+    }
+
+    /// Encodes the quantized DCT coefficients ```coefficients```, which keep their quantization tables,
+    /// so that the image loses no quality. The quality and subsampling settings do not apply.
+    pub fn encode_coefficients(&mut self, coefficients: &Coefficients) -> io::Result<()> {
+        let num_components = coefficients.components.len();
+
+        if num_components != 1 && num_components != 3 && num_components != 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unsupported number of components. Use 1, 3 or 4 components instead.",
+                Some(format!(
+                    "Images with {} components are not supported by this JPEG encoder.",
+                    num_components
+                ))
+            ))
+        }
+
+        // Components with the same quantization table share it
+        let mut tables: Vec<u8> = Vec::new();
+        let mut components = Vec::with_capacity(num_components);
+
+        for (i, c) in coefficients.components.iter().enumerate() {
+            let tq = match tables.chunks(64).position(|t| t == &c.qtable[..]) {
+                Some(tq) => tq,
+                None => {
+                    tables.push_all(&c.qtable);
+                    tables.len() / 64 - 1
+                }
+            };
+
+            let destination = if i == 0 {LUMADESTINATION}
+                              else {CHROMADESTINATION};
+
+            components.push(Component {
+                id: c.id,
+                h: c.h,
+                v: c.v,
+                tq: tq as u8,
+                dc_table: destination,
+                ac_table: destination,
+                dc_pred: 0
+            });
+        }
+
+        let blocks: Vec<Vec<i32>> = coefficients.components.iter().map(|c| c.blocks.clone()).collect();
+
+        let components = mem::replace(&mut self.components, components);
+        let tables = mem::replace(&mut self.tables, tables);
+
+        let result = self.write_frame(&blocks, coefficients.width, coefficients.height,
+                                      num_components, coefficients.adobe_transform);
+
+        self.components = components;
+        self.tables = tables;
+
+        result
+    }
+
+    // Writes an image of the quantized coefficients of its first num_components components.
+    // An Adobe APP14 segment replaces the JFIF header if adobe_transform is given.
+    fn write_frame(&mut self,
+                   coefficients: &[Vec<i32>],
+                   width: u32,
+                   height: u32,
+                   num_components: usize,
+                   adobe_transform: Option<u8>) -> io::Result<()> {
+
+        let icc_segments = match self.icc_profile {
+            Some(ref profile) => try!(build_icc_segments(profile)),
+            None => Vec::new()
+        };
+
+        let comment = self.comment.clone();
+
+        if let Some(ref comment) = comment {
+            if comment.len() > MAX_SEGMENT_DATA {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The comment does not fit into a COM segment.",
+                    Some(format!(
+                        "The comment has {} bytes, a COM segment holds up to {} bytes.",
+                        comment.len(), MAX_SEGMENT_DATA
+                    ))
+                ))
+            }
+        }
+
         let _ = try!(self.write_segment(SOI, None));
 
-        let buf = build_jfif_header();
-        let _   = try!(self.write_segment(APP0, Some(buf)));
+        let (marker, buf) = match adobe_transform {
+            Some(transform) => (APP14, build_adobe_header(transform)),
+            None => (APP0, build_jfif_header())
+        };
+        let _ = try!(self.write_segment(marker, Some(buf)));
+
+        for buf in icc_segments.into_iter() {
+            let _ = try!(self.write_segment(APP2, Some(buf)));
+        }
+
+        if let Some(comment) = comment {
+            let _ = try!(self.write_segment(COM, Some(comment.into_bytes())));
+        }
+
+        let sof = if self.progressive {SOF2}
+                  else {SOF0};
 
         let buf = build_frame_header(8, width as u16, height as u16, &self.components[..num_components]);
-        let _   = try!(self.write_segment(SOF0, Some(buf)));
+        let _   = try!(self.write_segment(sof, Some(buf)));
 
-        assert!(self.tables.len() / 64 == 2);
-        let numtables = if num_components == 1 {1}
-                        else {2};
+        let numtables = self.components[..num_components].iter().map(|c| c.tq as usize + 1).max().unwrap();
 
         let t = self.tables.clone();
 
@@ -207,51 +494,68 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
             let _   = try!(self.write_segment(DQT, Some(buf)));
         }
 
-        let numcodes = STD_LUMA_DC_CODE_LENGTHS;
-        let values   = STD_LUMA_DC_VALUES;
+        let optimize = self.optimize_huffman || self.progressive;
 
-        let buf = build_huffman_segment(DCCLASS, LUMADESTINATION, &numcodes, &values);
-        let _   = try!(self.write_segment(DHT, Some(buf)));
+        if !optimize {
+            self.huffman_tables = std_huffman_tables();
 
-        let numcodes = STD_LUMA_AC_CODE_LENGTHS;
-        let values   = STD_LUMA_AC_VALUES;
+            let numcodes = STD_LUMA_DC_CODE_LENGTHS;
+            let values   = STD_LUMA_DC_VALUES;
 
-        let buf = build_huffman_segment(ACCLASS, LUMADESTINATION, &numcodes, &values);
-        let _   = try!(self.write_segment(DHT, Some(buf)));
+            let buf = build_huffman_segment(DCCLASS, LUMADESTINATION, &numcodes, &values);
+            let _   = try!(self.write_segment(DHT, Some(buf)));
 
-        if num_components == 3 {
-            let numcodes = STD_CHROMA_DC_CODE_LENGTHS;
-            let values   = STD_CHROMA_DC_VALUES;
+            let numcodes = STD_LUMA_AC_CODE_LENGTHS;
+            let values   = STD_LUMA_AC_VALUES;
 
-            let buf = build_huffman_segment(DCCLASS, CHROMADESTINATION, &numcodes, &values);
+            let buf = build_huffman_segment(ACCLASS, LUMADESTINATION, &numcodes, &values);
             let _   = try!(self.write_segment(DHT, Some(buf)));
 
-            let numcodes = STD_CHROMA_AC_CODE_LENGTHS;
-            let values   = STD_CHROMA_AC_VALUES;
+            if num_components > 1 {
+                let numcodes = STD_CHROMA_DC_CODE_LENGTHS;
+                let values   = STD_CHROMA_DC_VALUES;
 
-            let buf = build_huffman_segment(ACCLASS, CHROMADESTINATION, &numcodes, &values);
-            let _   = try!(self.write_segment(DHT, Some(buf)));
+                let buf = build_huffman_segment(DCCLASS, CHROMADESTINATION, &numcodes, &values);
+                let _   = try!(self.write_segment(DHT, Some(buf)));
+
+                let numcodes = STD_CHROMA_AC_CODE_LENGTHS;
+                let values   = STD_CHROMA_AC_VALUES;
+
+                let buf = build_huffman_segment(ACCLASS, CHROMADESTINATION, &numcodes, &values);
+                let _   = try!(self.write_segment(DHT, Some(buf)));
+            }
         }
 
-        let buf = build_scan_header(&self.components[..num_components]);
-        let _   = try!(self.write_segment(SOS, Some(buf)));
+        if self.restart_interval > 0 {
+            let mut buf = Vec::new();
+            let _ = buf.write_u16::<BigEndian>(self.restart_interval);
+            let _ = try!(self.write_segment(DRI, Some(buf)));
+        }
 
-        match c {
-            color::ColorType::RGB(8)   => try!(self.encode_rgb(image, width as usize, height as usize, 3)),
-            color::ColorType::RGBA(8)  => try!(self.encode_rgb(image, width as usize, height as usize, 4)),
-            color::ColorType::Gray(8)  => try!(self.encode_gray(image, width as usize, height as usize, 1)),
-            color::ColorType::GrayA(8) => try!(self.encode_gray(image, width as usize, height as usize, 2)),
-            _  => return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Unsupported color type. Use 8 bit per channel RGB(A) or Gray(A) instead.",
-                Some(format!(
-                    "Color type {:?} is not suppored by this JPEG encoder.",
-                    c
-                ))
-            ))
+        let script: &[Scan] = match (self.progressive, num_components) {
+            (false, 1) => &BASELINE_GRAY_SCRIPT[..],
+            (false, 3) => &BASELINE_COLOR_SCRIPT[..],
+            (false, _) => &BASELINE_CMYK_SCRIPT[..],
+            (true, 1)  => &PROGRESSIVE_GRAY_SCRIPT[..],
+            (true, 3)  => &PROGRESSIVE_COLOR_SCRIPT[..],
+            (true, _)  => &PROGRESSIVE_CMYK_SCRIPT[..],
         };
 
-        let _ = try!(self.pad_byte());
+        for &scan in script.iter() {
+            if optimize {
+                let _ = try!(self.write_optimal_huffman_tables(coefficients, width as usize, height as usize, scan));
+            }
+
+            let (ids, ss, se, ah, al) = scan;
+            let components: Vec<Component> = ids.iter().map(|&i| self.components[i]).collect();
+
+            let buf = build_scan_header(&components, ss, se, ah, al);
+            let _   = try!(self.write_segment(SOS, Some(buf)));
+
+            let _ = try!(self.write_scan(coefficients, width as usize, height as usize, scan));
+            let _ = try!(self.pad_byte());
+        }
+
         self.write_segment(EOI, None)
     }
 
@@ -269,6 +573,10 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
     }
 
     fn write_bits(&mut self, bits: u16, size: u8) -> io::Result<()> {
+        if size == 0 || self.symbol_counts.is_some() {
+            return Ok(())
+        }
+
         self.accumulator |= (bits as u32) << (32 - (self.nbits + size)) as usize;
         self.nbits += size;
 
@@ -287,12 +595,19 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
         Ok(())
     }
 
+    // Fills the last byte of a scan with ones
     fn pad_byte(&mut self) -> io::Result<()> {
-        self.write_bits(0x7F, 7)
+        let n = (8 - self.nbits) % 8;
+        self.write_bits((1 << n as usize) - 1, n)
     }
 
-    fn huffman_encode(&mut self, val: u8, table: &[(u8, u16)]) -> io::Result<()> {
-        let (size, code) = table[val as usize];
+    fn huffman_encode(&mut self, val: u8, table: usize) -> io::Result<()> {
+        if let Some(ref mut counts) = self.symbol_counts {
+            counts[table][val as usize] += 1;
+            return Ok(())
+        }
+
+        let (size, code) = self.huffman_tables[table][val as usize];
 
         if size > 16 {
             panic!("bad huffman value");
@@ -301,132 +616,392 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
         self.write_bits(code, size)
     }
 
-    fn write_block(
-        &mut self,
-        block: &[i32],
-        prevdc: i32,
-        dctable: &[(u8, u16)],
-        actable: &[(u8, u16)]) -> io::Result<i32> {
+    // Returns the largest horizontal and vertical sampling factors of the components
+    fn max_sampling(&self) -> (usize, usize) {
+        self.components.iter().fold((1, 1), |(h, v), c| {
+            (cmp::max(h, c.h as usize), cmp::max(v, c.v as usize))
+        })
+    }
 
-        // Differential DC encoding
-        let dcval = block[0];
-        let diff  = dcval - prevdc;
-        let (size, value) = encode_coefficient(diff);
+    // Returns the number of MCUs per row and per column
+    fn mcus(&self, width: usize, height: usize) -> (usize, usize) {
+        let (h, v) = self.max_sampling();
 
-        let _ = try!(self.huffman_encode(size, dctable));
-        let _ = try!(self.write_bits(value, size));
+        ((width + 8 * h - 1) / (8 * h), (height + 8 * v - 1) / (8 * v))
+    }
 
-        // Figure F.2
-        let mut zero_run = 0;
-        let mut k = 0usize;
+    // Transforms and quantizes the blocks of every MCU of the image.
+    // The coefficients of a component are in natural order,
+    // and its blocks are stored row by row.
+    fn quantized_coefficients(&self, image: &[u8], width: usize, height: usize, bpp: usize) -> Vec<Vec<i32>> {
+        let (mcus_per_row, mcus_per_column) = self.mcus(width, height);
+        let num_components = if bpp < 3 {1}
+                             else {3};
 
-        loop {
-            k += 1;
+        let mut coefficients: Vec<Vec<i32>> = self.components[..num_components].iter().map(|c| {
+            let blocks = mcus_per_row * c.h as usize * mcus_per_column * c.v as usize;
+            repeat(0i32).take(blocks * 64).collect()
+        }).collect();
 
-            if block[UNZIGZAG[k] as usize] == 0 {
-                if k == 63 {
+        let h = self.components[0].h as usize;
+        let v = self.components[0].v as usize;
+        let line = mcus_per_row * h;
 
-                let _ = try!(self.huffman_encode(0x00, actable));
-                    break
+        let mut yblocks  = [0u8; 4 * 64];
+        let mut cb_block = [0u8; 64];
+        let mut cr_block = [0u8; 64];
+
+        for mcuy in (0..mcus_per_column) {
+            for mcux in (0..mcus_per_row) {
+                let (x, y) = (mcux * 8 * h, mcuy * 8 * v);
+
+                if num_components == 1 {
+                    let mut yblock = [0u8; 64];
+                    copy_blocks_gray(image, x, y, width, height, bpp, &mut yblock);
+
+                    let offset = (mcuy * mcus_per_row + mcux) * 64;
+                    transform_block(&yblock, &mut coefficients[0][offset..offset + 64], &self.tables[..64]);
+
+                    continue
                 }
 
-                zero_run += 1;
-            } else {
-                while zero_run > 15 {
-                    let _ = try!(self.huffman_encode(0xF0, actable));
-                    zero_run -= 16;
+                // RGB -> YCbCr
+                copy_blocks_ycbcr(image, x, y, width, height, bpp, h, v,
+                                  &mut yblocks, &mut cb_block, &mut cr_block);
+
+                for i in (0..h * v) {
+                    let offset = ((mcuy * v + i / h) * line + mcux * h + i % h) * 64;
+                    transform_block(&yblocks[i * 64..i * 64 + 64],
+                                    &mut coefficients[0][offset..offset + 64],
+                                    &self.tables[..64]);
                 }
 
-                let (size, value) = encode_coefficient(block[UNZIGZAG[k] as usize]);
-                let symbol = (zero_run << 4) | size;
+                let offset = (mcuy * mcus_per_row + mcux) * 64;
+                transform_block(&cb_block, &mut coefficients[1][offset..offset + 64], &self.tables[64..]);
+                transform_block(&cr_block, &mut coefficients[2][offset..offset + 64], &self.tables[64..]);
+            }
+        }
 
-                let _ = try!(self.huffman_encode(symbol, actable));
-                let _ = try!(self.write_bits(value, size));
+        coefficients
+    }
 
-                zero_run = 0;
+    // Section K.2
+    // Counts the symbols of the scan and writes the huffman tables that code them best
+    fn write_optimal_huffman_tables(&mut self,
+                                    coefficients: &[Vec<i32>],
+                                    width: usize,
+                                    height: usize,
+                                    scan: Scan) -> io::Result<()> {
 
-                if k == 63 {
-                    break
-                }
+        self.symbol_counts = Some(repeat(repeat(0u32).take(256).collect()).take(4).collect());
+        let result = self.write_scan(coefficients, width, height, scan);
+        let counts = self.symbol_counts.take().unwrap();
+
+        let _ = try!(result);
+
+        for (i, count) in counts.iter().enumerate() {
+            if count.iter().all(|&n| n == 0) {
+                continue
             }
+
+            let (numcodes, values) = build_optimal_table(count);
+
+            let buf = build_huffman_segment((i / 2) as u8, (i % 2) as u8, &numcodes, &values);
+            let _   = try!(self.write_segment(DHT, Some(buf)));
+
+            self.huffman_tables[i] = build_huff_lut(&numcodes, &values);
         }
 
-        Ok(dcval)
+        Ok(())
     }
 
-    fn encode_gray(&mut self, image: &[u8], width: usize, height: usize, bpp: usize) -> io::Result<()> {
-        let mut yblock     = [0u8; 64];
-        let mut y_dcprev   = 0;
-        let mut dct_yblock = [0i32; 64];
+    // Writes the entropy coded data of a scan. A scan of a single component is not interleaved,
+    // it codes only the blocks that cover the component.
+    fn write_scan(&mut self,
+                  coefficients: &[Vec<i32>],
+                  width: usize,
+                  height: usize,
+                  scan: Scan) -> io::Result<()> {
 
-        for y in (0 .. height).step_by(8) {
-            for x in (0 .. width).step_by(8) {
-                // RGB -> YCbCr
-                copy_blocks_gray(image, x, y, width, bpp, &mut yblock);
+        let (ids, ss, se, ah, al) = scan;
+        let (mcus_per_row, mcus_per_column) = self.mcus(width, height);
+
+        let (hmax, vmax) = self.max_sampling();
+
+        let mut dcpred = [0i32; 4];
+        let mut mcucount = 0;
+
+        self.eobrun = 0;
+        self.correction_bits.clear();
 
-                // Level shift and fdct
-                // Coeffs are scaled by 8
-                transform::fdct(&yblock, &mut dct_yblock);
+        // The AC scans of a progressive image have a single component
+        let actable = table_index(ACCLASS, self.components[ids[0]].ac_table);
 
-                // Quantization
-                for i in (0usize..64) {
-                    dct_yblock[i]   = ((dct_yblock[i] / 8)   as f32 / self.tables[i] as f32).round() as i32;
+        if ids.len() == 1 {
+            let i = ids[0];
+            let c = self.components[i];
+            let (h, v) = (c.h as usize, c.v as usize);
+
+            let blocks_per_row = ((width * h + hmax - 1) / hmax + 7) / 8;
+            let blocks_per_column = ((height * v + vmax - 1) / vmax + 7) / 8;
+            let line = mcus_per_row * h;
+
+            for by in (0..blocks_per_column) {
+                for bx in (0..blocks_per_row) {
+                    let offset = (by * line + bx) * 64;
+                    let block = &coefficients[i][offset..offset + 64];
+
+                    let _ = try!(self.write_block(block, &c, &mut dcpred[i], ss, se, ah, al));
+
+                    mcucount += 1;
+                    if mcucount < blocks_per_row * blocks_per_column {
+                        let _ = try!(self.write_restart(mcucount, &mut dcpred, actable));
+                    }
+                }
+            }
+        } else {
+            for mcuy in (0..mcus_per_column) {
+                for mcux in (0..mcus_per_row) {
+                    for &i in ids.iter() {
+                        let c = self.components[i];
+                        let line = mcus_per_row * c.h as usize;
+
+                        for v in (0..c.v as usize) {
+                            for h in (0..c.h as usize) {
+                                let offset = ((mcuy * c.v as usize + v) * line + mcux * c.h as usize + h) * 64;
+                                let block = &coefficients[i][offset..offset + 64];
+
+                                let _ = try!(self.write_block(block, &c, &mut dcpred[i], ss, se, ah, al));
+                            }
+                        }
+                    }
+
+                    mcucount += 1;
+                    if mcucount < mcus_per_row * mcus_per_column {
+                        let _ = try!(self.write_restart(mcucount, &mut dcpred, actable));
+                    }
                 }
+            }
+        }
+
+        self.write_eobrun(actable)
+    }
+
+    // Section F.1.2.3
+    // Ends the restart interval after mcucount MCUs with a restart marker,
+    // the data of the next interval is coded independently of the previous one.
+    fn write_restart(&mut self, mcucount: usize, dcpred: &mut [i32; 4], actable: usize) -> io::Result<()> {
+        let interval = self.restart_interval as usize;
 
-                let la = self.luma_actable.clone();
-                let ld = self.luma_dctable.clone();
+        if interval == 0 || mcucount % interval != 0 {
+            return Ok(())
+        }
 
-                y_dcprev  = try!(self.write_block(&dct_yblock, y_dcprev, &ld, &la));
+        let _ = try!(self.write_eobrun(actable));
+        let _ = try!(self.pad_byte());
+
+        // Markers are not counted while gathering the statistics of a scan
+        if self.symbol_counts.is_none() {
+            let marker = RST0 + ((mcucount / interval - 1) % 8) as u8;
+            let _ = try!(self.w.write_all(&[0xFF, marker]));
+        }
+
+        *dcpred = [0i32; 4];
+
+        Ok(())
+    }
+
+    // Writes the coefficients of the block that are coded in the scan
+    fn write_block(&mut self,
+                   block: &[i32],
+                   c: &Component,
+                   dcpred: &mut i32,
+                   ss: u8,
+                   se: u8,
+                   ah: u8,
+                   al: u8) -> io::Result<()> {
+
+        let actable = table_index(ACCLASS, c.ac_table);
+
+        if ss == 0 {
+            // Differential DC encoding
+            if ah == 0 {
+                let dcval = block[0] >> al as usize;
+                let (size, value) = encode_coefficient(dcval - *dcpred);
+
+                let _ = try!(self.huffman_encode(size, table_index(DCCLASS, c.dc_table)));
+                let _ = try!(self.write_bits(value, size));
+
+                *dcpred = dcval;
+            } else {
+                let _ = try!(self.write_bits(((block[0] >> al as usize) & 1) as u16, 1));
+            }
+
+            if se == 0 {
+                return Ok(())
+            }
+        }
+
+        // A sequential scan codes its AC coefficients like a first progressive scan,
+        // with an end of band run of a single block
+        let start = cmp::max(ss, 1) as usize;
+
+        if ah == 0 {
+            self.write_ac_first(block, actable, start, se as usize, al, ss == 0)
+        } else {
+            self.write_ac_refine(block, actable, start, se as usize, al)
+        }
+    }
+
+    // Figure F.2 and section G.1.2.2
+    fn write_ac_first(&mut self,
+                      block: &[i32],
+                      actable: usize,
+                      start: usize,
+                      end: usize,
+                      al: u8,
+                      sequential: bool) -> io::Result<()> {
+
+        let mut zero_run = 0;
+
+        for k in (start..end + 1) {
+            let coefficient = block[UNZIGZAG[k] as usize];
+            let magnitude = coefficient.abs() >> al as usize;
+
+            if magnitude == 0 {
+                zero_run += 1;
+                continue
+            }
+
+            let _ = try!(self.write_eobrun(actable));
+
+            while zero_run > 15 {
+                let _ = try!(self.huffman_encode(0xF0, actable));
+                zero_run -= 16;
+            }
+
+            let (size, value) = encode_coefficient(coefficient.signum() * magnitude);
+            let symbol = (zero_run << 4) | size;
+
+            let _ = try!(self.huffman_encode(symbol, actable));
+            let _ = try!(self.write_bits(value, size));
+
+            zero_run = 0;
+        }
+
+        if zero_run > 0 {
+            self.eobrun += 1;
+
+            if sequential || self.eobrun == MAX_EOBRUN {
+                let _ = try!(self.write_eobrun(actable));
             }
         }
 
         Ok(())
     }
 
-    fn encode_rgb(&mut self, image: &[u8], width: usize, height: usize, bpp: usize) -> io::Result<()> {
-        let mut y_dcprev = 0;
-        let mut cb_dcprev = 0;
-        let mut cr_dcprev = 0;
+    // Section G.1.2.3
+    // Coefficients that are already non-zero get a correction bit,
+    // new coefficients are 1 or -1 at this bit position.
+    fn write_ac_refine(&mut self,
+                       block: &[i32],
+                       actable: usize,
+                       start: usize,
+                       end: usize,
+                       al: u8) -> io::Result<()> {
 
-        let mut dct_yblock   = [0i32; 64];
-        let mut dct_cb_block = [0i32; 64];
-        let mut dct_cr_block = [0i32; 64];
+        let mut magnitudes = [0i32; 64];
+        let mut last_new = 0;
 
-        let mut yblock   = [0u8; 64];
-        let mut cb_block = [0u8; 64];
-        let mut cr_block = [0u8; 64];
+        for k in (start..end + 1) {
+            magnitudes[k] = block[UNZIGZAG[k] as usize].abs() >> al as usize;
 
-        for y in (0 .. height).step_by(8) {
-            for x in (0 .. width).step_by(8) {
-                // RGB -> YCbCr
-                copy_blocks_ycbcr(image, x, y, width, bpp, &mut yblock, &mut cb_block, &mut cr_block);
-
-                // Level shift and fdct
-                // Coeffs are scaled by 8
-                transform::fdct(&yblock, &mut dct_yblock);
-                transform::fdct(&cb_block, &mut dct_cb_block);
-                transform::fdct(&cr_block, &mut dct_cr_block);
-
-                // Quantization
-                for i in (0usize..64) {
-                    dct_yblock[i]   = ((dct_yblock[i] / 8)   as f32 / self.tables[i] as f32).round() as i32;
-                    dct_cb_block[i] = ((dct_cb_block[i] / 8) as f32 / self.tables[64..][i] as f32).round() as i32;
-                    dct_cr_block[i] = ((dct_cr_block[i] / 8) as f32 / self.tables[64..][i] as f32).round() as i32;
+            if magnitudes[k] == 1 {
+                last_new = k;
+            }
+        }
+
+        let mut zero_run = 0;
+        let mut bits = Vec::new();
+
+        for k in (start..end + 1) {
+            let magnitude = magnitudes[k];
+
+            if magnitude == 0 {
+                zero_run += 1;
+                continue
+            }
+
+            // Runs of zeros after the last new coefficient belong to the end of band
+            while zero_run > 15 && k <= last_new {
+                let _ = try!(self.write_eobrun(actable));
+                let _ = try!(self.huffman_encode(0xF0, actable));
+                zero_run -= 16;
+
+                for &bit in bits.iter() {
+                    let _ = try!(self.write_bits(bit as u16, 1));
                 }
 
-                let la = self.luma_actable.clone();
-                let ld = self.luma_dctable.clone();
-                let cd = self.chroma_dctable.clone();
-                let ca = self.chroma_actable.clone();
+                bits.clear();
+            }
 
-                y_dcprev  = try!(self.write_block(&dct_yblock, y_dcprev, &ld, &la));
-                cb_dcprev = try!(self.write_block(&dct_cb_block, cb_dcprev, &cd, &ca));
-                cr_dcprev = try!(self.write_block(&dct_cr_block, cr_dcprev, &cd, &ca));
+            if magnitude > 1 {
+                bits.push((magnitude & 1) as u8);
+                continue
+            }
+
+            let _ = try!(self.write_eobrun(actable));
+            let _ = try!(self.huffman_encode((zero_run << 4) | 1, actable));
+
+            let sign = if block[UNZIGZAG[k] as usize] < 0 {0}
+                       else {1};
+            let _ = try!(self.write_bits(sign, 1));
+
+            for &bit in bits.iter() {
+                let _ = try!(self.write_bits(bit as u16, 1));
+            }
+
+            bits.clear();
+            zero_run = 0;
+        }
+
+        if zero_run > 0 || bits.len() > 0 {
+            self.eobrun += 1;
+            self.correction_bits.push_all(&bits);
+
+            if self.eobrun == MAX_EOBRUN || self.correction_bits.len() > MAX_CORRECTION_BITS {
+                let _ = try!(self.write_eobrun(actable));
             }
         }
 
         Ok(())
     }
+
+    // Writes the pending end of band run, followed by the correction bits of its blocks
+    fn write_eobrun(&mut self, actable: usize) -> io::Result<()> {
+        if self.eobrun == 0 {
+            return Ok(())
+        }
+
+        let mut nbits = 0u8;
+
+        while self.eobrun >> (nbits + 1) as usize > 0 {
+            nbits += 1;
+        }
+
+        let _ = try!(self.huffman_encode(nbits << 4, actable));
+        let _ = try!(self.write_bits((self.eobrun & ((1 << nbits as usize) - 1)) as u16, nbits));
+
+        self.eobrun = 0;
+
+        let bits = mem::replace(&mut self.correction_bits, Vec::new());
+
+        for &bit in bits.iter() {
+            let _ = try!(self.write_bits(bit as u16, 1));
+        }
+
+        Ok(())
+    }
 }
 
 fn build_jfif_header() -> Vec<u8> {
@@ -445,6 +1020,41 @@ fn build_jfif_header() -> Vec<u8> {
     m
 }
 
+fn build_adobe_header(transform: u8) -> Vec<u8> {
+    let mut m = Vec::new();
+
+    let _ = write!(m, "Adobe");
+    let _ = m.write_u16::<BigEndian>(100);
+    let _ = m.write_u16::<BigEndian>(0);
+    let _ = m.write_u16::<BigEndian>(0);
+    let _ = m.write_all(&[transform]);
+
+    m
+}
+
+// Splits the ICC profile into the data of APP2 segments, every chunk is numbered from 1
+fn build_icc_segments(profile: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let count = (profile.len() + ICC_CHUNK_DATA - 1) / ICC_CHUNK_DATA;
+
+    if count > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The ICC profile does not fit into 255 APP2 segments.",
+            Some(format!("The ICC profile has {} bytes.", profile.len()))
+        ))
+    }
+
+    Ok(profile.chunks(ICC_CHUNK_DATA).enumerate().map(|(i, chunk)| {
+        let mut m = Vec::new();
+
+        let _ = m.write_all(ICC_HEADER);
+        let _ = m.write_all(&[i as u8 + 1, count as u8]);
+        let _ = m.write_all(chunk);
+
+        m
+    }).collect())
+}
+
 fn build_frame_header(precision: u8,
                       width: u16,
                       height: u16,
@@ -467,7 +1077,7 @@ fn build_frame_header(precision: u8,
     m
 }
 
-fn build_scan_header(components: &[Component]) -> Vec<u8> {
+fn build_scan_header(components: &[Component], ss: u8, se: u8, ah: u8, al: u8) -> Vec<u8> {
     let mut m = Vec::new();
 
     let _ = m.write_all(&[components.len() as u8]);
@@ -479,9 +1089,9 @@ fn build_scan_header(components: &[Component]) -> Vec<u8> {
     }
 
     // spectral start and end, approx. high and low
-    let _ = m.write_all(&[0]);
-    let _ = m.write_all(&[63]);
-    let _ = m.write_all(&[0]);
+    let _ = m.write_all(&[ss]);
+    let _ = m.write_all(&[se]);
+    let _ = m.write_all(&[(ah << 4) | al]);
 
     m
 }
@@ -533,6 +1143,136 @@ fn build_quantization_segment(precision: u8,
     m
 }
 
+// Section K.2
+// Returns the number of codes of each length and the symbols of the huffman table
+// that codes symbols with the given frequencies best, with codes of at most 16 bits
+fn build_optimal_table(frequencies: &[u32]) -> (Vec<u8>, Vec<u8>) {
+    // A reserved symbol with the lowest frequency ensures that no code consists of ones only
+    let mut freq: Vec<u32> = frequencies.iter().map(|&f| f).collect();
+    freq.push(1);
+
+    let mut codesize: Vec<usize> = repeat(0).take(257).collect();
+    let mut others: Vec<Option<usize>> = repeat(None).take(257).collect();
+
+    // Figure K.1
+    loop {
+        // The symbols with the lowest and the next lowest frequency,
+        // ties are won by the highest symbol
+        let mut v1 = None;
+        let mut v2 = None;
+
+        for i in (0usize..257) {
+            if freq[i] == 0 {
+                continue
+            }
+
+            match v1 {
+                Some(v) if freq[i] > freq[v] => {
+                    match v2 {
+                        Some(w) if freq[i] > freq[w] => (),
+                        _ => v2 = Some(i),
+                    }
+                }
+                _ => {
+                    v2 = v1;
+                    v1 = Some(i);
+                }
+            }
+        }
+
+        let (mut v1, mut v2) = match (v1, v2) {
+            (Some(v1), Some(v2)) => (v1, v2),
+            _ => break
+        };
+
+        freq[v1] += freq[v2];
+        freq[v2] = 0;
+
+        codesize[v1] += 1;
+        while let Some(next) = others[v1] {
+            v1 = next;
+            codesize[v1] += 1;
+        }
+
+        others[v1] = Some(v2);
+
+        codesize[v2] += 1;
+        while let Some(next) = others[v2] {
+            v2 = next;
+            codesize[v2] += 1;
+        }
+    }
+
+    // Figure K.2
+    let mut bits = [0u32; 33];
+
+    for &size in codesize.iter() {
+        if size > 0 {
+            bits[size] += 1;
+        }
+    }
+
+    // Figure K.3
+    for i in (17usize..33).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+
+            while bits[j] == 0 {
+                j -= 1;
+            }
+
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+
+    // Remove the code of the reserved symbol
+    let mut i = 16;
+
+    while bits[i] == 0 {
+        i -= 1;
+    }
+
+    bits[i] -= 1;
+
+    // Figure K.4
+    let mut values = Vec::new();
+
+    for size in (1usize..33) {
+        for symbol in (0usize..256) {
+            if codesize[symbol] == size {
+                values.push(symbol as u8);
+            }
+        }
+    }
+
+    (bits[1..17].iter().map(|&n| n as u8).collect(), values)
+}
+
+// Returns the codes of the standard huffman tables, indexed like the tables of the encoder
+fn std_huffman_tables() -> Vec<Vec<(u8, u16)>> {
+    let ld = build_huff_lut(&STD_LUMA_DC_CODE_LENGTHS, &STD_LUMA_DC_VALUES);
+    let la = build_huff_lut(&STD_LUMA_AC_CODE_LENGTHS, &STD_LUMA_AC_VALUES);
+
+    let cd = build_huff_lut(&STD_CHROMA_DC_CODE_LENGTHS, &STD_CHROMA_DC_VALUES);
+    let ca = build_huff_lut(&STD_CHROMA_AC_CODE_LENGTHS, &STD_CHROMA_AC_VALUES);
+
+    vec![ld, cd, la, ca]
+}
+
+// Returns the index of the huffman table of the class and destination
+fn table_index(class: u8, destination: u8) -> usize {
+    (class * 2 + destination) as usize
+}
+
+// Scales a quantizer by scale percent, limited to the range of baseline JPEG
+fn scale_quantizer(value: u8, scale: u32) -> u8 {
+    let v = (value as u32 * scale + 50) / 100;
+    cmp::min(cmp::max(v, 1), 255) as u8
+}
+
 fn encode_coefficient(coefficient: i32) -> (u8, u16) {
     let mut magnitude = coefficient.abs() as u16;
     let mut num_bits  = 0u8;
@@ -565,55 +1305,432 @@ fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
     (y as u8, cb as u8, cr as u8)
 }
 
-fn value_at(s: &[u8], index: usize) -> u8 {
-    if index < s.len() {
-        s[index]
-    } else {
-        s[s.len() - 1]
+// Level shift, fdct and quantization of the samples of a block
+fn transform_block(samples: &[u8], coeffs: &mut [i32], qtable: &[u8]) {
+    transform::fdct(samples, coeffs);
+
+    // Coeffs are scaled by 8
+    for i in (0usize..64) {
+        coeffs[i] = ((coeffs[i] / 8) as f32 / qtable[i] as f32).round() as i32;
     }
 }
 
+// Returns the pixel at (x, y), where the image is extended by repeating its last column and row
+fn pixel_at(source: &[u8], x: usize, y: usize, width: usize, height: usize, bpp: usize) -> &[u8] {
+    let x = cmp::min(x, width - 1);
+    let y = cmp::min(y, height - 1);
+    let i = (y * width + x) * bpp;
+
+    &source[i..i + bpp]
+}
+
+// Converts the MCU at (x0, y0) to h x v luma blocks and one block of each chroma component,
+// where every chroma sample is the average of h x v pixels
 fn copy_blocks_ycbcr(source: &[u8],
                      x0: usize,
                      y0: usize,
                      width: usize,
+                     height: usize,
                      bpp: usize,
-                     yb: &mut [u8; 64],
+                     h: usize,
+                     v: usize,
+                     yb: &mut [u8; 4 * 64],
                      cbb: &mut [u8; 64],
                      crb: &mut [u8; 64]) {
 
-    for y in (0usize..8) {
-        let ystride = (y0 + y) * bpp * width;
-
-        for x in (0usize..8) {
-            let xstride = x0 * bpp + x * bpp;
+    let mut cb_sum = [0u32; 64];
+    let mut cr_sum = [0u32; 64];
 
-            let r = value_at(source, ystride + xstride + 0);
-            let g = value_at(source, ystride + xstride + 1);
-            let b = value_at(source, ystride + xstride + 2);
+    for y in (0..8 * v) {
+        for x in (0..8 * h) {
+            let p = pixel_at(source, x0 + x, y0 + y, width, height, bpp);
+            let (yc, cb, cr) = rgb_to_ycbcr(p[0], p[1], p[2]);
 
-            let (yc, cb, cr) = rgb_to_ycbcr(r, g, b);
+            let block = (y / 8) * h + x / 8;
+            yb[block * 64 + (y % 8) * 8 + x % 8] = yc;
 
-            yb[y * 8 + x]  = yc;
-            cbb[y * 8 + x] = cb;
-            crb[y * 8 + x] = cr;
+            let c = (y / v) * 8 + x / h;
+            cb_sum[c] += cb as u32;
+            cr_sum[c] += cr as u32;
         }
     }
+
+    let n = (h * v) as u32;
+
+    for i in (0usize..64) {
+        cbb[i] = ((cb_sum[i] + n / 2) / n) as u8;
+        crb[i] = ((cr_sum[i] + n / 2) / n) as u8;
+    }
 }
 
 fn copy_blocks_gray(source: &[u8],
                     x0: usize,
                     y0: usize,
                     width: usize,
+                    height: usize,
                     bpp: usize,
                     gb: &mut [u8; 64]) {
 
     for y in (0usize..8) {
-        let ystride = (y0 + y) * bpp * width;
-
         for x in (0usize..8) {
-            let xstride = x0 * bpp + x * bpp;
-            gb[y * 8 + x] = value_at(source, ystride + xstride + 1);
+            gb[y * 8 + x] = pixel_at(source, x0 + x, y0 + y, width, height, bpp)[0];
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use std::io;
+    use std::num::SignedInt;
+    use image::{ImageDecoder, DecodingResult};
+    use color;
+    use super::{JPEGEncoder, Subsampling};
+    use super::super::JPEGDecoder;
+    use super::super::decoder::UNZIGZAG;
+
+    fn test_image(width: u32, height: u32, bpp: u32) -> Vec<u8> {
+        (0..width * height * bpp).map(|i| {
+            let p = i / bpp;
+            match i % bpp {
+                0 => (p % width * 6) as u8,
+                1 => (p / width * 10) as u8,
+                _ => 128,
+            }
+        }).collect()
+    }
+
+    // Decodes the image and returns the sum of the differences of every sample
+    fn decoding_error(encoded: Vec<u8>, image: &[u8], width: u32, height: u32) -> i32 {
+        let mut decoder = JPEGDecoder::new(io::Cursor::new(encoded));
+        assert_eq!(decoder.dimensions().unwrap(), (width, height));
+
+        let decoded = match decoder.read_image().unwrap() {
+            DecodingResult::U8(decoded) => decoded,
+            _ => panic!("unexpected bit depth")
+        };
+
+        assert_eq!(decoded.len(), image.len());
+
+        image.iter().zip(decoded.iter()).fold(0, |e, (&a, &b)| e + (a as i32 - b as i32).abs())
+    }
+
+    // Returns the tables of the DQT segments of the encoded image in row major order
+    fn quantization_tables(encoded: &[u8]) -> Vec<Vec<u8>> {
+        let mut tables = Vec::new();
+
+        for i in find_markers(encoded, 0xDB) {
+            let length = ((encoded[i + 2] as usize) << 8) | encoded[i + 3] as usize;
+            let segment = &encoded[i + 4..i + 2 + length];
+
+            // Every table has its precision and destination and 64 values in zigzag order
+            for table in segment.chunks(65) {
+                assert_eq!(table[0] >> 4, 0);
+
+                let mut natural = vec![0u8; 64];
+                for (k, &v) in table[1..].iter().enumerate() {
+                    natural[UNZIGZAG[k] as usize] = v;
+                }
+
+                tables.push(natural);
+            }
+        }
+
+        tables
+    }
+
+    // Returns the offsets of the marker in the encoded image
+    fn find_markers(encoded: &[u8], marker: u8) -> Vec<usize> {
+        (0..encoded.len() - 1).filter(|&i| encoded[i] == 0xFF && encoded[i + 1] == marker).collect()
+    }
+
+    // Returns the id and the horizontal and vertical sampling factors
+    // of every component in the SOF0 segment of the encoded image
+    fn sampling_factors(encoded: &[u8]) -> Vec<(u8, u8, u8)> {
+        let i = find_markers(encoded, 0xC0)[0];
+        let n = encoded[i + 9] as usize;
+
+        encoded[i + 10..i + 10 + 3 * n].chunks(3).map(|c| (c[0], c[1] >> 4, c[1] & 0x0F)).collect()
+    }
+
+    #[test]
+    /// Test that every chroma subsampling decodes to an image close to the original
+    fn test_subsampling_roundtrip() {
+        let (width, height) = (37u32, 21u32);
+        let image = test_image(width, height, 3);
+
+        let ratios = [
+            (Subsampling::Ratio444, (1, 1)),
+            (Subsampling::Ratio422, (2, 1)),
+            (Subsampling::Ratio420, (2, 2)),
+        ];
+
+        for &(subsampling, (h, v)) in ratios.iter() {
+            let mut encoded = Vec::new();
+            {
+                let mut encoder = JPEGEncoder::new_with_quality(&mut encoded, 90);
+                encoder.set_subsampling(subsampling);
+                encoder.encode(&image, width, height, color::ColorType::RGB(8)).unwrap();
+            }
+
+            // Only the luma component has larger sampling factors
+            assert_eq!(sampling_factors(&encoded), vec![(1, h, v), (2, 1, 1), (3, 1, 1)]);
+
+            // The mean error of the color channels
+            assert!(decoding_error(encoded, &image, width, height) < 4 * image.len() as i32);
+        }
+
+        // The default is 4:2:0, gray images are never subsampled
+        let mut encoded = Vec::new();
+        JPEGEncoder::new(&mut encoded).encode(&image, width, height, color::ColorType::RGB(8)).unwrap();
+        assert_eq!(sampling_factors(&encoded)[0], (1, 2, 2));
+
+        let gray = test_image(width, height, 1);
+        let mut encoded = Vec::new();
+        JPEGEncoder::new(&mut encoded).encode(&gray, width, height, color::ColorType::Gray(8)).unwrap();
+        assert_eq!(sampling_factors(&encoded), vec![(1, 1, 1)]);
+
+        // An image without pixels can not be encoded
+        for &(width, height) in [(0u32, 5u32), (5, 0), (0, 0)].iter() {
+            let mut encoded = Vec::new();
+            let r = JPEGEncoder::new(&mut encoded).encode(&[], width, height, color::ColorType::RGB(8));
+            assert_eq!(r.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    /// Test that optimized huffman tables and progressive images decode like baseline images
+    fn test_progressive_roundtrip() {
+        let (width, height) = (37u32, 21u32);
+
+        for &(c, bpp) in [(color::ColorType::Gray(8), 1), (color::ColorType::RGB(8), 3)].iter() {
+            let image = test_image(width, height, bpp);
+
+            let mut baseline = Vec::new();
+            JPEGEncoder::new_with_quality(&mut baseline, 90).encode(&image, width, height, c).unwrap();
+
+            let mut optimized = Vec::new();
+            {
+                let mut encoder = JPEGEncoder::new_with_quality(&mut optimized, 90);
+                encoder.set_optimized_huffman_tables(true);
+                encoder.encode(&image, width, height, c).unwrap();
+            }
+
+            let mut progressive = Vec::new();
+            {
+                let mut encoder = JPEGEncoder::new_with_quality(&mut progressive, 90);
+                encoder.set_progressive(true);
+                encoder.encode(&image, width, height, c).unwrap();
+            }
+
+            assert!(optimized.len() < baseline.len());
+
+            // The coefficients are the same, only their coding differs
+            let error = decoding_error(baseline, &image, width, height);
+            assert_eq!(decoding_error(optimized, &image, width, height), error);
+            assert_eq!(decoding_error(progressive, &image, width, height), error);
+        }
+    }
+
+    #[test]
+    /// Test that transformed coefficients are written without loss and decode to the transformed image
+    fn test_lossless_rotation() {
+        let (width, height) = (32u32, 16u32);
+        let image = test_image(width, height, 3);
+
+        let mut encoded = Vec::new();
+        JPEGEncoder::new_with_quality(&mut encoded, 90).encode(&image, width, height, color::ColorType::RGB(8)).unwrap();
+
+        let decode = |data: Vec<u8>| match JPEGDecoder::new(io::Cursor::new(data)).read_image().unwrap() {
+            DecodingResult::U8(decoded) => decoded,
+            _ => panic!("unexpected bit depth")
+        };
+
+        let original = decode(encoded.clone());
+        let rotated = JPEGDecoder::new(io::Cursor::new(encoded)).read_coefficients().unwrap().rotate90().unwrap();
+
+        for &progressive in [false, true].iter() {
+            let mut output = Vec::new();
+            {
+                let mut encoder = JPEGEncoder::new(&mut output);
+                encoder.set_progressive(progressive);
+                encoder.encode_coefficients(&rotated).unwrap();
+            }
+
+            let coefficients = JPEGDecoder::new(io::Cursor::new(output.clone())).read_coefficients().unwrap();
+            assert_eq!((coefficients.width, coefficients.height), (height, width));
+
+            for (a, b) in coefficients.components.iter().zip(rotated.components.iter()) {
+                assert_eq!((a.h, a.v, &a.qtable, &a.blocks), (b.h, b.v, &b.qtable, &b.blocks));
+            }
+
+            // The pixel at (x, y) was at (y, height - 1 - x)
+            let decoded = decode(output);
+            for y in (0..width as usize) {
+                for x in (0..height as usize) {
+                    let i = (y * height as usize + x) * 3;
+                    let j = ((height as usize - 1 - x) * width as usize + y) * 3;
+
+                    for c in (0..3) {
+                        assert!((decoded[i + c] as i32 - original[j + c] as i32).abs() <= 4);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    /// Test that restart markers do not change the decoded image and that the metadata segments are written
+    fn test_restart_markers_and_metadata() {
+        let (width, height) = (37u32, 21u32);
+        let image = test_image(width, height, 3);
+        let profile: Vec<u8> = (0..70000).map(|i| i as u8).collect();
+
+        for &progressive in [false, true].iter() {
+            let mut plain = Vec::new();
+            {
+                let mut encoder = JPEGEncoder::new_with_quality(&mut plain, 90);
+                encoder.set_progressive(progressive);
+                encoder.encode(&image, width, height, color::ColorType::RGB(8)).unwrap();
+            }
+
+            let mut restarts = Vec::new();
+            {
+                let mut encoder = JPEGEncoder::new_with_quality(&mut restarts, 90);
+                encoder.set_progressive(progressive);
+                encoder.set_restart_interval(2);
+                encoder.set_icc_profile(&profile);
+                encoder.set_comment("restarts");
+                encoder.encode(&image, width, height, color::ColorType::RGB(8)).unwrap();
+            }
+
+            // The profile is split into two chunks that are numbered from 1
+            let icc = find_markers(&restarts, 0xE2);
+            assert_eq!(icc.len(), 2);
+            assert_eq!(&restarts[icc[0] + 4..icc[0] + 18], &b"ICC_PROFILE\0\x01\x02"[..]);
+            assert_eq!(&restarts[icc[1] + 16..icc[1] + 18], &[2u8, 2][..]);
+
+            let com = find_markers(&restarts, 0xFE);
+            assert_eq!(&restarts[com[0] + 4..com[0] + 12], &b"restarts"[..]);
+
+            assert_eq!(find_markers(&restarts, 0xDD).len(), 1);
+            assert!(find_markers(&restarts, 0xD1).len() > 0);
+
+            let error = decoding_error(plain, &image, width, height);
+            assert_eq!(decoding_error(restarts, &image, width, height), error);
+        }
+    }
+
+    #[test]
+    /// Test that the quality scales the standard tables like libjpeg does,
+    /// the tables were taken from libjpeg with baseline limits
+    fn test_quality_scaling() {
+        let luma30 = [
+             27,  18,  17,  27,  40,  66,  85, 101,
+             20,  20,  23,  32,  43,  96, 100,  91,
+             23,  22,  27,  40,  66,  95, 115,  93,
+             23,  28,  37,  48,  85, 144, 133, 103,
+             30,  37,  61,  93, 113, 181, 171, 128,
+             40,  58,  91, 106, 134, 173, 188, 153,
+             81, 106, 129, 144, 171, 201, 199, 168,
+            120, 153, 158, 163, 186, 166, 171, 164,
+        ];
+
+        let chroma30 = [
+             28,  30,  40,  78, 164, 164, 164, 164,
+             30,  35,  43, 110, 164, 164, 164, 164,
+             40,  43,  93, 164, 164, 164, 164, 164,
+             78, 110, 164, 164, 164, 164, 164, 164,
+            164, 164, 164, 164, 164, 164, 164, 164,
+            164, 164, 164, 164, 164, 164, 164, 164,
+            164, 164, 164, 164, 164, 164, 164, 164,
+            164, 164, 164, 164, 164, 164, 164, 164,
+        ];
+
+        let luma75 = [
+             8,  6,  5,  8, 12, 20, 26, 31,
+             6,  6,  7, 10, 13, 29, 30, 28,
+             7,  7,  8, 12, 20, 29, 35, 28,
+             7,  9, 11, 15, 26, 44, 40, 31,
+             9, 11, 19, 28, 34, 55, 52, 39,
+            12, 18, 28, 32, 41, 52, 57, 46,
+            25, 32, 39, 44, 52, 61, 60, 51,
+            36, 46, 48, 49, 56, 50, 52, 50,
+        ];
+
+        let chroma75 = [
+             9,  9, 12, 24, 50, 50, 50, 50,
+             9, 11, 13, 33, 50, 50, 50, 50,
+            12, 13, 28, 50, 50, 50, 50, 50,
+            24, 33, 50, 50, 50, 50, 50, 50,
+            50, 50, 50, 50, 50, 50, 50, 50,
+            50, 50, 50, 50, 50, 50, 50, 50,
+            50, 50, 50, 50, 50, 50, 50, 50,
+            50, 50, 50, 50, 50, 50, 50, 50,
+        ];
+
+        // The quality is clamped to 1 - 100, the quantizers to 1 - 255
+        let qualities = [
+            (0, [255u8; 64], [255u8; 64]),
+            (1, [255u8; 64], [255u8; 64]),
+            (30, luma30, chroma30),
+            (75, luma75, chroma75),
+            (100, [1u8; 64], [1u8; 64]),
+            (200, [1u8; 64], [1u8; 64]),
+        ];
+
+        let image = test_image(16, 16, 3);
+
+        for &(quality, ref luma, ref chroma) in qualities.iter() {
+            let mut encoded = Vec::new();
+            JPEGEncoder::new_with_quality(&mut encoded, quality)
+                .encode(&image, 16, 16, color::ColorType::RGB(8)).unwrap();
+
+            let tables = quantization_tables(&encoded);
+            assert_eq!(tables.len(), 2);
+            assert_eq!(&tables[0][..], &luma[..]);
+            assert_eq!(&tables[1][..], &chroma[..]);
+        }
+    }
+
+    #[test]
+    /// Test that custom quantization tables are written to the DQT segments
+    fn test_custom_quantization_tables() {
+        let mut luma = [0u8; 64];
+        let mut chroma = [0u8; 64];
+
+        for i in (0usize..64) {
+            luma[i] = i as u8 * 3;
+            chroma[i] = 255 - i as u8;
+        }
+
+        let image = test_image(16, 16, 3);
+
+        let mut encoded = Vec::new();
+        {
+            let mut encoder = JPEGEncoder::new_with_quality(&mut encoded, 90);
+            encoder.set_quantization_tables(&luma, &chroma);
+            encoder.encode(&image, 16, 16, color::ColorType::RGB(8)).unwrap();
+        }
+
+        let tables = quantization_tables(&encoded);
+        assert_eq!(tables.len(), 2);
+
+        // A quantizer of 0 is raised to 1
+        assert_eq!(tables[0][0], 1);
+        assert_eq!(&tables[0][1..], &luma[1..]);
+        assert_eq!(&tables[1][..], &chroma[..]);
+
+        // Gray images only have the luma table
+        let mut encoded = Vec::new();
+        {
+            let mut encoder = JPEGEncoder::new(&mut encoded);
+            encoder.set_quantization_tables(&luma, &chroma);
+            encoder.encode(&test_image(16, 16, 1), 16, 16, color::ColorType::Gray(8)).unwrap();
+        }
+
+        let tables = quantization_tables(&encoded);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(&tables[0][1..], &luma[1..]);
+    }
+}
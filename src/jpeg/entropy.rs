@@ -212,3 +212,284 @@ pub fn derive_tables(bits: Vec<u8>, huffval: Vec<u8>) -> HuffTable {
         valptr: valptr
     }
 }
+
+// Table D.3
+// The probability estimation state machine of the QM coder,
+// the Qe value, the next state after a LPS and a MPS and whether a LPS switches the sense of MPS
+static QE_STATES: [(i32, u8, u8, u8); 114] = [
+    (0x5a1d,   1,   1, 1), (0x2586,  14,   2, 0), (0x1114,  16,   3, 0), (0x080b,  18,   4, 0),
+    (0x03d8,  20,   5, 0), (0x01da,  23,   6, 0), (0x00e5,  25,   7, 0), (0x006f,  28,   8, 0),
+    (0x0036,  30,   9, 0), (0x001a,  33,  10, 0), (0x000d,  35,  11, 0), (0x0006,   9,  12, 0),
+    (0x0003,  10,  13, 0), (0x0001,  12,  13, 0), (0x5a7f,  15,  15, 1), (0x3f25,  36,  16, 0),
+    (0x2cf2,  38,  17, 0), (0x207c,  39,  18, 0), (0x17b9,  40,  19, 0), (0x1182,  42,  20, 0),
+    (0x0cef,  43,  21, 0), (0x09a1,  45,  22, 0), (0x072f,  46,  23, 0), (0x055c,  48,  24, 0),
+    (0x0406,  49,  25, 0), (0x0303,  51,  26, 0), (0x0240,  52,  27, 0), (0x01b1,  54,  28, 0),
+    (0x0144,  56,  29, 0), (0x00f5,  57,  30, 0), (0x00b7,  59,  31, 0), (0x008a,  60,  32, 0),
+    (0x0068,  62,  33, 0), (0x004e,  63,  34, 0), (0x003b,  32,  35, 0), (0x002c,  33,   9, 0),
+    (0x5ae1,  37,  37, 1), (0x484c,  64,  38, 0), (0x3a0d,  65,  39, 0), (0x2ef1,  67,  40, 0),
+    (0x261f,  68,  41, 0), (0x1f33,  69,  42, 0), (0x19a8,  70,  43, 0), (0x1518,  72,  44, 0),
+    (0x1177,  73,  45, 0), (0x0e74,  74,  46, 0), (0x0bfb,  75,  47, 0), (0x09f8,  77,  48, 0),
+    (0x0861,  78,  49, 0), (0x0706,  79,  50, 0), (0x05cd,  48,  51, 0), (0x04de,  50,  52, 0),
+    (0x040f,  50,  53, 0), (0x0363,  51,  54, 0), (0x02d4,  52,  55, 0), (0x025c,  53,  56, 0),
+    (0x01f8,  54,  57, 0), (0x01a4,  55,  58, 0), (0x0160,  56,  59, 0), (0x0125,  57,  60, 0),
+    (0x00f6,  58,  61, 0), (0x00cb,  59,  62, 0), (0x00ab,  61,  63, 0), (0x008f,  61,  32, 0),
+    (0x5b12,  65,  65, 1), (0x4d04,  80,  66, 0), (0x412c,  81,  67, 0), (0x37d8,  82,  68, 0),
+    (0x2fe8,  83,  69, 0), (0x293c,  84,  70, 0), (0x2379,  86,  71, 0), (0x1edf,  87,  72, 0),
+    (0x1aa9,  87,  73, 0), (0x174e,  72,  74, 0), (0x1424,  72,  75, 0), (0x119c,  74,  76, 0),
+    (0x0f6b,  74,  77, 0), (0x0d51,  75,  78, 0), (0x0bb6,  77,  79, 0), (0x0a40,  77,  48, 0),
+    (0x5832,  80,  81, 1), (0x4d1c,  88,  82, 0), (0x438e,  89,  83, 0), (0x3bdd,  90,  84, 0),
+    (0x34ee,  91,  85, 0), (0x2eae,  92,  86, 0), (0x299a,  93,  87, 0), (0x2516,  86,  71, 0),
+    (0x5570,  88,  89, 1), (0x4ca9,  95,  90, 0), (0x44d9,  96,  91, 0), (0x3e22,  97,  92, 0),
+    (0x3824,  99,  93, 0), (0x32b4,  99,  94, 0), (0x2e17,  93,  86, 0), (0x56a8,  95,  96, 1),
+    (0x4f46, 101,  97, 0), (0x47e5, 102,  98, 0), (0x41cf, 103,  99, 0), (0x3c3d, 104, 100, 0),
+    (0x375e,  99,  93, 0), (0x5231, 105, 102, 0), (0x4c0f, 106, 103, 0), (0x4639, 107, 104, 0),
+    (0x415e, 103,  99, 0), (0x5627, 105, 106, 1), (0x50e7, 108, 107, 0), (0x4b85, 109, 103, 0),
+    (0x5597, 110, 109, 0), (0x504f, 111, 107, 0), (0x5a10, 110, 111, 1), (0x5522, 112, 109, 0),
+    (0x59eb, 112, 111, 1),
+    // A state that is never left, for the bin of values with an even probability
+    (0x5a1d, 113, 113, 0),
+];
+
+// The statistics bins of the four DC and AC conditioning tables,
+// followed by the bin with a fixed probability
+const DC_BINS: usize = 64;
+const AC_BINS: usize = 256;
+const AC_OFFSET: usize = 4 * DC_BINS;
+const FIXED_BIN: usize = AC_OFFSET + 4 * AC_BINS;
+
+pub struct ArithDecoder {
+    c: i32,
+    a: i32,
+    ct: i32,
+
+    // The state of each statistics bin, the highest bit is the sense of MPS
+    stats: Vec<u8>,
+
+    // The DC conditioning category of every component
+    dc_context: Vec<usize>,
+
+    // The conditioning of the DC and AC tables, defined by DAC segments
+    pub dc_lower: [u8; 4],
+    pub dc_upper: [u8; 4],
+    pub ac_kx: [u8; 4],
+
+    pub marker: u8,
+}
+
+impl ArithDecoder {
+    pub fn new() -> ArithDecoder {
+        let mut d = ArithDecoder {
+            c: 0,
+            a: 0,
+            ct: 0,
+            stats: repeat(0u8).take(FIXED_BIN + 1).collect(),
+            dc_context: repeat(0usize).take(256).collect(),
+            dc_lower: [0; 4],
+            dc_upper: [1; 4],
+            ac_kx: [5; 4],
+            marker: 0
+        };
+
+        d.reset();
+        d
+    }
+
+    // Section F.2.4.3
+    // Restarts the decoder at the beginning of a scan or after a restart marker
+    pub fn reset(&mut self) {
+        self.c = 0;
+        self.a = 0;
+        self.ct = -16;
+        self.marker = 0;
+
+        for s in self.stats.iter_mut() {
+            *s = 0;
+        }
+
+        for context in self.dc_context.iter_mut() {
+            *context = 0;
+        }
+
+        self.stats[FIXED_BIN] = 113;
+    }
+
+    // Section D.2
+    // Figure D.19, the decoder is initialized by the first renormalization
+    fn decode<R: Read>(&mut self, r: &mut R, bin: usize) -> ImageResult<bool> {
+        while self.a < 0x8000 {
+            self.ct -= 1;
+
+            if self.ct < 0 {
+                // Once a marker is found the rest of the data is zero
+                let data = if self.marker != 0 {
+                    0
+                } else {
+                    let mut byte = try!(r.read_u8());
+
+                    if byte == 0xFF {
+                        let mut byte2 = try!(r.read_u8());
+
+                        while byte2 == 0xFF {
+                            byte2 = try!(r.read_u8());
+                        }
+
+                        if byte2 != 0 {
+                            self.marker = byte2;
+                            byte = 0;
+                        }
+                    }
+
+                    byte
+                };
+
+                self.c = (self.c << 8) | data as i32;
+                self.ct += 8;
+
+                if self.ct < 0 {
+                    self.ct += 1;
+
+                    if self.ct == 0 {
+                        self.a = 0x8000;
+                    }
+                }
+            }
+
+            self.a <<= 1;
+        }
+
+        let state = self.stats[bin];
+        let (qe, lps, mps, switch) = QE_STATES[(state & 0x7F) as usize];
+        let sense = state & 0x80;
+        let after_lps = sense ^ (lps | switch << 7);
+        let after_mps = sense ^ mps;
+
+        self.a -= qe;
+        let temp = self.a << self.ct as usize;
+
+        // The conditional exchange decodes the LPS for the larger subinterval
+        let lps_decoded = if self.c >= temp {
+            self.c -= temp;
+            let exchanged = self.a < qe;
+            self.a = qe;
+            !exchanged
+        } else if self.a < 0x8000 {
+            self.a < qe
+        } else {
+            return Ok(sense != 0)
+        };
+
+        if lps_decoded {
+            self.stats[bin] = after_lps;
+            Ok(sense == 0)
+        } else {
+            self.stats[bin] = after_mps;
+            Ok(sense != 0)
+        }
+    }
+
+    // Decodes a decision of the bin at index of the AC statistics of table
+    pub fn decode_ac<R: Read>(&mut self, r: &mut R, table: u8, index: usize) -> ImageResult<bool> {
+        self.decode(r, AC_OFFSET + table as usize * AC_BINS + index)
+    }
+
+    // Decodes a decision with an even probability
+    pub fn decode_fixed<R: Read>(&mut self, r: &mut R) -> ImageResult<bool> {
+        self.decode(r, FIXED_BIN)
+    }
+
+    // Section F.1.4.4.1
+    // Figure F.19, decodes the DC difference of component in the context of its previous difference
+    pub fn decode_dc_diff<R: Read>(&mut self, r: &mut R, component: u8, table: u8) -> ImageResult<i32> {
+        let base = table as usize * DC_BINS;
+        let st = base + self.dc_context[component as usize];
+
+        if !try!(self.decode(r, st)) {
+            self.dc_context[component as usize] = 0;
+            return Ok(0)
+        }
+
+        let sign = try!(self.decode(r, st + 1)) as usize;
+        let st = st + 2 + sign;
+
+        let (m, st) = if try!(self.decode(r, st)) {
+            try!(self.decode_magnitude(r, 1, base + 20))
+        } else {
+            (0, st)
+        };
+
+        // Section F.1.4.4.1.2
+        let (lower, upper) = (self.dc_lower[table as usize], self.dc_upper[table as usize]);
+        self.dc_context[component as usize] = if m < (1 << lower as usize) >> 1 {
+            0
+        } else if m > (1 << upper as usize) >> 1 {
+            12 + sign * 4
+        } else {
+            4 + sign * 4
+        };
+
+        let v = try!(self.decode_magnitude_bits(r, m, st + 14));
+        Ok(if sign == 1 { -v } else { v })
+    }
+
+    // Section F.1.4.4.2
+    // Figure F.21, decodes the non-zero AC coefficient at k in zigzag order
+    pub fn decode_ac_value<R: Read>(&mut self, r: &mut R, table: u8, k: usize) -> ImageResult<i32> {
+        let base = AC_OFFSET + table as usize * AC_BINS;
+        let sign = try!(self.decode_fixed(r));
+
+        let st = base + 3 * (k - 1) + 2;
+        let (m, st) = if try!(self.decode(r, st)) {
+            let kx = self.ac_kx[table as usize] as usize;
+            let x2 = base + if k <= kx { 189 } else { 217 };
+
+            // The first two categories share their bin
+            if try!(self.decode(r, st)) {
+                try!(self.decode_magnitude(r, 2, x2))
+            } else {
+                (1, st)
+            }
+        } else {
+            (0, st)
+        };
+
+        let v = try!(self.decode_magnitude_bits(r, m, st + 14));
+        Ok(if sign { -v } else { v })
+    }
+
+    // Figure F.23
+    // Decodes the rest of the magnitude category of a value, whose magnitude is at least m,
+    // from consecutive bins starting at st.
+    // Returns the upper bound of the magnitude and the bin of the last decision.
+    fn decode_magnitude<R: Read>(&mut self, r: &mut R, m: i32, st: usize) -> ImageResult<(i32, usize)> {
+        let mut m = m;
+        let mut st = st;
+
+        while try!(self.decode(r, st)) {
+            m <<= 1;
+            st += 1;
+
+            if m == 0x8000 {
+                return Err(image::ImageError::FormatError("Invalid magnitude category.".to_string()))
+            }
+        }
+
+        Ok((m, st))
+    }
+
+    // Figure F.24
+    // Decodes the bits below the upper bound m of a magnitude, returns the value
+    fn decode_magnitude_bits<R: Read>(&mut self, r: &mut R, m: i32, st: usize) -> ImageResult<i32> {
+        let mut v = m;
+        let mut bit = m >> 1;
+
+        while bit != 0 {
+            if try!(self.decode(r, st)) {
+                v |= bit;
+            }
+
+            bit >>= 1;
+        }
+
+        Ok(v + 1)
+    }
+}
@@ -1,7 +1,12 @@
 //! Decoding and Encoding of JPEG Images
 //!
 //! JPEG (Joint Photographic Experts Group) is an image format that supports lossy compression.
-//! This module implements the Baseline JPEG standard.
+//! This module implements the Baseline JPEG standard, progressive images can be decoded and encoded as well.
+//! Extended images with 12 bit samples and lossless images of up to 16 bits can be decoded as well.
+//! Sequential and progressive images can be huffman or arithmetic coded.
+//! Images can be decoded at 1/2, 1/4 or 1/8 of their size, which is faster than decoding and resizing them.
+//! Four component CMYK and YCCK images are converted to RGB.
+//! The quantized coefficients of an image can be read, rotated, flipped, cropped and written again without loss.
 //!
 //! #Related Links
 //! * http://www.w3.org/Graphics/JPEG/itu-t81.pdf - The JPEG specification
@@ -9,9 +14,12 @@
 
 pub use self::decoder::JPEGDecoder;
 pub use self::encoder::JPEGEncoder;
+pub use self::encoder::Subsampling;
 pub use self::decoder::Component;
+pub use self::coefficients::{Coefficients, ComponentCoefficients};
 
 mod encoder;
 mod decoder;
 mod entropy;
 mod transform;
+mod coefficients;
@@ -1,3 +1,4 @@
+use std::num::Float;
 use std::num::wrapping::WrappingOps;
 
 // The forward dct's output coefficients are scaled by 8
@@ -358,3 +359,119 @@ pub fn idct(coeffs: &[i32], samples: &mut [u8]) {
         samples[y0 + 4] = level_shift_up(a);
     }
 }
+
+// The reduced idcts are translations of jpeg_idct_4x4, jpeg_idct_2x2 and jpeg_idct_1x1
+// from jidctint.c of libjpeg version 9a. They transform the low frequency coefficients
+// of an 8x8 block to a block of 4x4, 2x2 or 1x1 samples, stored row by row.
+pub fn idct4x4(coeffs: &[i32], samples: &mut [u8]) {
+    let mut tmp = [0i32; 16];
+
+    for x in (0usize..4) {
+        // Even part
+        let t0 = coeffs[x + 8 * 0];
+        let t2 = coeffs[x + 8 * 2];
+
+        let t10 = (t0 + t2) << PASS1_BITS as usize;
+        let t12 = (t0 - t2) << PASS1_BITS as usize;
+
+        // Odd part
+        let z2 = coeffs[x + 8 * 1];
+        let z3 = coeffs[x + 8 * 3];
+
+        let mut z1 = (z2 + z3) * FIX_0_541196100;
+        // Add fudge factor here for final descale
+        z1 += 1 << (CONST_BITS - PASS1_BITS - 1) as usize;
+
+        let t0 = (z1 + z2 * FIX_0_765366865) >> (CONST_BITS - PASS1_BITS) as usize;
+        let t2 = (z1 - z3 * FIX_1_847759065) >> (CONST_BITS - PASS1_BITS) as usize;
+
+        tmp[x + 4 * 0] = t10 + t0;
+        tmp[x + 4 * 3] = t10 - t0;
+        tmp[x + 4 * 1] = t12 + t2;
+        tmp[x + 4 * 2] = t12 - t2;
+    }
+
+    for y in (0usize..4) {
+        let y0 = y * 4;
+
+        // Even part
+        let t0 = tmp[y0 + 0] + (1 << (PASS1_BITS + 2) as usize);
+        let t2 = tmp[y0 + 2];
+
+        let t10 = (t0 + t2) << CONST_BITS as usize;
+        let t12 = (t0 - t2) << CONST_BITS as usize;
+
+        // Odd part
+        let z2 = tmp[y0 + 1];
+        let z3 = tmp[y0 + 3];
+
+        let z1 = (z2 + z3) * FIX_0_541196100;
+        let t0 = z1 + z2 * FIX_0_765366865;
+        let t2 = z1 - z3 * FIX_1_847759065;
+
+        samples[y0 + 0] = level_shift_up((t10 + t0) >> (CONST_BITS + PASS1_BITS + 3) as usize);
+        samples[y0 + 3] = level_shift_up((t10 - t0) >> (CONST_BITS + PASS1_BITS + 3) as usize);
+        samples[y0 + 1] = level_shift_up((t12 + t2) >> (CONST_BITS + PASS1_BITS + 3) as usize);
+        samples[y0 + 2] = level_shift_up((t12 - t2) >> (CONST_BITS + PASS1_BITS + 3) as usize);
+    }
+}
+
+pub fn idct2x2(coeffs: &[i32], samples: &mut [u8]) {
+    // Column 0, with the fudge factor for the final descale
+    let t4 = coeffs[8 * 0] + (1 << 2);
+    let t5 = coeffs[8 * 1];
+    let t0 = t4 + t5;
+    let t2 = t4 - t5;
+
+    // Column 1
+    let t4 = coeffs[8 * 0 + 1];
+    let t5 = coeffs[8 * 1 + 1];
+    let t1 = t4 + t5;
+    let t3 = t4 - t5;
+
+    samples[0] = level_shift_up((t0 + t1) >> 3);
+    samples[1] = level_shift_up((t0 - t1) >> 3);
+    samples[2] = level_shift_up((t2 + t3) >> 3);
+    samples[3] = level_shift_up((t2 - t3) >> 3);
+}
+
+pub fn idct1x1(coeffs: &[i32], samples: &mut [u8]) {
+    // The sample is the DC coefficient divided by 8
+    samples[0] = level_shift_up((coeffs[0] + (1 << 2)) >> 3);
+}
+
+// The factors c(u) cos((2x + 1) u pi / 16) / 2 of the inverse dct at index x * 8 + u,
+// where c(0) is the square root of 1/2 and c(u) is 1 otherwise
+static IDCT_COSINES: [f32; 64] = [
+     0.353553391,  0.490392640,  0.461939766,  0.415734806,  0.353553391,  0.277785117,  0.191341716,  0.097545161,
+     0.353553391,  0.415734806,  0.191341716, -0.097545161, -0.353553391, -0.490392640, -0.461939766, -0.277785117,
+     0.353553391,  0.277785117, -0.191341716, -0.490392640, -0.353553391,  0.097545161,  0.461939766,  0.415734806,
+     0.353553391,  0.097545161, -0.461939766, -0.277785117,  0.353553391,  0.415734806, -0.191341716, -0.490392640,
+     0.353553391, -0.097545161, -0.461939766,  0.277785117,  0.353553391, -0.415734806, -0.191341716,  0.490392640,
+     0.353553391, -0.277785117, -0.191341716,  0.490392640, -0.353553391, -0.097545161,  0.461939766, -0.415734806,
+     0.353553391, -0.415734806,  0.191341716,  0.097545161, -0.353553391,  0.490392640, -0.461939766,  0.277785117,
+     0.353553391, -0.490392640,  0.461939766, -0.415734806,  0.353553391, -0.277785117,  0.191341716, -0.097545161,
+];
+
+// Section A.3.3
+// A floating point inverse dct for samples of more than 8 bits, which would overflow
+// the integer idct. The output samples are clamped to the range of precision bits.
+pub fn idct_high_precision(coeffs: &[i32], samples: &mut [u16], precision: u8) {
+    let mut tmp = [0f32; 64];
+
+    for v in (0usize..8) {
+        for x in (0usize..8) {
+            tmp[v * 8 + x] = (0..8).fold(0.0, |sum, u| sum + IDCT_COSINES[x * 8 + u] * coeffs[v * 8 + u] as f32);
+        }
+    }
+
+    let center = (1 << (precision - 1) as usize) as f32;
+    let max = ((1 << precision as usize) - 1) as f32;
+
+    for y in (0usize..8) {
+        for x in (0usize..8) {
+            let sample = (0..8).fold(center, |sum, v| sum + IDCT_COSINES[y * 8 + v] * tmp[v * 8 + x]);
+            samples[y * 8 + x] = sample.round().max(0.0).min(max) as u16;
+        }
+    }
+}
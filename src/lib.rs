@@ -14,13 +14,11 @@
 #![feature(collections)]
 #![feature(io)]
 #![feature(std_misc)]
-#![feature(rustc_private)]
 #![feature(step_by)]
 #![feature(convert)]
 #![cfg_attr(test, feature(test))]
 
 extern crate byteorder;
-extern crate flate;
 extern crate num;
 #[cfg(test)] extern crate test;
 
@@ -88,6 +86,7 @@ pub use image::MutPixels as MutPixels;
 /// Opening and loading images
 pub use dynimage:: {
     open,
+    open_upright,
     load,
     load_from_memory,
     load_from_memory_with_format,
@@ -98,12 +97,16 @@ pub use dynimage::DynamicImage:: {
     ImageRgba8,
     ImageLuma8,
     ImageLumaA8,
+    ImageLuma16,
+    ImageRgb16,
 };
 
 pub use animation:: {
     Frame, Frames
 };
 
+pub use indexed::IndexedImage;
+
 // Math utils
 pub mod math;
 
@@ -134,3 +137,4 @@ mod color;
 mod buffer;
 mod traits;
 mod animation;
+mod indexed;
@@ -0,0 +1,342 @@
+//! Animated PNG images
+//!
+//! An APNG image stores the number of frames in an acTL chunk before the image data.
+//! Each frame is described by an fcTL chunk, its data follows in fdAT chunks.
+//! The default image can be the first frame, in which case its fcTL chunk precedes the IDAT chunks.
+//!
+//! # Related Links
+//! * https://wiki.mozilla.org/APNG_Specification - The APNG Specification
+
+use std::io;
+use std::cmp;
+use std::num::FromPrimitive;
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use num::rational::Ratio;
+
+use image::{ImageError, ImageResult};
+use buffer::{ImageBuffer, RgbaImage, Pixel};
+use color::Rgba;
+use animation::Frame;
+
+use super::hash::Crc32;
+use super::decoder::PNGSIGNATURE;
+
+/// How the region of a frame is disposed before the next frame is rendered
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+pub enum DisposeOp {
+    /// The region is left as it is
+    None = 0,
+
+    /// The region is cleared to transparent black
+    Background = 1,
+
+    /// The region is reverted to its content before the frame was rendered
+    Previous = 2,
+}
+
+/// How a frame is rendered to its region
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+pub enum BlendOp {
+    /// The frame replaces the region
+    Source = 0,
+
+    /// The frame is composited over the region using its alpha channel
+    Over = 1,
+}
+
+/// The content of an fcTL chunk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FrameControl {
+    pub sequence_number: u32,
+    pub width: u32,
+    pub height: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub delay_num: u16,
+    pub delay_den: u16,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    /// Reads an fcTL chunk
+    pub fn from_bytes(data: &[u8]) -> ImageResult<FrameControl> {
+        let mut r = io::Cursor::new(data);
+
+        let sequence_number = try!(r.read_u32::<BigEndian>());
+        let width = try!(r.read_u32::<BigEndian>());
+        let height = try!(r.read_u32::<BigEndian>());
+        let x_offset = try!(r.read_u32::<BigEndian>());
+        let y_offset = try!(r.read_u32::<BigEndian>());
+        let delay_num = try!(r.read_u16::<BigEndian>());
+        let delay_den = try!(r.read_u16::<BigEndian>());
+
+        let dispose_op = FromPrimitive::from_u8(try!(r.read_u8()));
+        let blend_op = FromPrimitive::from_u8(try!(r.read_u8()));
+
+        match (dispose_op, blend_op) {
+            (Some(dispose_op), Some(blend_op)) if width > 0 && height > 0 => Ok(FrameControl {
+                sequence_number: sequence_number,
+                width: width,
+                height: height,
+                x_offset: x_offset,
+                y_offset: y_offset,
+                delay_num: delay_num,
+                delay_den: delay_den,
+                dispose_op: dispose_op,
+                blend_op: blend_op
+            }),
+            _ => Err(ImageError::FormatError("Frame control chunk malformed.".to_string()))
+        }
+    }
+
+    /// Returns the content of the fcTL chunk
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut m = Vec::with_capacity(26);
+
+        let _ = m.write_u32::<BigEndian>(self.sequence_number);
+        let _ = m.write_u32::<BigEndian>(self.width);
+        let _ = m.write_u32::<BigEndian>(self.height);
+        let _ = m.write_u32::<BigEndian>(self.x_offset);
+        let _ = m.write_u32::<BigEndian>(self.y_offset);
+        let _ = m.write_u16::<BigEndian>(self.delay_num);
+        let _ = m.write_u16::<BigEndian>(self.delay_den);
+        m.push(self.dispose_op as u8);
+        m.push(self.blend_op as u8);
+
+        m
+    }
+
+    /// The delay of the frame in seconds, a denominator of 0 means hundredths of a second
+    pub fn delay(&self) -> Ratio<u16> {
+        match self.delay_den {
+            0 => Ratio::new(self.delay_num, 100),
+            den => Ratio::new(self.delay_num, den)
+        }
+    }
+}
+
+/// Builds a PNG image of the frame data of ```control```.
+/// ```header``` are the IHDR chunk of the animation and the chunks that
+/// the frame shares with it, like PLTE and tRNS, as chunk type and content.
+pub fn frame_image(header: &[(Vec<u8>, Vec<u8>)], control: &FrameControl, data: &[u8]) -> Vec<u8> {
+    let mut png = PNGSIGNATURE.to_vec();
+
+    for &(ref chunk_type, ref chunk) in header.iter() {
+        if &**chunk_type == b"IHDR" {
+            let mut ihdr = Vec::with_capacity(chunk.len());
+            let _ = ihdr.write_u32::<BigEndian>(control.width);
+            let _ = ihdr.write_u32::<BigEndian>(control.height);
+            ihdr.push_all(&chunk[8..]);
+
+            write_chunk(&mut png, chunk_type, &ihdr);
+        } else {
+            write_chunk(&mut png, chunk_type, chunk);
+        }
+    }
+
+    write_chunk(&mut png, b"IDAT", data);
+    write_chunk(&mut png, b"IEND", &[]);
+
+    png
+}
+
+/// Appends a chunk with its length and checksum to ```w```
+pub fn write_chunk(w: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+    let mut crc = Crc32::new();
+    crc.update(chunk_type);
+    crc.update(data);
+
+    let _ = w.write_u32::<BigEndian>(data.len() as u32);
+    w.push_all(chunk_type);
+    w.push_all(data);
+    let _ = w.write_u32::<BigEndian>(crc.checksum());
+}
+
+/// Renders the frames of an animation onto a canvas of ```width``` x ```height``` pixels,
+/// that is transparent black before the first frame.
+/// Returns a frame with the whole canvas after each frame was rendered.
+pub fn compose(width: u32, height: u32, frames: Vec<(FrameControl, RgbaImage)>) -> ImageResult<Vec<Frame>> {
+    let mut canvas: RgbaImage = ImageBuffer::new(width, height);
+    let mut composited = Vec::with_capacity(frames.len());
+
+    for (i, (control, image)) in frames.into_iter().enumerate() {
+        let (x0, y0) = (control.x_offset, control.y_offset);
+
+        if x0 as u64 + control.width as u64 > width as u64 ||
+           y0 as u64 + control.height as u64 > height as u64 ||
+           image.dimensions() != (control.width, control.height) {
+            return Err(ImageError::FormatError("Frame is outside of the image.".to_string()))
+        }
+
+        // The first frame is disposed to the background if it should be reverted
+        let previous = match control.dispose_op {
+            DisposeOp::Previous if i > 0 => Some(canvas.clone()),
+            _ => None
+        };
+
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let target = canvas.get_pixel_mut(x0 + x, y0 + y);
+
+            match control.blend_op {
+                BlendOp::Source => *target = *pixel,
+                BlendOp::Over => target.blend(pixel)
+            }
+        }
+
+        composited.push(Frame::from_parts(canvas.clone(), 0, 0, control.delay()));
+
+        match (control.dispose_op, previous) {
+            (DisposeOp::None, _) => (),
+            (DisposeOp::Previous, Some(previous)) => canvas = previous,
+            (_, _) => {
+                for y in (y0..y0 + control.height) {
+                    for x in (x0..x0 + control.width) {
+                        canvas.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(composited)
+}
+
+/// Computes the regions of the canvas that the frames of an animation change.
+/// Each frame replaces the pixels of the canvas at its offset, the canvas is large enough
+/// to hold every frame and transparent black before the first frame.
+/// The first region is the whole canvas, the following regions are the smallest rectangles
+/// that contain the changed pixels, after the previous region was disposed in the way that
+/// changes the least pixels. Returns the size of the canvas and the regions with their image,
+/// the sequence numbers of the frame controls are not set.
+pub fn frame_regions(frames: &[Frame]) -> (u32, u32, Vec<(FrameControl, RgbaImage)>) {
+    let width = frames.iter().map(|f| f.left() + f.buffer().width()).max().unwrap_or(0);
+    let height = frames.iter().map(|f| f.top() + f.buffer().height()).max().unwrap_or(0);
+
+    // The canvas as it is shown after each frame
+    let mut canvas: RgbaImage = ImageBuffer::new(width, height);
+    let canvases: Vec<RgbaImage> = frames.iter().map(|frame| {
+        for (x, y, pixel) in frame.buffer().enumerate_pixels() {
+            canvas.put_pixel(frame.left() + x, frame.top() + y, *pixel);
+        }
+
+        canvas.clone()
+    }).collect();
+
+    let mut regions: Vec<(FrameControl, RgbaImage)> = Vec::with_capacity(frames.len());
+
+    // The canvas before the previous frame was rendered
+    let mut before: RgbaImage = ImageBuffer::new(width, height);
+
+    for (i, (frame, target)) in frames.iter().zip(canvases.iter()).enumerate() {
+        if i == 0 {
+            let control = frame_control(0, 0, width, height, frame, BlendOp::Source);
+            regions.push((control, target.clone()));
+            continue
+        }
+
+        let previous = &canvases[i - 1];
+        let mut cleared = previous.clone();
+        {
+            let last = &regions[i - 1].0;
+            for y in (last.y_offset..last.y_offset + last.height) {
+                for x in (last.x_offset..last.x_offset + last.width) {
+                    cleared.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                }
+            }
+        }
+
+        let mut candidates = vec![(DisposeOp::None, previous.clone()), (DisposeOp::Background, cleared)];
+
+        // Decoders dispose the first frame to the background instead of reverting it
+        if i > 1 {
+            candidates.push((DisposeOp::Previous, before));
+        }
+
+        // No changed pixels is the smallest area
+        let area = |bounds: Option<(u32, u32, u32, u32)>| bounds.map(|(_, _, w, h)| w as u64 * h as u64);
+        let mut best = None;
+
+        for (dispose, base) in candidates.into_iter() {
+            let bounds = changed_bounds(&base, target);
+
+            let better = match best {
+                Some((_, _, ref best_bounds)) => area(bounds) < area(*best_bounds),
+                None => true
+            };
+
+            if better {
+                best = Some((dispose, base, bounds));
+            }
+        }
+
+        let (dispose, base, bounds) = best.unwrap();
+
+        regions[i - 1].0.dispose_op = dispose;
+
+        // A frame that changes nothing still has to cover a pixel
+        let (x0, y0, w, h) = bounds.unwrap_or((0, 0, 1, 1));
+
+        // Changed pixels that are opaque can be blended over the canvas,
+        // which lets the unchanged pixels be transparent
+        let over = (y0..y0 + h).all(|y| (x0..x0 + w).all(|x| {
+            let pixel = target.get_pixel(x, y);
+            pixel[3] == 255 || pixel == base.get_pixel(x, y)
+        }));
+
+        let image = ImageBuffer::from_fn(w, h, |x, y| {
+            let pixel = *target.get_pixel(x0 + x, y0 + y);
+
+            if over && pixel == *base.get_pixel(x0 + x, y0 + y) {
+                Rgba([0, 0, 0, 0])
+            } else {
+                pixel
+            }
+        });
+
+        let blend = if over {BlendOp::Over} else {BlendOp::Source};
+        regions.push((frame_control(x0, y0, w, h, frame, blend), image));
+
+        before = base;
+    }
+
+    (width, height, regions)
+}
+
+fn frame_control(x: u32, y: u32, width: u32, height: u32, frame: &Frame, blend_op: BlendOp) -> FrameControl {
+    let delay = frame.delay();
+
+    FrameControl {
+        sequence_number: 0,
+        width: width,
+        height: height,
+        x_offset: x,
+        y_offset: y,
+        delay_num: *delay.numer(),
+        delay_den: *delay.denom(),
+        dispose_op: DisposeOp::None,
+        blend_op: blend_op
+    }
+}
+
+// Returns the offset and size of the smallest rectangle that contains the pixels
+// which differ in a and b, None if the images are equal
+fn changed_bounds(a: &RgbaImage, b: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+    let (width, height) = a.dimensions();
+    let (mut x0, mut y0, mut x1, mut y1) = (width, height, 0, 0);
+
+    for (x, y, pixel) in a.enumerate_pixels() {
+        if pixel != b.get_pixel(x, y) {
+            x0 = cmp::min(x0, x);
+            y0 = cmp::min(y0, y);
+            x1 = cmp::max(x1, x + 1);
+            y1 = cmp::max(y1, y + 1);
+        }
+    }
+
+    if x0 < x1 {
+        Some((x0, y0, x1 - x0, y1 - y0))
+    } else {
+        None
+    }
+}
@@ -1,9 +1,6 @@
 use std::io::{self, Read};
 use std::cmp;
-use std::mem;
-use std::iter;
 use std::iter::repeat;
-use std::str;
 use std::slice;
 use std::num::FromPrimitive;
 use byteorder::{ReadBytesExt, BigEndian};
@@ -15,10 +12,16 @@ use image::{
     ImageError
 };
 use color;
+use color::Rgba;
+use indexed::IndexedImage;
+use animation::{Frame, Frames};
+use buffer::{ImageBuffer, RgbaImage};
 
 use super::filter::unfilter;
 use super::hash::Crc32;
 use super::zlib::ZlibDecoder;
+use super::metadata::{self, Metadata};
+use super::apng::{self, FrameControl};
 
 use std::num::Float;
 
@@ -32,9 +35,7 @@ enum PNGState {
     HaveIHDR,
     HavePLTE,
     HaveFirstIDat,
-    #[allow(dead_code)]
     HaveLastIDat,
-    #[allow(dead_code)]
     HaveIEND
 }
 
@@ -44,6 +45,13 @@ enum InterlaceMethod {
     Adam7 = 1
 }
 
+/// The first column, the first row, and the distances between the columns
+/// and the rows of the pixels of each Adam7 pass
+static ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4),
+    (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)
+];
+
 /// This iterator iterates over the different passes of an image Adam7 encoded
 /// PNG image
 /// The glorious pattern is:
@@ -57,7 +65,7 @@ enum InterlaceMethod {
 ///     77777777
 ///
 #[derive(Copy)]
-struct Adam7Iterator {
+pub struct Adam7Iterator {
     line: u32,
     lines: u32,
     line_width: u32,
@@ -80,22 +88,20 @@ impl Adam7Iterator {
         this
     }
 
+    /// Returns the first column, the first row, and the distances between the columns
+    /// and the rows of the pixels of the pass ```pass```, which ranges from 1 to 7
+    pub fn pass_geometry(pass: u8) -> (u32, u32, u32, u32) {
+        ADAM7[pass as usize - 1]
+    }
+
     /// Calculates the bounds of the current pass
     fn init_pass(&mut self) {
-        let w = self.width as f64;
-        let h = self.height as f64;
-        let (line_width, lines) = match self.current_pass {
-            1 => (w/8.0, h/8.0),
-            2 => ((w-4.0)/8.0, h/8.0),
-            3 => (w/4.0, (h-4.0)/8.0),
-            4 => ((w-2.0)/4.0, h/4.0),
-            5 => (w/2.0, (h-2.0)/4.0),
-            6 => ((w-1.0)/2.0, h/2.0),
-            7 => (w, (h-1.0)/2.0),
-            _ => unreachable!()
-        };
-        self.line_width = line_width.ceil() as u32;
-        self.lines = lines.ceil() as u32;
+        let (x0, y0, dx, dy) = Adam7Iterator::pass_geometry(self.current_pass);
+
+        self.line_width = if self.width > x0 { (self.width - x0 + dx - 1) / dx } else { 0 };
+
+        // A pass without columns has no lines either, they would be empty
+        self.lines = if self.height > y0 && self.line_width > 0 { (self.height - y0 + dy - 1) / dy } else { 0 };
         self.line = 0;
     }
 }
@@ -137,6 +143,31 @@ pub struct PNGDecoder<R> {
 
     palette: Option<Vec<(u8, u8, u8)>>,
 
+    // The alpha of the palette entries and the samples of the transparent color
+    // of gray and RGB images, from the tRNS chunk
+    palette_alpha: Option<Vec<u8>>,
+    transparent_color: Option<Vec<u16>>,
+
+    // Palette images are decoded to one index per byte instead of colors
+    indexed: bool,
+
+    metadata: Metadata,
+
+    // The chunks that the frames of an animation share with the default image
+    header_chunks: Vec<(Vec<u8>, Vec<u8>)>,
+
+    // The number of frames and plays of the acTL chunk, the fcTL chunk of the default image
+    // if it is the first frame and the fcTL chunks of the other frames with their image data
+    animation_control: Option<(u32, u32)>,
+    default_frame: Option<FrameControl>,
+    frames: Vec<(FrameControl, Vec<u8>)>,
+
+    // The fcTL and fdAT chunks share one sequence of numbers, without gaps from 0
+    next_sequence_number: u32,
+
+    // The error that ended the chunks after the image data, the frames of an animation are incomplete then
+    trailing_error: Option<ImageError>,
+
     interlace_method: InterlaceMethod,
     pass_iterator: Option<Adam7Iterator>,
 
@@ -156,6 +187,16 @@ impl<R: Read> PNGDecoder<R> {
         PNGDecoder {
             pixel_type: color::ColorType::Gray(1),
             palette: None,
+            palette_alpha: None,
+            transparent_color: None,
+            indexed: false,
+            metadata: Default::default(),
+            header_chunks: Vec::new(),
+            animation_control: None,
+            default_frame: None,
+            frames: Vec::new(),
+            next_sequence_number: 0,
+            trailing_error: None,
 
             previous: Vec::new(),
             state: PNGState::Start,
@@ -188,6 +229,64 @@ impl<R: Read> PNGDecoder<R> {
         }
     }
 
+    /// Decodes a palette image to the palette indices of its pixels, without expanding them
+    /// to colors. The alpha of the palette entries is read from the tRNS chunk.
+    pub fn read_indexed(&mut self) -> ImageResult<IndexedImage> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        if self.colour_type != 3 {
+            return Err(ImageError::UnsupportedError(
+                "Only palette images can be decoded to indices.".to_string()
+            ))
+        }
+        if self.decoded_rows > 0 {
+            return Err(ImageError::ImageEnd)
+        }
+
+        self.indexed = true;
+        self.pixel_type = color::ColorType::Palette(8);
+
+        let indices = match try!(self.read_image()) {
+            DecodingResult::U8(indices) => indices,
+            DecodingResult::U16(_) => unreachable!()
+        };
+
+        let palette = self.palette().iter().enumerate().map(|(i, &(r, g, b))| {
+            let alpha = match self.palette_alpha {
+                Some(ref alpha) => alpha.get(i).map(|&a| a).unwrap_or(255),
+                None => 255
+            };
+
+            Rgba([r, g, b, alpha])
+        }).collect();
+
+        IndexedImage::from_parts(self.width, self.height, indices, palette).ok_or(
+            ImageError::FormatError("Palette index out of range.".to_string())
+        )
+    }
+
+    /// Returns the metadata of the ancillary chunks.
+    /// Chunks that follow the image data are only read after the whole image was decoded.
+    pub fn metadata(&mut self) -> ImageResult<&Metadata> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(&self.metadata)
+    }
+
+    /// Returns the number of times the animation is played, 0 if it is repeated forever.
+    /// Returns ```None``` if the image is not animated.
+    pub fn num_plays(&mut self) -> ImageResult<Option<u32>> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(self.animation_control.map(|(_, plays)| plays))
+    }
+
     fn read_signature(&mut self) -> ImageResult<bool> {
         let mut png = Vec::with_capacity(8);
         try!(self.z.inner().r.by_ref().take(8).read_to_end(&mut png));
@@ -272,6 +371,15 @@ impl<R: Read> PNGDecoder<R> {
         (self.bits_per_pixel as u32 * width + 7) / 8
     }
 
+    // The number of bits of each decoded pixel, palette indices are unpacked to one byte
+    fn decoded_bits_per_pixel(&self) -> usize {
+        if self.indexed {
+            8
+        } else {
+            color::bits_per_pixel(self.pixel_type)
+        }
+    }
+
     fn parse_plte(&mut self, buf: Vec<u8>) -> ImageResult<()> {
         self.crc.update(&*buf);
 
@@ -281,19 +389,45 @@ impl<R: Read> PNGDecoder<R> {
             return Err(ImageError::FormatError("Color palette malformed.".to_string()))
         }
 
-        let p: Vec<(u8, u8, u8)> = (0usize..256).map(|i| {
-            if i < len {
-                let r = buf[3 * i];
-                let g = buf[3 * i + 1];
-                let b = buf[3 * i + 2];
+        let p: Vec<(u8, u8, u8)> = buf.chunks(3).map(|rgb| (rgb[0], rgb[1], rgb[2])).collect();
 
-                (r, g, b)
-            } else {
-                (0, 0, 0)
+        self.palette = Some(p);
+
+        Ok(())
+    }
+
+    // Images with a tRNS chunk get an alpha channel, that is 0 for the transparent color
+    // and the alpha of each palette entry, samples of less than 8 bits are scaled to 8 bits
+    fn parse_trns(&mut self, buf: Vec<u8>) -> ImageResult<()> {
+        self.crc.update(&*buf);
+
+        match (self.colour_type, self.bit_depth) {
+            (3, _) => {
+                if buf.len() > 256 {
+                    return Err(ImageError::FormatError("Transparency chunk malformed.".to_string()))
+                }
+
+                self.pixel_type = color::ColorType::RGBA(8);
+                self.palette_alpha = Some(buf);
             }
-        }).collect();
+            (0, _) | (2, _) => {
+                let channels = if self.colour_type == 0 {1} else {3};
 
-        self.palette = Some(p);
+                if buf.len() != 2 * channels {
+                    return Err(ImageError::FormatError("Transparency chunk malformed.".to_string()))
+                }
+
+                self.pixel_type = match (self.colour_type, self.bit_depth) {
+                    (0, 16) => color::ColorType::GrayA(16),
+                    (0, _)  => color::ColorType::GrayA(8),
+                    (_, 16) => color::ColorType::RGBA(16),
+                    (_, _)  => color::ColorType::RGBA(8),
+                };
+                self.transparent_color = Some(buf.chunks(2).map(|s| (s[0] as u16) << 8 | s[1] as u16).collect());
+            }
+            // Images with an alpha channel must not have a tRNS chunk, it is ignored
+            _ => ()
+        }
 
         Ok(())
     }
@@ -323,21 +457,48 @@ impl<R: Read> PNGDecoder<R> {
 
                     let mut d = Vec::with_capacity(length as usize);
                     try!(self.z.inner().r.by_ref().take(length as u64).read_to_end(&mut d));
+                    self.header_chunks.push((chunk, d.clone()));
                     try!(self.parse_ihdr(d));
 
                     self.state = PNGState::HaveIHDR;
                 }
 
                 (b"PLTE", PNGState::HaveIHDR) => {
-                    let mut d = Vec::with_capacity(length as usize);
+                    let mut d = Vec::new();
                     try!(self.z.inner().r.by_ref().take(length as u64).read_to_end(&mut d));
+                    self.header_chunks.push((chunk, d.clone()));
                     try!(self.parse_plte(d));
                     self.state = PNGState::HavePLTE;
                 }
 
-                // (b"tRNS", HavePLTE) => {
-                //     TODO #199: handle transparency
-                // }
+                (b"tRNS", PNGState::HaveIHDR) | (b"tRNS", PNGState::HavePLTE) => {
+                    // The buffer grows with the data that is read, the length can be larger than the file
+                    let mut d = Vec::new();
+                    try!(self.z.inner().r.by_ref().take(length as u64).read_to_end(&mut d));
+                    self.header_chunks.push((chunk, d.clone()));
+                    try!(self.parse_trns(d));
+                }
+
+                (b"acTL", PNGState::HaveIHDR) | (b"acTL", PNGState::HavePLTE) => {
+                    let mut d = Vec::new();
+                    try!(self.z.inner().r.by_ref().take(length as u64).read_to_end(&mut d));
+                    self.crc.update(&*d);
+
+                    let mut m = io::Cursor::new(d);
+                    let num_frames = try!(m.read_u32::<BigEndian>());
+                    let num_plays = try!(m.read_u32::<BigEndian>());
+                    self.animation_control = Some((num_frames, num_plays));
+                }
+
+                (b"fcTL", PNGState::HaveIHDR) | (b"fcTL", PNGState::HavePLTE) => {
+                    let mut d = Vec::new();
+                    try!(self.z.inner().r.by_ref().take(length as u64).read_to_end(&mut d));
+                    self.crc.update(&*d);
+
+                    let control = try!(FrameControl::from_bytes(&d));
+                    try!(self.check_sequence_number(control.sequence_number));
+                    self.default_frame = Some(control);
+                }
 
                 (b"IDAT", PNGState::HaveIHDR) if self.colour_type != 3 => {
                     self.state = PNGState::HaveFirstIDat;
@@ -356,9 +517,10 @@ impl<R: Read> PNGDecoder<R> {
                 }
 
                 _ => {
-                    let mut b = Vec::with_capacity(length as usize);
+                    let mut b = Vec::new();
                     try!(self.z.inner().r.by_ref().take(length as u64).read_to_end(&mut b));
                     self.crc.update(&*b);
+                    self.read_ancillary_chunk(&b);
                 }
             }
 
@@ -375,6 +537,102 @@ impl<R: Read> PNGDecoder<R> {
         Ok(())
     }
 
+    // Finishes the image data and reads the chunks that follow it.
+    // They only hold metadata and the frames of an animation, so a corrupt or truncated
+    // end of the file does not fail the image that was decoded, its error is kept instead.
+    fn read_trailing_chunks(&mut self) -> ImageResult<()> {
+        if self.state == PNGState::HaveIEND {
+            return Ok(())
+        }
+
+        // Skip the rest of the image data, the zlib stream can end before the last IDAT chunk
+        let _ = try!(io::copy(self.z.inner(), &mut io::sink()));
+        if !self.z.inner().eof {
+            return Err(ImageError::FormatError("CRC checksum invalid.".to_string()))
+        }
+
+        self.state = PNGState::HaveLastIDat;
+        self.chunk_length = self.z.inner().chunk_length;
+        self.chunk_type = self.z.inner().next_chunk.clone();
+
+        if let Err(err) = self.read_chunks_after_image() {
+            self.trailing_error = Some(err);
+        }
+
+        self.state = PNGState::HaveIEND;
+        Ok(())
+    }
+
+    // Reads the chunks after the image data up to the IEND chunk
+    fn read_chunks_after_image(&mut self) -> ImageResult<()> {
+        loop {
+            if self.chunk_type.len() != 4 {
+                return Err(ImageError::ImageEnd)
+            }
+
+            // The buffer grows with the data that is read, the length can be larger than the file
+            let mut d = Vec::new();
+            try!(self.z.inner().r.by_ref().take(self.chunk_length as u64).read_to_end(&mut d));
+
+            if d.len() != self.chunk_length as usize {
+                return Err(ImageError::ImageEnd)
+            }
+
+            self.crc.reset();
+            self.crc.update(&*self.chunk_type);
+            self.crc.update(&*d);
+
+            let chunk_crc = try!(self.z.inner().r.read_u32::<BigEndian>());
+            if self.crc.checksum() != chunk_crc {
+                return Err(ImageError::FormatError("CRC checksum invalid.".to_string()))
+            }
+
+            match &*self.chunk_type {
+                b"fcTL" => {
+                    let control = try!(FrameControl::from_bytes(&d));
+                    try!(self.check_sequence_number(control.sequence_number));
+                    self.frames.push((control, Vec::new()));
+                }
+
+                // The data of fdAT chunks follows their sequence number
+                b"fdAT" if d.len() >= 4 && !self.frames.is_empty() => {
+                    let sequence_number = try!(io::Cursor::new(&d[..4]).read_u32::<BigEndian>());
+                    try!(self.check_sequence_number(sequence_number));
+
+                    let last = self.frames.len() - 1;
+                    self.frames[last].1.push_all(&d[4..]);
+                }
+
+                b"fdAT" => return Err(ImageError::FormatError("Unexpected frame data chunk.".to_string())),
+
+                b"IEND" => return Ok(()),
+
+                _ => self.read_ancillary_chunk(&d)
+            }
+
+            self.chunk_length = try!(self.z.inner().r.read_u32::<BigEndian>());
+            self.chunk_type.clear();
+            try!(self.z.inner().r.by_ref().take(4).read_to_end(&mut self.chunk_type));
+        }
+    }
+
+    // Counts the sequence number of an fcTL or fdAT chunk, which has to be the next one
+    fn check_sequence_number(&mut self, sequence_number: u32) -> ImageResult<()> {
+        if sequence_number != self.next_sequence_number {
+            return Err(ImageError::FormatError(format!("Invalid APNG sequence number {}", sequence_number)))
+        }
+
+        self.next_sequence_number += 1;
+        Ok(())
+    }
+
+    // Reads the current chunk into the metadata, a malformed chunk is skipped
+    fn read_ancillary_chunk(&mut self, data: &[u8]) {
+        if metadata::read_chunk(&mut self.metadata, &self.chunk_type, data).is_err() {
+            self.metadata.skipped_chunks.push(self.chunk_type.clone());
+        }
+    }
+
     fn extract_scanline(&mut self, buf: &mut [u8], rlength: u32) -> ImageResult<u32> {
         let filter_type = match FromPrimitive::from_u8(try!(try!(self.z.by_ref().bytes().next().ok_or(ImageError::ImageEnd)))) {
             Some(v) => v,
@@ -393,8 +651,14 @@ impl<R: Read> PNGDecoder<R> {
         unfilter(filter_type, self.bpp as usize, &self.previous, &mut buf[..rlength as usize]);
         slice::bytes::copy_memory(&mut self.previous, &buf[..rlength as usize]);
 
-        if let Some(ref palette) = self.palette {
-            expand_palette(buf, &palette, rlength as usize, self.bit_depth);
+        let pixels = buf.len() * 8 / self.decoded_bits_per_pixel();
+
+        if self.indexed {
+            unpack_indices(buf, pixels, self.bit_depth);
+        } else if let Some(ref palette) = self.palette {
+            expand_palette(buf, &palette, self.palette_alpha.as_ref().map(|a| &a[..]), pixels, self.bit_depth);
+        } else if let Some(ref transparent) = self.transparent_color {
+            expand_transparent_color(buf, &transparent, pixels, self.bit_depth);
         }
 
         self.decoded_rows += 1;
@@ -420,12 +684,69 @@ impl<R: Read> ImageDecoder for PNGDecoder<R> {
         Ok(self.pixel_type)
     }
 
+    fn is_animated(&mut self) -> ImageResult<bool> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(self.animation_control.is_some())
+    }
+
+    /// Returns the frames of an animated image composited onto the canvas, as the image
+    /// would be shown after each frame. Images that are not animated have a single frame.
+    fn into_frames(mut self) -> ImageResult<Frames> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        // Decoding the default image reads the frames that follow it
+        let default_image = try!(decode_rgba(&mut self));
+
+        if self.animation_control.is_none() {
+            return Ok(Frames::new(vec![Frame::new(default_image)]))
+        }
+
+        if let Some(err) = self.trailing_error.take() {
+            return Err(err)
+        }
+
+        // Every frame of the acTL chunk needs its fcTL chunk and image data
+        let num_frames = self.animation_control.map_or(0, |(frames, _)| frames);
+        let decoded_frames = self.frames.len() + if self.default_frame.is_some() { 1 } else { 0 };
+
+        if decoded_frames != num_frames as usize {
+            return Err(ImageError::FormatError(format!(
+                "The animation has {} of {} frames", decoded_frames, num_frames
+            )))
+        }
+
+        if self.frames.iter().any(|&(_, ref data)| data.is_empty()) {
+            return Err(ImageError::FormatError("Missing frame data.".to_string()))
+        }
+
+        let mut frames = Vec::with_capacity(self.frames.len() + 1);
+
+        if let Some(control) = self.default_frame {
+            frames.push((control, default_image));
+        }
+
+        // Each frame is decoded as an image that shares the header of the default image
+        for &(ref control, ref data) in self.frames.iter() {
+            let png = apng::frame_image(&self.header_chunks, control, data);
+            let image = try!(decode_rgba(&mut PNGDecoder::new(io::Cursor::new(png))));
+
+            frames.push((*control, image));
+        }
+
+        Ok(Frames::new(try!(apng::compose(self.width, self.height, frames))))
+    }
+
     fn row_len(&mut self) -> ImageResult<usize> {
         if self.state == PNGState::Start {
             let _ = try!(self.read_metadata());
         }
 
-        let bits = color::bits_per_pixel(self.pixel_type);
+        let bits = self.decoded_bits_per_pixel();
 
         Ok((bits * self.width as usize + 7) / 8)
     }
@@ -438,7 +759,13 @@ impl<R: Read> ImageDecoder for PNGDecoder<R> {
             return Err(ImageError::UnsupportedError("Image is interlaced, extraction of single scanlines is unsupported".to_string()))
         }
         let rlength = self.raw_row_length(self.width);
-        self.extract_scanline(buf, rlength)
+        let row = try!(self.extract_scanline(buf, rlength));
+
+        if row == self.height {
+            try!(self.read_trailing_chunks());
+        }
+
+        Ok(row)
     }
 
     fn read_image(&mut self) -> ImageResult<DecodingResult> {
@@ -450,7 +777,7 @@ impl<R: Read> ImageDecoder for PNGDecoder<R> {
         if let Some(pass_iterator) = self.pass_iterator { // Method == Adam7
             let mut pass_buf: Vec<u8> = repeat(0u8).take(max_rowlen).collect();
             let mut old_pass = 1;
-            let bytes = color::bits_per_pixel(self.pixel_type)/8;
+            let bits = self.decoded_bits_per_pixel();
             for (pass, line, width) in pass_iterator {
                 let rlength = self.raw_row_length(width);
                 if old_pass != pass {
@@ -459,18 +786,15 @@ impl<R: Read> ImageDecoder for PNGDecoder<R> {
                         *v = 0;
                     }
                 }
-                let bits = color::bits_per_pixel(self.pixel_type);
                 let _ = try!(
                     self.extract_scanline(&mut pass_buf[..
                         ((bits * width as usize + 7) / 8)
                     ], rlength)
                 );
-                expand_pass(
-                    &mut buf, self.width * bytes as u32,
-                    &mut pass_buf[..width as usize * bytes], pass, line, bytes as u8
-                );
+                expand_pass(&mut buf, max_rowlen, &pass_buf, pass, line, width, bits);
                 old_pass = pass;
             }
+            try!(self.read_trailing_chunks());
             Ok(DecodingResult::U8(buf))
         } else {
             for chunk in buf.chunks_mut(max_rowlen) {
@@ -481,63 +805,143 @@ impl<R: Read> ImageDecoder for PNGDecoder<R> {
     }
 }
 
-macro_rules! expand_pass(
-    ($img:expr, $scanline:expr, $j:ident, $pos:expr, $bytes_pp:expr) => {
-        for ($j, pixel) in $scanline.chunks($bytes_pp).enumerate() {
-            for (offset, val) in pixel.iter().enumerate() {
-                $img[$pos + offset] = *val
+// Copies the ```width``` pixels of the line ```line_no``` of the pass ```pass``` to their positions
+// in the image, ```rowlen``` is the length of the rows of the image in bytes
+fn expand_pass(
+    img: &mut[u8], rowlen: usize, scanline: &[u8],
+    pass: u8, line_no: u32, width: u32, bits: usize) {
+    let (x0, y0, dx, dy) = Adam7Iterator::pass_geometry(pass);
+    let row = (y0 + line_no * dy) as usize * rowlen;
+    let img_row = &mut img[row..row + rowlen];
+
+    for j in (0..width) {
+        copy_pixel(scanline, j as usize, img_row, (x0 + j * dx) as usize, bits);
+    }
+}
+
+/// Copies the pixel ```x``` of ```row``` to the pixel ```i``` of ```out```,
+/// pixels with less than 8 bits are packed starting with the most significant bits
+pub fn copy_pixel(row: &[u8], x: usize, out: &mut [u8], i: usize, bits: usize) {
+    if bits >= 8 {
+        let n = bits / 8;
+        slice::bytes::copy_memory(&mut out[i * n..(i + 1) * n], &row[x * n..(x + 1) * n]);
+    } else {
+        let mask = (1u8 << bits) - 1;
+        let sample = (row[x * bits / 8] >> (8 - bits - x * bits % 8)) & mask;
+
+        out[i * bits / 8] |= sample << (8 - bits - i * bits % 8);
+    }
+}
+
+// Expands the palette indices at the start of buf to the colors of the palette entries,
+// the pixels are expanded from the last to the first to keep the indices that are not read yet
+fn expand_palette(buf: &mut[u8], palette: &[(u8, u8, u8)], alpha: Option<&[u8]>,
+                  pixels: usize, bit_depth: u8) {
+    let channels = if alpha.is_some() {4} else {3};
+
+    for i in (0..pixels).rev() {
+        let index = unpack_sample(buf, i, bit_depth) as usize;
+        // Indices that are not in the palette are black
+        let (r, g, b) = palette.get(index).map(|&c| c).unwrap_or((0, 0, 0));
+        let pixel = &mut buf[i * channels..(i + 1) * channels];
+
+        pixel[0] = r;
+        pixel[1] = g;
+        pixel[2] = b;
+
+        // Entries without an alpha value are opaque
+        if let Some(alpha) = alpha {
+            pixel[3] = alpha.get(index).map(|&a| a).unwrap_or(255);
+        }
+    }
+}
+
+// Decodes the image to RGBA with 8 bit samples
+fn decode_rgba<R: Read>(decoder: &mut PNGDecoder<R>) -> ImageResult<RgbaImage> {
+    let (width, height) = try!(decoder.dimensions());
+    let colortype = try!(decoder.colortype());
+    let rowlen = try!(decoder.row_len());
+
+    let data = match try!(decoder.read_image()) {
+        DecodingResult::U8(data) => data,
+        DecodingResult::U16(_) => unreachable!()
+    };
+
+    let channels = color::num_components(colortype);
+    let bit_depth = (color::bits_per_pixel(colortype) / channels) as u8;
+    let max = (1u32 << bit_depth as usize) - 1;
+
+    let mut image: RgbaImage = ImageBuffer::new(width, height);
+    let mut samples = [0u8; 4];
+
+    for y in (0..height) {
+        let row = &data[y as usize * rowlen..][..rowlen];
+
+        for x in (0..width) {
+            for c in (0..channels) {
+                let sample = unpack_sample(row, x as usize * channels + c, bit_depth) as u32;
+                samples[c] = (sample * 255 / max) as u8;
             }
+
+            let pixel = match channels {
+                1 => [samples[0], samples[0], samples[0], 255],
+                2 => [samples[0], samples[0], samples[0], samples[1]],
+                3 => [samples[0], samples[1], samples[2], 255],
+                _ => samples
+            };
+
+            image.put_pixel(x, y, Rgba(pixel));
         }
     }
-);
 
-fn expand_pass(
-    img: &mut[u8], width: u32, scanline: &mut[u8],
-    pass: u8, line_no: u32, bytes_pp: u8) {
-    let line_no = line_no as usize;
-    let width = width as usize;
-    let bytes_pp = bytes_pp as usize;
-    match pass {
-        1 => expand_pass!(img, scanline, j,  8*line_no    * width + bytes_pp * j*8     , bytes_pp),
-        2 => expand_pass!(img, scanline, j,  8*line_no    * width + bytes_pp *(j*8 + 4), bytes_pp),
-        3 => expand_pass!(img, scanline, j, (8*line_no+4) * width + bytes_pp * j*4     , bytes_pp),
-        4 => expand_pass!(img, scanline, j,  4*line_no    * width + bytes_pp *(j*4 + 2), bytes_pp),
-        5 => expand_pass!(img, scanline, j, (4*line_no+2) * width + bytes_pp * j*2     , bytes_pp),
-        6 => expand_pass!(img, scanline, j,  2*line_no    * width + bytes_pp *(j*2+1)  , bytes_pp),
-        7 => expand_pass!(img, scanline, j, (2*line_no+1) * width + bytes_pp * j       , bytes_pp),
-        _ => {}
+    Ok(image)
+}
+
+// Unpacks the palette indices at the start of buf to one index per byte
+fn unpack_indices(buf: &mut[u8], pixels: usize, bit_depth: u8) {
+    for i in (0..pixels).rev() {
+        buf[i] = unpack_sample(buf, i, bit_depth) as u8;
     }
 }
 
-fn expand_palette(buf: &mut[u8], palette: &[(u8, u8, u8)],
-                  entries: usize, bit_depth: u8) {
-    let bpp = 8 / bit_depth as usize;
-    assert_eq!(buf.len(), 3 * (entries * bpp - buf.len() % bpp));
-    let mask = ((1u16 << bit_depth) - 1) as u8;
-    // Unsafe copy create two views into the vector
-    // This is unproblematic since it is only locally to this function and a &[u8]
-    let data = unsafe {
-        let view: &mut [u8] = mem::transmute_copy(&buf);
-        &view[..entries]
-    };
-    let pixels = data
-        .iter()
-        .rev() // Reverse iterator
-        .flat_map(|&v|
-            // This has to be reversed to
-            (0 .. 8).step_by(bit_depth)
-            .zip(iter::iterate(
-                v, |v| v
-            )
-        ))
-        //.skip(buf.len() % bpp) // not necessary, why!?
-        .map(|(shift, pixel)| (pixel & mask << shift as usize) >> shift as usize);
-    for (chunk, (r, g, b)) in buf.chunks_mut(3).rev().zip(pixels.map(|i|
-        palette[i as usize]
-    )) {
-        chunk[0] = r;
-        chunk[1] = g;
-        chunk[2] = b;
+// Adds an alpha channel to the gray or RGB samples at the start of buf,
+// the pixels of the transparent color get an alpha of 0
+fn expand_transparent_color(buf: &mut[u8], transparent: &[u16], pixels: usize, bit_depth: u8) {
+    let channels = transparent.len();
+    let max = ((1u32 << bit_depth as usize) - 1) as u16;
+    let mut samples = [0u16; 4];
+
+    for i in (0..pixels).rev() {
+        for c in (0..channels) {
+            samples[c] = unpack_sample(buf, i * channels + c, bit_depth);
+        }
+
+        samples[channels] = if &samples[..channels] == transparent {0} else {max};
+
+        for (c, &sample) in samples[..channels + 1].iter().enumerate() {
+            let j = i * (channels + 1) + c;
+
+            if bit_depth == 16 {
+                buf[2 * j] = (sample >> 8) as u8;
+                buf[2 * j + 1] = sample as u8;
+            } else {
+                buf[j] = (sample as u32 * 255 / max as u32) as u8;
+            }
+        }
+    }
+}
+
+// Returns the sample at index i of a row of samples with bit_depth bits
+fn unpack_sample(row: &[u8], i: usize, bit_depth: u8) -> u16 {
+    match bit_depth {
+        8  => row[i] as u16,
+        16 => (row[2 * i] as u16) << 8 | row[2 * i + 1] as u16,
+        _  => {
+            let bit = i * bit_depth as usize;
+            let shift = 8 - bit_depth as usize - bit % 8;
+
+            ((row[bit / 8] >> shift) as u16) & ((1 << bit_depth as usize) - 1)
+        }
     }
 }
 
@@ -547,6 +951,9 @@ pub struct IDATReader<R> {
 
     eof: bool,
     chunk_length: u32,
+
+    // The type of the chunk that follows the IDAT chunks
+    next_chunk: Vec<u8>,
 }
 
 impl<R: Read> IDATReader<R> {
@@ -556,6 +963,7 @@ impl<R: Read> IDATReader<R> {
             crc: Crc32::new(),
             eof: false,
             chunk_length: 0,
+            next_chunk: Vec::new(),
         }
     }
 
@@ -593,18 +1001,25 @@ impl<R: Read> Read for IDATReader<R> {
                 }
 
                 self.crc.reset();
-                self.chunk_length = try!(self.r.read_u32::<BigEndian>());
+
+                // A file that ends after the image data lacks its IEND chunk,
+                // which is left to the reader of the trailing chunks
+                self.chunk_length = match self.r.read_u32::<BigEndian>() {
+                    Ok(length) => length,
+                    Err(_) => {
+                        self.eof = true;
+                        break
+                    }
+                };
 
                 let mut v = Vec::with_capacity(4);
                 try!(self.r.by_ref().take(4).read_to_end(&mut v));
                 self.crc.update(&v);
 
-                match str::from_utf8(&v) {
-                    Ok("IDAT") => (),
-                    _ => {
-                        self.eof = true;
-                        break
-                    }
+                if &*v != b"IDAT" {
+                    self.eof = true;
+                    self.next_chunk = v;
+                    break
                 }
             }
         }
@@ -618,6 +1033,7 @@ mod tests {
     extern crate glob;
 
     use std::io::{self, Read};
+    use std::iter::repeat;
     use std::fs::File;
     use std::ffi::OsStr;
     use std::path::{Path, PathBuf};
@@ -625,11 +1041,34 @@ mod tests {
 
     use image::{
         ImageDecoder,
+        ImageError,
         ImageResult,
         DecodingResult
     };
 
-    use super::PNGDecoder;
+    use color;
+    use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+    use num::rational::Ratio;
+
+    use animation::Frame;
+    use color::Rgba;
+
+    use indexed::IndexedImage;
+    use super::{PNGDecoder, PNGSIGNATURE};
+    use super::super::{PNGEncoder, FilterStrategy, FilterType};
+    use super::super::apng::write_chunk;
+    use super::super::hash::Crc32;
+    use super::super::metadata::{
+        Metadata,
+        Text,
+        Chromaticities,
+        RenderingIntent,
+        IccProfile,
+        PhysicalDimensions,
+        Unit,
+        Background,
+        ModificationTime,
+    };
 
     /// Filters the testsuite images for certain features
     fn get_testimages(feature: &str, color_type: &str, test_interlaced: bool) -> Vec<PathBuf> {
@@ -724,6 +1163,473 @@ mod tests {
     //    }
     //}
 
+    #[test]
+    /// Test that the transparent color and the alpha of palette entries become an alpha channel
+    fn test_transparency() {
+        let images = [
+            ("tbbn0g04.png", color::ColorType::GrayA(8), 464),
+            ("tbwn0g16.png", color::ColorType::GrayA(16), 453),
+            ("tbrn2c08.png", color::ColorType::RGBA(8), 453),
+            ("tbbn2c16.png", color::ColorType::RGBA(16), 453),
+            ("tbbn3p08.png", color::ColorType::RGBA(8), 454),
+            ("tm3n3p02.png", color::ColorType::RGBA(8), 256),
+        ];
+
+        for &(name, colortype, transparent) in images.iter() {
+            let path: PathBuf = [".", "src", "png", "testdata", "pngsuite", name].iter().collect();
+            let mut decoder = PNGDecoder::new(File::open(&path).unwrap());
+            assert_eq!(decoder.colortype().unwrap(), colortype);
+
+            let data = match decoder.read_image().unwrap() {
+                DecodingResult::U8(data) => data,
+                _ => panic!("unexpected bit depth")
+            };
+
+            // The alpha is the last sample of every pixel
+            let pixel_bytes = color::bits_per_pixel(colortype) / 8;
+            let alpha_bytes = pixel_bytes / color::num_components(colortype);
+
+            let count = data.chunks(pixel_bytes).filter(|pixel| {
+                pixel[pixel_bytes - alpha_bytes..].iter().all(|&a| a == 0)
+            }).count();
+            assert_eq!(count, transparent);
+        }
+
+        // A tRNS chunk that is longer than the file is an error, instead of an allocation of its length
+        let mut png = PNGSIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]);
+        png.push_all(&[0xFF, 0xFF, 0xFF, 0xF0]);
+        png.push_all(b"tRNS");
+        png.push_all(&[0, 0]);
+
+        assert!(PNGDecoder::new(io::Cursor::new(png)).dimensions().is_err());
+    }
+
+    fn load_indexed(name: &str) -> ImageResult<IndexedImage> {
+        let path: PathBuf = [".", "src", "png", "testdata", "pngsuite", name].iter().collect();
+        PNGDecoder::new(try!(File::open(&path))).read_indexed()
+    }
+
+    #[test]
+    /// Test that palette images are decoded to indices and encoded again unchanged
+    fn test_indexed() {
+        let image = load_indexed("basn3p02.png").unwrap();
+        assert_eq!(image.dimensions(), (32, 32));
+        assert_eq!(image.palette().len(), 4);
+        assert_eq!(load_indexed("basi3p02.png").unwrap(), image);
+
+        let image = load_indexed("tm3n3p02.png").unwrap();
+        let alpha: Vec<u8> = image.palette().iter().map(|entry| entry[3]).collect();
+        assert_eq!(alpha, vec![0, 85, 170, 255]);
+
+        for &name in ["tm3n3p02.png", "tbbn3p08.png"].iter() {
+            let image = load_indexed(name).unwrap();
+            let mut encoded = Vec::new();
+            PNGEncoder::new(&mut encoded).encode_indexed(&image).unwrap();
+
+            let decoded = PNGDecoder::new(io::Cursor::new(encoded)).read_indexed().unwrap();
+            assert_eq!(decoded, image);
+        }
+
+        assert!(load_indexed("basn2c08.png").is_err());
+    }
+
+    #[test]
+    /// Test that the ancillary chunks are read and written
+    fn test_metadata() {
+        let metadata = |name: &str| {
+            let path: PathBuf = [".", "src", "png", "testdata", "pngsuite", name].iter().collect();
+            PNGDecoder::new(File::open(&path).unwrap()).metadata().unwrap().clone()
+        };
+
+        let m = metadata("ctzn0g04.png");
+        assert_eq!(m.gamma, Some(100000));
+        assert_eq!(m.text[0], Text::new("Title", "PngSuite"));
+        assert_eq!(m.text[2].keyword, "Copyright");
+        assert_eq!(m.text[2].text, "Copyright Willem van Schaik, Singapore 1995-96");
+        assert!(m.text[2].compressed);
+
+        let m = metadata("cthn0g04.png");
+        assert_eq!(m.text[0].language_tag, Some("hi".to_string()));
+        assert_eq!(m.text[0].text, "PngSuite");
+
+        assert_eq!(metadata("ccwn2c08.png").chromaticities.unwrap().white, (31270, 32900));
+        assert_eq!(metadata("bgwn6a08.png").background, Some(Background::RGB(255, 255, 255)));
+
+        let m = metadata("cdun2c08.png");
+        assert_eq!(m.significant_bits, Some(vec![4, 4, 4]));
+        assert_eq!(m.physical_dimensions, Some(PhysicalDimensions { x: 1000, y: 1000, unit: Unit::Meter }));
+
+        let time = ModificationTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+        assert_eq!(metadata("cm7n0g04.png").modification_time, Some(time));
+
+        let mut m = metadata("ctzn0g04.png");
+        m.text.push(Text {
+            keyword: "Title".to_string(),
+            text: "\u{936}\u{940}\u{930}\u{94d}\u{937}\u{915}".to_string(),
+            compressed: true,
+            language_tag: Some("hi".to_string()),
+            translated_keyword: Some("Title".to_string())
+        });
+        m.chromaticities = Some(Chromaticities {
+            white: (31270, 32900),
+            red: (64000, 33000),
+            green: (30000, 60000),
+            blue: (15000, 6000)
+        });
+        m.srgb = Some(RenderingIntent::Perceptual);
+        m.icc_profile = Some(IccProfile { name: "Profile".to_string(), data: vec![1, 2, 3] });
+        m.physical_dimensions = Some(PhysicalDimensions { x: 2835, y: 2835, unit: Unit::Meter });
+        m.background = Some(Background::Gray(128));
+        m.significant_bits = Some(vec![8]);
+
+        let mut encoded = Vec::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut encoded);
+            encoder.set_metadata(m.clone());
+            encoder.encode(&[128], 1, 1, color::ColorType::Gray(8)).unwrap();
+        }
+
+        let mut decoder = PNGDecoder::new(io::Cursor::new(encoded.clone()));
+        assert_eq!(decoder.metadata().unwrap(), &m);
+
+        // Chunks after the image data are read after the image
+        let mut chunk = vec![0, 0, 0, 7];
+        chunk.push_all(b"tIME\x07\xdf\x04\x01\x0c\x00\x00");
+        let mut crc = Crc32::new();
+        crc.update(&chunk[4..]);
+        let _ = chunk.write_u32::<BigEndian>(crc.checksum());
+
+        let iend = encoded.len() - 12;
+        let encoded: Vec<u8> = encoded[..iend].iter().chain(chunk.iter()).chain(encoded[iend..].iter())
+                                              .map(|&b| b).collect();
+
+        let mut decoder = PNGDecoder::new(io::Cursor::new(encoded));
+        assert_eq!(decoder.metadata().unwrap().modification_time, None);
+        assert!(decoder.read_image().is_ok());
+        assert_eq!(decoder.metadata().unwrap().modification_time.unwrap().year, 2015);
+
+        // Compressed texts that decompress to more than 8 MiB are rejected
+        let mut m: Metadata = Default::default();
+        m.text.push(Text {
+            keyword: "Comment".to_string(),
+            text: repeat('a').take(8 * 1024 * 1024 + 1).collect(),
+            compressed: true,
+            language_tag: None,
+            translated_keyword: None
+        });
+
+        let mut encoded = Vec::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut encoded);
+            encoder.set_metadata(m);
+            encoder.encode(&[128], 1, 1, color::ColorType::Gray(8)).unwrap();
+        }
+
+        let mut decoder = PNGDecoder::new(io::Cursor::new(encoded));
+        assert_eq!(decoder.metadata().unwrap().text, vec![]);
+        assert_eq!(decoder.metadata().unwrap().skipped_chunks, vec![b"zTXt".to_vec()]);
+    }
+
+    #[test]
+    /// Test that malformed ancillary chunks are skipped, and that a corrupt
+    /// or missing end of the file does not fail the decoded image
+    fn test_corrupt_chunks() {
+        let mut encoded = Vec::new();
+        PNGEncoder::new(&mut encoded).encode(&[10, 20, 30, 40], 2, 2, color::ColorType::Gray(8)).unwrap();
+
+        let idat = (0..encoded.len()).find(|&i| &encoded[i..i + 4] == b"IDAT").unwrap() - 4;
+        let iend = encoded.len() - 12;
+
+        // Returns the image with the chunks inserted before the image data and before the IEND chunk
+        let insert = |before: &[u8], after: &[u8], end: &[u8]| {
+            let mut png = encoded[..idat].to_vec();
+            png.push_all(before);
+            png.push_all(&encoded[idat..iend]);
+            png.push_all(after);
+            png.push_all(end);
+            png
+        };
+
+        let decode = |png: Vec<u8>| {
+            let mut decoder = PNGDecoder::new(io::Cursor::new(png));
+            match decoder.read_image().unwrap() {
+                DecodingResult::U8(data) => assert_eq!(data, vec![10, 20, 30, 40]),
+                _ => panic!("unexpected bit depth")
+            }
+            decoder.metadata().unwrap().clone()
+        };
+
+        // A tEXt chunk without the null separator after its keyword
+        let mut corrupt = Vec::new();
+        write_chunk(&mut corrupt, b"tEXt", b"Comment");
+        let mut text = corrupt.clone();
+        write_chunk(&mut text, b"tEXt", b"Title\0Image");
+
+        let m = decode(insert(&text, &corrupt, &encoded[iend..]));
+        assert_eq!(m.text, vec![Text::new("Title", "Image")]);
+        assert_eq!(m.skipped_chunks, vec![b"tEXt".to_vec(), b"tEXt".to_vec()]);
+
+        // A trailing chunk with a wrong checksum, one that is longer than the file, and a missing IEND chunk
+        let mut time = Vec::new();
+        write_chunk(&mut time, b"tIME", b"\x07\xdf\x04\x01\x0c\x00\x00");
+        let last = time.len() - 1;
+        time[last] ^= 1;
+
+        let m = decode(insert(&[], &time, &encoded[iend..]));
+        assert_eq!(m.modification_time, None);
+
+        decode(insert(&[], &[0xFF, 0xFF, 0xFF, 0xF0, b't', b'E', b'X', b't', 0], &[]));
+        decode(insert(&text, &[], &[]));
+    }
+
+    // Returns the concatenated content of the chunks of type chunk_type
+    fn chunk_data(png: &[u8], chunk_type: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut i = 8;
+
+        while i < png.len() {
+            let length = (&png[i..]).read_u32::<BigEndian>().unwrap() as usize;
+            if &png[i + 4..i + 8] == chunk_type {
+                data.push_all(&png[i + 8..i + 8 + length]);
+            }
+            i += length + 12;
+        }
+
+        data
+    }
+
+    #[test]
+    /// Test that the frames of an animation are composited
+    fn test_animation() {
+        let encode = |width, height, data: &[u8]| {
+            let mut encoded = Vec::new();
+            PNGEncoder::new(&mut encoded).encode(data, width, height, color::ColorType::RGBA(8)).unwrap();
+            encoded
+        };
+        let fctl = |sequence: u8, size: u8, offset: u8, dispose: u8, blend: u8| {
+            vec![0, 0, 0, sequence, 0, 0, 0, size, 0, 0, 0, size, 0, 0, 0, offset, 0, 0, 0, offset,
+                 0, 1, 0, 10, dispose, blend]
+        };
+
+        // A red default image, half transparent blue over the bottom right pixel,
+        // that is reverted, and green replacing the top left pixel
+        let red = encode(2, 2, &[255, 0, 0, 255, 255, 0, 0, 255, 255, 0, 0, 255, 255, 0, 0, 255]);
+        let blue = encode(1, 1, &[0, 0, 255, 128]);
+        let green = encode(1, 1, &[0, 255, 0, 255]);
+
+        let mut png = PNGSIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &chunk_data(&red, b"IHDR"));
+        write_chunk(&mut png, b"acTL", &[0, 0, 0, 3, 0, 0, 0, 2]);
+        write_chunk(&mut png, b"fcTL", &fctl(0, 2, 0, 0, 0));
+        write_chunk(&mut png, b"IDAT", &chunk_data(&red, b"IDAT"));
+
+        for &(sequence, ref image, offset, dispose) in [(1, &blue, 1, 2), (3, &green, 0, 1)].iter() {
+            write_chunk(&mut png, b"fcTL", &fctl(sequence, 1, offset, dispose, 1));
+            let mut fdat = vec![0, 0, 0, sequence + 1];
+            fdat.push_all(&chunk_data(&image[..], b"IDAT"));
+            write_chunk(&mut png, b"fdAT", &fdat);
+        }
+        write_chunk(&mut png, b"IEND", &[]);
+
+        let mut decoder = PNGDecoder::new(io::Cursor::new(png));
+        assert!(decoder.is_animated().unwrap());
+        assert_eq!(decoder.num_plays().unwrap(), Some(2));
+
+        let frames: Vec<Frame> = decoder.into_frames().unwrap().collect();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].delay(), Ratio::new(1, 10));
+
+        let red = Rgba([255, 0, 0, 255]);
+        assert!(frames[0].buffer().pixels().all(|&p| p == red));
+
+        let blended = *frames[1].buffer().get_pixel(1, 1);
+        assert!(blended[0] > 100 && blended[2] > 100 && blended[3] == 255);
+        assert_eq!(*frames[1].buffer().get_pixel(0, 0), red);
+
+        assert_eq!(*frames[2].buffer().get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+        assert_eq!(*frames[2].buffer().get_pixel(1, 1), red);
+    }
+
+    #[test]
+    /// Test that opaque pixels blended over a translucent canvas replace it exactly
+    fn test_animation_opaque_over() {
+        let encode = |data: &[u8]| {
+            let mut encoded = Vec::new();
+            PNGEncoder::new(&mut encoded).encode(data, 2, 1, color::ColorType::RGBA(8)).unwrap();
+            encoded
+        };
+        let fctl = |sequence: u8, blend: u8| {
+            vec![0, 0, 0, sequence, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0,
+                 0, 1, 0, 10, 0, blend]
+        };
+
+        // Half transparent red and transparent black, covered by two opaque colors
+        let canvas = encode(&[255, 0, 0, 128, 0, 0, 0, 0]);
+        let opaque = encode(&[200, 100, 51, 255, 1, 2, 3, 255]);
+
+        let mut png = PNGSIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &chunk_data(&canvas, b"IHDR"));
+        write_chunk(&mut png, b"acTL", &[0, 0, 0, 2, 0, 0, 0, 0]);
+        write_chunk(&mut png, b"fcTL", &fctl(0, 0));
+        write_chunk(&mut png, b"IDAT", &chunk_data(&canvas, b"IDAT"));
+        write_chunk(&mut png, b"fcTL", &fctl(1, 1));
+        let mut fdat = vec![0, 0, 0, 2];
+        fdat.push_all(&chunk_data(&opaque, b"IDAT"));
+        write_chunk(&mut png, b"fdAT", &fdat);
+        write_chunk(&mut png, b"IEND", &[]);
+
+        let frames: Vec<Frame> = PNGDecoder::new(io::Cursor::new(png)).into_frames().unwrap().collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(*frames[1].buffer().get_pixel(0, 0), Rgba([200, 100, 51, 255]));
+        assert_eq!(*frames[1].buffer().get_pixel(1, 0), Rgba([1, 2, 3, 255]));
+    }
+
+    #[test]
+    /// Test that animations with sequence numbers out of order or missing frames are rejected
+    fn test_animation_sequence() {
+        let mut image = Vec::new();
+        PNGEncoder::new(&mut image).encode(&[1, 2, 3, 255], 1, 1, color::ColorType::RGBA(8)).unwrap();
+
+        let fctl = |sequence: u8| {
+            vec![0, 0, 0, sequence, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 10, 0, 0]
+        };
+        let fdat = |sequence: u8| {
+            let mut fdat = vec![0, 0, 0, sequence];
+            fdat.push_all(&chunk_data(&image, b"IDAT"));
+            fdat
+        };
+
+        // An animation of the default image and the frames of the chunks after it
+        let animation = |num_frames: u8, chunks: &[(&[u8; 4], Vec<u8>)]| {
+            let mut png = PNGSIGNATURE.to_vec();
+            write_chunk(&mut png, b"IHDR", &chunk_data(&image, b"IHDR"));
+            write_chunk(&mut png, b"acTL", &[0, 0, 0, num_frames, 0, 0, 0, 0]);
+            write_chunk(&mut png, b"fcTL", &fctl(0));
+            write_chunk(&mut png, b"IDAT", &chunk_data(&image, b"IDAT"));
+
+            for &(chunk_type, ref data) in chunks.iter() {
+                write_chunk(&mut png, chunk_type, data);
+            }
+            write_chunk(&mut png, b"IEND", &[]);
+
+            PNGDecoder::new(io::Cursor::new(png)).into_frames().map(|frames| frames.count())
+        };
+
+        assert_eq!(animation(2, &[(b"fcTL", fctl(1)), (b"fdAT", fdat(2))]), Ok(2));
+
+        for result in [
+            animation(2, &[(b"fcTL", fctl(2)), (b"fdAT", fdat(1))]),
+            animation(2, &[(b"fcTL", fctl(1)), (b"fdAT", fdat(3))]),
+            animation(3, &[(b"fcTL", fctl(1)), (b"fdAT", fdat(2))]),
+            animation(2, &[(b"fcTL", fctl(1)), (b"fdAT", fdat(2)), (b"fcTL", fctl(3)), (b"fdAT", fdat(4))]),
+            animation(3, &[(b"fcTL", fctl(1)), (b"fdAT", fdat(2)), (b"fcTL", fctl(3))]),
+        ].iter() {
+            match *result {
+                Err(ImageError::FormatError(_)) => (),
+                _ => panic!("the animation is invalid")
+            }
+        }
+    }
+
+    #[test]
+    /// Test that images encoded with each compression level, filter strategy
+    /// and interlacing decode to the same pixels
+    fn test_encoder_options() {
+        let (width, height) = (13, 11);
+        let data: Vec<u8> = (0..width * height * 3).map(|i| (i * i / 7) as u8).collect();
+
+        let strategies = [
+            FilterStrategy::Fixed(FilterType::NoFilter),
+            FilterStrategy::Fixed(FilterType::Sub),
+            FilterStrategy::Fixed(FilterType::Up),
+            FilterStrategy::Fixed(FilterType::Avg),
+            FilterStrategy::Fixed(FilterType::Paeth),
+            FilterStrategy::Adaptive,
+            FilterStrategy::BruteForce,
+        ];
+
+        for &interlaced in [false, true].iter() {
+            for &level in [0, 1, 9].iter() {
+                for &strategy in strategies.iter() {
+                    let mut encoded = Vec::new();
+                    {
+                        let mut encoder = PNGEncoder::new(&mut encoded);
+                        encoder.set_compression(level);
+                        encoder.set_filter(strategy);
+                        encoder.set_interlacing(interlaced);
+                        encoder.encode(&data, width, height, color::ColorType::RGB(8)).unwrap();
+                    }
+
+                    assert_eq!(chunk_data(&encoded, b"IHDR")[12], interlaced as u8);
+
+                    let mut decoder = PNGDecoder::new(io::Cursor::new(encoded));
+                    match decoder.read_image().unwrap() {
+                        DecodingResult::U8(decoded) => assert!(decoded == data),
+                        _ => panic!("expected 8 bit samples")
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    /// Test that interlaced images with empty passes and pixels with less than 8 bits
+    /// are decoded to the encoded data
+    fn test_interlaced_sizes() {
+        let colors = [
+            color::ColorType::Gray(1),
+            color::ColorType::Gray(2),
+            color::ColorType::Gray(4),
+            color::ColorType::Gray(8),
+            color::ColorType::RGB(8),
+        ];
+
+        for &(width, height) in [(1, 1), (3, 7)].iter() {
+            for &c in colors.iter() {
+                let bits = color::bits_per_pixel(c);
+                let rowlen = (bits * width as usize + 7) / 8;
+                let mut data: Vec<u8> = (0..rowlen * height as usize).map(|i| (i * 97 + 13) as u8).collect();
+
+                // The bits after the last pixel of a row are zero
+                if bits * width as usize % 8 != 0 {
+                    let mask = 0xFFu8 << (8 - bits * width as usize % 8);
+                    for row in data.chunks_mut(rowlen) {
+                        row[rowlen - 1] &= mask;
+                    }
+                }
+
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = PNGEncoder::new(&mut encoded);
+                    encoder.set_interlacing(true);
+                    encoder.encode(&data, width, height, c).unwrap();
+                }
+
+                let mut decoder = PNGDecoder::new(io::Cursor::new(encoded));
+                match decoder.read_image().unwrap() {
+                    DecodingResult::U8(decoded) => assert_eq!(decoded, data),
+                    _ => panic!("expected 8 bit samples")
+                }
+            }
+        }
+
+        // A palette image with 2 bit indices
+        let palette = vec![Rgba([0, 0, 0, 255]), Rgba([255, 0, 0, 255]), Rgba([0, 255, 0, 255]), Rgba([0, 0, 255, 128])];
+        let indices = (0..21).map(|i| (i * 7 % 4) as u8).collect();
+        let image = IndexedImage::from_parts(3, 7, indices, palette).unwrap();
+
+        let mut encoded = Vec::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut encoded);
+            encoder.set_interlacing(true);
+            encoder.encode_indexed(&image).unwrap();
+        }
+
+        assert_eq!(chunk_data(&encoded, b"IHDR")[8], 2);
+        assert_eq!(PNGDecoder::new(io::Cursor::new(encoded)).read_indexed().unwrap(), image);
+    }
+
     #[test]
     /// Test corrupted images, they should all fail
     fn test_corrupted() {
@@ -8,7 +8,7 @@
 use std::cmp;
 use std::iter::repeat;
 use std::num::wrapping::Wrapping as w;
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use byteorder::ReadBytesExt;
 
 static LITERALLENGTHCODES: u16 = 286;
@@ -60,6 +60,34 @@ enum BlockType {
     Compressed
 }
 
+static WINDOWSIZE: usize = 32768;
+static HASHBITS: usize = 15;
+static MINMATCH: usize = 3;
+static MAXMATCH: usize = 258;
+static MAXSTOREDLENGTH: usize = 65535;
+
+/// The number of input bytes that are compressed into one block.
+static BLOCKSIZE: usize = 65536;
+
+/// The maximum length of the hash chains that are searched
+/// and the match length that ends the search, for each compression level.
+/// Like zlib, the searches are shorter at level 4 than at level 3,
+/// because from ```LAZYLEVEL``` on the match at the next byte is searched as well.
+static LEVELS: [(usize, usize); 10] = [
+    (0, 0), (4, 8), (8, 16), (32, 32), (16, 16),
+    (32, 32), (128, 128), (256, 128), (1024, 258), (4096, 258)
+];
+
+/// The compression levels from which on a match is only taken
+/// if the match at the next byte is not longer.
+static LAZYLEVEL: u8 = 4;
+
+#[derive(Copy, Clone)]
+enum Token {
+    Literal(u8),
+    Match(u16, u16)
+}
+
 /// A DEFLATE compressed stream decoder.
 pub struct Inflater<R> {
     h:
@@ -282,6 +310,594 @@ impl<R: Read> Read for Inflater<R> {
     }
 }
 
+/// A DEFLATE compressed stream encoder.
+///
+/// The input is compressed in blocks of 64 KiB, so that the output
+/// is written to the wrapped Writer as the input arrives.
+/// ```finish``` has to be called to write the last block.
+pub struct Deflater<W> {
+    w: W,
+    bits: BitWriter,
+    level: u8,
+
+    buf: Vec<u8>,
+    pos: usize,
+    offset: usize,
+    inserted: usize,
+
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl<W: Write> Deflater<W> {
+    /// Create a new encoder that encodes to a Writer.
+    /// ```level``` ranges from 0 (no compression) to 9 (best compression),
+    /// higher levels are treated as 9.
+    pub fn new(w: W, level: u8) -> Deflater<W> {
+        Deflater {
+            w: w,
+            bits: BitWriter::new(),
+            level: cmp::min(level, 9),
+
+            buf: Vec::new(),
+            pos: 0,
+            offset: 0,
+            inserted: 0,
+
+            head: repeat(0).take(1 << HASHBITS).collect(),
+            prev: repeat(0).take(WINDOWSIZE).collect(),
+        }
+    }
+
+    /// Return a mutable reference to the wrapped Writer
+    pub fn inner(&mut self) -> &mut W {
+        &mut self.w
+    }
+
+    /// Compress the remaining input as the final block
+    /// and return the wrapped Writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let _ = try!(self.finish_stream());
+
+        Ok(self.w)
+    }
+
+    /// Compress the remaining input as the final block and start
+    /// a new stream, that is written to the same Writer.
+    /// The tables of the previous stream are kept, the new stream continues
+    /// its positions so that they are never matched.
+    pub fn restart(&mut self) -> io::Result<()> {
+        let _ = try!(self.finish_stream());
+
+        self.offset += self.buf.len();
+        self.buf.clear();
+        self.pos = 0;
+        self.inserted = 0;
+
+        Ok(())
+    }
+
+    fn finish_stream(&mut self) -> io::Result<()> {
+        let length = self.buf.len() - self.pos;
+        let _ = try!(self.compress_block(length, true));
+
+        self.bits.byte_align();
+        let _ = try!(self.w.write_all(&self.bits.out));
+        self.bits.out.clear();
+
+        Ok(())
+    }
+
+    fn compress_block(&mut self, length: usize, last: bool) -> io::Result<()> {
+        let end = self.pos + length;
+
+        if self.level == 0 {
+            self.write_stored_blocks(end, last);
+        } else {
+            let tokens = self.find_matches(end);
+            self.write_compressed_block(&tokens, end, last);
+        }
+
+        self.pos = end;
+
+        // Keep one window of already compressed input to find matches in.
+        if self.pos > 2 * WINDOWSIZE {
+            let n = self.pos - WINDOWSIZE;
+
+            self.buf = self.buf[n..].to_vec();
+            self.pos -= n;
+            self.inserted = self.inserted.saturating_sub(n);
+            self.offset += n;
+        }
+
+        let _ = try!(self.w.write_all(&self.bits.out));
+        self.bits.out.clear();
+
+        Ok(())
+    }
+
+    fn hash(&self, i: usize) -> usize {
+        let a = self.buf[i] as usize;
+        let b = self.buf[i + 1] as usize;
+        let c = self.buf[i + 2] as usize;
+
+        ((a << 10) ^ (b << 5) ^ c) & ((1 << HASHBITS) - 1)
+    }
+
+    fn insert(&mut self, i: usize) {
+        if i < self.inserted || i + MINMATCH > self.buf.len() {
+            return
+        }
+
+        let h = self.hash(i);
+        let position = self.offset + i;
+
+        self.prev[position % WINDOWSIZE] = self.head[h];
+        self.head[h] = position + 1;
+        self.inserted = i + 1;
+    }
+
+    fn longest_match(&self, i: usize, end: usize) -> (usize, usize) {
+        let max_length = cmp::min(MAXMATCH, end - i);
+        if max_length < MINMATCH {
+            return (0, 0)
+        }
+
+        let (max_chain, nice_length) = LEVELS[self.level as usize];
+        let position = self.offset + i;
+
+        let mut best = (0, 0);
+        let mut next = self.head[self.hash(i)];
+
+        for _ in (0..max_chain) {
+            // Positions before the input are left from a previous stream
+            if next == 0 || next - 1 < self.offset || position - (next - 1) > WINDOWSIZE {
+                break
+            }
+
+            let candidate = next - 1;
+            let j = candidate - self.offset;
+
+            let mut length = 0;
+            while length < max_length && self.buf[j + length] == self.buf[i + length] {
+                length += 1;
+            }
+
+            if length > best.0 {
+                best = (length, position - candidate);
+
+                if length >= nice_length || length == max_length {
+                    break
+                }
+            }
+
+            let n = self.prev[candidate % WINDOWSIZE];
+            if n >= next {
+                break
+            }
+
+            next = n;
+        }
+
+        if best.0 >= MINMATCH {
+            best
+        } else {
+            (0, 0)
+        }
+    }
+
+    fn find_matches(&mut self, end: usize) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut i = self.pos;
+
+        while i < end {
+            let (length, distance) = self.longest_match(i, end);
+
+            if length == 0 {
+                self.insert(i);
+                tokens.push(Token::Literal(self.buf[i]));
+                i += 1;
+
+                continue
+            }
+
+            if self.level >= LAZYLEVEL && i + 1 < end {
+                self.insert(i);
+
+                let (next_length, _) = self.longest_match(i + 1, end);
+                if next_length > length {
+                    tokens.push(Token::Literal(self.buf[i]));
+                    i += 1;
+
+                    continue
+                }
+            }
+
+            for j in (i..i + length) {
+                self.insert(j);
+            }
+
+            tokens.push(Token::Match(length as u16, distance as u16));
+            i += length;
+        }
+
+        tokens
+    }
+
+    fn write_stored_blocks(&mut self, end: usize, last: bool) {
+        let mut start = self.pos;
+
+        loop {
+            let length = cmp::min(end - start, MAXSTOREDLENGTH);
+            let is_final = last && start + length == end;
+
+            self.bits.write_bits(is_final as u32, 1);
+            self.bits.write_bits(0b00, 2);
+            self.bits.byte_align();
+            self.bits.write_bits(length as u32, 16);
+            self.bits.write_bits(!length as u32 & 0xFFFF, 16);
+            self.bits.out.push_all(&self.buf[start..start + length]);
+
+            start += length;
+            if start == end {
+                break
+            }
+        }
+    }
+
+    fn write_compressed_block(&mut self, tokens: &[Token], end: usize, last: bool) {
+        let mut ll_freqs: Vec<u32> = repeat(0).take(LITERALLENGTHCODES as usize).collect();
+        let mut d_freqs: Vec<u32> = repeat(0).take(DISTANCECODES as usize).collect();
+
+        for &t in tokens.iter() {
+            match t {
+                Token::Literal(b) => ll_freqs[b as usize] += 1,
+                Token::Match(length, distance) => {
+                    ll_freqs[257 + length_code(length)] += 1;
+                    d_freqs[distance_code(distance)] += 1;
+                }
+            }
+        }
+
+        ll_freqs[256] = 1;
+
+        let fixed_ll = fixed_lengths();
+        let fixed_d: Vec<u8> = repeat(5u8).take(DISTANCECODES as usize).collect();
+
+        let ll_lengths = huffman_lengths(&ll_freqs, 15);
+        let d_lengths  = huffman_lengths(&d_freqs, 15);
+        let header = DynamicHeader::new(&ll_lengths, &d_lengths);
+
+        let fixed_size = 3 + data_size(&ll_freqs, &d_freqs, &fixed_ll, &fixed_d);
+        let dynamic_size = 3 + header.size() + data_size(&ll_freqs, &d_freqs, &ll_lengths, &d_lengths);
+        let stored_size = 8 * (end - self.pos) + 40 * ((end - self.pos) / MAXSTOREDLENGTH + 1);
+
+        if stored_size <= cmp::min(fixed_size, dynamic_size) {
+            self.write_stored_blocks(end, last);
+        } else if fixed_size <= dynamic_size {
+            self.bits.write_bits(last as u32, 1);
+            self.bits.write_bits(0b01, 2);
+            self.write_tokens(tokens, &fixed_ll, &fixed_d);
+        } else {
+            self.bits.write_bits(last as u32, 1);
+            self.bits.write_bits(0b10, 2);
+            header.write(&mut self.bits);
+            self.write_tokens(tokens, &ll_lengths, &d_lengths);
+        }
+    }
+
+    fn write_tokens(&mut self, tokens: &[Token], ll_lengths: &[u8], d_lengths: &[u8]) {
+        let ll_codes = codes_from_lengths(ll_lengths);
+        let d_codes = codes_from_lengths(d_lengths);
+
+        for &t in tokens.iter() {
+            match t {
+                Token::Literal(b) => {
+                    self.bits.write_bits(ll_codes[b as usize] as u32, ll_lengths[b as usize]);
+                }
+
+                Token::Match(length, distance) => {
+                    let l = length_code(length);
+                    self.bits.write_bits(ll_codes[257 + l] as u32, ll_lengths[257 + l]);
+                    self.bits.write_bits((length - LENGTHS[l]) as u32, EXTRA_LENGTHS[l]);
+
+                    let d = distance_code(distance);
+                    self.bits.write_bits(d_codes[d] as u32, d_lengths[d]);
+                    self.bits.write_bits((distance - DISTANCES[d]) as u32, EXTRA_DISTANCES[d]);
+                }
+            }
+        }
+
+        self.bits.write_bits(ll_codes[256] as u32, ll_lengths[256]);
+    }
+}
+
+impl<W: Write> Write for Deflater<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.push_all(buf);
+
+        while self.buf.len() - self.pos >= BLOCKSIZE {
+            let _ = try!(self.compress_block(BLOCKSIZE, false));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// The header of a block compressed with dynamic huffman codes.
+struct DynamicHeader {
+    hlit: usize,
+    hdist: usize,
+    hclen: usize,
+
+    symbols: Vec<(u8, u8)>,
+    lengths: Vec<u8>,
+}
+
+impl DynamicHeader {
+    fn new(ll_lengths: &[u8], d_lengths: &[u8]) -> DynamicHeader {
+        let hlit = cmp::max(257, last_used(ll_lengths));
+        let hdist = cmp::max(1, last_used(d_lengths));
+
+        let mut all_lengths = ll_lengths[..hlit].to_vec();
+        all_lengths.push_all(&d_lengths[..hdist]);
+
+        let symbols = run_lengths(&all_lengths);
+
+        let mut freqs: Vec<u32> = repeat(0).take(CODEORDER.len()).collect();
+        for &(s, _) in symbols.iter() {
+            freqs[s as usize] += 1;
+        }
+
+        let lengths = huffman_lengths(&freqs, 7);
+
+        let mut hclen = CODEORDER.len();
+        while hclen > 4 && lengths[CODEORDER[hclen - 1] as usize] == 0 {
+            hclen -= 1;
+        }
+
+        DynamicHeader {
+            hlit: hlit,
+            hdist: hdist,
+            hclen: hclen,
+
+            symbols: symbols,
+            lengths: lengths,
+        }
+    }
+
+    /// The size of the header in bits
+    fn size(&self) -> usize {
+        let mut size = 5 + 5 + 4 + 3 * self.hclen;
+
+        for &(s, _) in self.symbols.iter() {
+            size += self.lengths[s as usize] as usize + extra_code_length_bits(s) as usize;
+        }
+
+        size
+    }
+
+    fn write(&self, bits: &mut BitWriter) {
+        bits.write_bits((self.hlit - 257) as u32, 5);
+        bits.write_bits((self.hdist - 1) as u32, 5);
+        bits.write_bits((self.hclen - 4) as u32, 4);
+
+        for &i in CODEORDER[..self.hclen].iter() {
+            bits.write_bits(self.lengths[i as usize] as u32, 3);
+        }
+
+        let codes = codes_from_lengths(&self.lengths);
+
+        for &(s, extra) in self.symbols.iter() {
+            bits.write_bits(codes[s as usize] as u32, self.lengths[s as usize]);
+            bits.write_bits(extra as u32, extra_code_length_bits(s));
+        }
+    }
+}
+
+fn extra_code_length_bits(symbol: u8) -> u8 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _  => 0
+    }
+}
+
+/// Encodes code lengths with the repeat codes 16, 17 and 18.
+/// Returns the symbols together with the value of their extra bits.
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut symbols = Vec::new();
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let length = lengths[i];
+
+        let mut run = 1;
+        while i + run < lengths.len() && lengths[i + run] == length {
+            run += 1;
+        }
+
+        i += run;
+
+        if length == 0 {
+            while run >= 11 {
+                let n = cmp::min(run, 138);
+                symbols.push((18, (n - 11) as u8));
+                run -= n;
+            }
+
+            if run >= 3 {
+                symbols.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            symbols.push((length, 0));
+            run -= 1;
+
+            while run >= 3 {
+                let n = cmp::min(run, 6);
+                symbols.push((16, (n - 3) as u8));
+                run -= n;
+            }
+        }
+
+        for _ in (0..run) {
+            symbols.push((length, 0));
+        }
+    }
+
+    symbols
+}
+
+fn last_used(lengths: &[u8]) -> usize {
+    lengths.iter().rposition(|&l| l != 0).map_or(0, |i| i + 1)
+}
+
+/// The size of the compressed data of a block in bits
+fn data_size(ll_freqs: &[u32], d_freqs: &[u32], ll_lengths: &[u8], d_lengths: &[u8]) -> usize {
+    let mut size = 0;
+
+    for (i, &f) in ll_freqs.iter().enumerate() {
+        let extra = if i > 256 { EXTRA_LENGTHS[i - 257] } else { 0 };
+        size += f as usize * (ll_lengths[i] + extra) as usize;
+    }
+
+    for (i, &f) in d_freqs.iter().enumerate() {
+        size += f as usize * (d_lengths[i] + EXTRA_DISTANCES[i]) as usize;
+    }
+
+    size
+}
+
+fn fixed_lengths() -> Vec<u8> {
+    (0u32..288).map(|i|
+        if i < 144 { 8u8 }
+        else if i < 256 { 9u8 }
+        else if i < 280 { 7u8 }
+        else { 8u8 }
+    ).collect()
+}
+
+fn length_code(length: u16) -> usize {
+    LENGTHS.iter().rposition(|&l| l <= length).unwrap()
+}
+
+fn distance_code(distance: u16) -> usize {
+    DISTANCES.iter().rposition(|&d| d <= distance).unwrap()
+}
+
+/// Computes the lengths of a huffman code for the frequencies of the symbols
+/// with no code longer than ```max_length```.
+/// At least two symbols get a code, so that the code is complete.
+fn huffman_lengths(freqs: &[u32], max_length: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+
+    let used = freqs.iter().filter(|&&f| f > 0).count();
+    for _ in (0..2 - cmp::min(used, 2)) {
+        match freqs.iter().position(|&f| f == 0) {
+            Some(j) => freqs[j] = 1,
+            None    => break
+        }
+    }
+
+    loop {
+        let lengths = build_huffman_lengths(&freqs);
+
+        if lengths.iter().all(|&l| l <= max_length) {
+            return lengths
+        }
+
+        // Flatten the distribution until the code is short enough.
+        for f in freqs.iter_mut() {
+            if *f > 0 {
+                *f = (*f + 1) / 2;
+            }
+        }
+    }
+}
+
+fn build_huffman_lengths(freqs: &[u32]) -> Vec<u8> {
+    let mut leaves: Vec<(u32, usize)> = freqs.iter()
+                                             .enumerate()
+                                             .filter(|&(_, &f)| f > 0)
+                                             .map(|(i, &f)| (f, i))
+                                             .collect();
+    leaves.sort();
+
+    // Nodes 0..n are the leaves in order of their frequency, the
+    // internal nodes are appended in the order they are created.
+    // Since their weights never decrease they form a second sorted queue.
+    let n = leaves.len();
+    let mut weights: Vec<u32> = leaves.iter().map(|&(f, _)| f).collect();
+    let mut parent: Vec<usize> = repeat(0).take(2 * n - 1).collect();
+
+    let mut next_leaf = 0;
+    let mut next_node = n;
+
+    for node in (n..2 * n - 1) {
+        let mut children = [0; 2];
+
+        for c in children.iter_mut() {
+            if next_leaf < n && (next_node == node || weights[next_leaf] <= weights[next_node]) {
+                *c = next_leaf;
+                next_leaf += 1;
+            } else {
+                *c = next_node;
+                next_node += 1;
+            }
+        }
+
+        weights.push(weights[children[0]] + weights[children[1]]);
+        parent[children[0]] = node;
+        parent[children[1]] = node;
+    }
+
+    let mut depths: Vec<u8> = repeat(0).take(2 * n - 1).collect();
+    for node in (0..2 * n - 2).rev() {
+        depths[node] = depths[parent[node]] + 1;
+    }
+
+    let mut lengths: Vec<u8> = repeat(0).take(freqs.len()).collect();
+    for (node, &(_, symbol)) in leaves.iter().enumerate() {
+        lengths[symbol] = depths[node];
+    }
+
+    lengths
+}
+
+/// Computes the canonical huffman codes for code lengths,
+/// bit reversed so that they can be written starting with the least significant bit.
+fn codes_from_lengths(lengths: &[u8]) -> Vec<u16> {
+    let mut bl_count = [0u16; 16];
+    for &len in lengths.iter() {
+        bl_count[len as usize] += 1;
+    }
+    bl_count[0] = 0;
+
+    let mut next_code = [0u16; 16];
+    let mut code = 0u16;
+    for bits in (1usize..16) {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+
+    lengths.iter().map(|&len| {
+        if len == 0 {
+            return 0
+        }
+
+        let code = next_code[len as usize];
+        next_code[len as usize] += 1;
+
+        reverse(code) >> (16 - len) as usize
+    }).collect()
+}
+
 fn reverse(a: u16) -> u16 {
     let b = (((!0x5555) & a) >> 1) | ((0x5555 & a) << 1);
     let c = (((!0x3333) & b) >> 2) | ((0x3333 & b) << 2);
@@ -429,3 +1045,34 @@ impl<R: Read> HuffReader<R> {
         }
     }
 }
+
+struct BitWriter {
+    pub out: Vec<u8>,
+
+    bits: u32,
+    num_bits: u8,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {out: Vec::new(), bits: 0, num_bits: 0}
+    }
+
+    pub fn write_bits(&mut self, val: u32, n: u8) {
+        self.bits |= val << self.num_bits as usize;
+        self.num_bits += n;
+
+        while self.num_bits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.num_bits -= 8;
+        }
+    }
+
+    pub fn byte_align(&mut self) {
+        if self.num_bits > 0 {
+            let n = 8 - self.num_bits;
+            self.write_bits(0, n);
+        }
+    }
+}
@@ -1,11 +1,14 @@
 //! A PNG Encoder
 //!
-//! This implementation uses the ```flate``` crate for compression
-//! and selects the filter type using the sum of absolute differences method.
+//! This implementation compresses the image data with ```zlib::ZlibEncoder```
+//! and by default selects the filter type using the sum of absolute differences method.
 //!
 //! For each row the filter method that produces the lowest integer when its bytes
 //! are interpreted as signed numbers and summed is chosen as the filter.
+//! The compression level, the filter strategy and Adam7 interlacing can be set
+//! before an image is encoded.
 
+use std::cmp;
 use std::slice;
 use std::io;
 use std::io::Write;
@@ -14,15 +17,41 @@ use std::iter::repeat;
 use byteorder::{WriteBytesExt, BigEndian};
 
 use color;
+use indexed::IndexedImage;
+use animation::Frame;
 use super::hash::Crc32;
-
-use super::filter::filter;
-use super::decoder::PNGSIGNATURE;
+use super::zlib::ZlibEncoder;
+use super::deflate::Deflater;
+use super::metadata::{self, Metadata};
+use super::apng;
+
+use super::filter::{filter, FilterType};
+use super::decoder::{PNGSIGNATURE, Adam7Iterator, copy_pixel};
+
+/// How the filter of each row is chosen
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterStrategy {
+    /// Every row is filtered with the same filter
+    Fixed(FilterType),
+
+    /// The filter that produces the lowest sum of absolute differences is chosen
+    /// for each row. This is the default.
+    Adaptive,
+
+    /// Each row is compressed with each of the five filters
+    /// and the filter that compresses best is chosen. This is slow.
+    BruteForce,
+}
 
 /// The representation of a PNG encoder
 pub struct PNGEncoder<'a, W: 'a> {
     w: &'a mut W,
-    crc: Crc32
+    crc: Crc32,
+    metadata: Metadata,
+
+    compression: u8,
+    filter: FilterStrategy,
+    interlaced: bool,
 }
 
 impl<'a, W: Write> PNGEncoder<'a, W> {
@@ -30,10 +59,37 @@ impl<'a, W: Write> PNGEncoder<'a, W> {
     pub fn new(w: &mut W) -> PNGEncoder<W> {
         PNGEncoder {
             w: w,
-            crc: Crc32::new()
+            crc: Crc32::new(),
+            metadata: Default::default(),
+
+            compression: 6,
+            filter: FilterStrategy::Adaptive,
+            interlaced: false,
         }
     }
 
+    /// Sets the compression level of the image data, from 0 (no compression)
+    /// to 9 (best compression). The default is 6.
+    pub fn set_compression(&mut self, level: u8) {
+        self.compression = cmp::min(level, 9);
+    }
+
+    /// Sets how the filter of each row is chosen
+    pub fn set_filter(&mut self, strategy: FilterStrategy) {
+        self.filter = strategy;
+    }
+
+    /// Sets whether the image is stored in seven Adam7 passes,
+    /// so that decoders can show a coarse version of the image before all of it is read
+    pub fn set_interlacing(&mut self, interlaced: bool) {
+        self.interlaced = interlaced;
+    }
+
+    /// Sets the metadata that is written to the ancillary chunks of the image
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
+
     /// Encodes the image ```image```
     /// that has dimensions ```width``` and ```height```
     /// and ```ColorType``` ```c```
@@ -43,14 +99,138 @@ impl<'a, W: Write> PNGEncoder<'a, W> {
                   height: u32,
                   c: color::ColorType) -> io::Result<()> {
 
+        let color_space_chunks = try!(metadata::color_space_chunks(&self.metadata));
+        let chunks = try!(metadata::chunks(&self.metadata));
+
         let _ = try!(self.write_signature());
-        let (bytes, bpp) = build_ihdr(width, height, c);
+        let bytes = build_ihdr(width, height, c, self.interlaced);
 
         let _ = try!(self.write_chunk("IHDR", &bytes));
-        let compressed_bytes = build_idat(image, bpp, width, height);
+        let _ = try!(self.write_chunks(&color_space_chunks));
+        let _ = try!(self.write_chunks(&chunks));
+        let _ = try!(self.write_idat(image, color::bits_per_pixel(c), width, height));
 
-        for chunk in compressed_bytes.chunks(1024 * 256) {
-            let _ = try!(self.write_chunk("IDAT", chunk));
+        self.write_chunk("IEND", &[])
+    }
+
+    /// Encodes the indices and the palette of ```image``` unchanged as a palette image,
+    /// with the smallest bit depth that can store the indices.
+    /// The alpha of the palette entries is written to a tRNS chunk unless all entries are opaque.
+    pub fn encode_indexed(&mut self, image: &IndexedImage) -> io::Result<()> {
+        let (width, height) = image.dimensions();
+
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PNG images must not be empty.",
+                None
+            ))
+        }
+
+        let palette = image.palette();
+        let bit_depth = match palette.len() {
+            0 ... 2  => 1,
+            3 ... 4  => 2,
+            5 ... 16 => 4,
+            _        => 8
+        };
+
+        // Pack the indices of each row, the first index is stored in the most significant bits
+        let rowlen = (bit_depth * width as usize + 7) / 8;
+        let mut data: Vec<u8> = repeat(0u8).take(rowlen * height as usize).collect();
+
+        for (row, indices) in data.chunks_mut(rowlen).zip(image.indices().chunks(width as usize)) {
+            for (i, &index) in indices.iter().enumerate() {
+                let bit = i * bit_depth;
+                row[bit / 8] |= index << (8 - bit_depth - bit % 8);
+            }
+        }
+
+        let mut plte = Vec::with_capacity(3 * palette.len());
+        for entry in palette.iter() {
+            plte.push_all(&entry.0[..3]);
+        }
+
+        // Opaque entries at the end of the palette can be left out of the tRNS chunk
+        let alpha_len = palette.iter().rposition(|entry| entry[3] != 255).map_or(0, |i| i + 1);
+        let trns: Vec<u8> = palette[..alpha_len].iter().map(|entry| entry[3]).collect();
+
+        let color_space_chunks = try!(metadata::color_space_chunks(&self.metadata));
+        let chunks = try!(metadata::chunks(&self.metadata));
+
+        let _ = try!(self.write_signature());
+        let bytes = build_ihdr(width, height, color::ColorType::Palette(bit_depth as u8), self.interlaced);
+
+        let _ = try!(self.write_chunk("IHDR", &bytes));
+        let _ = try!(self.write_chunks(&color_space_chunks));
+        let _ = try!(self.write_chunk("PLTE", &plte));
+
+        if !trns.is_empty() {
+            let _ = try!(self.write_chunk("tRNS", &trns));
+        }
+
+        let _ = try!(self.write_chunks(&chunks));
+
+        let _ = try!(self.write_idat(&data, bit_depth, width, height));
+
+        self.write_chunk("IEND", &[])
+    }
+
+    /// Encodes ```frames``` as an animation with 8 bit RGBA samples,
+    /// that is played ```num_plays``` times, 0 repeats it forever.
+    /// Each frame replaces the pixels at its offset of a canvas that is large enough to hold
+    /// every frame, only the regions of the canvas that change are stored.
+    pub fn encode_frames(&mut self, frames: &[Frame], num_plays: u32) -> io::Result<()> {
+        let (width, height, regions) = apng::frame_regions(frames);
+
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PNG images must not be empty.",
+                None
+            ))
+        }
+
+        let color_space_chunks = try!(metadata::color_space_chunks(&self.metadata));
+        let chunks = try!(metadata::chunks(&self.metadata));
+
+        let _ = try!(self.write_signature());
+        let bytes = build_ihdr(width, height, color::ColorType::RGBA(8), self.interlaced);
+
+        let _ = try!(self.write_chunk("IHDR", &bytes));
+        let _ = try!(self.write_chunks(&color_space_chunks));
+
+        let mut actl = Vec::with_capacity(8);
+        let _ = actl.write_u32::<BigEndian>(regions.len() as u32);
+        let _ = actl.write_u32::<BigEndian>(num_plays);
+
+        let _ = try!(self.write_chunk("acTL", &actl));
+        let _ = try!(self.write_chunks(&chunks));
+
+        // The fcTL and fdAT chunks share one sequence, the first frame is the default image
+        let mut sequence_number = 0;
+
+        for (i, &(ref control, ref image)) in regions.iter().enumerate() {
+            let mut control = *control;
+            control.sequence_number = sequence_number;
+            sequence_number += 1;
+
+            let _ = try!(self.write_chunk("fcTL", &control.to_bytes()));
+
+            let compressed_bytes = try!(self.build_idat(image, 32, control.width, control.height));
+
+            for chunk in compressed_bytes.chunks(1024 * 256) {
+                if i == 0 {
+                    let _ = try!(self.write_chunk("IDAT", chunk));
+                } else {
+                    let mut fdat = Vec::with_capacity(4 + chunk.len());
+                    let _ = fdat.write_u32::<BigEndian>(sequence_number);
+                    fdat.push_all(chunk);
+                    sequence_number += 1;
+
+                    let _ = try!(self.write_chunk("fdAT", &fdat));
+                }
+            }
         }
 
         self.write_chunk("IEND", &[])
@@ -60,6 +240,108 @@ impl<'a, W: Write> PNGEncoder<'a, W> {
         self.w.write_all(&PNGSIGNATURE)
     }
 
+    fn write_chunks(&mut self, chunks: &[(&str, Vec<u8>)]) -> io::Result<()> {
+        for &(name, ref buf) in chunks.iter() {
+            let _ = try!(self.write_chunk(name, buf));
+        }
+
+        Ok(())
+    }
+
+    fn write_idat(&mut self, image: &[u8], bits: usize, width: u32, height: u32) -> io::Result<()> {
+        let compressed_bytes = try!(self.build_idat(image, bits, width, height));
+
+        for chunk in compressed_bytes.chunks(1024 * 256) {
+            let _ = try!(self.write_chunk("IDAT", chunk));
+        }
+
+        Ok(())
+    }
+
+    // Filters and compresses the rows of an image with ```bits``` bits per pixel
+    fn build_idat(&self, image: &[u8], bits: usize, width: u32, height: u32) -> io::Result<Vec<u8>> {
+        let bpp = (bits + 7) / 8;
+        let rowlen = (bits * width as usize + 7) / 8;
+        let mut b = Vec::with_capacity(height as usize + rowlen * height as usize);
+
+        // The rows are compressed with each filter by one encoder, which keeps its tables
+        let mut scratch = match self.filter {
+            FilterStrategy::BruteForce => Some(Deflater::new(Vec::new(), self.compression)),
+            _ => None
+        };
+
+        if self.interlaced {
+            let mut previous = Vec::new();
+            let mut current_pass = 0;
+
+            // Passes without pixels have no lines
+            for (pass, line, pass_width) in Adam7Iterator::new(width, height) {
+                let pass_rowlen = (bits * pass_width as usize + 7) / 8;
+
+                if pass != current_pass {
+                    // Each pass is filtered as a separate image
+                    previous = repeat(0u8).take(pass_rowlen).collect();
+                    current_pass = pass;
+                }
+
+                let (x0, y0, dx, dy) = Adam7Iterator::pass_geometry(pass);
+                let y = (y0 + line * dy) as usize;
+                let row = &image[y * rowlen..(y + 1) * rowlen];
+
+                let mut pass_row: Vec<u8> = repeat(0u8).take(pass_rowlen).collect();
+                for i in (0..pass_width) {
+                    copy_pixel(row, (x0 + i * dx) as usize, &mut pass_row, i as usize, bits);
+                }
+
+                let _ = try!(self.filter_row(&pass_row, bpp, &previous, &mut b, &mut scratch));
+                previous = pass_row;
+            }
+        } else {
+            let mut previous: Vec<u8> = repeat(0u8).take(rowlen).collect();
+
+            for row in image.chunks(rowlen).take(height as usize) {
+                let _ = try!(self.filter_row(row, bpp, &previous, &mut b, &mut scratch));
+                slice::bytes::copy_memory(&mut previous, row);
+            }
+        }
+
+        let mut e = ZlibEncoder::new(Vec::new(), self.compression);
+        let _ = try!(e.write_all(&b));
+
+        e.finish()
+    }
+
+    // Appends the filter type and the filtered row to ```out```,
+    // ```scratch``` is the encoder of the brute force strategy
+    fn filter_row(&self, row: &[u8], bpp: usize, previous: &[u8], out: &mut Vec<u8>,
+                  scratch: &mut Option<Deflater<Vec<u8>>>) -> io::Result<()> {
+        let rowlen = row.len();
+
+        if let FilterStrategy::Fixed(f) = self.filter {
+            let mut current = row.to_vec();
+            filter(f, bpp, previous, &mut current);
+
+            out.push(f as u8);
+            out.push_all(&current);
+
+            return Ok(())
+        }
+
+        let rows = filtered_rows(row, bpp, previous);
+
+        let method = match *scratch {
+            Some(ref mut d) => try!(select_filter_brute_force(rowlen, &rows, out, d)),
+            None => select_filter(rowlen, &rows)
+        };
+
+        let stride = method as usize * rowlen;
+
+        out.push(method);
+        out.push_all(&rows[stride..stride + rowlen]);
+
+        Ok(())
+    }
+
     fn write_chunk(&mut self, name: &str, buf: &[u8]) -> io::Result<()> {
         self.crc.reset();
         self.crc.update(name.as_bytes());
@@ -76,7 +358,7 @@ impl<'a, W: Write> PNGEncoder<'a, W> {
     }
 }
 
-fn build_ihdr(width: u32, height: u32, c: color::ColorType) -> (Vec<u8>, usize) {
+fn build_ihdr(width: u32, height: u32, c: color::ColorType, interlaced: bool) -> Vec<u8> {
     let mut m = Vec::with_capacity(13);
 
     let _ = m.write_u32::<BigEndian>(width);
@@ -104,76 +386,148 @@ fn build_ihdr(width: u32, height: u32, c: color::ColorType) -> (Vec<u8>, usize)
     let _ = m.write_all(&[bit_depth]);
     let _ = m.write_all(&[colortype]);
 
-    // Compression method, filter method and interlace
+    // Compression method, filter method and interlace method
     let _ = m.write_all(&[0]);
     let _ = m.write_all(&[0]);
-    let _ = m.write_all(&[0]);
-
-    let channels = match colortype {
-        0 => 1,
-        2 => 3,
-        3 => 3,
-        4 => 2,
-        6 => 4,
-        _ => panic!("unknown colour type")
-    };
+    let _ = m.write_all(&[interlaced as u8]);
 
-    let bpp = ((channels * bit_depth + 7) / 8) as usize;
-
-    (m, bpp)
+    m
 }
 
 fn sum_abs_difference(buf: &[u8]) -> i32 {
     buf.iter().fold(0i32, | sum, &b | sum + if b < 128 {b as i32} else {256 - b as i32})
 }
 
-fn select_filter(rowlength: usize, bpp: usize, previous: &[u8], current_s: &mut [u8]) -> u8 {
-    let mut sum    = sum_abs_difference(&current_s[..rowlength]);
-    let mut method = 0;
+// Returns the row filtered with each of the five filters, one after another
+fn filtered_rows(row: &[u8], bpp: usize, previous: &[u8]) -> Vec<u8> {
+    let rowlen = row.len();
+    let mut rows: Vec<u8> = repeat(0u8).take(5 * rowlen).collect();
+
+    for (i, current) in rows.chunks_mut(rowlen).enumerate() {
+        slice::bytes::copy_memory(current, row);
+        filter(FromPrimitive::from_u8(i as u8).unwrap(), bpp, previous, current);
+    }
+
+    rows
+}
 
-    for (i, current) in current_s.chunks_mut(rowlength).enumerate() {
-        filter(FromPrimitive::from_u8(i as u8 + 1).unwrap(), bpp, previous, current);
+fn select_filter(rowlength: usize, rows: &[u8]) -> u8 {
+    let mut sum    = sum_abs_difference(&rows[..rowlength]);
+    let mut method = 0;
 
+    for (i, current) in rows.chunks(rowlength).enumerate().skip(1) {
         let this_sum = sum_abs_difference(current);
 
         if this_sum < sum {
             sum = this_sum;
-            method = i as u8 + 1;
+            method = i as u8;
         }
     }
 
     method
 }
 
-fn build_idat(image: &[u8], bpp: usize, width: u32, height: u32) -> Vec<u8> {
-    use flate::deflate_bytes_zlib;
+// Compresses each filtered row after the previous filtered row in ```out```
+// with ```d``` and returns the filter of the smallest result
+fn select_filter_brute_force(rowlength: usize, rows: &[u8], out: &[u8],
+                             d: &mut Deflater<Vec<u8>>) -> io::Result<u8> {
+    let context = &out[out.len() - cmp::min(out.len(), rowlength + 1)..];
 
-    let rowlen = bpp * width as usize;
+    let mut size   = None;
+    let mut method = 0;
 
-    let mut p: Vec<u8> = repeat(0u8).take(rowlen).collect();
-    let mut c: Vec<u8> = repeat(0u8).take(4 * rowlen).collect();
-    let mut b: Vec<u8> = repeat(0u8).take(height as usize + rowlen * height as usize).collect();
+    for (i, current) in rows.chunks(rowlength).enumerate() {
+        let _ = try!(d.write_all(context));
+        let _ = try!(d.write_all(&[i as u8]));
+        let _ = try!(d.write_all(current));
+        let _ = try!(d.restart());
 
-    for (row, outrow) in image.chunks(rowlen).zip(b.chunks_mut(1 + rowlen)) {
-        for s in c.chunks_mut(rowlen) {
-            slice::bytes::copy_memory(s, row);
-        }
+        let this_size = d.inner().len();
+        d.inner().clear();
 
-        let filter = select_filter(rowlen, bpp, &p, &mut c);
+        if size.map_or(true, |size| this_size < size) {
+            size = Some(this_size);
+            method = i as u8;
+        }
+    }
 
-        outrow[0]  = filter;
-        let out    = &mut outrow[1..];
+    Ok(method)
+}
 
-        match filter {
-            0 => slice::bytes::copy_memory(out, row),
-            _ => {
-                let stride = (filter as usize - 1) * rowlen;
-                slice::bytes::copy_memory(out, &c[stride..stride + rowlen])
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use byteorder::{ReadBytesExt, BigEndian};
+    use num::rational::Ratio;
+
+    use image::ImageDecoder;
+    use animation::Frame;
+    use buffer::{ImageBuffer, RgbaImage};
+    use color::Rgba;
+
+    use super::PNGEncoder;
+    use super::super::PNGDecoder;
+
+    // Returns the content of the fcTL chunks
+    fn frame_controls(png: &[u8]) -> Vec<Vec<u8>> {
+        let mut controls = Vec::new();
+        let mut i = 8;
+
+        while i < png.len() {
+            let length = (&png[i..]).read_u32::<BigEndian>().unwrap() as usize;
+            if &png[i + 4..i + 8] == b"fcTL" {
+                controls.push(png[i + 8..i + 8 + length].to_vec());
             }
+            i += length + 12;
         }
 
-        slice::bytes::copy_memory(&mut p, row);
+        controls
     }
 
-    deflate_bytes_zlib(&b).to_vec()
+    #[test]
+    /// Test that animations are encoded with the regions that change
+    /// and the dispose and blend operations that keep them small
+    fn test_encode_animation() {
+        let red: RgbaImage = ImageBuffer::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+        let blue: RgbaImage = ImageBuffer::from_pixel(2, 2, Rgba([0, 0, 255, 255]));
+        let reverted: RgbaImage = ImageBuffer::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
+        let cleared: RgbaImage = ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 0, 0]));
+
+        // The blue square is reverted to the red canvas, which is then cleared at the top left pixel
+        let frames = vec![
+            Frame::new(red),
+            Frame::from_parts(blue, 1, 1, Ratio::new(1, 2)),
+            Frame::from_parts(reverted, 1, 1, Ratio::new(3, 100)),
+            Frame::from_parts(cleared, 0, 0, Ratio::new(1, 10)),
+        ];
+
+        let mut encoded = Vec::new();
+        PNGEncoder::new(&mut encoded).encode_frames(&frames, 0).unwrap();
+
+        // The second frame only covers the blue pixels
+        let controls = frame_controls(&encoded);
+        assert_eq!(controls.len(), 4);
+        assert_eq!(&controls[1][..20], &[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1][..]);
+
+        // The dispose operations are none, previous, background and none,
+        // the first frame replaces the canvas and the other frames are blended over it
+        let ops: Vec<(u8, u8)> = controls.iter().map(|c| (c[24], c[25])).collect();
+        assert_eq!(ops, vec![(0, 0), (2, 1), (1, 1), (0, 1)]);
+
+        let mut decoder = PNGDecoder::new(io::Cursor::new(encoded));
+        assert_eq!(decoder.num_plays().unwrap(), Some(0));
+
+        let decoded: Vec<Frame> = decoder.into_frames().unwrap().collect();
+        assert_eq!(decoded.len(), 4);
+
+        let mut canvas: RgbaImage = ImageBuffer::new(4, 4);
+        for (frame, decoded) in frames.iter().zip(decoded.iter()) {
+            for (x, y, &pixel) in frame.buffer().enumerate_pixels() {
+                canvas.put_pixel(frame.left() + x, frame.top() + y, pixel);
+            }
+
+            assert_eq!(decoded.delay(), frame.delay());
+            assert!(decoded.buffer().pixels().zip(canvas.pixels()).all(|(a, b)| a == b));
+        }
+    }
 }
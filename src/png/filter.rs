@@ -1,12 +1,19 @@
 use std::num::SignedInt;
 use std::num::wrapping::Wrapping as w;
 
-#[derive(FromPrimitive, Debug)]
+/// The filters that can be applied to a row before it is compressed
+#[derive(Copy, Clone, FromPrimitive, Debug, PartialEq)]
 pub enum FilterType {
+    /// The row is not changed
     NoFilter = 0,
+    /// Each byte is replaced by its difference to the corresponding byte of the pixel to the left
     Sub = 1,
+    /// Each byte is replaced by its difference to the byte above
     Up = 2,
+    /// Each byte is replaced by its difference to the average of the bytes to the left and above
     Avg = 3,
+    /// Each byte is replaced by its difference to the Paeth predictor of the bytes
+    /// to the left, above and above left
     Paeth = 4
 }
 
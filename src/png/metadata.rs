@@ -0,0 +1,520 @@
+//! Ancillary chunks of PNG images
+//!
+//! The ancillary chunks store metadata about the image, like texts, the color space
+//! and the physical size of the pixels. They are not needed to decode the image.
+
+use std::io::{self, Read, Write};
+use std::num::FromPrimitive;
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+use image::{ImageError, ImageResult};
+
+use super::zlib::{ZlibDecoder, ZlibEncoder};
+
+/// A text of a tEXt, zTXt or iTXt chunk
+#[derive(Clone, Debug, PartialEq)]
+pub struct Text {
+    /// The keyword that tells what the text is about, like "Title", "Author" or "Comment"
+    pub keyword: String,
+
+    /// The text
+    pub text: String,
+
+    /// Whether the text is compressed
+    pub compressed: bool,
+
+    /// The language of an international text, ```None``` for texts in Latin-1
+    pub language_tag: Option<String>,
+
+    /// The keyword translated to the language of an international text,
+    /// ```None``` for texts in Latin-1
+    pub translated_keyword: Option<String>,
+}
+
+impl Text {
+    /// Creates an uncompressed text.
+    /// Texts that have characters which are not in Latin-1 are written as international texts.
+    pub fn new(keyword: &str, text: &str) -> Text {
+        Text {
+            keyword: keyword.to_string(),
+            text: text.to_string(),
+            compressed: false,
+            language_tag: None,
+            translated_keyword: None
+        }
+    }
+}
+
+/// The chromaticities of the white point and the primaries of the cHRM chunk,
+/// as CIE x and y coordinates times 100000
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Chromaticities {
+    /// The white point
+    pub white: (u32, u32),
+
+    /// The red primary
+    pub red: (u32, u32),
+
+    /// The green primary
+    pub green: (u32, u32),
+
+    /// The blue primary
+    pub blue: (u32, u32),
+}
+
+/// The rendering intent of images in the sRGB color space
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+pub enum RenderingIntent {
+    /// For images that prefer good adaptation to the output device gamut, like photographs
+    Perceptual = 0,
+
+    /// For images that require color appearance matching, like logos
+    RelativeColorimetric = 1,
+
+    /// For images that prefer preservation of saturation, like charts
+    Saturation = 2,
+
+    /// For images that require preservation of absolute colorimetry, like proofs
+    AbsoluteColorimetric = 3,
+}
+
+/// An embedded ICC profile
+#[derive(Clone, Debug, PartialEq)]
+pub struct IccProfile {
+    /// The name of the profile
+    pub name: String,
+
+    /// The uncompressed profile
+    pub data: Vec<u8>,
+}
+
+/// The unit of the physical pixel dimensions
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Unit {
+    /// Only the aspect ratio of the pixels is known
+    Unknown,
+
+    /// The dimensions are pixels per meter
+    Meter,
+}
+
+/// The number of pixels per unit of the pHYs chunk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PhysicalDimensions {
+    /// Pixels per unit in horizontal direction
+    pub x: u32,
+
+    /// Pixels per unit in vertical direction
+    pub y: u32,
+
+    /// The unit
+    pub unit: Unit,
+}
+
+impl PhysicalDimensions {
+    /// Returns the horizontal and vertical resolution in dots per inch,
+    /// ```None``` if the unit is unknown.
+    pub fn dots_per_inch(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            Unit::Meter   => Some((self.x as f64 * 0.0254, self.y as f64 * 0.0254)),
+            Unit::Unknown => None
+        }
+    }
+}
+
+/// The background color of the bKGD chunk, which has to match the color type of the image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Background {
+    /// The palette index of the background color of palette images
+    Index(u8),
+
+    /// The gray level of gray images
+    Gray(u16),
+
+    /// The red, green and blue samples of RGB images
+    RGB(u16, u16, u16),
+}
+
+/// The time of the last modification of the tIME chunk, in UTC
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ModificationTime {
+    /// The complete year, like 2015
+    pub year: u16,
+
+    /// The month, 1 to 12
+    pub month: u8,
+
+    /// The day, 1 to 31
+    pub day: u8,
+
+    /// The hour, 0 to 23
+    pub hour: u8,
+
+    /// The minute, 0 to 59
+    pub minute: u8,
+
+    /// The second, 0 to 60 for leap seconds
+    pub second: u8,
+}
+
+/// The metadata of the ancillary chunks of an image.
+/// Chunks that are not present are ```None```.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// The texts of the tEXt, zTXt and iTXt chunks in the order of the chunks
+    pub text: Vec<Text>,
+
+    /// The gamma of the gAMA chunk times 100000
+    pub gamma: Option<u32>,
+
+    /// The chromaticities of the cHRM chunk
+    pub chromaticities: Option<Chromaticities>,
+
+    /// The rendering intent of the sRGB chunk, which tells that the image is in the sRGB color space
+    pub srgb: Option<RenderingIntent>,
+
+    /// The ICC profile of the iCCP chunk
+    pub icc_profile: Option<IccProfile>,
+
+    /// The physical pixel dimensions of the pHYs chunk
+    pub physical_dimensions: Option<PhysicalDimensions>,
+
+    /// The background color of the bKGD chunk
+    pub background: Option<Background>,
+
+    /// The number of significant bits of each channel of the sBIT chunk
+    pub significant_bits: Option<Vec<u8>>,
+
+    /// The time of the last modification of the tIME chunk
+    pub modification_time: Option<ModificationTime>,
+
+    /// The types of the malformed ancillary chunks, which were skipped
+    pub skipped_chunks: Vec<Vec<u8>>,
+}
+
+/// Reads the ancillary chunk ```chunk_type``` into ```metadata```, other chunks are ignored
+pub fn read_chunk(metadata: &mut Metadata, chunk_type: &[u8], data: &[u8]) -> ImageResult<()> {
+    let mut r = io::Cursor::new(data);
+
+    match chunk_type {
+        b"tEXt" => {
+            let (keyword, text) = try!(split_null(data));
+
+            metadata.text.push(Text {
+                keyword: from_latin1(keyword),
+                text: from_latin1(text),
+                compressed: false,
+                language_tag: None,
+                translated_keyword: None
+            });
+        }
+
+        b"zTXt" => {
+            let (keyword, rest) = try!(split_null(data));
+
+            if rest.len() < 1 || rest[0] != 0 {
+                return Err(unknown_compression())
+            }
+
+            metadata.text.push(Text {
+                keyword: from_latin1(keyword),
+                text: from_latin1(&try!(inflate(&rest[1..]))),
+                compressed: true,
+                language_tag: None,
+                translated_keyword: None
+            });
+        }
+
+        b"iTXt" => {
+            let (keyword, rest) = try!(split_null(data));
+
+            if rest.len() < 2 {
+                return Err(malformed(chunk_type))
+            }
+
+            let compressed = rest[0] != 0;
+            if compressed && rest[1] != 0 {
+                return Err(unknown_compression())
+            }
+
+            let (language_tag, rest) = try!(split_null(&rest[2..]));
+            let (translated_keyword, text) = try!(split_null(rest));
+
+            let text = if compressed {
+                try!(inflate(text))
+            } else {
+                text.to_vec()
+            };
+
+            metadata.text.push(Text {
+                keyword: from_latin1(keyword),
+                text: try!(from_utf8(text)),
+                compressed: compressed,
+                language_tag: Some(from_latin1(language_tag)),
+                translated_keyword: Some(try!(from_utf8(translated_keyword.to_vec())))
+            });
+        }
+
+        b"gAMA" => metadata.gamma = Some(try!(r.read_u32::<BigEndian>())),
+
+        b"cHRM" => {
+            let mut v = [0u32; 8];
+            for value in v.iter_mut() {
+                *value = try!(r.read_u32::<BigEndian>());
+            }
+
+            metadata.chromaticities = Some(Chromaticities {
+                white: (v[0], v[1]),
+                red: (v[2], v[3]),
+                green: (v[4], v[5]),
+                blue: (v[6], v[7])
+            });
+        }
+
+        b"sRGB" => {
+            metadata.srgb = match FromPrimitive::from_u8(try!(r.read_u8())) {
+                Some(intent) => Some(intent),
+                None => return Err(malformed(chunk_type))
+            };
+        }
+
+        b"iCCP" => {
+            let (name, rest) = try!(split_null(data));
+
+            if rest.len() < 1 || rest[0] != 0 {
+                return Err(unknown_compression())
+            }
+
+            metadata.icc_profile = Some(IccProfile {
+                name: from_latin1(name),
+                data: try!(inflate(&rest[1..]))
+            });
+        }
+
+        b"pHYs" => {
+            let x = try!(r.read_u32::<BigEndian>());
+            let y = try!(r.read_u32::<BigEndian>());
+
+            let unit = match try!(r.read_u8()) {
+                0 => Unit::Unknown,
+                1 => Unit::Meter,
+                _ => return Err(malformed(chunk_type))
+            };
+
+            metadata.physical_dimensions = Some(PhysicalDimensions { x: x, y: y, unit: unit });
+        }
+
+        // The length of the chunk depends on the color type
+        b"bKGD" => {
+            metadata.background = Some(match data.len() {
+                1 => Background::Index(data[0]),
+                2 => Background::Gray(try!(r.read_u16::<BigEndian>())),
+                6 => Background::RGB(try!(r.read_u16::<BigEndian>()),
+                                     try!(r.read_u16::<BigEndian>()),
+                                     try!(r.read_u16::<BigEndian>())),
+                _ => return Err(malformed(chunk_type))
+            });
+        }
+
+        b"sBIT" => {
+            if data.len() < 1 || data.len() > 4 {
+                return Err(malformed(chunk_type))
+            }
+
+            metadata.significant_bits = Some(data.to_vec());
+        }
+
+        b"tIME" => {
+            metadata.modification_time = Some(ModificationTime {
+                year: try!(r.read_u16::<BigEndian>()),
+                month: try!(r.read_u8()),
+                day: try!(r.read_u8()),
+                hour: try!(r.read_u8()),
+                minute: try!(r.read_u8()),
+                second: try!(r.read_u8())
+            });
+        }
+
+        _ => ()
+    }
+
+    Ok(())
+}
+
+/// Returns the chunks of ```metadata``` that have to precede the PLTE chunk
+pub fn color_space_chunks(metadata: &Metadata) -> io::Result<Vec<(&'static str, Vec<u8>)>> {
+    let mut chunks = Vec::new();
+
+    if let Some(gamma) = metadata.gamma {
+        let mut m = Vec::with_capacity(4);
+        let _ = m.write_u32::<BigEndian>(gamma);
+        chunks.push(("gAMA", m));
+    }
+
+    if let Some(c) = metadata.chromaticities {
+        let mut m = Vec::with_capacity(32);
+        for &(x, y) in [c.white, c.red, c.green, c.blue].iter() {
+            let _ = m.write_u32::<BigEndian>(x);
+            let _ = m.write_u32::<BigEndian>(y);
+        }
+        chunks.push(("cHRM", m));
+    }
+
+    if let Some(intent) = metadata.srgb {
+        chunks.push(("sRGB", vec![intent as u8]));
+    }
+
+    if let Some(ref profile) = metadata.icc_profile {
+        let mut m = try!(keyword(&profile.name));
+        m.push(0);
+        m.push_all(&try!(deflate(&profile.data)));
+        chunks.push(("iCCP", m));
+    }
+
+    if let Some(ref bits) = metadata.significant_bits {
+        chunks.push(("sBIT", bits.clone()));
+    }
+
+    Ok(chunks)
+}
+
+/// Returns the chunks of ```metadata``` that follow the PLTE chunk
+pub fn chunks(metadata: &Metadata) -> io::Result<Vec<(&'static str, Vec<u8>)>> {
+    let mut chunks = Vec::new();
+
+    if let Some(background) = metadata.background {
+        let mut m = Vec::with_capacity(6);
+        match background {
+            Background::Index(i) => m.push(i),
+            Background::Gray(g)  => { let _ = m.write_u16::<BigEndian>(g); }
+            Background::RGB(r, g, b) => {
+                let _ = m.write_u16::<BigEndian>(r);
+                let _ = m.write_u16::<BigEndian>(g);
+                let _ = m.write_u16::<BigEndian>(b);
+            }
+        }
+        chunks.push(("bKGD", m));
+    }
+
+    if let Some(p) = metadata.physical_dimensions {
+        let mut m = Vec::with_capacity(9);
+        let _ = m.write_u32::<BigEndian>(p.x);
+        let _ = m.write_u32::<BigEndian>(p.y);
+        m.push(if p.unit == Unit::Meter {1} else {0});
+        chunks.push(("pHYs", m));
+    }
+
+    if let Some(t) = metadata.modification_time {
+        let mut m = Vec::with_capacity(7);
+        let _ = m.write_u16::<BigEndian>(t.year);
+        m.push_all(&[t.month, t.day, t.hour, t.minute, t.second]);
+        chunks.push(("tIME", m));
+    }
+
+    for text in metadata.text.iter() {
+        let mut m = try!(keyword(&text.keyword));
+        let latin1 = to_latin1(&text.text);
+
+        // Texts with characters that are not in Latin-1 have to be international texts
+        if text.language_tag.is_some() || text.translated_keyword.is_some() || latin1.is_none() {
+            m.push(text.compressed as u8);
+            m.push(0);
+            m.push_all(text.language_tag.as_ref().map(|s| s.as_bytes()).unwrap_or(&[]));
+            m.push(0);
+            m.push_all(text.translated_keyword.as_ref().map(|s| s.as_bytes()).unwrap_or(&[]));
+            m.push(0);
+
+            if text.compressed {
+                m.push_all(&try!(deflate(text.text.as_bytes())));
+            } else {
+                m.push_all(text.text.as_bytes());
+            }
+            chunks.push(("iTXt", m));
+        } else if text.compressed {
+            m.push(0);
+            m.push_all(&try!(deflate(&latin1.unwrap())));
+            chunks.push(("zTXt", m));
+        } else {
+            m.push_all(&latin1.unwrap());
+            chunks.push(("tEXt", m));
+        }
+    }
+
+    Ok(chunks)
+}
+
+// Returns a keyword followed by a null separator
+fn keyword(keyword: &str) -> io::Result<Vec<u8>> {
+    match to_latin1(keyword) {
+        Some(ref k) if k.len() >= 1 && k.len() <= 79 && !k.contains(&0) => {
+            let mut m = k.clone();
+            m.push(0);
+            Ok(m)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid PNG keyword.",
+            Some(format!("Keywords must be 1 to 79 Latin-1 characters, found {:?}", keyword))
+        ))
+    }
+}
+
+// Splits data at the first null byte
+fn split_null(data: &[u8]) -> ImageResult<(&[u8], &[u8])> {
+    match data.iter().position(|&b| b == 0) {
+        Some(i) => Ok((&data[..i], &data[i + 1..])),
+        None => Err(ImageError::FormatError("Null separator of chunk not found.".to_string()))
+    }
+}
+
+// Compressed chunks are rejected when they decompress to more than 8 MiB,
+// a few bytes of deflated data can expand to gigabytes
+const MAX_INFLATED_LENGTH: u64 = 8 * 1024 * 1024;
+
+fn inflate(data: &[u8]) -> ImageResult<Vec<u8>> {
+    let mut out = Vec::new();
+    let decoder = ZlibDecoder::new(io::Cursor::new(data));
+    try!(decoder.take(MAX_INFLATED_LENGTH + 1).read_to_end(&mut out));
+
+    if out.len() as u64 > MAX_INFLATED_LENGTH {
+        return Err(ImageError::FormatError(format!(
+            "A compressed chunk decompresses to more than {} bytes.", MAX_INFLATED_LENGTH
+        )))
+    }
+
+    Ok(out)
+}
+
+fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut e = ZlibEncoder::new(Vec::new(), 6);
+    let _ = try!(e.write_all(data));
+
+    e.finish()
+}
+
+// Latin-1 characters have the same code points in unicode
+fn from_latin1(data: &[u8]) -> String {
+    data.iter().map(|&b| b as char).collect()
+}
+
+fn to_latin1(s: &str) -> Option<Vec<u8>> {
+    if s.chars().all(|c| (c as u32) < 256) {
+        Some(s.chars().map(|c| c as u8).collect())
+    } else {
+        None
+    }
+}
+
+fn from_utf8(data: Vec<u8>) -> ImageResult<String> {
+    String::from_utf8(data).map_err(|_| {
+        ImageError::FormatError("International text is not valid UTF-8.".to_string())
+    })
+}
+
+fn malformed(chunk_type: &[u8]) -> ImageError {
+    ImageError::FormatError(format!("{} chunk malformed.", from_latin1(chunk_type)))
+}
+
+fn unknown_compression() -> ImageError {
+    ImageError::UnsupportedError("Unknown compression method.".to_string())
+}
@@ -7,11 +7,25 @@
 //!
 
 pub use self::decoder::PNGDecoder;
-pub use self::encoder::PNGEncoder;
+pub use self::encoder::{PNGEncoder, FilterStrategy};
+pub use self::filter::FilterType;
+pub use self::metadata::{
+    Metadata,
+    Text,
+    Chromaticities,
+    RenderingIntent,
+    IccProfile,
+    PhysicalDimensions,
+    Unit,
+    Background,
+    ModificationTime,
+};
 
 mod filter;
 mod decoder;
 mod encoder;
+mod metadata;
+mod apng;
 pub mod zlib;
 pub mod deflate;
 pub mod hash;
\ No newline at end of file
@@ -1,15 +1,15 @@
 //! An Implementation of RFC 1950
 //!
-//! Decoding of zlib compressed streams.
+//! Decoding and encoding of zlib compressed streams.
 //!
 //! # Related Links
 //! *http://tools.ietf.org/html/rfc1950 - ZLIB Compressed Data Format Specification
 
-use std::io::{self, Read};
-use byteorder::{ReadBytesExt, BigEndian};
+use std::io::{self, Read, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
 use super::hash::Adler32;
-use super::deflate::Inflater;
+use super::deflate::{Inflater, Deflater};
 
 enum ZlibState {
     Start,
@@ -97,3 +97,145 @@ impl<R: Read> Read for ZlibDecoder<R> {
         }
     }
 }
+
+/// A Zlib compressed stream encoder.
+pub struct ZlibEncoder<W> {
+    deflate: Deflater<W>,
+    adler: Adler32,
+    state: ZlibState,
+    level: u8,
+}
+
+impl<W: Write> ZlibEncoder<W> {
+    /// Create a new encoder that encodes to a Writer.
+    /// ```level``` ranges from 0 (no compression) to 9 (best compression).
+    pub fn new(w: W, level: u8) -> ZlibEncoder<W> {
+        ZlibEncoder {
+            deflate: Deflater::new(w, level),
+            adler: Adler32::new(),
+            state: ZlibState::Start,
+            level: level,
+        }
+    }
+
+    /// Return a mutable reference to the wrapped Writer
+    pub fn inner(&mut self) -> &mut W {
+        self.deflate.inner()
+    }
+
+    /// Compress the remaining input, write the checksum
+    /// and return the wrapped Writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let ZlibState::Start = self.state {
+            let _ = try!(self.write_header());
+        }
+
+        let mut w = try!(self.deflate.finish());
+        let _ = try!(w.write_u32::<BigEndian>(self.adler.checksum()));
+
+        Ok(w)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        // Deflate with a window size of 32K
+        let cmf = 0x78u8;
+
+        let flevel = match self.level {
+            0 ... 1 => 0,
+            2 ... 5 => 1,
+            6       => 2,
+            _       => 3
+        };
+
+        let flg = flevel << 6;
+        let flg = flg + 31 - ((cmf as u16 * 256 + flg as u16) % 31) as u8;
+
+        let _ = try!(self.inner().write_u8(cmf));
+        let _ = try!(self.inner().write_u8(flg));
+
+        self.state = ZlibState::CompressedData;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ZlibEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let ZlibState::Start = self.state {
+            let _ = try!(self.write_header());
+        }
+
+        let n = try!(self.deflate.write(buf));
+        self.adler.update(&buf[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deflate.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use super::{ZlibDecoder, ZlibEncoder};
+    use super::super::deflate::Deflater;
+
+    #[test]
+    fn test_roundtrip() {
+        // Text that compresses well, followed by noise that does not,
+        // long enough to span several blocks
+        let mut data = Vec::new();
+        for i in (0..20000u32) {
+            data.push_all(format!("{} bottles of beer. ", i % 100).as_bytes());
+        }
+        let mut x = 1u32;
+        for _ in (0..100000) {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.push((x >> 16) as u8);
+        }
+
+        for level in (0..10) {
+            let mut e = ZlibEncoder::new(Vec::new(), level);
+            for chunk in data.chunks(10000) {
+                e.write_all(chunk).unwrap();
+            }
+            let compressed = e.finish().unwrap();
+
+            if level > 0 {
+                assert!(compressed.len() < data.len() / 2);
+            }
+
+            let mut out = Vec::new();
+            ZlibDecoder::new(io::Cursor::new(compressed)).read_to_end(&mut out).unwrap();
+            assert!(out == data);
+        }
+
+        let compressed = ZlibEncoder::new(Vec::new(), 6).finish().unwrap();
+        let mut out = Vec::new();
+        ZlibDecoder::new(io::Cursor::new(compressed)).read_to_end(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    /// Test that a restarted encoder compresses as a new one, without matches in the previous stream
+    fn test_restart() {
+        let inputs: Vec<Vec<u8>> = (0..4).map(|i| {
+            (0..3000).map(|j: u32| (j % (10 + i)) as u8).collect()
+        }).collect();
+
+        let mut d = Deflater::new(Vec::new(), 9);
+
+        for input in inputs.iter() {
+            d.write_all(input).unwrap();
+            d.restart().unwrap();
+
+            let mut fresh = Deflater::new(Vec::new(), 9);
+            fresh.write_all(input).unwrap();
+
+            assert_eq!(*d.inner(), fresh.finish().unwrap());
+            d.inner().clear();
+        }
+    }
+}
@@ -1,7 +1,5 @@
 use std::io::{self, Read, Seek};
-use std::mem;
 use std::num::{ Int, Float, FromPrimitive };
-use std::collections::HashMap;
 use byteorder;
 
 use image;
@@ -254,68 +252,26 @@ impl<R: Read + Seek> TIFFDecoder<R> {
         self.reader.seek(io::SeekFrom::Start(offset as u64)).map(|_| ())
     }
 
-    /// Reads a IFD entry.
-    ///
-    /// And IFD entry has four fields
-    /// Tag   2 bytes
-    /// Type  2 bytes
-    /// Count 4 bytes
-    /// Value 4 bytes either a pointer the value itself
-    fn read_entry(&mut self) -> ImageResult<Option<(ifd::Tag, ifd::Entry)>> {
-        let tag = ifd::Tag::from_u16(try!(self.read_short()));
-        let type_: ifd::Type = match FromPrimitive::from_u16(try!(self.read_short())) {
-            Some(t) => t,
-            None => {
-                // Unknown type. Skip this entry according to spec.
-                try!(self.read_long());
-                try!(self.read_long());
-                return Ok(None)
-
-            }
-        };
-        Ok(Some((tag, ifd::Entry::new(
-            type_,
-            try!(self.read_long()), // count
-            try!(self.read_offset())  // offset
-        ))))
-    }
-
     /// Reads the next IFD
     fn read_ifd(&mut self) -> ImageResult<Directory> {
-        let mut dir: Directory = HashMap::new();
-        match self.next_ifd {
+        let offset = match self.next_ifd {
             None => return Err(image::ImageError::FormatError(
                 "Image file directory not found.".to_string())
             ),
-            Some(offset) => try!(self.goto_offset(offset))
-        }
-        for _ in (0..try!(self.read_short())) {
-            let (tag, entry) = match try!(self.read_entry()) {
-                Some(val) => val,
-                None => continue // Unknown data type in tag, skip
-            };
-            dir.insert(tag, entry);
-        }
-        self.next_ifd = match try!(self.read_long()) {
-            0 => None,
-            n => Some(n)
+            Some(offset) => offset
         };
+        let (dir, next_ifd) = try!(ifd::read_directory(&mut self.reader, offset));
+        self.next_ifd = next_ifd;
         Ok(dir)
     }
 
     /// Tries to retrieve a tag.
     /// Return `Ok(None)` if the tag is not present.
     fn find_tag(&mut self, tag: ifd::Tag) -> ImageResult<Option<ifd::Value>> {
-        let ifd: &Directory = unsafe {
-            let ifd = self.ifd.as_ref().unwrap(); // Ok to fail
-            // Get a immutable borrow of self
-            // This is ok because entry val only changes the stream
-            // but not the directory.
-            mem::transmute_copy(&ifd)
-        };
+        let ifd = self.ifd.as_ref().unwrap(); // Ok to fail
         match ifd.get(&tag) {
             None => Ok(None),
-            Some(entry) => Ok(Some(try!(entry.val(self))))
+            Some(entry) => Ok(Some(try!(entry.val(&mut self.reader))))
         }
     }
 
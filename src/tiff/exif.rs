@@ -0,0 +1,213 @@
+//! Reading of EXIF metadata
+//!
+//! EXIF metadata is a TIFF structure without an image, which JPEG images store in an APP1 segment.
+//!
+//! # Related Links
+//! * http://www.cipa.jp/std/documents/e/DC-008-2012_E.pdf - The EXIF specification
+
+use std::io::{self, Read, Seek};
+
+use image;
+use image::ImageResult;
+use imageops::Orientation;
+
+use super::ifd;
+use super::ifd::{Directory, Tag, Value};
+use super::stream::{ByteOrder, EndianReader, SmartReader};
+
+/// The EXIF metadata of an image.
+/// Tags that are not present are ```None```.
+#[derive(Clone, Debug, Default)]
+pub struct Exif {
+    /// The orientation of the stored image
+    pub orientation: Option<Orientation>,
+
+    /// The manufacturer of the camera
+    pub make: Option<String>,
+
+    /// The model of the camera
+    pub model: Option<String>,
+
+    /// The date and time the image was last changed, as "YYYY:MM:DD HH:MM:SS"
+    pub date_time: Option<String>,
+
+    /// The date and time the image was taken, as "YYYY:MM:DD HH:MM:SS"
+    pub date_time_original: Option<String>,
+
+    /// The latitude in degrees, negative in the southern hemisphere
+    pub latitude: Option<f64>,
+
+    /// The longitude in degrees, negative in the western hemisphere
+    pub longitude: Option<f64>,
+
+    /// The altitude in meters, negative below sea level
+    pub altitude: Option<f64>,
+}
+
+impl Exif {
+    /// Reads the metadata from ```data```, the TIFF structure that follows
+    /// the "Exif\0\0" identifier of an APP1 segment.
+    pub fn from_bytes(data: &[u8]) -> ImageResult<Exif> {
+        let mut r = SmartReader::wrap(io::Cursor::new(data), ByteOrder::LittleEndian);
+
+        let mut endianess = Vec::with_capacity(2);
+        try!(r.by_ref().take(2).read_to_end(&mut endianess));
+        r.byte_order = match &*endianess {
+            b"II" => ByteOrder::LittleEndian,
+            b"MM" => ByteOrder::BigEndian,
+            _ => return Err(image::ImageError::FormatError(
+                "TIFF signature not found.".to_string()
+            ))
+        };
+        if try!(r.read_u16()) != 42 {
+            return Err(image::ImageError::FormatError("TIFF signature invalid.".to_string()))
+        }
+        let offset = try!(r.read_u32());
+
+        let mut exif: Exif = Default::default();
+
+        let (dir, _) = try!(ifd::read_directory(&mut r, offset));
+        exif.orientation = try!(find_u32(&mut r, &dir, Tag::Orientation)).and_then(Orientation::from_tag);
+        exif.make = try!(find_string(&mut r, &dir, Tag::Make));
+        exif.model = try!(find_string(&mut r, &dir, Tag::Model));
+        exif.date_time = try!(find_string(&mut r, &dir, Tag::DateTime));
+
+        if let Some(offset) = try!(find_u32(&mut r, &dir, Tag::ExifIfd)) {
+            let (exif_dir, _) = try!(ifd::read_directory(&mut r, offset));
+            exif.date_time_original = try!(find_string(&mut r, &exif_dir, Tag::DateTimeOriginal));
+        }
+
+        if let Some(offset) = try!(find_u32(&mut r, &dir, Tag::GpsIfd)) {
+            let (gps, _) = try!(ifd::read_directory(&mut r, offset));
+            exif.latitude = try!(find_coordinate(&mut r, &gps, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"));
+            exif.longitude = try!(find_coordinate(&mut r, &gps, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"));
+
+            if let Some(altitude) = try!(find_tag(&mut r, &gps, Tag::GPSAltitude)) {
+                let altitude = try!(altitude.as_f64());
+
+                // A reference of 1 is below sea level
+                exif.altitude = match try!(find_u32(&mut r, &gps, Tag::GPSAltitudeRef)) {
+                    Some(1) => Some(-altitude),
+                    _ => Some(altitude)
+                };
+            }
+        }
+
+        Ok(exif)
+    }
+}
+
+/// Tries to retrieve a tag of the directory.
+/// Return `Ok(None)` if the tag is not present.
+fn find_tag<R: Read + Seek>(r: &mut SmartReader<R>, dir: &Directory, tag: Tag) -> ImageResult<Option<Value>> {
+    match dir.get(&tag) {
+        None => Ok(None),
+        Some(entry) => Ok(Some(try!(entry.val(r))))
+    }
+}
+
+fn find_u32<R: Read + Seek>(r: &mut SmartReader<R>, dir: &Directory, tag: Tag) -> ImageResult<Option<u32>> {
+    match try!(find_tag(r, dir, tag)) {
+        Some(val) => Ok(Some(try!(val.as_u32()))),
+        None => Ok(None)
+    }
+}
+
+fn find_string<R: Read + Seek>(r: &mut SmartReader<R>, dir: &Directory, tag: Tag) -> ImageResult<Option<String>> {
+    match try!(find_tag(r, dir, tag)) {
+        Some(val) => Ok(Some(try!(val.as_string()))),
+        None => Ok(None)
+    }
+}
+
+/// Retrieves a GPS coordinate, stored as degrees, minutes and seconds.
+/// The coordinate is negative if its reference tag is ```negative```.
+fn find_coordinate<R: Read + Seek>(r: &mut SmartReader<R>,
+                                   dir: &Directory,
+                                   tag: Tag,
+                                   reference: Tag,
+                                   negative: &str) -> ImageResult<Option<f64>> {
+    let dms = match try!(find_tag(r, dir, tag)) {
+        Some(val) => try!(val.as_f64_vec()),
+        None => return Ok(None)
+    };
+
+    let degrees = dms.iter().zip([1.0, 60.0, 3600.0].iter()).fold(0.0, |sum, (&v, &unit)| sum + v / unit);
+
+    match try!(find_string(r, dir, reference)) {
+        Some(ref val) if &val[..] == negative => Ok(Some(-degrees)),
+        _ => Ok(Some(degrees))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use imageops::Orientation;
+    use image::ImageError;
+    use super::Exif;
+
+    #[test]
+    /// Test that the tags of the main, EXIF and GPS directories are read
+    fn test_read_exif() {
+        let data: Vec<u8> = vec![
+            // Header, big endian with the directory at 8
+            b'M', b'M', 0, 42, 0, 0, 0, 8,
+            // Orientation 6, Make "Cam", the EXIF directory at 62, the GPS directory at 92
+            0, 4,
+            0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0,
+            0x01, 0x0F, 0, 2, 0, 0, 0, 4, b'C', b'a', b'm', 0,
+            0x87, 0x69, 0, 4, 0, 0, 0, 1, 0, 0, 0, 62,
+            0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 92,
+            0, 0, 0, 0,
+            // EXIF directory, DateTimeOriginal at 80
+            0, 1,
+            0x90, 0x03, 0, 2, 0, 0, 0, 12, 0, 0, 0, 80,
+            0, 0, 0, 0,
+            b'2', b'0', b'1', b'5', b':', b'0', b'4', b':', b'0', b'1', b' ', 0,
+            // GPS directory, latitude 52 30 0 south with the rationals at 122
+            0, 2,
+            0, 1, 0, 2, 0, 0, 0, 2, b'S', 0, 0, 0,
+            0, 2, 0, 5, 0, 0, 0, 3, 0, 0, 0, 122,
+            0, 0, 0, 0,
+            0, 0, 0, 52, 0, 0, 0, 1,
+            0, 0, 0, 30, 0, 0, 0, 1,
+            0, 0, 0, 0, 0, 0, 0, 1,
+        ];
+
+        let exif = Exif::from_bytes(&data).unwrap();
+
+        assert_eq!(exif.orientation, Some(Orientation::Rotate90));
+        assert_eq!(exif.make, Some("Cam".to_string()));
+        assert_eq!(exif.model, None);
+        assert_eq!(exif.date_time_original, Some("2015:04:01 ".to_string()));
+        assert_eq!(exif.latitude, Some(-52.5));
+        assert_eq!(exif.longitude, None);
+    }
+
+    #[test]
+    /// Test that entries with more values than the data holds are rejected before they are read
+    fn test_value_count_exceeds_data() {
+        let entries = [
+            // Make with 0xFFFFFFFF characters at 26
+            [0x01, 0x0F, 0, 2, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 26],
+            // Orientation with 1000000 values at 26
+            [0x01, 0x12, 0, 3, 0, 0x0F, 0x42, 0x40, 0, 0, 0, 26],
+            // Make with 20 characters at 26, but only 16 bytes follow
+            [0x01, 0x0F, 0, 2, 0, 0, 0, 20, 0, 0, 0, 26],
+        ];
+
+        for entry in entries.iter() {
+            // Header, big endian with a directory of one entry at 8
+            let mut data = vec![b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 1];
+            data.push_all(entry);
+            data.push_all(&[0, 0, 0, 0]);
+            data.push_all(b"Camera Model 16\0");
+
+            match Exif::from_bytes(&data) {
+                Err(ImageError::FormatError(_)) => (),
+                _ => panic!("an entry exceeding the data was accepted")
+            }
+        }
+    }
+}
@@ -2,10 +2,11 @@
 
 use std::io::{self, Read, Seek};
 use std::collections::{HashMap};
+use std::num::FromPrimitive;
 
 use super::stream::{ByteOrder, SmartReader, EndianReader};
 
-use self::Value::{Unsigned, List};
+use self::Value::{Unsigned, Rational, Ascii, List};
 
 macro_rules! tags {
     {$(
@@ -72,6 +73,17 @@ tags!{
     YResolution 283;
     // Advanced tags
     Predictor 317;
+    // EXIF tags:
+    ExifIfd 34665;
+    GpsIfd 34853;
+    DateTimeOriginal 36867;
+    // GPS tags, in their own directory
+    GPSLatitudeRef 1;
+    GPSLatitude 2;
+    GPSLongitudeRef 3;
+    GPSLongitude 4;
+    GPSAltitudeRef 5;
+    GPSAltitude 6;
 }
 
 #[derive(Copy, Debug, FromPrimitive)]
@@ -89,6 +101,8 @@ pub enum Type {
 pub enum Value {
     //Signed(i32),
     Unsigned(u32),
+    Rational(u32, u32),
+    Ascii(String),
     List(Vec<Value>)
 }
 
@@ -111,7 +125,41 @@ impl Value {
                 Ok(new_vec)
             },
             Unsigned(val) => Ok(vec![val]),
-            //_ => Err(::image::FormatError("Tag data malformed.".to_string()))
+            val => Err(::image::ImageError::FormatError(format!(
+                "Expected unsigned integers, {:?} found.", val
+            )))
+        }
+    }
+    pub fn as_f64(self) -> ::image::ImageResult<f64> {
+        match self {
+            Unsigned(val) => Ok(val as f64),
+            Rational(_, 0) => Err(::image::ImageError::FormatError(
+                "Rational with a zero denominator found.".to_string()
+            )),
+            Rational(n, d) => Ok(n as f64 / d as f64),
+            val => Err(::image::ImageError::FormatError(format!(
+                "Expected number, {:?} found.", val
+            )))
+        }
+    }
+    pub fn as_f64_vec(self) -> ::image::ImageResult<Vec<f64>> {
+        match self {
+            List(vec) => {
+                let mut new_vec = Vec::with_capacity(vec.len());
+                for v in vec.into_iter() {
+                    new_vec.push(try!(v.as_f64()))
+                }
+                Ok(new_vec)
+            },
+            val => Ok(vec![try!(val.as_f64())])
+        }
+    }
+    pub fn as_string(self) -> ::image::ImageResult<String> {
+        match self {
+            Ascii(val) => Ok(val),
+            val => Err(::image::ImageError::FormatError(format!(
+                "Expected string, {:?} found.", val
+            )))
         }
     }
 }
@@ -149,9 +197,11 @@ impl Entry {
         )
     }
 
-    pub fn val<R: Read + Seek>(&self, decoder: &mut super::TIFFDecoder<R>)
+    /// Returns the value of the entry, values that do not fit
+    /// into the offset field are read from ```reader```
+    pub fn val<R: Read + Seek>(&self, reader: &mut SmartReader<R>)
     -> ::image::ImageResult<Value> {
-        let bo = decoder.byte_order();
+        let bo = reader.byte_order;
         match (self.type_, self.count) {
             // TODO check if this could give wrong results
             // at a different endianess of file/computer.
@@ -165,22 +215,47 @@ impl Entry {
                 ]))
             },
             (Type::SHORT, n) => {
+                try!(goto_values(reader, try!(self.r(bo).read_u32()), n, 2));
                 let mut v = Vec::with_capacity(n as usize);
-                try!(decoder.goto_offset(try!(self.r(bo).read_u32())));
                 for _ in 0 .. n {
-                    v.push(Unsigned(try!(decoder.read_short()) as u32))
+                    v.push(Unsigned(try!(reader.read_u16()) as u32))
                 }
                 Ok(List(v))
             },
             (Type::LONG, 1) => Ok(Unsigned(try!(self.r(bo).read_u32()))),
             (Type::LONG, n) => {
+                try!(goto_values(reader, try!(self.r(bo).read_u32()), n, 4));
                 let mut v = Vec::with_capacity(n as usize);
-                try!(decoder.goto_offset(try!(self.r(bo).read_u32())));
                 for _ in 0 .. n {
-                    v.push(Unsigned(try!(decoder.read_long())))
+                    v.push(Unsigned(try!(reader.read_u32())))
                 }
                 Ok(List(v))
             }
+            (Type::RATIONAL, n) => {
+                try!(goto_values(reader, try!(self.r(bo).read_u32()), n, 8));
+                let mut v = Vec::with_capacity(n as usize);
+                for _ in 0 .. n {
+                    let numerator = try!(reader.read_u32());
+                    v.push(Rational(numerator, try!(reader.read_u32())))
+                }
+                if n == 1 {
+                    Ok(v.pop().unwrap())
+                } else {
+                    Ok(List(v))
+                }
+            }
+            (Type::ASCII, n) => {
+                let mut v = Vec::new();
+                if n <= 4 {
+                    v.push_all(&self.offset[..n as usize]);
+                } else {
+                    try!(goto_values(reader, try!(self.r(bo).read_u32()), n, 1));
+                    try!(reader.by_ref().take(n as u64).read_to_end(&mut v));
+                }
+                // The string is terminated by a NUL
+                let end = v.iter().position(|&b| b == 0).unwrap_or(v.len());
+                Ok(Ascii(String::from_utf8_lossy(&v[..end]).into_owned()))
+            }
             _ => Err(::image::ImageError::UnsupportedError("Unsupported data type.".to_string()))
         }
     }
@@ -188,3 +263,71 @@ impl Entry {
 
 /// Type representing an Image File Directory
 pub type Directory = HashMap<Tag, Entry>;
+
+fn goto_offset<R: Read + Seek>(reader: &mut SmartReader<R>, offset: u32) -> io::Result<()> {
+    reader.seek(io::SeekFrom::Start(offset as u64)).map(|_| ())
+}
+
+/// Seeks to the ```count``` values of ```size``` bytes at ```offset```.
+/// The count is untrusted, so the values must lie within the data
+/// before anything is allocated for them.
+fn goto_values<R: Read + Seek>(reader: &mut SmartReader<R>, offset: u32, count: u32, size: u64)
+-> ::image::ImageResult<()> {
+    let len = try!(reader.seek(io::SeekFrom::End(0)));
+
+    if offset as u64 + count as u64 * size > len {
+        return Err(::image::ImageError::FormatError(
+            "The values of a directory entry exceed the data.".to_string()
+        ))
+    }
+
+    try!(goto_offset(reader, offset));
+    Ok(())
+}
+
+/// Reads a IFD entry.
+///
+/// And IFD entry has four fields
+/// Tag   2 bytes
+/// Type  2 bytes
+/// Count 4 bytes
+/// Value 4 bytes either a pointer the value itself
+fn read_entry<R: Read + Seek>(reader: &mut SmartReader<R>) -> ::image::ImageResult<Option<(Tag, Entry)>> {
+    let tag = Tag::from_u16(try!(reader.read_u16()));
+    let type_: Type = match FromPrimitive::from_u16(try!(reader.read_u16())) {
+        Some(t) => t,
+        None => {
+            // Unknown type. Skip this entry according to spec.
+            try!(reader.read_u32());
+            try!(reader.read_u32());
+            return Ok(None)
+
+        }
+    };
+    let count = try!(reader.read_u32());
+    let mut offset = [0; 4];
+    if try!(reader.read(&mut offset)) != 4 {
+        return Err(::image::ImageError::FormatError("Image file directory truncated.".to_string()))
+    }
+    Ok(Some((tag, Entry::new(type_, count, offset))))
+}
+
+/// Reads the IFD at ```offset```.
+/// Returns the directory and the offset of the next IFD, if there is one.
+pub fn read_directory<R: Read + Seek>(reader: &mut SmartReader<R>, offset: u32)
+-> ::image::ImageResult<(Directory, Option<u32>)> {
+    let mut dir: Directory = HashMap::new();
+    try!(goto_offset(reader, offset));
+    for _ in (0..try!(reader.read_u16())) {
+        let (tag, entry) = match try!(read_entry(reader)) {
+            Some(val) => val,
+            None => continue // Unknown data type in tag, skip
+        };
+        dir.insert(tag, entry);
+    }
+    let next = match try!(reader.read_u32()) {
+        0 => None,
+        n => Some(n)
+    };
+    Ok((dir, next))
+}
@@ -9,7 +9,9 @@
 
 pub use self::decoder::TIFFDecoder;
 pub use self::stream::ByteOrder;
+pub use self::exif::Exif;
 
 mod decoder;
+mod exif;
 mod ifd;
 mod stream;
\ No newline at end of file
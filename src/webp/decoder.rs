@@ -1,42 +1,98 @@
-use std::slice;
+use std::cmp;
 use std::io;
+use std::u16;
 use std::io::Read;
-use std::default::Default;
+use std::slice;
+use std::iter::repeat;
 use byteorder::{ReadBytesExt, LittleEndian};
+use num::rational::Ratio;
 
 use image;
 use image::ImageResult;
 use image::ImageDecoder;
 
 use color;
+use buffer::ImageBuffer;
+use animation::{Frame, Frames};
+use dynimage::decoder_to_image;
+use math::utils::clamp;
 
-use super::vp8::Frame;
+use super::vp8;
 use super::vp8::VP8Decoder;
+use super::lossless::LosslessDecoder;
 
 
 
 /// A Representation of a Webp Image format decoder.
 pub struct WebpDecoder<R> {
     r: R,
-    frame: Frame,
+    width: u32,
+    height: u32,
+    colortype: color::ColorType,
+    buf: Vec<u8>,
     have_frame: bool,
     decoded_rows: u32,
+
+    icc_profile: Option<Vec<u8>>,
+    exif: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+
+    loop_count: u16,
+
+    // The headers and the image data of the frames of an animation
+    frames: Vec<(FrameHeader, Vec<u8>)>,
 }
 
 impl<R: Read> WebpDecoder<R> {
     /// Create a new WebpDecoder from the Reader ```r```.
     /// This function takes ownership of the Reader.
     pub fn new(r: R) -> WebpDecoder<R> {
-        let f: Frame = Default::default();
-
         WebpDecoder {
             r: r,
+            width: 0,
+            height: 0,
+            colortype: color::ColorType::RGB(8),
+            buf: Vec::new(),
             have_frame: false,
-            frame: f,
-            decoded_rows: 0
+            decoded_rows: 0,
+
+            icc_profile: None,
+            exif: None,
+            xmp: None,
+
+            loop_count: 0,
+            frames: Vec::new(),
         }
     }
 
+    /// Returns the embedded ICC color profile of an extended image
+    pub fn icc_profile(&mut self) -> ImageResult<Option<&[u8]>> {
+        let _ = try!(self.read_metadata());
+
+        Ok(self.icc_profile.as_ref().map(|v| &**v))
+    }
+
+    /// Returns the raw EXIF metadata of an extended image
+    pub fn exif(&mut self) -> ImageResult<Option<&[u8]>> {
+        let _ = try!(self.read_metadata());
+
+        Ok(self.exif.as_ref().map(|v| &**v))
+    }
+
+    /// Returns the XMP metadata of an extended image
+    pub fn xmp(&mut self) -> ImageResult<Option<&[u8]>> {
+        let _ = try!(self.read_metadata());
+
+        Ok(self.xmp.as_ref().map(|v| &**v))
+    }
+
+    /// Returns how often an animation is played, 0 means infinitely
+    pub fn loop_count(&mut self) -> ImageResult<u16> {
+        let _ = try!(self.read_metadata());
+
+        Ok(self.loop_count)
+    }
+
     fn read_riff_header(&mut self) -> ImageResult<u32> {
         let mut riff = Vec::with_capacity(4);
         try!(self.r.by_ref().take(4).read_to_end(&mut riff));
@@ -55,28 +111,89 @@ impl<R: Read> WebpDecoder<R> {
         Ok(size)
     }
 
-    fn read_vp8_header(&mut self) -> ImageResult<()> {
-        let mut vp8 = Vec::with_capacity(4);
-        try!(self.r.by_ref().take(4).read_to_end(&mut vp8));
+    fn read_frame(&mut self, fourcc: &[u8], data: Vec<u8>, alpha: Option<Vec<u8>>) -> ImageResult<()> {
+        let (width, height, colortype, buf) = try!(decode_image(fourcc, data, alpha));
 
-        if &*vp8 != "VP8 ".as_bytes() {
-            return Err(image::ImageError::FormatError("Invalid VP8 signature.".to_string()))
+        self.width  = width;
+        self.height = height;
+        self.colortype = colortype;
+        self.buf = buf;
+        self.have_frame = true;
+
+        Ok(())
+    }
+
+    // Reads the chunks following a VP8X header
+    fn read_extended(&mut self, header: &[u8]) -> ImageResult<()> {
+        if header.len() < 10 {
+            return Err(image::ImageError::FormatError("Invalid VP8X chunk.".to_string()))
         }
 
-        let _len = try!(self.r.read_u32::<LittleEndian>());
+        let canvas_width  = read_u24(&header[4..7]) + 1;
+        let canvas_height = read_u24(&header[7..10]) + 1;
+
+        let mut alpha = None;
+
+        while let Some((fourcc, data)) = try!(read_chunk(&mut self.r)) {
+            match &*fourcc {
+                b"ICCP" => self.icc_profile = Some(data),
+                b"EXIF" => self.exif = Some(data),
+                b"XMP " => self.xmp = Some(data),
+                b"ALPH" => alpha = Some(data),
+
+                b"VP8 " | b"VP8L" => {
+                    let _ = try!(self.read_frame(&fourcc, data, alpha.take()));
+                }
+
+                b"ANIM" => {
+                    if data.len() < 6 {
+                        return Err(image::ImageError::FormatError("Invalid ANIM chunk.".to_string()))
+                    }
+
+                    // The background color is only a hint and not used for compositing
+                    self.loop_count = data[4] as u16 | ((data[5] as u16) << 8);
+                }
+
+                // The frames are only decoded when they are requested
+                b"ANMF" => {
+                    let header = try!(FrameHeader::from_bytes(&data));
+
+                    if header.left + header.width > canvas_width
+                    || header.top + header.height > canvas_height {
+                        return Err(image::ImageError::FormatError(
+                            "Animation frame exceeds the canvas.".to_string()
+                        ))
+                    }
+
+                    self.frames.push((header, data[16..].to_vec()));
+                }
+
+                // Unknown chunks are ignored
+                _ => ()
+            }
+        }
+
+        if !self.frames.is_empty() {
+            self.width  = canvas_width;
+            self.height = canvas_height;
+            self.colortype = color::ColorType::RGBA(8);
+            self.have_frame = true;
+        }
 
         Ok(())
     }
 
-    fn read_frame(&mut self) -> ImageResult<()> {
-        let mut framedata = Vec::new();
-        try!(self.r.read_to_end(&mut framedata));
-        let m = io::Cursor::new(framedata);
+    // The image of an animated file is its first frame
+    fn read_first_frame(&mut self) -> ImageResult<()> {
+        let _ = try!(self.read_metadata());
 
-        let mut v = VP8Decoder::new(m);
-        let frame = try!(v.decode_frame());
+        if self.buf.is_empty() && !self.frames.is_empty() {
+            let mut canvas = Canvas::new(self.width, self.height);
+            let &(ref header, ref data) = &self.frames[0];
 
-        self.frame = frame.clone();
+            let frame = try!(canvas.draw_frame(header, data));
+            self.buf = frame.into_buffer().into_raw();
+        }
 
         Ok(())
     }
@@ -84,10 +201,21 @@ impl<R: Read> WebpDecoder<R> {
     fn read_metadata(&mut self) -> ImageResult<()> {
         if !self.have_frame {
             let _ = try!(self.read_riff_header());
-            let _ = try!(self.read_vp8_header());
-            let _ = try!(self.read_frame());
 
-            self.have_frame = true;
+            let (fourcc, data) = match try!(read_chunk(&mut self.r)) {
+                Some(chunk) => chunk,
+                None => return Err(image::ImageError::ImageEnd)
+            };
+
+            if &*fourcc == "VP8X".as_bytes() {
+                let _ = try!(self.read_extended(&data));
+            } else {
+                let _ = try!(self.read_frame(&fourcc, data, None));
+            }
+
+            if !self.have_frame {
+                return Err(image::ImageError::FormatError("No image data.".to_string()))
+            }
         }
 
         Ok(())
@@ -98,41 +226,736 @@ impl<R: Read> ImageDecoder for WebpDecoder<R> {
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         let _ = try!(self.read_metadata());
 
-        Ok((self.frame.width as u32, self.frame.height as u32))
+        Ok((self.width, self.height))
     }
 
     fn colortype(&mut self) -> ImageResult<color::ColorType> {
-        Ok(color::ColorType::Gray(8))
+        let _ = try!(self.read_metadata());
+
+        Ok(self.colortype)
     }
 
     fn row_len(&mut self) -> ImageResult<usize> {
         let _ = try!(self.read_metadata());
 
-        Ok(self.frame.width as usize)
+        let bpp = color::bits_per_pixel(self.colortype) / 8;
+
+        Ok(bpp * self.width as usize)
     }
 
-    fn read_scanline(&mut self, buf: &mut [u8]) -> ImageResult<u32> {
+    fn is_animated(&mut self) -> ImageResult<bool> {
+        let _ = try!(self.read_metadata());
+
+        Ok(!self.frames.is_empty())
+    }
+
+    fn into_frames(mut self) -> ImageResult<Frames> {
         let _ = try!(self.read_metadata());
 
-        if self.decoded_rows > self.frame.height as u32 {
+        if self.frames.is_empty() {
+            return Ok(Frames::new(vec![
+                Frame::new(try!(decoder_to_image(self)).to_rgba())
+            ]))
+        }
+
+        let mut canvas = Canvas::new(self.width, self.height);
+        let mut frames = Vec::with_capacity(self.frames.len());
+
+        for &(ref header, ref data) in self.frames.iter() {
+            frames.push(try!(canvas.draw_frame(header, data)));
+        }
+
+        Ok(Frames::new(frames))
+    }
+
+    fn read_scanline(&mut self, buf: &mut [u8]) -> ImageResult<u32> {
+        let _ = try!(self.read_first_frame());
+        let rlen = try!(self.row_len());
+
+        if self.decoded_rows >= self.height {
             return Err(image::ImageError::ImageEnd)
         }
 
-        let rlen  = buf.len();
-        let slice = &self.frame.ybuf[
-            self.decoded_rows as usize * rlen..
-            self.decoded_rows as usize * rlen + rlen
-        ];
+        let start = self.decoded_rows as usize * rlen;
+        slice::bytes::copy_memory(buf, &self.buf[start..start + rlen]);
 
-        slice::bytes::copy_memory(buf, slice);
         self.decoded_rows += 1;
 
         Ok(self.decoded_rows)
     }
 
     fn read_image(&mut self) -> ImageResult<image::DecodingResult> {
-        let _ = try!(self.read_metadata());
+        let _ = try!(self.read_first_frame());
+
+        Ok(image::DecodingResult::U8(self.buf.clone()))
+    }
+}
+
+// The header of an ANMF chunk
+#[derive(Copy, Clone)]
+struct FrameHeader {
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+
+    // The duration in milliseconds
+    duration: u32,
+
+    blend: bool,
+    dispose: bool,
+}
+
+impl FrameHeader {
+    fn from_bytes(data: &[u8]) -> ImageResult<FrameHeader> {
+        if data.len() < 16 {
+            return Err(image::ImageError::FormatError("Invalid ANMF chunk.".to_string()))
+        }
+
+        Ok(FrameHeader {
+            left:     2 * read_u24(&data[0..3]),
+            top:      2 * read_u24(&data[3..6]),
+            width:    read_u24(&data[6..9]) + 1,
+            height:   read_u24(&data[9..12]) + 1,
+            duration: read_u24(&data[12..15]),
+            blend:    data[15] & 2 == 0,
+            dispose:  data[15] & 1 == 1,
+        })
+    }
+
+    fn delay(&self) -> Ratio<u16> {
+        if self.duration <= u16::MAX as u32 {
+            Ratio::new(self.duration as u16, 1000)
+        } else {
+            Ratio::from_integer(cmp::min(self.duration / 1000, u16::MAX as u32) as u16)
+        }
+    }
+}
+
+// The canvas of an animated image.
+// Every frame is composited onto the canvas, yielding full size frames.
+struct Canvas {
+    width: u32,
+    height: u32,
+    buf: Vec<u8>,
+
+    // The area of the previous frame, if it is disposed to the background
+    dispose: Option<(u32, u32, u32, u32)>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32) -> Canvas {
+        Canvas {
+            width: width,
+            height: height,
+            buf: repeat(0u8).take(4 * width as usize * height as usize).collect(),
+            dispose: None,
+        }
+    }
+
+    // Decodes the image data of an ANMF chunk, draws it and returns a copy of the canvas.
+    // The frame must lie within the canvas.
+    fn draw_frame(&mut self, header: &FrameHeader, data: &[u8]) -> ImageResult<Frame> {
+        let (left, top) = (header.left, header.top);
+        let (width, height) = (header.width, header.height);
+
+        let mut r = io::Cursor::new(data);
+        let mut alpha = None;
+        let mut decoded = None;
+
+        while let Some((fourcc, data)) = try!(read_chunk(&mut r)) {
+            match &*fourcc {
+                b"ALPH" => alpha = Some(data),
+                b"VP8 " | b"VP8L" => {
+                    decoded = Some(try!(decode_image(&fourcc, data, alpha.take())));
+                    break
+                }
+                _ => ()
+            }
+        }
+
+        let (w, h, colortype, buf) = match decoded {
+            Some(decoded) => decoded,
+            None => return Err(image::ImageError::FormatError(
+                "Animation frame without image data.".to_string()
+            ))
+        };
+
+        if w != width || h != height {
+            return Err(image::ImageError::FormatError(
+                "Animation frame has not the expected size.".to_string()
+            ))
+        }
+
+        if let Some((x, y, w, h)) = self.dispose.take() {
+            self.fill_rect(x, y, w, h, &[0, 0, 0, 0]);
+        }
+
+        let bpp = color::bits_per_pixel(colortype) / 8;
+
+        for (i, pixel) in buf.chunks(bpp).enumerate() {
+            let x = left + i as u32 % width;
+            let y = top + i as u32 / width;
+            let index = 4 * (y as usize * self.width as usize + x as usize);
+
+            let src = [pixel[0], pixel[1], pixel[2], if bpp == 4 { pixel[3] } else { 255 }];
+            let dst = &mut self.buf[index..index + 4];
+
+            if header.blend {
+                blend_pixel(&src, dst);
+            } else {
+                slice::bytes::copy_memory(dst, &src);
+            }
+        }
+
+        if header.dispose {
+            self.dispose = Some((left, top, width, height));
+        }
+
+        let buffer = ImageBuffer::from_raw(self.width, self.height, self.buf.clone()).unwrap();
+
+        Ok(Frame::from_parts(buffer, 0, 0, header.delay()))
+    }
+
+    fn fill_rect(&mut self, left: u32, top: u32, width: u32, height: u32, color: &[u8]) {
+        for y in (top..top + height) {
+            for x in (left..left + width) {
+                let index = 4 * (y as usize * self.width as usize + x as usize);
+                slice::bytes::copy_memory(&mut self.buf[index..index + 4], color);
+            }
+        }
+    }
+}
+
+// Alpha blends src onto dst as libwebp does for non-premultiplied colors
+fn blend_pixel(src: &[u8], dst: &mut [u8]) {
+    let src_alpha = src[3] as u32;
+
+    if src_alpha == 0 {
+        return
+    }
+
+    let dst_alpha = (dst[3] as u32 * (256 - src_alpha)) >> 8;
+    let alpha = src_alpha + dst_alpha;
+    let scale = (1u32 << 24) / alpha;
+
+    for c in (0usize..3) {
+        let v = src[c] as u32 * src_alpha + dst[c] as u32 * dst_alpha;
+        dst[c] = ((v * scale) >> 24) as u8;
+    }
+
+    dst[3] = alpha as u8;
+}
+
+fn read_u24(b: &[u8]) -> u32 {
+    b[0] as u32 | ((b[1] as u32) << 8) | ((b[2] as u32) << 16)
+}
+
+// Returns the four character code and the payload of the next chunk,
+// or None at the end of the file
+fn read_chunk<R: Read>(r: &mut R) -> ImageResult<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut fourcc = Vec::with_capacity(4);
+    try!(r.by_ref().take(4).read_to_end(&mut fourcc));
+
+    if fourcc.len() < 4 {
+        return Ok(None)
+    }
+
+    let len = try!(r.read_u32::<LittleEndian>());
+
+    // The buffer grows with the data that is read, the length can be larger than the file
+    let mut data = Vec::new();
+    try!(r.by_ref().take(len as u64).read_to_end(&mut data));
+
+    if data.len() != len as usize {
+        return Err(image::ImageError::ImageEnd)
+    }
+
+    // Chunks are padded to an even size
+    if len & 1 == 1 {
+        let mut padding = Vec::with_capacity(1);
+        try!(r.by_ref().take(1).read_to_end(&mut padding));
+    }
+
+    Ok(Some((fourcc, data)))
+}
+
+// Decodes a VP8 or VP8L chunk to RGB or RGBA.
+// Lossy images may be accompanied by the payload of an ALPH chunk.
+fn decode_image(fourcc: &[u8], data: Vec<u8>, alpha: Option<Vec<u8>>)
+    -> ImageResult<(u32, u32, color::ColorType, Vec<u8>)> {
+
+    let m = io::Cursor::new(data);
+
+    match fourcc {
+        b"VP8 " => {
+            let mut v = VP8Decoder::new(m);
+            let frame = try!(v.decode_frame());
+
+            let width  = frame.width as u32;
+            let height = frame.height as u32;
+            let rgb = frame_to_rgb(frame);
+
+            match alpha {
+                Some(alpha) => {
+                    let alpha = try!(decode_alpha(&alpha, width as usize, height as usize));
+                    let mut buf = Vec::with_capacity(4 * alpha.len());
+
+                    for (p, &a) in rgb.chunks(3).zip(alpha.iter()) {
+                        buf.push_all(p);
+                        buf.push(a);
+                    }
+
+                    Ok((width, height, color::ColorType::RGBA(8), buf))
+                }
+
+                None => Ok((width, height, color::ColorType::RGB(8), rgb))
+            }
+        }
+
+        b"VP8L" => {
+            let mut v = LosslessDecoder::new(m);
+            let frame = try!(v.decode_frame());
+
+            let rlen = 4 * frame.width as usize;
+            let mut buf: Vec<u8> = repeat(0u8).take(rlen * frame.height as usize).collect();
+
+            for (y, row) in buf.chunks_mut(rlen).enumerate() {
+                frame.fill_rgba_row(y, row);
+            }
+
+            Ok((frame.width as u32, frame.height as u32, color::ColorType::RGBA(8), buf))
+        }
+
+        _ => Err(image::ImageError::FormatError("Invalid VP8 signature.".to_string()))
+    }
+}
+
+// Returns the chroma value of the luma sample at (x, y).
+// The four nearest chroma samples are weighted 9:3:3:1
+// (the "fancy upsampling" of libwebp).
+fn upsample_chroma(plane: &[u8], width: usize, height: usize, x: usize, y: usize) -> u32 {
+    let cx = x / 2;
+    let cy = y / 2;
+
+    let nx = if x & 1 == 0 { if cx == 0 { 0 } else { cx - 1 } }
+             else { cmp::min(cx + 1, width - 1) };
+
+    let ny = if y & 1 == 0 { if cy == 0 { 0 } else { cy - 1 } }
+             else { cmp::min(cy + 1, height - 1) };
+
+    let a = plane[cy * width + cx] as u32;
+    let b = plane[cy * width + nx] as u32;
+    let c = plane[ny * width + cx] as u32;
+    let d = plane[ny * width + nx] as u32;
+
+    (9 * a + 3 * b + 3 * c + d + 8) >> 4
+}
+
+fn clip8(v: i32) -> u8 {
+    clamp(v >> 6, 0, 255) as u8
+}
+
+// BT.601 conversion using the fixed point constants of libwebp
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> (u8, u8, u8) {
+    let y = (y as i32 * 19077) >> 8;
+    let u = u as i32;
+    let v = v as i32;
+
+    let r = clip8(y + ((v * 26149) >> 8) - 14234);
+    let g = clip8(y - ((u * 6419) >> 8) - ((v * 13320) >> 8) + 8708);
+    let b = clip8(y + ((u * 33050) >> 8) - 17685);
+
+    (r, g, b)
+}
+
+// Decodes the payload of an ALPH chunk into an alpha plane
+fn decode_alpha(data: &[u8], width: usize, height: usize) -> ImageResult<Vec<u8>> {
+    if data.is_empty() {
+        return Err(image::ImageError::FormatError("Empty ALPH chunk.".to_string()))
+    }
+
+    let compression = data[0] & 3;
+    let filtering = (data[0] >> 2) & 3;
+
+    let mut alpha = match compression {
+        0 => {
+            if data.len() < 1 + width * height {
+                return Err(image::ImageError::ImageEnd)
+            }
+
+            data[1..1 + width * height].to_vec()
+        }
+
+        1 => {
+            let mut d = LosslessDecoder::new(io::Cursor::new(data[1..].to_vec()));
+            let frame = try!(d.decode_image_data(width as u16, height as u16));
+
+            // The alpha values are stored in the green channel
+            frame.buf.iter().map(|&p| (p >> 8) as u8).collect()
+        }
+
+        _ => return Err(image::ImageError::FormatError(
+            "Unknown alpha compression method.".to_string()
+        ))
+    };
+
+    unfilter_alpha(&mut alpha, width, height, filtering);
+
+    Ok(alpha)
+}
+
+// Undoes the horizontal (1), vertical (2) or gradient (3) filter of an alpha plane.
+// The first row is always predicted from the left, the first column from above.
+fn unfilter_alpha(alpha: &mut [u8], width: usize, height: usize, method: u8) {
+    if method == 0 {
+        return
+    }
+
+    for y in (0..height) {
+        for x in (0..width) {
+            let i = y * width + x;
+
+            let prediction = if x == 0 && y == 0 {
+                0
+            } else if y == 0 {
+                alpha[i - 1]
+            } else if x == 0 {
+                alpha[i - width]
+            } else {
+                match method {
+                    1 => alpha[i - 1],
+                    2 => alpha[i - width],
+                    _ => {
+                        let g = alpha[i - 1] as i32 + alpha[i - width] as i32
+                              - alpha[i - width - 1] as i32;
+
+                        clamp(g, 0, 255) as u8
+                    }
+                }
+            };
+
+            alpha[i] = (alpha[i] as u16 + prediction as u16) as u8;
+        }
+    }
+}
+
+// Converts a decoded lossy frame to RGB
+fn frame_to_rgb(frame: &vp8::Frame) -> Vec<u8> {
+    let rlen = 3 * frame.width as usize;
+    let mut buf: Vec<u8> = repeat(0u8).take(rlen * frame.height as usize).collect();
+
+    for (y, row) in buf.chunks_mut(rlen).enumerate() {
+        fill_rgb_row(frame, y, row);
+    }
+
+    buf
+}
+
+fn fill_rgb_row(frame: &vp8::Frame, y: usize, buf: &mut [u8]) {
+    let width  = frame.width as usize;
+    let cw = (frame.width as usize + 1) / 2;
+    let ch = (frame.height as usize + 1) / 2;
+
+    for (x, rgb) in buf.chunks_mut(3).take(width).enumerate() {
+        let luma = frame.ybuf[y * width + x];
+        let u = upsample_chroma(&frame.ubuf, cw, ch, x, y) as u8;
+        let v = upsample_chroma(&frame.vbuf, cw, ch, x, y) as u8;
+
+        let (r, g, b) = yuv_to_rgb(luma, u, v);
 
-        Ok(image::DecodingResult::U8(self.frame.ybuf.clone()))
+        rgb[0] = r;
+        rgb[1] = g;
+        rgb[2] = b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use byteorder::{WriteBytesExt, LittleEndian};
+    use num::rational::Ratio;
+    use image::{ImageDecoder, ImageError, DecodingResult};
+    use color;
+    use color::Rgba;
+    use animation::Frame;
+    use super::{WebpDecoder, yuv_to_rgb, frame_to_rgb, decode_alpha, unfilter_alpha};
+    use super::super::vp8;
+    use super::super::lossless_encoder;
+
+    // Writes the chunks into a RIFF container
+    fn riff(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut body = b"WEBP".to_vec();
+
+        for &(fourcc, data) in chunks.iter() {
+            body.push_all(fourcc);
+            body.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            body.push_all(data);
+
+            if data.len() & 1 == 1 {
+                body.push(0);
+            }
+        }
+
+        let mut file = b"RIFF".to_vec();
+        file.write_u32::<LittleEndian>(body.len() as u32).unwrap();
+        file.push_all(&body);
+        file
+    }
+
+    // Returns the payload of an ANMF chunk whose frame has a single color
+    fn anmf(left: u32, top: u32, width: u32, height: u32, duration: u32, flags: u8, color: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for &v in [left / 2, top / 2, width - 1, height - 1, duration].iter() {
+            data.push_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8]);
+        }
+
+        data.push(flags);
+
+        let pixels: Vec<u32> = (0..width * height).map(|_| color).collect();
+        let vp8l = lossless_encoder::encode(&pixels, width, height).unwrap();
+
+        // The frame data consists of chunks itself
+        data.push_all(&riff(&[(&b"VP8L"[..], &vp8l[..])])[12..]);
+        data
+    }
+
+    // An animation on a 4x4 canvas with an extended header, the flags of the frames are
+    // 1 to dispose the frame to the background and 2 to overwrite the canvas without blending
+    fn animation(frames: &[Vec<u8>]) -> Vec<u8> {
+        let vp8x = [0x12, 0, 0, 0, 3, 0, 0, 3, 0, 0];
+        let anim = [0xff, 0xff, 0xff, 0xff, 3, 0];
+
+        let mut chunks = vec![(&b"VP8X"[..], &vp8x[..]), (&b"ANIM"[..], &anim[..])];
+        for frame in frames.iter() {
+            chunks.push((&b"ANMF"[..], &frame[..]));
+        }
+
+        riff(&chunks)
+    }
+
+    #[test]
+    /// Test the conversion of BT.601 studio range colors
+    fn test_yuv_to_rgb() {
+        assert_eq!(yuv_to_rgb(16, 128, 128), (0, 0, 0));
+        assert_eq!(yuv_to_rgb(235, 128, 128), (255, 255, 255));
+        assert_eq!(yuv_to_rgb(81, 90, 240), (254, 0, 0));
+        assert_eq!(yuv_to_rgb(128, 100, 100), (86, 164, 74));
+    }
+
+    #[test]
+    /// Test that the chroma planes are upsampled with 9:3:3:1 weights
+    /// before the conversion to RGB
+    fn test_chroma_upsampling() {
+        let mut frame: vp8::Frame = Default::default();
+        frame.width = 4;
+        frame.height = 4;
+        frame.ybuf = vec![128; 16];
+
+        // U changes from left to right, V from top to bottom
+        frame.ubuf = vec![100, 200, 100, 200];
+        frame.vbuf = vec![100, 100, 200, 200];
+
+        let rgb = frame_to_rgb(&frame);
+
+        // The upsampled U of the columns is 100, 125, 175, 200, the V of the rows too
+        let expected = [
+            [(86, 164, 74), (86, 154, 124), (86, 135, 225), (86, 125, 255)],
+            [(126, 144, 74), (126, 134, 124), (126, 114, 225), (126, 105, 255)],
+            [(205, 103, 74), (205, 93, 124), (205, 74, 225), (205, 64, 255)],
+            [(245, 83, 74), (245, 73, 124), (245, 53, 225), (245, 44, 255)],
+        ];
+
+        for (y, row) in expected.iter().enumerate() {
+            for (x, &(r, g, b)) in row.iter().enumerate() {
+                let i = 3 * (4 * y + x);
+                assert_eq!((rgb[i], rgb[i + 1], rgb[i + 2]), (r, g, b));
+            }
+        }
+    }
+
+    // The residuals of a 3x3 alpha plane
+    const RESIDUALS: [u8; 9] = [10, 5, 150, 3, 4, 5, 200, 2, 1];
+
+    #[test]
+    /// Test the horizontal, vertical and gradient filters of the alpha plane.
+    /// The first row is predicted from the left and the first column from above
+    /// for every method, the gradient is clamped to 0..255
+    fn test_unfilter_alpha() {
+        let expected = [
+            (0, [10, 5, 150, 3, 4, 5, 200, 2, 1]),
+            (1, [10, 15, 165, 13, 17, 22, 213, 215, 216]),
+            (2, [10, 15, 165, 13, 19, 170, 213, 21, 171]),
+            (3, [10, 15, 165, 13, 22, 177, 213, 224, 0]),
+        ];
+
+        for &(method, ref alpha) in expected.iter() {
+            let mut plane = RESIDUALS;
+            unfilter_alpha(&mut plane, 3, 3, method);
+            assert_eq!(plane, *alpha);
+        }
+    }
+
+    #[test]
+    /// Test uncompressed and losslessly compressed ALPH chunks
+    fn test_decode_alpha() {
+        // Uncompressed with the horizontal filter
+        let mut data = vec![0x04];
+        data.push_all(&RESIDUALS);
+        assert_eq!(decode_alpha(&data, 3, 3).unwrap(), vec![10, 15, 165, 13, 17, 22, 213, 215, 216]);
+
+        // Compressed with the gradient filter, the preprocessing bits are only a hint
+        let mut data = vec![0x01 | 0x0c | 0x10];
+        data.push_all(&lossless_encoder::encode_alpha(&RESIDUALS, 3, 3).unwrap());
+        assert_eq!(decode_alpha(&data, 3, 3).unwrap(), vec![10, 15, 165, 13, 22, 177, 213, 224, 0]);
+
+        // A truncated plane and an unknown compression method
+        match decode_alpha(&[0x00, 1, 2, 3], 3, 3) {
+            Err(ImageError::ImageEnd) => (),
+            _ => panic!("a truncated alpha plane was accepted")
+        }
+
+        match decode_alpha(&[0x02, 1, 2, 3], 1, 1) {
+            Err(ImageError::FormatError(_)) => (),
+            _ => panic!("an unknown alpha compression was accepted")
+        }
+    }
+
+    #[test]
+    /// Test that an extended image yields its metadata and image data,
+    /// odd sized chunks are padded and unknown chunks are skipped
+    fn test_extended() {
+        let pixels = [0xff102030, 0x80405060, 0x00708090, 0xffa0b0c0, 0x01d0e0f0, 0xff000000];
+        let vp8l = lossless_encoder::encode(&pixels, 3, 2).unwrap();
+
+        // The ICC profile, alpha, EXIF and XMP flags on a 3x2 canvas
+        let vp8x = [0x3c, 0, 0, 0, 2, 0, 0, 1, 0, 0];
+
+        let file = riff(&[
+            (&b"VP8X"[..], &vp8x[..]),
+            (&b"ICCP"[..], &b"icc"[..]),
+            (&b"ABCD"[..], &b"unknown"[..]),
+            (&b"VP8L"[..], &vp8l[..]),
+            (&b"EXIF"[..], &b"exif"[..]),
+            (&b"XMP "[..], &b"<xmp/>"[..]),
+        ]);
+
+        let mut decoder = WebpDecoder::new(io::Cursor::new(file));
+        assert_eq!(decoder.icc_profile().unwrap(), Some(&b"icc"[..]));
+        assert_eq!(decoder.exif().unwrap(), Some(&b"exif"[..]));
+        assert_eq!(decoder.xmp().unwrap(), Some(&b"<xmp/>"[..]));
+        assert_eq!(decoder.dimensions().unwrap(), (3, 2));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+        assert!(!decoder.is_animated().unwrap());
+
+        let expected: Vec<u8> = pixels.iter().flat_map(|&p| {
+            vec![(p >> 16) as u8, (p >> 8) as u8, p as u8, (p >> 24) as u8].into_iter()
+        }).collect();
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(decoded) => assert_eq!(decoded, expected),
+            _ => panic!("unexpected bit depth")
+        }
+
+        // A VP8X chunk too short for the canvas size
+        let file = riff(&[(&b"VP8X"[..], &[0, 0, 0, 0, 2, 0, 0][..]), (&b"VP8L"[..], &vp8l[..])]);
+
+        match WebpDecoder::new(io::Cursor::new(file)).dimensions() {
+            Err(ImageError::FormatError(_)) => (),
+            _ => panic!("an invalid VP8X chunk was accepted")
+        }
+
+        // A chunk that claims to be almost 4 GiB long in a file of a few bytes
+        let mut file = riff(&[(&b"VP8L"[..], &vp8l[..])]);
+        file.truncate(24);
+        for (b, &length) in file[16..20].iter_mut().zip([0xf0, 0xff, 0xff, 0xff].iter()) {
+            *b = length;
+        }
+
+        match WebpDecoder::new(io::Cursor::new(file)).dimensions() {
+            Err(ImageError::ImageEnd) => (),
+            _ => panic!("a truncated chunk was accepted")
+        }
+    }
+
+    #[test]
+    /// Test the offsets, alpha blending and disposal of animation frames
+    fn test_animation() {
+        let file = animation(&[
+            anmf(0, 0, 4, 4, 100, 2, 0xff102030),
+            anmf(2, 2, 2, 2, 50, 1, 0x80ff0000),
+            anmf(0, 2, 2, 1, 70000, 2, 0xff00ff00),
+        ]);
+
+        let mut decoder = WebpDecoder::new(io::Cursor::new(file.clone()));
+        assert_eq!(decoder.dimensions().unwrap(), (4, 4));
+        assert_eq!(decoder.loop_count().unwrap(), 3);
+        assert!(decoder.is_animated().unwrap());
+
+        let frames: Vec<Frame> = decoder.into_frames().unwrap().collect();
+        assert_eq!(frames.len(), 3);
+
+        let background = Rgba([0x10, 0x20, 0x30, 255]);
+        assert!(frames[0].buffer().pixels().all(|&p| p == background));
+        assert_eq!(frames[0].delay(), Ratio::new(1, 10));
+
+        // The second frame is blended onto the bottom right corner
+        for (x, y, p) in frames[1].buffer().enumerate_pixels() {
+            if x >= 2 && y >= 2 {
+                assert_eq!(*p, Rgba([135, 15, 23, 255]));
+            } else {
+                assert_eq!(*p, background);
+            }
+        }
+
+        assert_eq!(frames[1].delay(), Ratio::new(1, 20));
+
+        // The corner is cleared before the third frame is drawn
+        for (x, y, p) in frames[2].buffer().enumerate_pixels() {
+            if x >= 2 && y >= 2 {
+                assert_eq!(*p, Rgba([0, 0, 0, 0]));
+            } else if x < 2 && y == 2 {
+                assert_eq!(*p, Rgba([0, 255, 0, 255]));
+            } else {
+                assert_eq!(*p, background);
+            }
+        }
+
+        assert_eq!(frames[2].delay(), Ratio::from_integer(70));
+
+        // The image of the file is the first frame
+        let mut decoder = WebpDecoder::new(io::Cursor::new(file));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(image) => assert_eq!(image, frames[0].buffer().clone().into_raw()),
+            _ => panic!("unexpected bit depth")
+        }
+    }
+
+    #[test]
+    /// Test that the frames of an animation are only decoded when they are requested
+    fn test_animation_is_decoded_lazily() {
+        let mut broken = anmf(0, 0, 4, 4, 100, 2, 0xff102030);
+        let len = broken.len();
+        broken.truncate(len - 8);
+
+        let file = animation(&[anmf(0, 0, 4, 4, 100, 2, 0xff102030), broken]);
+
+        // Only the headers of the frames are read with the metadata
+        let mut decoder = WebpDecoder::new(io::Cursor::new(file.clone()));
+        assert_eq!(decoder.dimensions().unwrap(), (4, 4));
+        assert!(decoder.is_animated().unwrap());
+        assert!(decoder.read_image().is_ok());
+
+        assert!(WebpDecoder::new(io::Cursor::new(file)).into_frames().is_err());
+
+        // Frames outside of the canvas are rejected with the metadata
+        let file = animation(&[anmf(2, 0, 4, 4, 100, 2, 0xff102030)]);
+
+        match WebpDecoder::new(io::Cursor::new(file)).dimensions() {
+            Err(ImageError::FormatError(_)) => (),
+            _ => panic!("a frame outside of the canvas was accepted")
+        }
     }
 }
@@ -0,0 +1,327 @@
+//! Encoding of WebP images
+//!
+//! Images are written as lossless (VP8L) or lossy (VP8) WebP files.
+//! Lossy images with transparency are written in the extended format,
+//! with a losslessly compressed alpha plane.
+
+use std::io;
+use std::io::Write;
+use std::cmp;
+use std::default::Default;
+use byteorder::{WriteBytesExt, LittleEndian};
+
+use color;
+
+use super::vp8;
+use super::lossless_encoder;
+
+/// The representation of a WebP encoder
+pub struct WebpEncoder<'a, W: 'a> {
+    w: &'a mut W,
+
+    // The quality of lossy encoding, lossless encoding is used without it
+    quality: Option<u8>,
+}
+
+impl<'a, W: Write> WebpEncoder<'a, W> {
+    /// Create a new encoder that writes lossless images to ```w```
+    pub fn new(w: &mut W) -> WebpEncoder<W> {
+        WebpEncoder {
+            w: w,
+            quality: None,
+        }
+    }
+
+    /// Create a new encoder that writes lossy images to ```w```.
+    /// The ```quality``` ranges from 0, the smallest files, to 100, the best quality.
+    pub fn new_with_quality(w: &mut W, quality: u8) -> WebpEncoder<W> {
+        WebpEncoder {
+            w: w,
+            quality: Some(cmp::min(quality, 100)),
+        }
+    }
+
+    /// Encodes the image ```image```
+    /// that has dimensions ```width``` and ```height```
+    /// and ```ColorType``` ```c```
+    pub fn encode(&mut self,
+                  image: &[u8],
+                  width: u32,
+                  height: u32,
+                  c: color::ColorType) -> io::Result<()> {
+
+        let expected = width as u64 * height as u64 * (color::bits_per_pixel(c) / 8) as u64;
+
+        if image.len() as u64 != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The image buffer does not match the dimensions.",
+                Some(format!("Expected {} bytes for {} x {} pixels, found {}.",
+                             expected, width, height, image.len()))
+            ))
+        }
+
+        let pixels = try!(to_argb(image, c));
+
+        match self.quality {
+            Some(quality) => self.encode_lossy(&pixels, width, height, quality),
+
+            None => {
+                let data = try!(lossless_encoder::encode(&pixels, width, height));
+                self.write_file(&[("VP8L", &data[..])])
+            }
+        }
+    }
+
+    fn encode_lossy(&mut self, pixels: &[u32], width: u32, height: u32, quality: u8) -> io::Result<()> {
+        if width == 0 || height == 0 || width > 0x3fff || height > 0x3fff {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid image dimensions.",
+                Some(format!("A lossy WebP image can not be {} x {} pixels.", width, height))
+            ))
+        }
+
+        let frame = argb_to_frame(pixels, width as usize, height as usize);
+
+        let mut data = Vec::new();
+        try!(vp8::VP8Encoder::new(&mut data, quality).encode_frame(&frame));
+
+        if pixels.iter().all(|&p| p >> 24 == 0xff) {
+            return self.write_file(&[("VP8 ", &data[..])])
+        }
+
+        let alpha: Vec<u8> = pixels.iter().map(|&p| (p >> 24) as u8).collect();
+
+        // Lossless compression without filtering
+        let mut alph = vec![1u8];
+        alph.push_all(&try!(lossless_encoder::encode_alpha(&alpha, width, height)));
+
+        // Only the alpha flag is set
+        let mut vp8x = vec![0x10u8, 0, 0, 0];
+        for &size in [width - 1, height - 1].iter() {
+            vp8x.push_all(&[size as u8, (size >> 8) as u8, (size >> 16) as u8]);
+        }
+
+        self.write_file(&[("VP8X", &vp8x[..]), ("ALPH", &alph[..]), ("VP8 ", &data[..])])
+    }
+
+    // Writes the RIFF container holding the chunks
+    fn write_file(&mut self, chunks: &[(&str, &[u8])]) -> io::Result<()> {
+        let riff_size = chunks.iter().fold(4, |size, &(_, data)| size + 8 + data.len() + (data.len() & 1));
+
+        try!(self.w.write_all(b"RIFF"));
+        try!(self.w.write_u32::<LittleEndian>(riff_size as u32));
+        try!(self.w.write_all(b"WEBP"));
+
+        for &(fourcc, data) in chunks.iter() {
+            try!(self.w.write_all(fourcc.as_bytes()));
+            try!(self.w.write_u32::<LittleEndian>(data.len() as u32));
+            try!(self.w.write_all(data));
+
+            // Chunks are padded to an even size
+            if data.len() & 1 == 1 {
+                try!(self.w.write_all(&[0]));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Converts the image to pixels in ARGB order
+fn to_argb(image: &[u8], c: color::ColorType) -> io::Result<Vec<u32>> {
+    let argb = |r: u8, g: u8, b: u8, a: u8| {
+        ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+    };
+
+    let pixels = match c {
+        color::ColorType::Gray(8) => image.iter().map(|&l| argb(l, l, l, 255)).collect(),
+        color::ColorType::GrayA(8) => image.chunks(2).map(|p| argb(p[0], p[0], p[0], p[1])).collect(),
+        color::ColorType::RGB(8) => image.chunks(3).map(|p| argb(p[0], p[1], p[2], 255)).collect(),
+        color::ColorType::RGBA(8) => image.chunks(4).map(|p| argb(p[0], p[1], p[2], p[3])).collect(),
+
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unsupported color type.",
+            Some(format!("WebP encoding of {:?} is not supported.", c))
+        ))
+    };
+
+    Ok(pixels)
+}
+
+// Converts the pixels to BT.601 luma and chroma planes, the inverse of the conversion of the decoder.
+// The chroma planes are subsampled by averaging blocks of 2x2 pixels.
+fn argb_to_frame(pixels: &[u32], width: usize, height: usize) -> vp8::Frame {
+    let mut frame: vp8::Frame = Default::default();
+
+    frame.width  = width as u16;
+    frame.height = height as u16;
+
+    frame.ybuf = pixels.iter().map(|&p| {
+        let (r, g, b) = (((p >> 16) & 0xff) as i32, ((p >> 8) & 0xff) as i32, (p & 0xff) as i32);
+        (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8
+    }).collect();
+
+    let chroma_width  = (width + 1) / 2;
+    let chroma_height = (height + 1) / 2;
+
+    for cy in (0..chroma_height) {
+        for cx in (0..chroma_width) {
+            let (mut r, mut g, mut b, mut n) = (0i32, 0i32, 0i32, 0i32);
+
+            for y in (2 * cy..cmp::min(2 * cy + 2, height)) {
+                for x in (2 * cx..cmp::min(2 * cx + 2, width)) {
+                    let p = pixels[y * width + x];
+
+                    r += ((p >> 16) & 0xff) as i32;
+                    g += ((p >> 8) & 0xff) as i32;
+                    b += (p & 0xff) as i32;
+                    n += 1;
+                }
+            }
+
+            let (r, g, b) = ((r + n / 2) / n, (g + n / 2) / n, (b + n / 2) / n);
+
+            frame.ubuf.push((((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8);
+            frame.vbuf.push((((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8);
+        }
+    }
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io;
+    use std::num::SignedInt;
+    use image::{ImageDecoder, DecodingResult};
+    use color;
+    use super::WebpEncoder;
+    use super::super::WebpDecoder;
+
+    #[test]
+    /// Test that lossless encoding round trips through the decoder
+    fn test_lossless_roundtrip() {
+        let (width, height) = (37u32, 21u32);
+        let image: Vec<u8> = (0..width * height * 4).map(|i| {
+            let p = i / 4;
+            match i % 4 {
+                0 => (p % width * 7) as u8,
+                1 => (p / width * 12) as u8,
+                2 => ((p % 5) * 50) as u8,
+                _ => if p % 3 == 0 { 128 } else { 255 },
+            }
+        }).collect();
+
+        let mut encoded = Vec::new();
+        WebpEncoder::new(&mut encoded).encode(&image, width, height, color::ColorType::RGBA(8)).unwrap();
+
+        let mut decoder = WebpDecoder::new(io::Cursor::new(encoded));
+        assert_eq!(decoder.dimensions().unwrap(), (width, height));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(decoded) => assert!(decoded == image),
+            _ => panic!("unexpected bit depth")
+        }
+    }
+
+    #[test]
+    /// Test that images of each color type are encoded losslessly
+    fn test_color_types() {
+        let (width, height) = (5u32, 3u32);
+        let samples: Vec<u8> = (0..width * height * 4).map(|i| (i * 17) as u8).collect();
+
+        for &(c, channels) in [(color::ColorType::Gray(8), 1),
+                               (color::ColorType::GrayA(8), 2),
+                               (color::ColorType::RGB(8), 3),
+                               (color::ColorType::RGBA(8), 4)].iter() {
+
+            let image = &samples[..(width * height * channels) as usize];
+
+            let mut encoded = Vec::new();
+            WebpEncoder::new(&mut encoded).encode(image, width, height, c).unwrap();
+
+            let decoded = match WebpDecoder::new(io::Cursor::new(encoded)).read_image().unwrap() {
+                DecodingResult::U8(decoded) => decoded,
+                _ => panic!("unexpected bit depth")
+            };
+
+            for (p, rgba) in image.chunks(channels as usize).zip(decoded.chunks(4)) {
+                let expected = match channels {
+                    1 => [p[0], p[0], p[0], 255],
+                    2 => [p[0], p[0], p[0], p[1]],
+                    3 => [p[0], p[1], p[2], 255],
+                    _ => [p[0], p[1], p[2], p[3]],
+                };
+
+                assert_eq!(rgba, &expected[..]);
+            }
+        }
+    }
+
+    #[test]
+    /// Test that buffers that do not match the dimensions are rejected
+    fn test_buffer_size() {
+        let image = [0u8; 4 * 4 * 3];
+
+        for &(width, height, c) in [(4, 5, color::ColorType::RGB(8)),
+                                    (4, 3, color::ColorType::RGB(8)),
+                                    (4, 4, color::ColorType::RGBA(8)),
+                                    (47, 1, color::ColorType::Gray(8))].iter() {
+
+            let mut encoded = Vec::new();
+            let result = WebpEncoder::new(&mut encoded).encode(&image, width, height, c);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+            let result = WebpEncoder::new_with_quality(&mut encoded, 50).encode(&image, width, height, c);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let mut encoded = Vec::new();
+        assert!(WebpEncoder::new(&mut encoded).encode(&image, 4, 4, color::ColorType::RGB(8)).is_ok());
+    }
+
+    #[test]
+    /// Test that lossy encoding keeps the alpha channel and stays close to the colors
+    fn test_lossy_roundtrip() {
+        let (width, height) = (37u32, 21u32);
+        let image: Vec<u8> = (0..width * height * 4).map(|i| {
+            let p = i / 4;
+            match i % 4 {
+                0 => (p % width * 6) as u8,
+                1 => (p / width * 10) as u8,
+                2 => (p % width * 3 + p / width * 4) as u8,
+                _ => (p % width * 7) as u8,
+            }
+        }).collect();
+
+        let mut encoded = Vec::new();
+        WebpEncoder::new_with_quality(&mut encoded, 90)
+            .encode(&image, width, height, color::ColorType::RGBA(8)).unwrap();
+
+        let mut decoder = WebpDecoder::new(io::Cursor::new(encoded));
+        assert_eq!(decoder.dimensions().unwrap(), (width, height));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+
+        let decoded = match decoder.read_image().unwrap() {
+            DecodingResult::U8(decoded) => decoded,
+            _ => panic!("unexpected bit depth")
+        };
+
+        let mut error = 0;
+        for (i, (&a, &b)) in image.iter().zip(decoded.iter()).enumerate() {
+            if i % 4 == 3 {
+                assert_eq!(a, b);
+            } else {
+                error += (a as i32 - b as i32).abs();
+            }
+        }
+
+        // The mean error of the color channels
+        assert!(error < 3 * 3 * (width * height) as i32);
+    }
+}
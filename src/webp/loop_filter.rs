@@ -0,0 +1,206 @@
+//! The VP8 in-loop deblocking filters as described in section 15 of RFC-6386.
+//!
+//! All functions filter a single segment of pixels perpendicular to an edge.
+//! ```point``` is the index of the first pixel after the edge (q0) and
+//! ```stride``` the distance between two pixels of the segment.
+
+use std::num::SignedInt;
+
+use math::utils::clamp;
+
+// Clamp to the range of a signed byte
+fn c(v: i32) -> i32 {
+    clamp(v, -128, 127)
+}
+
+// Convert an unsigned pixel value to its signed representation
+fn u2s(v: u8) -> i32 {
+    v as i32 - 128
+}
+
+// Convert a signed value back to an unsigned pixel value
+fn s2u(v: i32) -> u8 {
+    (c(v) + 128) as u8
+}
+
+// Section 15.2
+fn common_adjust(use_outer_taps: bool, pixels: &mut [u8], point: usize, stride: usize) -> i32 {
+    let p1 = u2s(pixels[point - 2 * stride]);
+    let p0 = u2s(pixels[point - stride]);
+    let q0 = u2s(pixels[point]);
+    let q1 = u2s(pixels[point + stride]);
+
+    let outer = if use_outer_taps { c(p1 - q1) } else { 0 };
+    let a = c(outer + 3 * (q0 - p0));
+
+    // b balances the rounding of a / 8 when its fractional part is exactly 1/2
+    let b = c(a + 3) >> 3;
+    let a = c(a + 4) >> 3;
+
+    pixels[point] = s2u(q0 - a);
+    pixels[point - stride] = s2u(p0 + b);
+
+    a
+}
+
+fn simple_threshold(edge_limit: i32, pixels: &[u8], point: usize, stride: usize) -> bool {
+    let p1 = pixels[point - 2 * stride] as i32;
+    let p0 = pixels[point - stride] as i32;
+    let q0 = pixels[point] as i32;
+    let q1 = pixels[point + stride] as i32;
+
+    (p0 - q0).abs() * 2 + (p1 - q1).abs() / 2 <= edge_limit
+}
+
+fn should_filter(interior_limit: i32, edge_limit: i32, pixels: &[u8], point: usize, stride: usize) -> bool {
+    let p3 = pixels[point - 4 * stride] as i32;
+    let p2 = pixels[point - 3 * stride] as i32;
+    let p1 = pixels[point - 2 * stride] as i32;
+    let p0 = pixels[point - stride] as i32;
+    let q0 = pixels[point] as i32;
+    let q1 = pixels[point + stride] as i32;
+    let q2 = pixels[point + 2 * stride] as i32;
+    let q3 = pixels[point + 3 * stride] as i32;
+
+    simple_threshold(edge_limit, pixels, point, stride) &&
+    (p3 - p2).abs() <= interior_limit && (p2 - p1).abs() <= interior_limit &&
+    (p1 - p0).abs() <= interior_limit && (q3 - q2).abs() <= interior_limit &&
+    (q2 - q1).abs() <= interior_limit && (q1 - q0).abs() <= interior_limit
+}
+
+// High edge variance
+fn hev(threshold: i32, pixels: &[u8], point: usize, stride: usize) -> bool {
+    let p1 = pixels[point - 2 * stride] as i32;
+    let p0 = pixels[point - stride] as i32;
+    let q0 = pixels[point] as i32;
+    let q1 = pixels[point + stride] as i32;
+
+    (p1 - p0).abs() > threshold || (q1 - q0).abs() > threshold
+}
+
+/// Section 15.2
+/// The simple filter, applied to the luma plane only
+pub fn simple_segment(edge_limit: i32, pixels: &mut [u8], point: usize, stride: usize) {
+    if simple_threshold(edge_limit, pixels, point, stride) {
+        let _ = common_adjust(true, pixels, point, stride);
+    }
+}
+
+/// Section 15.3
+/// The normal filter for edges between the subblocks of a macroblock
+pub fn subblock_filter(hev_threshold: i32,
+                       interior_limit: i32,
+                       edge_limit: i32,
+                       pixels: &mut [u8],
+                       point: usize,
+                       stride: usize) {
+
+    if should_filter(interior_limit, edge_limit, pixels, point, stride) {
+        let hv = hev(hev_threshold, pixels, point, stride);
+
+        let p1 = u2s(pixels[point - 2 * stride]);
+        let q1 = u2s(pixels[point + stride]);
+
+        let a = (common_adjust(hv, pixels, point, stride) + 1) >> 1;
+
+        if !hv {
+            pixels[point + stride] = s2u(q1 - a);
+            pixels[point - 2 * stride] = s2u(p1 + a);
+        }
+    }
+}
+
+/// Section 15.3
+/// The normal filter for the edges between macroblocks
+pub fn macroblock_filter(hev_threshold: i32,
+                         interior_limit: i32,
+                         edge_limit: i32,
+                         pixels: &mut [u8],
+                         point: usize,
+                         stride: usize) {
+
+    if should_filter(interior_limit, edge_limit, pixels, point, stride) {
+        if !hev(hev_threshold, pixels, point, stride) {
+            let p2 = u2s(pixels[point - 3 * stride]);
+            let p1 = u2s(pixels[point - 2 * stride]);
+            let p0 = u2s(pixels[point - stride]);
+            let q0 = u2s(pixels[point]);
+            let q1 = u2s(pixels[point + stride]);
+            let q2 = u2s(pixels[point + 2 * stride]);
+
+            let w = c(c(p1 - q1) + 3 * (q0 - p0));
+
+            // Roughly 3/7th, 2/7th and 1/7th of the edge difference
+            let a = c((27 * w + 63) >> 7);
+            pixels[point] = s2u(q0 - a);
+            pixels[point - stride] = s2u(p0 + a);
+
+            let a = c((18 * w + 63) >> 7);
+            pixels[point + stride] = s2u(q1 - a);
+            pixels[point - 2 * stride] = s2u(p1 + a);
+
+            let a = c((9 * w + 63) >> 7);
+            pixels[point + 2 * stride] = s2u(q2 - a);
+            pixels[point - 3 * stride] = s2u(p2 + a);
+        } else {
+            let _ = common_adjust(true, pixels, point, stride);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{simple_segment, subblock_filter, macroblock_filter};
+
+    // The expected pixels were computed with a port of the filter functions of libwebp
+    // (DoFilter2, DoFilter4 and DoFilter6 with their thresholds)
+
+    #[test]
+    fn test_simple_filter() {
+        let mut pixels = [100, 100, 100, 100, 120, 120, 120, 120];
+        simple_segment(50, &mut pixels, 4, 1);
+        assert_eq!(pixels, [100, 100, 100, 105, 115, 120, 120, 120]);
+
+        let mut pixels = [60, 70, 80, 90, 150, 160, 170, 180];
+        simple_segment(160, &mut pixels, 4, 1);
+        assert_eq!(pixels, [60, 70, 80, 102, 137, 160, 170, 180]);
+
+        // Just above the edge limit the edge is kept
+        let mut pixels = [60, 70, 80, 90, 150, 160, 170, 180];
+        simple_segment(159, &mut pixels, 4, 1);
+        assert_eq!(pixels, [60, 70, 80, 90, 150, 160, 170, 180]);
+    }
+
+    #[test]
+    fn test_subblock_filter() {
+        let mut pixels = [90, 92, 94, 96, 110, 112, 114, 116];
+        subblock_filter(2, 8, 40, &mut pixels, 4, 1);
+        assert_eq!(pixels, [90, 92, 97, 101, 105, 109, 114, 116]);
+
+        // With high edge variance only p0 and q0 change
+        let mut pixels = [90, 92, 96, 100, 110, 116, 118, 120];
+        subblock_filter(2, 8, 40, &mut pixels, 4, 1);
+        assert_eq!(pixels, [90, 92, 96, 101, 109, 116, 118, 120]);
+    }
+
+    #[test]
+    fn test_macroblock_filter() {
+        let mut pixels = [90, 92, 94, 96, 110, 112, 114, 116];
+        macroblock_filter(2, 8, 40, &mut pixels, 4, 1);
+        assert_eq!(pixels, [90, 94, 97, 101, 105, 109, 112, 116]);
+
+        let mut pixels = [90, 92, 96, 100, 110, 116, 118, 120];
+        macroblock_filter(2, 8, 40, &mut pixels, 4, 1);
+        assert_eq!(pixels, [90, 92, 96, 101, 109, 116, 118, 120]);
+
+        // A difference above the interior limit keeps the edge
+        let mut pixels = [80, 92, 94, 96, 110, 112, 114, 116];
+        macroblock_filter(2, 8, 40, &mut pixels, 4, 1);
+        assert_eq!(pixels, [80, 92, 94, 96, 110, 112, 114, 116]);
+
+        // A vertical segment of a plane that is two pixels wide
+        let mut pixels = [90, 0, 92, 0, 94, 0, 96, 0, 110, 0, 112, 0, 114, 0, 116, 0];
+        macroblock_filter(2, 8, 40, &mut pixels, 8, 2);
+        assert_eq!(pixels, [90, 0, 94, 0, 97, 0, 101, 0, 105, 0, 109, 0, 112, 0, 116, 0]);
+    }
+}
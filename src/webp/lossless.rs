@@ -0,0 +1,873 @@
+//! An implementation of the WebP lossless (VP8L) format
+//!
+//! The image data is coded with prefix codes, a color cache and LZ77
+//! backward references. Up to four invertible transforms
+//! (predictor, cross-color, subtract-green and color-indexing)
+//! are applied to the decoded pixels.
+//!
+//! # Related Links
+//! * [webp-lossless-bitstream-spec](https://developers.google.com/speed/webp/docs/webp_lossless_bitstream_specification)
+//! - The specification of the WebP lossless bitstream
+
+use std::cmp;
+use std::io::Read;
+use std::iter::repeat;
+use std::num::SignedInt;
+use std::num::wrapping::Wrapping as w;
+use std::default::Default;
+
+use image;
+use image::ImageResult;
+
+use math::utils::clamp;
+use utils::bitstream::{BitReader, LsbReader};
+
+/// The first byte of a VP8L bitstream
+pub const SIGNATURE: u16 = 0x2f;
+
+/// The maximum length of a prefix code
+pub const MAX_CODE_LENGTH: usize = 15;
+/// The number of prefix codes of backward reference lengths
+pub const NUM_LENGTH_CODES: usize = 24;
+/// The number of prefix codes of backward reference distances
+pub const NUM_DISTANCE_CODES: usize = 40;
+/// The number of symbols of the code that codes the code lengths
+pub const NUM_CODE_LENGTH_CODES: usize = 19;
+
+/// The order in which the lengths of the code length code are stored
+pub static CODE_LENGTH_ORDER: [usize; NUM_CODE_LENGTH_CODES] = [
+    17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+];
+
+// The indices of the prefix codes in a prefix code group
+const GREEN: usize = 0;
+const RED: usize = 1;
+const BLUE: usize = 2;
+const ALPHA: usize = 3;
+const DISTANCE: usize = 4;
+
+/// Maps the 120 smallest distance codes to offsets in the 2d plane.
+/// The high nibble is the vertical offset, the low nibble is 8 minus the horizontal offset.
+pub static CODE_TO_PLANE: [u8; 120] = [
+    0x18, 0x07, 0x17, 0x19, 0x28, 0x06, 0x27, 0x29, 0x16, 0x1a,
+    0x26, 0x2a, 0x38, 0x05, 0x37, 0x39, 0x15, 0x1b, 0x36, 0x3a,
+    0x25, 0x2b, 0x48, 0x04, 0x47, 0x49, 0x14, 0x1c, 0x35, 0x3b,
+    0x46, 0x4a, 0x24, 0x2c, 0x58, 0x45, 0x4b, 0x34, 0x3c, 0x03,
+    0x57, 0x59, 0x13, 0x1d, 0x56, 0x5a, 0x23, 0x2d, 0x44, 0x4c,
+    0x55, 0x5b, 0x33, 0x3d, 0x68, 0x02, 0x67, 0x69, 0x12, 0x1e,
+    0x66, 0x6a, 0x22, 0x2e, 0x54, 0x5c, 0x43, 0x4d, 0x65, 0x6b,
+    0x32, 0x3e, 0x78, 0x01, 0x77, 0x79, 0x53, 0x5d, 0x11, 0x1f,
+    0x64, 0x6c, 0x42, 0x4e, 0x76, 0x7a, 0x21, 0x2f, 0x75, 0x7b,
+    0x31, 0x3f, 0x63, 0x6d, 0x52, 0x5e, 0x00, 0x74, 0x7c, 0x41,
+    0x4f, 0x10, 0x20, 0x62, 0x6e, 0x30, 0x73, 0x7d, 0x51, 0x5f,
+    0x40, 0x72, 0x7e, 0x61, 0x6f, 0x50, 0x71, 0x7f, 0x60, 0x70
+];
+
+fn format_error<T>(desc: &str) -> ImageResult<T> {
+    Err(image::ImageError::FormatError(desc.to_string()))
+}
+
+/// A canonical prefix code
+struct HuffmanTree {
+    // The number of codes of each length
+    counts: [u16; MAX_CODE_LENGTH + 1],
+
+    // The symbols sorted by their codes
+    symbols: Vec<u16>,
+}
+
+impl HuffmanTree {
+    fn from_lengths(lengths: &[u8]) -> ImageResult<HuffmanTree> {
+        let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+
+        for &len in lengths.iter() {
+            counts[len as usize] += 1;
+        }
+
+        counts[0] = 0;
+
+        let mut offsets = [0u16; MAX_CODE_LENGTH + 1];
+        for len in (1usize..MAX_CODE_LENGTH) {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let num_symbols = (offsets[MAX_CODE_LENGTH] + counts[MAX_CODE_LENGTH]) as usize;
+        let mut symbols: Vec<u16> = repeat(0u16).take(num_symbols).collect();
+
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        match num_symbols {
+            0 => return format_error("Empty prefix code."),
+
+            // A code with a single symbol is coded with zero bits
+            1 => return Ok(HuffmanTree {counts: counts, symbols: symbols}),
+
+            _ => ()
+        }
+
+        // Only complete codes are valid
+        let mut left = 1i32;
+        for len in (1usize..MAX_CODE_LENGTH + 1) {
+            left = (left << 1) - counts[len] as i32;
+
+            if left < 0 {
+                return format_error("Over-subscribed prefix code.")
+            }
+        }
+
+        if left != 0 {
+            return format_error("Incomplete prefix code.")
+        }
+
+        Ok(HuffmanTree {counts: counts, symbols: symbols})
+    }
+
+    fn read_symbol<R: Read>(&self, r: &mut LsbReader<R>) -> ImageResult<u16> {
+        if self.symbols.len() == 1 {
+            return Ok(self.symbols[0])
+        }
+
+        let mut code  = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+
+        for len in (1usize..MAX_CODE_LENGTH + 1) {
+            code |= try!(r.read_bits(1)) as i32;
+
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize])
+            }
+
+            index += count;
+            first  = (first + count) << 1;
+            code <<= 1;
+        }
+
+        format_error("Invalid prefix code.")
+    }
+}
+
+// Section 4
+enum Transform {
+    Predictor(u8, Vec<u32>),
+    CrossColor(u8, Vec<u32>),
+    SubtractGreen,
+    ColorIndexing(Vec<u32>),
+}
+
+/// A decoded lossless image
+#[derive(Default, Debug, Clone)]
+pub struct LosslessFrame {
+    /// The width of the image
+    pub width: u16,
+
+    /// The height of the image
+    pub height: u16,
+
+    /// The pixels of the image in ARGB order
+    pub buf: Vec<u32>,
+}
+
+impl LosslessFrame {
+    /// Fills ```buf``` with the RGBA values of row ```y```
+    pub fn fill_rgba_row(&self, y: usize, buf: &mut [u8]) {
+        let width = self.width as usize;
+        let row   = &self.buf[y * width..(y + 1) * width];
+
+        for (&argb, rgba) in row.iter().zip(buf.chunks_mut(4)) {
+            rgba[0] = (argb >> 16) as u8;
+            rgba[1] = (argb >> 8) as u8;
+            rgba[2] = argb as u8;
+            rgba[3] = (argb >> 24) as u8;
+        }
+    }
+}
+
+/// VP8L Decoder
+pub struct LosslessDecoder<R> {
+    r: LsbReader<R>,
+    frame: LosslessFrame,
+}
+
+impl<R: Read> LosslessDecoder<R> {
+    /// Create a new decoder.
+    /// The reader must present a raw VP8L bitstream to the decoder
+    pub fn new(r: R) -> LosslessDecoder<R> {
+        LosslessDecoder {
+            r: LsbReader::new(r),
+            frame: Default::default(),
+        }
+    }
+
+    /// Decodes the image and returns a reference to it
+    pub fn decode_frame(&mut self) -> ImageResult<&LosslessFrame> {
+        if try!(self.r.read_bits(8)) != SIGNATURE {
+            return format_error("Invalid VP8L signature.")
+        }
+
+        let width  = try!(self.r.read_bits(14)) + 1;
+        let height = try!(self.r.read_bits(14)) + 1;
+
+        let _alpha_is_used = try!(self.r.read_bits(1));
+        let version = try!(self.r.read_bits(3));
+
+        if version != 0 {
+            return format_error("Unknown VP8L version.")
+        }
+
+        self.decode_image_data(width, height)
+    }
+
+    /// Decodes image data without the VP8L header.
+    /// This is how the alpha plane of an extended lossy image is coded,
+    /// the dimensions of the image are known from the surrounding container
+    pub fn decode_image_data(&mut self, width: u16, height: u16) -> ImageResult<&LosslessFrame> {
+        self.frame.width  = width;
+        self.frame.height = height;
+
+        let height = height as usize;
+        let mut xsize = width as usize;
+        let mut transforms = Vec::new();
+        let mut seen = [false; 4];
+
+        // Section 4
+        while try!(self.r.read_bits(1)) == 1 {
+            let kind = try!(self.r.read_bits(2)) as usize;
+
+            if seen[kind] {
+                return format_error("A VP8L transform is used more than once.")
+            }
+
+            seen[kind] = true;
+
+            let transform = try!(self.read_transform(kind, xsize, height));
+            let width = xsize;
+
+            // Color indexing bundles several pixels into one
+            if let Transform::ColorIndexing(ref table) = transform {
+                xsize = subsample(xsize, color_indexing_bits(table.len()));
+            }
+
+            transforms.push((width, transform));
+        }
+
+        let mut data = try!(self.decode_image_stream(xsize, height, true));
+
+        // The transforms are undone in the reverse order of how they are read
+        for &(width, ref transform) in transforms.iter().rev() {
+            match *transform {
+                Transform::Predictor(bits, ref image) => {
+                    apply_predictor_transform(&mut data, width, height, bits, image)
+                }
+                Transform::CrossColor(bits, ref image) => {
+                    apply_cross_color_transform(&mut data, width, height, bits, image)
+                }
+                Transform::SubtractGreen => apply_subtract_green_transform(&mut data),
+                Transform::ColorIndexing(ref table) => {
+                    data = apply_color_indexing_transform(&data, width, height, table)
+                }
+            }
+        }
+
+        self.frame.buf = data;
+
+        Ok(&self.frame)
+    }
+
+    fn read_transform(&mut self, kind: usize, xsize: usize, ysize: usize) -> ImageResult<Transform> {
+        match kind {
+            0 | 1 => {
+                let bits = try!(self.r.read_bits(3)) as u8 + 2;
+                let image = try!(self.decode_image_stream(
+                    subsample(xsize, bits), subsample(ysize, bits), false));
+
+                Ok(if kind == 0 { Transform::Predictor(bits, image) }
+                   else { Transform::CrossColor(bits, image) })
+            }
+
+            2 => Ok(Transform::SubtractGreen),
+
+            _ => {
+                let size = try!(self.r.read_bits(8)) as usize + 1;
+                let mut table = try!(self.decode_image_stream(size, 1, false));
+
+                // The color table is delta coded
+                for i in (1usize..size) {
+                    table[i] = add_pixels(table[i], table[i - 1]);
+                }
+
+                Ok(Transform::ColorIndexing(table))
+            }
+        }
+    }
+
+    // Section 5.2
+    fn decode_image_stream(&mut self, xsize: usize, ysize: usize, is_argb_image: bool) -> ImageResult<Vec<u32>> {
+        let cache_bits = if try!(self.r.read_bits(1)) == 1 {
+            let bits = try!(self.r.read_bits(4)) as u8;
+
+            if bits < 1 || bits > 11 {
+                return format_error("Invalid VP8L color cache size.")
+            }
+
+            bits
+        } else {
+            0
+        };
+
+        // Only the main image may use more than one group of prefix codes
+        let (meta_bits, meta_image) = if is_argb_image && try!(self.r.read_bits(1)) == 1 {
+            let bits  = try!(self.r.read_bits(3)) as u8 + 2;
+            let image = try!(self.decode_image_stream(
+                subsample(xsize, bits), subsample(ysize, bits), false));
+
+            (bits, image)
+        } else {
+            (0, Vec::new())
+        };
+
+        let num_groups = meta_image.iter()
+                                   .map(|&p| ((p >> 8) & 0xffff) as usize + 1)
+                                   .max()
+                                   .unwrap_or(1);
+
+        let mut groups = Vec::with_capacity(num_groups);
+        for _ in (0..num_groups) {
+            groups.push(try!(self.read_prefix_code_group(cache_bits)));
+        }
+
+        self.decode_pixels(xsize, ysize, cache_bits, meta_bits, &meta_image, &groups)
+    }
+
+    fn read_prefix_code_group(&mut self, cache_bits: u8) -> ImageResult<Vec<HuffmanTree>> {
+        let cache_size = if cache_bits > 0 { 1usize << cache_bits as usize } else { 0 };
+        let alphabet_sizes = [256 + NUM_LENGTH_CODES + cache_size, 256, 256, 256, NUM_DISTANCE_CODES];
+
+        let mut group = Vec::with_capacity(5);
+        for &size in alphabet_sizes.iter() {
+            group.push(try!(self.read_prefix_code(size)));
+        }
+
+        Ok(group)
+    }
+
+    // Section 5.2.2
+    fn read_prefix_code(&mut self, alphabet_size: usize) -> ImageResult<HuffmanTree> {
+        let mut lengths: Vec<u8> = repeat(0u8).take(alphabet_size).collect();
+
+        if try!(self.r.read_bits(1)) == 1 {
+            // Simple code length code
+            let num_symbols = try!(self.r.read_bits(1)) + 1;
+            let first_bits  = if try!(self.r.read_bits(1)) == 1 { 8 } else { 1 };
+
+            let mut symbols = vec![try!(self.r.read_bits(first_bits)) as usize];
+            if num_symbols == 2 {
+                symbols.push(try!(self.r.read_bits(8)) as usize);
+            }
+
+            for &symbol in symbols.iter() {
+                if symbol >= alphabet_size {
+                    return format_error("Invalid VP8L prefix code symbol.")
+                }
+
+                lengths[symbol] = 1;
+            }
+        } else {
+            // Normal code length code
+            let mut code_length_lengths = [0u8; NUM_CODE_LENGTH_CODES];
+            let num_codes = try!(self.r.read_bits(4)) as usize + 4;
+
+            for &i in CODE_LENGTH_ORDER[..num_codes].iter() {
+                code_length_lengths[i] = try!(self.r.read_bits(3)) as u8;
+            }
+
+            let tree = try!(HuffmanTree::from_lengths(&code_length_lengths));
+            try!(self.read_code_lengths(&tree, &mut lengths));
+        }
+
+        HuffmanTree::from_lengths(&lengths)
+    }
+
+    fn read_code_lengths(&mut self, tree: &HuffmanTree, lengths: &mut [u8]) -> ImageResult<()> {
+        let mut max_symbol = if try!(self.r.read_bits(1)) == 1 {
+            let length_bits = 2 + 2 * try!(self.r.read_bits(3)) as u8;
+            let max_symbol  = 2 + try!(self.r.read_bits(length_bits)) as usize;
+
+            if max_symbol > lengths.len() {
+                return format_error("Invalid VP8L code length count.")
+            }
+
+            max_symbol
+        } else {
+            lengths.len()
+        };
+
+        let mut previous = 8u8;
+        let mut symbol = 0;
+
+        while symbol < lengths.len() && max_symbol > 0 {
+            max_symbol -= 1;
+
+            let code = try!(tree.read_symbol(&mut self.r)) as u8;
+
+            if code < 16 {
+                lengths[symbol] = code;
+                symbol += 1;
+
+                if code != 0 {
+                    previous = code;
+                }
+
+                continue
+            }
+
+            let (length, extra_bits, offset) = match code {
+                16 => (previous, 2, 3),
+                17 => (0, 3, 3),
+                _  => (0, 7, 11),
+            };
+
+            let repeat = try!(self.r.read_bits(extra_bits)) as usize + offset;
+
+            if symbol + repeat > lengths.len() {
+                return format_error("Invalid VP8L code lengths.")
+            }
+
+            for _ in (0..repeat) {
+                lengths[symbol] = length;
+                symbol += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Section 5.2.1
+    fn read_prefix_value(&mut self, symbol: u16) -> ImageResult<usize> {
+        if symbol < 4 {
+            return Ok(symbol as usize + 1)
+        }
+
+        let extra_bits = (symbol as usize - 2) >> 1;
+        let offset = (2 + (symbol as usize & 1)) << extra_bits;
+
+        // There are up to 18 extra bits
+        let low  = try!(self.r.read_bits(cmp::min(extra_bits, 16) as u8)) as usize;
+        let high = if extra_bits > 16 {
+            try!(self.r.read_bits((extra_bits - 16) as u8)) as usize
+        } else {
+            0
+        };
+
+        Ok(offset + ((high << 16) | low) + 1)
+    }
+
+    // Section 5.2.2
+    fn decode_pixels(&mut self,
+                     xsize: usize,
+                     ysize: usize,
+                     cache_bits: u8,
+                     meta_bits: u8,
+                     meta_image: &[u32],
+                     groups: &[Vec<HuffmanTree>]) -> ImageResult<Vec<u32>> {
+
+        let total = xsize * ysize;
+        let meta_xsize = subsample(xsize, meta_bits);
+
+        let mut data: Vec<u32> = Vec::with_capacity(total);
+        let mut cache: Vec<u32> = repeat(0u32).take(
+            if cache_bits > 0 { 1usize << cache_bits as usize } else { 0 }).collect();
+
+        // The number of pixels that are inserted into the color cache
+        let mut cached = 0;
+
+        while data.len() < total {
+            let pos = data.len();
+
+            let group = if meta_image.is_empty() {
+                &groups[0]
+            } else {
+                let x = (pos % xsize) >> meta_bits as usize;
+                let y = (pos / xsize) >> meta_bits as usize;
+
+                &groups[((meta_image[y * meta_xsize + x] >> 8) & 0xffff) as usize]
+            };
+
+            let green = try!(group[GREEN].read_symbol(&mut self.r)) as usize;
+
+            if green < 256 {
+                let red   = try!(group[RED].read_symbol(&mut self.r)) as u32;
+                let blue  = try!(group[BLUE].read_symbol(&mut self.r)) as u32;
+                let alpha = try!(group[ALPHA].read_symbol(&mut self.r)) as u32;
+
+                data.push((alpha << 24) | (red << 16) | ((green as u32) << 8) | blue);
+            } else if green < 256 + NUM_LENGTH_CODES {
+                let length = try!(self.read_prefix_value((green - 256) as u16));
+
+                let symbol = try!(group[DISTANCE].read_symbol(&mut self.r));
+                let code   = try!(self.read_prefix_value(symbol));
+                let dist   = plane_code_to_distance(xsize, code);
+
+                if dist > pos || pos + length > total {
+                    return format_error("Invalid VP8L backward reference.")
+                }
+
+                for i in (0..length) {
+                    let p = data[pos + i - dist];
+                    data.push(p);
+                }
+            } else {
+                let index = green - 256 - NUM_LENGTH_CODES;
+
+                if index >= cache.len() {
+                    return format_error("Invalid VP8L color cache index.")
+                }
+
+                data.push(cache[index]);
+            }
+
+            if cache_bits > 0 {
+                while cached < data.len() {
+                    let p = data[cached];
+                    cache[color_cache_hash(p, cache_bits)] = p;
+                    cached += 1;
+                }
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+/// Divide by 2^bits, rounding up
+pub fn subsample(size: usize, bits: u8) -> usize {
+    (size + (1 << bits as usize) - 1) >> bits as usize
+}
+
+fn color_cache_hash(argb: u32, bits: u8) -> usize {
+    ((w(argb) * w(0x1e35a7bd)).0 >> (32 - bits as usize)) as usize
+}
+
+/// Converts a distance code to the distance of a backward reference
+/// in an image that is ```xsize``` pixels wide
+pub fn plane_code_to_distance(xsize: usize, code: usize) -> usize {
+    if code > CODE_TO_PLANE.len() {
+        return code - CODE_TO_PLANE.len()
+    }
+
+    let offset = CODE_TO_PLANE[code - 1];
+    let yoffset = (offset >> 4) as isize;
+    let xoffset = 8 - (offset & 0xf) as isize;
+    let dist = yoffset * xsize as isize + xoffset;
+
+    if dist >= 1 { dist as usize } else { 1 }
+}
+
+/// The number of bits used to bundle pixels for a color table of the given size
+pub fn color_indexing_bits(size: usize) -> u8 {
+    if size <= 2 { 3 }
+    else if size <= 4 { 2 }
+    else if size <= 16 { 1 }
+    else { 0 }
+}
+
+/// Adds the channels of two pixels, modulo 256
+pub fn add_pixels(a: u32, b: u32) -> u32 {
+    let mut out = 0;
+
+    for shift in (0usize..32).step_by(8) {
+        out |= (((a >> shift) + (b >> shift)) & 0xff) << shift;
+    }
+
+    out
+}
+
+fn average2(a: u32, b: u32) -> u32 {
+    (((a ^ b) & 0xfefefefe) >> 1) + (a & b)
+}
+
+fn channel(p: u32, shift: usize) -> i32 {
+    ((p >> shift) & 0xff) as i32
+}
+
+fn select(l: u32, t: u32, tl: u32) -> u32 {
+    let mut pl = 0;
+    let mut pt = 0;
+
+    for shift in (0usize..32).step_by(8) {
+        pl += (channel(t, shift) - channel(tl, shift)).abs();
+        pt += (channel(l, shift) - channel(tl, shift)).abs();
+    }
+
+    if pl < pt { l } else { t }
+}
+
+fn clamp_add_subtract_full(a: u32, b: u32, c: u32) -> u32 {
+    let mut out = 0;
+
+    for shift in (0usize..32).step_by(8) {
+        let v = channel(a, shift) + channel(b, shift) - channel(c, shift);
+        out |= (clamp(v, 0, 255) as u32) << shift;
+    }
+
+    out
+}
+
+fn clamp_add_subtract_half(a: u32, b: u32) -> u32 {
+    let mut out = 0;
+
+    for shift in (0usize..32).step_by(8) {
+        let a = channel(a, shift);
+        let v = a + (a - channel(b, shift)) / 2;
+        out |= (clamp(v, 0, 255) as u32) << shift;
+    }
+
+    out
+}
+
+/// Predicts a pixel with the predictor ```mode``` from its left, top, top right
+/// and top left neighbours (Section 4.1)
+pub fn predict(mode: u32, l: u32, t: u32, tr: u32, tl: u32) -> u32 {
+    match mode {
+        1  => l,
+        2  => t,
+        3  => tr,
+        4  => tl,
+        5  => average2(average2(l, tr), t),
+        6  => average2(l, tl),
+        7  => average2(l, t),
+        8  => average2(tl, t),
+        9  => average2(t, tr),
+        10 => average2(average2(l, tl), average2(t, tr)),
+        11 => select(l, t, tl),
+        12 => clamp_add_subtract_full(l, t, tl),
+        13 => clamp_add_subtract_half(average2(l, t), tl),
+        _  => 0xff000000,
+    }
+}
+
+fn apply_predictor_transform(data: &mut [u32], width: usize, height: usize, bits: u8, image: &[u32]) {
+    let block_width = subsample(width, bits);
+
+    for y in (0..height) {
+        for x in (0..width) {
+            let i = y * width + x;
+
+            let prediction = if y == 0 {
+                if x == 0 { 0xff000000 } else { data[i - 1] }
+            } else if x == 0 {
+                data[i - width]
+            } else {
+                let block = image[(y >> bits as usize) * block_width + (x >> bits as usize)];
+                let mode  = (block >> 8) & 0xf;
+
+                // The top right pixel of the last column is the first pixel of the current row
+                predict(mode, data[i - 1], data[i - width], data[i - width + 1], data[i - width - 1])
+            };
+
+            data[i] = add_pixels(data[i], prediction);
+        }
+    }
+}
+
+fn color_transform_delta(t: u8, c: u8) -> i32 {
+    (t as i8 as i32 * c as i8 as i32) >> 5
+}
+
+// Section 4.2
+fn apply_cross_color_transform(data: &mut [u32], width: usize, height: usize, bits: u8, image: &[u32]) {
+    let block_width = subsample(width, bits);
+
+    for y in (0..height) {
+        for x in (0..width) {
+            let i = y * width + x;
+            let m = image[(y >> bits as usize) * block_width + (x >> bits as usize)];
+
+            let green_to_red  = m as u8;
+            let green_to_blue = (m >> 8) as u8;
+            let red_to_blue   = (m >> 16) as u8;
+
+            let p = data[i];
+            let green = (p >> 8) as u8;
+
+            let red  = (channel(p, 16) + color_transform_delta(green_to_red, green)) & 0xff;
+            let blue = (channel(p, 0) + color_transform_delta(green_to_blue, green)) & 0xff;
+            let blue = (blue + color_transform_delta(red_to_blue, red as u8)) & 0xff;
+
+            data[i] = (p & 0xff00ff00) | ((red as u32) << 16) | blue as u32;
+        }
+    }
+}
+
+// Section 4.3
+fn apply_subtract_green_transform(data: &mut [u32]) {
+    for p in data.iter_mut() {
+        let green = (*p >> 8) & 0xff;
+        *p = add_pixels(*p, (green << 16) | green);
+    }
+}
+
+// Section 4.4
+fn apply_color_indexing_transform(data: &[u32], width: usize, height: usize, table: &[u32]) -> Vec<u32> {
+    let bits = color_indexing_bits(table.len()) as usize;
+    let packed_width = subsample(width, bits as u8);
+
+    let bits_per_pixel = 8 >> bits;
+    let mask = (1 << bits_per_pixel) - 1;
+
+    let mut out = Vec::with_capacity(width * height);
+
+    for y in (0..height) {
+        for x in (0..width) {
+            let packed = (data[y * packed_width + (x >> bits)] >> 8) & 0xff;
+            let index  = (packed >> (bits_per_pixel * (x & ((1 << bits) - 1)))) & mask;
+
+            // Indices outside of the table are transparent black
+            out.push(if (index as usize) < table.len() { table[index as usize] } else { 0 });
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::{LosslessDecoder, LosslessFrame};
+
+    // The streams were written with a minimal encoder that applies the forward
+    // transforms of the specification and codes the residuals with prefix codes only.
+
+    fn argb(a: u32, r: u32, g: u32, b: u32) -> u32 {
+        (a & 0xff) << 24 | (r & 0xff) << 16 | (g & 0xff) << 8 | (b & 0xff)
+    }
+
+    fn decode(data: &[u8]) -> LosslessFrame {
+        LosslessDecoder::new(io::Cursor::new(data)).decode_frame().unwrap().clone()
+    }
+
+    fn check(frame: &LosslessFrame, width: u32, height: u32, pixel: fn(u32, u32) -> u32) {
+        assert_eq!(frame.width as u32, width);
+        assert_eq!(frame.height as u32, height);
+        assert_eq!(frame.buf.len() as u32, width * height);
+
+        for y in (0..height) {
+            for x in (0..width) {
+                assert_eq!(frame.buf[(y * width + x) as usize], pixel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    /// Test a 6x4 image coded with the subtract green transform
+    fn test_subtract_green() {
+        fn pixel(x: u32, y: u32) -> u32 {
+            let alpha = if (x + y) % 3 == 0 { 0x80 } else { 0xff };
+            argb(alpha, 16 * x + 8 * y, 40 + 8 * y + 4 * x, 3 * x * y)
+        }
+
+        let data = [
+            0x2f, 0x05, 0xc0, 0x00, 0x10, 0x85, 0xcf, 0x00, 0x4c, 0x00, 0x00, 0x00,
+            0x00, 0x50, 0xd6, 0x3b, 0x41, 0x10, 0x04, 0x83, 0xc1, 0x60, 0x10, 0x04,
+            0x41, 0x7e, 0x01, 0x26, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xec, 0x1b, 0x40,
+            0xfe, 0x5c, 0x01, 0x04, 0x30, 0x80, 0x79, 0x52, 0x00, 0x43, 0x00, 0x00,
+            0x00, 0x00, 0x65, 0xfd, 0xff, 0x57, 0x7b, 0x58, 0x25, 0x06, 0x93, 0x1c,
+            0x38, 0xe0, 0x7f, 0xc4, 0x56, 0x7e, 0x16, 0xdc, 0x02, 0xa2, 0xd9, 0xbf,
+            0xc6, 0xfd, 0x0a, 0x12, 0x8d, 0x0a, 0xba, 0x0e, 0xd3, 0xbc, 0x11, 0x68,
+            0xf3, 0x84, 0xb6, 0x5d, 0x7b, 0x7c, 0x39, 0xd8, 0xb5, 0x5c, 0xe6, 0xf7,
+            0x02,
+        ];
+
+        check(&decode(&data), 6, 4, pixel);
+    }
+
+    #[test]
+    /// Test a 16x8 image coded with the predictor transform.
+    /// The 4x4 blocks use the modes 11, 12, 13, 3 and 5, 10, 15, 9,
+    /// the top right pixel of the last column is taken from the current row
+    fn test_predictor() {
+        fn pixel(x: u32, y: u32) -> u32 {
+            argb(0xff, 16 * x, 8 * y, (x * y) & 0xf0)
+        }
+
+        let data = [
+            0x2f, 0x0f, 0xc0, 0x01, 0x10, 0x01, 0x2f, 0x08, 0x08, 0x00, 0x00, 0x00,
+            0x00, 0x60, 0x74, 0xc8, 0x00, 0x89, 0x88, 0x48, 0x07, 0xfa, 0x82, 0xdf,
+            0x84, 0x61, 0x02, 0x00, 0x00, 0x00, 0x30, 0x1f, 0x8a, 0x84, 0x9c, 0xe1,
+            0xe8, 0xe8, 0x58, 0x3f, 0x01, 0xdb, 0x04, 0x00, 0x00, 0x00, 0xa0, 0xa2,
+            0x1c, 0x11, 0x50, 0xb9, 0x9c, 0x48, 0x40, 0x02, 0x12, 0x26, 0x6d, 0x67,
+            0x7d, 0x86, 0xa2, 0x23, 0x06, 0x00, 0x00, 0x80, 0xb2, 0x1e, 0x04, 0x0e,
+            0x02, 0x7f, 0x84, 0x60, 0x31, 0x58, 0xbc, 0xfd, 0x27, 0x2a, 0x34, 0x02,
+            0x10, 0x04, 0x41, 0x10, 0x04, 0x41, 0x10, 0x04, 0x41, 0x10, 0x04, 0x11,
+            0x42, 0x08, 0x00, 0x00, 0x30, 0x1a, 0x8d, 0x46, 0xad, 0x56, 0x8b, 0x15,
+            0x42, 0x08, 0x00, 0x00, 0x30, 0x66, 0xac, 0xb1, 0xc6, 0x6a, 0xd3, 0xa6,
+            0x0d, 0x9b, 0x10, 0x42, 0x00, 0x00, 0x02, 0xc6, 0x6f, 0x34, 0x1a, 0xd3,
+            0xa6, 0x4d, 0x1b, 0x76, 0x82, 0x10, 0x42, 0xa3, 0x47, 0x4b, 0xdd, 0x3b,
+            0xf7, 0xcd, 0xfd, 0x73, 0x67, 0x6a, 0x6a, 0x6a, 0xce, 0x99, 0x20, 0x84,
+            0xfc, 0x7a, 0x94, 0xd2, 0x7e, 0x77, 0xde, 0x9b, 0xf7, 0xf7, 0xcd, 0x56,
+            0x35, 0x35, 0xe7, 0x3c, 0x41, 0x08, 0x69, 0x2d, 0xa5, 0xad, 0xf5, 0xef,
+            0xfe, 0xb7, 0xff, 0xef, 0x9f, 0x5b, 0x4f, 0x55, 0x73, 0xce, 0x06, 0x21,
+            0xbf, 0x94, 0xd2, 0xd6, 0xd2, 0xde, 0xbb, 0xfb, 0x76, 0xff, 0xdd, 0xb9,
+            0x35, 0xf5, 0xd4, 0x9c, 0xf3, 0x02,
+        ];
+
+        check(&decode(&data), 16, 8, pixel);
+    }
+
+    #[test]
+    /// Test an 8x4 image coded with the cross color transform,
+    /// the second 4x4 block uses negative multipliers
+    fn test_cross_color() {
+        fn pixel(x: u32, y: u32) -> u32 {
+            argb(0xff, 32 * x + 16 * y, 0x30 + 0x28 * y + 0x10 * x, 7 * x * y)
+        }
+
+        let data = [
+            0x2f, 0x07, 0xc0, 0x00, 0x10, 0x83, 0x83, 0x04, 0x1e, 0x02, 0xfe, 0x30,
+            0x7f, 0x11, 0x38, 0x7c, 0x06, 0x00, 0x13, 0x00, 0x00, 0x00, 0x80, 0x92,
+            0xb5, 0x13, 0x3a, 0xa3, 0x13, 0x3a, 0x21, 0x0c, 0xc3, 0x30, 0x0c, 0x87,
+            0xe1, 0x30, 0x1c, 0x0e, 0xc3, 0x61, 0x38, 0x0c, 0xc3, 0x30, 0x0c, 0xf3,
+            0xe2, 0x00, 0x30, 0x00, 0x00, 0x00, 0x00, 0x0a, 0xff, 0x81, 0x79, 0x84,
+            0xea, 0x0f, 0x3e, 0x62, 0x5e, 0x62, 0x3e, 0x20, 0x8a, 0x37, 0x7f, 0xb0,
+            0x09, 0x82, 0x20, 0x5c, 0x10, 0x6c, 0xf8, 0x81, 0x28, 0xd9, 0x45, 0x5e,
+            0x1c, 0x00, 0x06, 0x00, 0x00, 0x00, 0x40, 0xf1, 0xdd, 0x37, 0x48, 0xa2,
+            0x4d, 0x17, 0x6d, 0x54, 0x55, 0x96, 0xec, 0x36, 0x59, 0x56, 0x25, 0x11,
+            0x4f, 0x90, 0x75, 0xd1, 0x26, 0xf8, 0x8a, 0xa4, 0xff, 0xe1, 0xca, 0xf1,
+            0x6d, 0x84, 0x9e, 0xd3, 0x82, 0x82, 0x11, 0xe0, 0xa2, 0x25, 0xa4, 0x43,
+            0xf4, 0x3d, 0x87, 0x54, 0xc1, 0x37, 0x61, 0xba, 0x94, 0xcc, 0xaa, 0xbc,
+            0xa7, 0x53, 0xc1, 0x01, 0xbc, 0x96, 0x48, 0x8f, 0x3f, 0x67, 0x44, 0xcb,
+            0xb9, 0x63, 0xd6, 0x53, 0x98, 0xb3, 0x90, 0xca, 0x15, 0xe9, 0x1e, 0xed,
+            0xf2, 0xfd, 0x89, 0x99, 0x5d, 0x6c, 0xfa,
+        ];
+
+        check(&decode(&data), 8, 4, pixel);
+    }
+
+    #[test]
+    /// Test a 7x3 image coded with a color table of three colors.
+    /// Four indices are bundled into one pixel, the last bundle of a row is incomplete
+    fn test_color_indexing() {
+        fn pixel(x: u32, y: u32) -> u32 {
+            let table = [0xff000000, 0xff20c040, 0x80ffffff];
+            table[((x * x + y) % 3) as usize]
+        }
+
+        let data = [
+            0x2f, 0x06, 0x80, 0x00, 0x10, 0x17, 0xe0, 0x11, 0x48, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xc4, 0x9b, 0xad, 0x9e, 0x47, 0x20, 0x01, 0x00, 0x00, 0x00,
+            0x00, 0x30, 0x34, 0xca, 0xfe, 0x52, 0x79, 0x04, 0x12, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xf1, 0x68, 0x9b, 0xe7, 0x11, 0x48, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0xc4, 0xab, 0x4f, 0x5e, 0x54, 0x7c, 0x33, 0xbc, 0x04, 0x48, 0x00,
+            0x00, 0x00, 0x00, 0x80, 0xd1, 0xe4, 0x03, 0x93, 0xc9, 0x7b, 0xdc, 0x20,
+            0xa2, 0xff, 0xd1, 0x30, 0x0f,
+        ];
+
+        check(&decode(&data), 7, 3, pixel);
+    }
+}
@@ -0,0 +1,747 @@
+//! Encoding of lossless WebP (VP8L) images
+//!
+//! The encoder applies the subtract-green and the predictor transform,
+//! where the predictor of each block is chosen by the sum of the absolute residuals.
+//! The pixels are then compressed with LZ77 backward references
+//! and a single group of prefix codes.
+
+use std::io;
+use std::io::Write;
+use std::cmp;
+use std::u32;
+use std::usize;
+use std::iter::repeat;
+use std::num::SignedInt;
+use std::num::wrapping::Wrapping as w;
+
+use utils::bitstream::{BitWriter, LsbWriter};
+
+use super::lossless::{
+    SIGNATURE,
+    MAX_CODE_LENGTH,
+    NUM_LENGTH_CODES,
+    NUM_DISTANCE_CODES,
+    NUM_CODE_LENGTH_CODES,
+    CODE_LENGTH_ORDER,
+    CODE_TO_PLANE,
+    subsample,
+    plane_code_to_distance,
+    predict,
+};
+
+// The size of the blocks that share a predictor is 2^PREDICTOR_BITS
+const PREDICTOR_BITS: u8 = 4;
+const NUM_PREDICTORS: u32 = 14;
+
+// LZ77 parameters
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 4096;
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: usize = 16;
+
+// The largest distance that can be expressed with a distance code
+const MAX_DISTANCE: usize = (1 << 20) - 120;
+
+// The maximum length of the code length code
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+
+// An element of the compressed pixel stream
+#[derive(Copy)]
+enum Token {
+    Literal(u32),
+    // A backward reference of a length and a distance code
+    Copy(usize, usize),
+}
+
+// A prefix code for encoding
+struct HuffmanCode {
+    lengths: Vec<u8>,
+    codes: Vec<u16>,
+
+    // A code with only one used symbol is written with zero bits
+    single: bool,
+}
+
+impl HuffmanCode {
+    fn from_histogram(histogram: &[u32], max_length: u8) -> HuffmanCode {
+        let lengths = build_code_lengths(histogram, max_length);
+        let used = lengths.iter().filter(|&&l| l > 0).count();
+        let codes = canonical_codes(&lengths);
+
+        HuffmanCode {
+            lengths: lengths,
+            codes: codes,
+            single: used <= 1,
+        }
+    }
+
+    fn write_symbol<W: BitWriter>(&self, w: &mut W, symbol: usize) -> io::Result<()> {
+        if self.single {
+            return Ok(())
+        }
+
+        w.write_bits(self.codes[symbol], self.lengths[symbol])
+    }
+}
+
+/// Encodes ```pixels``` in ARGB order as a VP8L bitstream
+pub fn encode(pixels: &[u32], width: u32, height: u32) -> io::Result<Vec<u8>> {
+    try!(check_dimensions(width, height));
+
+    let mut out = Vec::new();
+
+    {
+        let mut w = LsbWriter::new(&mut out);
+
+        try!(w.write_bits(SIGNATURE, 8));
+        try!(w.write_bits((width - 1) as u16, 14));
+        try!(w.write_bits((height - 1) as u16, 14));
+
+        let alpha_is_used = pixels.iter().any(|&p| p >> 24 != 0xff);
+        try!(w.write_bits(if alpha_is_used { 1 } else { 0 }, 1));
+
+        // Version
+        try!(w.write_bits(0, 3));
+
+        try!(write_image_data(&mut w, pixels, width as usize, height as usize, true));
+        try!(w.flush());
+    }
+
+    Ok(out)
+}
+
+/// Encodes an alpha plane as VP8L image data without a header,
+/// as it is stored in the ALPH chunk of an extended image
+pub fn encode_alpha(alpha: &[u8], width: u32, height: u32) -> io::Result<Vec<u8>> {
+    try!(check_dimensions(width, height));
+
+    // The alpha values are stored in the green channel
+    let pixels: Vec<u32> = alpha.iter().map(|&a| 0xff000000 | ((a as u32) << 8)).collect();
+    let mut out = Vec::new();
+
+    {
+        let mut w = LsbWriter::new(&mut out);
+
+        try!(write_image_data(&mut w, &pixels, width as usize, height as usize, false));
+        try!(w.flush());
+    }
+
+    Ok(out)
+}
+
+fn check_dimensions(width: u32, height: u32) -> io::Result<()> {
+    if width == 0 || height == 0 || width > 1 << 14 || height > 1 << 14 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid image dimensions.",
+            Some(format!("A lossless WebP image can not be {} x {} pixels.", width, height))
+        ))
+    }
+
+    Ok(())
+}
+
+// Writes the transforms and the entropy coded image.
+// The subtract green transform is only worthwhile for color images.
+fn write_image_data<W: BitWriter>(w: &mut W,
+                                  pixels: &[u32],
+                                  width: usize,
+                                  height: usize,
+                                  subtract_green: bool) -> io::Result<()> {
+
+    let mut data = pixels.to_vec();
+
+    if subtract_green {
+        try!(w.write_bits(1, 1));
+        try!(w.write_bits(2, 2));
+        apply_subtract_green(&mut data);
+    }
+
+    // Predictor
+    let modes = select_predictors(&data, width, height);
+
+    try!(w.write_bits(1, 1));
+    try!(w.write_bits(0, 2));
+    try!(w.write_bits((PREDICTOR_BITS - 2) as u16, 3));
+    try!(write_image_stream(w, &modes, subsample(width, PREDICTOR_BITS), false));
+
+    let residuals = apply_predictor(&data, width, height, &modes);
+
+    // No more transforms
+    try!(w.write_bits(0, 1));
+
+    write_image_stream(w, &residuals, width, true)
+}
+
+fn apply_subtract_green(data: &mut [u32]) {
+    for p in data.iter_mut() {
+        let green = (*p >> 8) & 0xff;
+        *p = sub_pixels(*p, (green << 16) | green);
+    }
+}
+
+// Subtracts the channels of two pixels, modulo 256
+fn sub_pixels(a: u32, b: u32) -> u32 {
+    let mut out = 0;
+
+    for shift in (0usize..32).step_by(8) {
+        out |= ((w((a >> shift) & 0xff) - w((b >> shift) & 0xff)).0 & 0xff) << shift;
+    }
+
+    out
+}
+
+// The prediction the decoder makes for the pixel at (x, y)
+fn prediction(data: &[u32], width: usize, x: usize, y: usize, mode: u32) -> u32 {
+    let i = y * width + x;
+
+    if y == 0 {
+        if x == 0 { 0xff000000 } else { data[i - 1] }
+    } else if x == 0 {
+        data[i - width]
+    } else {
+        predict(mode, data[i - 1], data[i - width], data[i - width + 1], data[i - width - 1])
+    }
+}
+
+// Returns the sum of the absolute values of the channels interpreted as signed bytes
+fn residual_cost(residual: u32) -> u32 {
+    let mut cost = 0;
+
+    for shift in (0usize..32).step_by(8) {
+        cost += ((residual >> shift) as u8 as i8 as i32).abs() as u32;
+    }
+
+    cost
+}
+
+// Chooses the predictor with the smallest residuals for every block
+fn select_predictors(data: &[u32], width: usize, height: usize) -> Vec<u32> {
+    let block_width  = subsample(width, PREDICTOR_BITS);
+    let block_height = subsample(height, PREDICTOR_BITS);
+    let size = 1usize << PREDICTOR_BITS as usize;
+
+    let mut modes = Vec::with_capacity(block_width * block_height);
+
+    for by in (0..block_height) {
+        for bx in (0..block_width) {
+            let mut best = (0, u32::MAX);
+
+            for mode in (0..NUM_PREDICTORS) {
+                let mut cost = 0;
+
+                for y in (by * size..cmp::min((by + 1) * size, height)) {
+                    for x in (bx * size..cmp::min((bx + 1) * size, width)) {
+                        let p = prediction(data, width, x, y, mode);
+                        cost += residual_cost(sub_pixels(data[y * width + x], p));
+                    }
+                }
+
+                if cost < best.1 {
+                    best = (mode, cost);
+                }
+            }
+
+            // The mode is stored in the green channel
+            modes.push(0xff000000 | (best.0 << 8));
+        }
+    }
+
+    modes
+}
+
+fn apply_predictor(data: &[u32], width: usize, height: usize, modes: &[u32]) -> Vec<u32> {
+    let block_width = subsample(width, PREDICTOR_BITS);
+    let mut residuals = Vec::with_capacity(data.len());
+
+    for y in (0..height) {
+        for x in (0..width) {
+            let block = modes[(y >> PREDICTOR_BITS as usize) * block_width + (x >> PREDICTOR_BITS as usize)];
+            let p = prediction(data, width, x, y, (block >> 8) & 0xf);
+
+            residuals.push(sub_pixels(data[y * width + x], p));
+        }
+    }
+
+    residuals
+}
+
+fn pixel_hash(a: u32, b: u32) -> usize {
+    let h = (w(a) * w(0x1e35a7bd)).0 ^ (w(b) * w(0x9e3779b1)).0;
+    (h >> (32 - HASH_BITS)) as usize
+}
+
+fn insert_hash(data: &[u32], head: &mut [usize], prev: &mut [usize], pos: usize) {
+    if pos + 1 < data.len() {
+        let h = pixel_hash(data[pos], data[pos + 1]);
+        prev[pos] = head[h];
+        head[h] = pos;
+    }
+}
+
+// Returns the distance code of a backward reference,
+// preferring the short codes for nearby pixels in the 2d plane
+fn distance_code(xsize: usize, distance: usize) -> usize {
+    for code in (1..CODE_TO_PLANE.len() + 1) {
+        if plane_code_to_distance(xsize, code) == distance {
+            return code
+        }
+    }
+
+    distance + CODE_TO_PLANE.len()
+}
+
+// Greedy LZ77 with hash chains
+fn backward_references(data: &[u32], xsize: usize) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut head: Vec<usize> = repeat(usize::MAX).take(1 << HASH_BITS).collect();
+    let mut prev: Vec<usize> = repeat(usize::MAX).take(data.len()).collect();
+
+    let mut i = 0;
+
+    while i < data.len() {
+        let mut best_length = 0;
+        let mut best_distance = 0;
+
+        if i + 1 < data.len() {
+            let mut candidate = head[pixel_hash(data[i], data[i + 1])];
+            let mut chain = 0;
+            let max_length = cmp::min(MAX_MATCH, data.len() - i);
+
+            while candidate != usize::MAX && chain < MAX_CHAIN && i - candidate <= MAX_DISTANCE {
+                let mut length = 0;
+                while length < max_length && data[candidate + length] == data[i + length] {
+                    length += 1;
+                }
+
+                if length > best_length {
+                    best_length = length;
+                    best_distance = i - candidate;
+
+                    if length == max_length {
+                        break
+                    }
+                }
+
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            tokens.push(Token::Copy(best_length, distance_code(xsize, best_distance)));
+
+            for j in (i..i + best_length) {
+                insert_hash(data, &mut head, &mut prev, j);
+            }
+
+            i += best_length;
+        } else {
+            tokens.push(Token::Literal(data[i]));
+            insert_hash(data, &mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+// Returns the prefix symbol, the number of extra bits and their value
+fn prefix_encode(value: usize) -> (usize, u8, usize) {
+    let v = value - 1;
+
+    if v < 4 {
+        return (v, 0, 0)
+    }
+
+    let mut highest_bit = 0;
+    while v >> (highest_bit + 1) != 0 {
+        highest_bit += 1;
+    }
+
+    let second_highest_bit = (v >> (highest_bit - 1)) & 1;
+    let extra_bits = highest_bit - 1;
+
+    (2 * highest_bit + second_highest_bit, extra_bits as u8, v & ((1 << extra_bits) - 1))
+}
+
+fn write_extra_bits<W: BitWriter>(w: &mut W, value: usize, n: u8) -> io::Result<()> {
+    if n > 16 {
+        try!(w.write_bits((value & 0xffff) as u16, 16));
+        w.write_bits((value >> 16) as u16, n - 16)
+    } else {
+        w.write_bits(value as u16, n)
+    }
+}
+
+// Writes an entropy coded image without a color cache
+fn write_image_stream<W: BitWriter>(w: &mut W, data: &[u32], xsize: usize, is_argb_image: bool) -> io::Result<()> {
+    // No color cache
+    try!(w.write_bits(0, 1));
+
+    if is_argb_image {
+        // No meta prefix codes
+        try!(w.write_bits(0, 1));
+    }
+
+    let tokens = backward_references(data, xsize);
+
+    let mut histograms: Vec<Vec<u32>> = [256 + NUM_LENGTH_CODES, 256, 256, 256, NUM_DISTANCE_CODES]
+        .iter()
+        .map(|&size| repeat(0u32).take(size).collect())
+        .collect();
+
+    for &token in tokens.iter() {
+        match token {
+            Token::Literal(p) => {
+                histograms[0][((p >> 8) & 0xff) as usize] += 1;
+                histograms[1][((p >> 16) & 0xff) as usize] += 1;
+                histograms[2][(p & 0xff) as usize] += 1;
+                histograms[3][(p >> 24) as usize] += 1;
+            }
+
+            Token::Copy(length, code) => {
+                histograms[0][256 + prefix_encode(length).0] += 1;
+                histograms[4][prefix_encode(code).0] += 1;
+            }
+        }
+    }
+
+    let mut codes = Vec::with_capacity(5);
+    for histogram in histograms.iter() {
+        let code = HuffmanCode::from_histogram(histogram, MAX_CODE_LENGTH as u8);
+        try!(write_huffman_code(w, &code));
+        codes.push(code);
+    }
+
+    for &token in tokens.iter() {
+        match token {
+            Token::Literal(p) => {
+                try!(codes[0].write_symbol(w, ((p >> 8) & 0xff) as usize));
+                try!(codes[1].write_symbol(w, ((p >> 16) & 0xff) as usize));
+                try!(codes[2].write_symbol(w, (p & 0xff) as usize));
+                try!(codes[3].write_symbol(w, (p >> 24) as usize));
+            }
+
+            Token::Copy(length, code) => {
+                let (symbol, n, extra) = prefix_encode(length);
+                try!(codes[0].write_symbol(w, 256 + symbol));
+                try!(write_extra_bits(w, extra, n));
+
+                let (symbol, n, extra) = prefix_encode(code);
+                try!(codes[4].write_symbol(w, symbol));
+                try!(write_extra_bits(w, extra, n));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_huffman_code<W: BitWriter>(w: &mut W, code: &HuffmanCode) -> io::Result<()> {
+    let symbols: Vec<usize> = code.lengths.iter()
+                                          .enumerate()
+                                          .filter(|&(_, &l)| l > 0)
+                                          .map(|(s, _)| s)
+                                          .collect();
+
+    // Simple codes hold up to two symbols smaller than 256
+    if symbols.len() <= 2 && symbols.iter().all(|&s| s < 256) {
+        try!(w.write_bits(1, 1));
+
+        let first = if symbols.is_empty() { 0 } else { symbols[0] };
+
+        try!(w.write_bits(if symbols.len() == 2 { 1 } else { 0 }, 1));
+
+        if first < 2 {
+            try!(w.write_bits(0, 1));
+            try!(w.write_bits(first as u16, 1));
+        } else {
+            try!(w.write_bits(1, 1));
+            try!(w.write_bits(first as u16, 8));
+        }
+
+        if symbols.len() == 2 {
+            try!(w.write_bits(symbols[1] as u16, 8));
+        }
+
+        return Ok(())
+    }
+
+    try!(w.write_bits(0, 1));
+
+    let tokens = code_length_tokens(&code.lengths);
+
+    let mut histogram = [0u32; NUM_CODE_LENGTH_CODES];
+    for &(symbol, _, _) in tokens.iter() {
+        histogram[symbol] += 1;
+    }
+
+    let length_code = HuffmanCode::from_histogram(&histogram, MAX_CODE_LENGTH_CODE_LENGTH);
+
+    let mut num_codes = NUM_CODE_LENGTH_CODES;
+    while num_codes > 4 && length_code.lengths[CODE_LENGTH_ORDER[num_codes - 1]] == 0 {
+        num_codes -= 1;
+    }
+
+    try!(w.write_bits((num_codes - 4) as u16, 4));
+
+    for &i in CODE_LENGTH_ORDER[..num_codes].iter() {
+        try!(w.write_bits(length_code.lengths[i] as u16, 3));
+    }
+
+    // Code lengths are given for the whole alphabet
+    try!(w.write_bits(0, 1));
+
+    for &(symbol, n, extra) in tokens.iter() {
+        try!(length_code.write_symbol(w, symbol));
+        try!(w.write_bits(extra, n));
+    }
+
+    Ok(())
+}
+
+// Run length encodes the code lengths.
+// Returns the code length code symbols with the number of extra bits and their value.
+fn code_length_tokens(lengths: &[u8]) -> Vec<(usize, u8, u16)> {
+    let mut tokens = Vec::new();
+
+    // The length repeated by code 16, as tracked by the decoder
+    let mut previous = 8u8;
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let length = lengths[i];
+
+        let mut run = 1;
+        while i + run < lengths.len() && lengths[i + run] == length {
+            run += 1;
+        }
+
+        if length == 0 && run >= 11 {
+            let n = cmp::min(run, 138);
+            tokens.push((18, 7, (n - 11) as u16));
+            i += n;
+        } else if length == 0 && run >= 3 {
+            tokens.push((17, 3, (run - 3) as u16));
+            i += run;
+        } else if length != 0 && length == previous && run >= 3 {
+            let n = cmp::min(run, 6);
+            tokens.push((16, 2, (n - 3) as u16));
+            i += n;
+        } else {
+            tokens.push((length as usize, 0, 0));
+
+            if length != 0 {
+                previous = length;
+            }
+
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+// Builds the code lengths of a Huffman code limited to max_length bits.
+// The histogram is flattened until the code fits.
+fn build_code_lengths(histogram: &[u32], max_length: u8) -> Vec<u8> {
+    let mut counts = histogram.to_vec();
+
+    loop {
+        let lengths = huffman_code_lengths(&counts);
+
+        if lengths.iter().all(|&l| l <= max_length) {
+            return lengths
+        }
+
+        for c in counts.iter_mut() {
+            if *c > 0 {
+                *c = (*c + 1) / 2;
+            }
+        }
+    }
+}
+
+fn huffman_code_lengths(counts: &[u32]) -> Vec<u8> {
+    let mut lengths: Vec<u8> = repeat(0u8).take(counts.len()).collect();
+
+    let mut leaves: Vec<(u32, usize)> = counts.iter()
+                                              .enumerate()
+                                              .filter(|&(_, &c)| c > 0)
+                                              .map(|(s, &c)| (c, s))
+                                              .collect();
+
+    match leaves.len() {
+        0 => return lengths,
+        1 => {
+            lengths[leaves[0].1] = 1;
+            return lengths
+        }
+        _ => ()
+    }
+
+    leaves.sort();
+
+    // Two queue construction, leaves are the nodes 0..n,
+    // the internal nodes n..2n - 1 are created in order of their weight
+    let n = leaves.len();
+    let mut weights: Vec<u64> = leaves.iter().map(|&(c, _)| c as u64).collect();
+    let mut parents: Vec<usize> = repeat(0).take(2 * n - 1).collect();
+
+    let mut next_leaf = 0;
+    let mut next_node = n;
+
+    for node in (n..2 * n - 1) {
+        let mut children = [0usize; 2];
+
+        for child in children.iter_mut() {
+            let take_leaf = next_leaf < n && (next_node >= node || weights[next_leaf] <= weights[next_node]);
+
+            if take_leaf {
+                *child = next_leaf;
+                next_leaf += 1;
+            } else {
+                *child = next_node;
+                next_node += 1;
+            }
+        }
+
+        weights.push(weights[children[0]] + weights[children[1]]);
+        parents[children[0]] = node;
+        parents[children[1]] = node;
+    }
+
+    let mut depths: Vec<u8> = repeat(0).take(2 * n - 1).collect();
+    for node in (0..2 * n - 2).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+
+    for (i, &(_, symbol)) in leaves.iter().enumerate() {
+        lengths[symbol] = depths[i];
+    }
+
+    lengths
+}
+
+// Assigns canonical codes to the lengths.
+// The codes are bit reversed since they are written LSB first.
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+
+    for &len in lengths.iter() {
+        counts[len as usize] += 1;
+    }
+
+    counts[0] = 0;
+
+    let mut next_code = [0u16; MAX_CODE_LENGTH + 1];
+    let mut code = 0u16;
+
+    for len in (1usize..MAX_CODE_LENGTH + 1) {
+        code = (code + counts[len - 1]) << 1;
+        next_code[len] = code;
+    }
+
+    lengths.iter().map(|&len| {
+        if len == 0 {
+            return 0
+        }
+
+        let code = next_code[len as usize];
+        next_code[len as usize] += 1;
+
+        let mut reversed = 0u16;
+        for bit in (0..len) {
+            reversed |= ((code >> bit as usize) & 1) << (len - 1 - bit) as usize;
+        }
+
+        reversed
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::{
+        encode,
+        apply_subtract_green,
+        select_predictors,
+        apply_predictor,
+        NUM_PREDICTORS,
+    };
+    use super::super::lossless::{LosslessDecoder, add_pixels, predict};
+
+    // Pixels where every channel changes from pixel to pixel
+    fn test_pixels(width: u32, height: u32) -> Vec<u32> {
+        (0..width * height).map(|i| {
+            let (x, y) = (i % width, i / width);
+            ((255 - x * 3) << 24) | (((x * 7 + y) & 0xff) << 16) | (((y * 11) & 0xff) << 8) | ((x * y) & 0xff)
+        }).collect()
+    }
+
+    fn decode(data: &[u8]) -> Vec<u32> {
+        LosslessDecoder::new(io::Cursor::new(data)).decode_frame().unwrap().buf.clone()
+    }
+
+    #[test]
+    /// Test that the green channel is subtracted from the red and blue channels
+    fn test_subtract_green() {
+        let mut data = vec![0xff102030, 0x8000ff01, 0x00000000];
+        apply_subtract_green(&mut data);
+        assert_eq!(data, vec![0xfff02010, 0x8001ff02, 0x00000000]);
+
+        let pixels = test_pixels(19, 7);
+        assert_eq!(decode(&encode(&pixels, 19, 7).unwrap()), pixels);
+    }
+
+    #[test]
+    /// Test that the residuals of each predictor are inverted by the predictions of the decoder
+    fn test_predictors() {
+        let (width, height) = (37, 21);
+        let pixels = test_pixels(width as u32, height as u32);
+
+        for mode in (0..NUM_PREDICTORS) {
+            let modes = vec![0xff000000 | (mode << 8); 3 * 2];
+            let residuals = apply_predictor(&pixels, width, height, &modes);
+
+            let mut decoded = residuals.clone();
+            for y in (0..height) {
+                for x in (0..width) {
+                    let i = y * width + x;
+
+                    let p = if y == 0 {
+                        if x == 0 { 0xff000000 } else { decoded[i - 1] }
+                    } else if x == 0 {
+                        decoded[i - width]
+                    } else {
+                        predict(mode, decoded[i - 1], decoded[i - width],
+                                decoded[i - width + 1], decoded[i - width - 1])
+                    };
+
+                    decoded[i] = add_pixels(residuals[i], p);
+                }
+            }
+
+            assert!(decoded == pixels);
+        }
+    }
+
+    #[test]
+    /// Test that the predictor without residuals is chosen for each block
+    fn test_predictor_selection() {
+        let (width, height) = (20, 20);
+
+        // Every column has one color, the top pixel predicts it exactly
+        let columns: Vec<u32> = (0..width * height).map(|i| 0xff000000 | ((i % width * 10 + 5) << 8)).collect();
+        assert_eq!(select_predictors(&columns, width as usize, height as usize), vec![0xff000200; 4]);
+
+        // Every row has one color, the left pixel predicts it exactly
+        let rows: Vec<u32> = (0..width * height).map(|i| 0xff000000 | ((i / width * 10 + 5) << 16)).collect();
+        assert_eq!(select_predictors(&rows, width as usize, height as usize), vec![0xff000100; 4]);
+
+        for pixels in [columns, rows].iter() {
+            assert!(decode(&encode(pixels, width, height).unwrap()) == *pixels);
+        }
+    }
+}
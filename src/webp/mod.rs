@@ -1,8 +1,17 @@
-//! Decoding of Webp Images
+//! Decoding and Encoding of Webp Images
+//!
+//! Lossy, lossless and extended images with an alpha channel are supported.
+//! Animated images are composited into full size frames.
+//! Images are encoded losslessly, or lossy with a quality setting.
 
 pub use self::decoder::WebpDecoder as WebpDecoder;
+pub use self::encoder::WebpEncoder as WebpEncoder;
 
 mod decoder;
+mod encoder;
 mod transform;
+mod loop_filter;
+mod lossless_encoder;
 
-pub mod vp8;
\ No newline at end of file
+pub mod vp8;
+pub mod lossless;
@@ -3,7 +3,11 @@
 //! This module contains a partial implementation of the
 //! VP8 video format as defined in RFC-6386.
 //!
-//! It decodes Keyframes only sans Loop Filtering.
+//! It decodes Keyframes only.
+//! The luma and both chroma planes are reconstructed and
+//! deblocked by the normal or simple loop filter.
+//! Keyframes are encoded with intra prediction modes chosen by
+//! their rate and distortion.
 //! VP8 is the underpinning of the Webp image format
 //!
 //! # Related Links
@@ -13,18 +17,26 @@
 //!
 
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::cmp;
+use std::u64;
+use std::slice;
 use std::default::Default;
 use std::iter::repeat;
-use byteorder::{ReadBytesExt, LittleEndian};
+use std::num::{SignedInt, Float};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 
 use super::transform;
+use super::loop_filter;
 
 use math::utils::clamp;
 
 const MAX_SEGMENTS: usize = 4;
 const NUM_DCT_TOKENS: usize = 12;
 
+// The largest level that is coded by the encoder
+const MAX_LEVEL: i32 = 2047;
+
 // Prediction modes
 const DC_PRED: i8 = 0;
 const V_PRED: i8 = 1;
@@ -645,11 +657,11 @@ impl BoolReader {
 
     pub fn init(&mut self, buf: Vec<u8>) {
         self.buf = buf;
+        self.index = 0;
         self.value = 0;
 
         for _ in (0usize..2) {
-            self.value = (self.value << 8) | self.buf[self.index] as u32;
-            self.index += 1;
+            self.value = (self.value << 8) | self.next_byte() as u32;
         }
 
         self.range = 255;
@@ -676,14 +688,26 @@ impl BoolReader {
 
             if self.bit_count == 8 {
                 self.bit_count = 0;
-                self.value |= self.buf[self.index] as u32;
-                self.index += 1;
+                self.value |= self.next_byte() as u32;
             }
         }
 
         retval
     }
 
+    // Section 7.3
+    // Reading past the end of the partition yields zeros
+    fn next_byte(&mut self) -> u8 {
+        let byte = if self.index < self.buf.len() {
+            self.buf[self.index]
+        } else {
+            0
+        };
+
+        self.index += 1;
+        byte
+    }
+
     pub fn read_literal(&mut self, n: u8) -> u8 {
         let mut v = 0u8;
         let mut n = n;
@@ -728,6 +752,124 @@ impl BoolReader {
     }
 }
 
+// Section 7.3
+// The boolean entropy encoder, the counterpart of the BoolReader
+struct BoolWriter {
+    buf: Vec<u8>,
+
+    range: u32,
+    bottom: u32,
+    bit_count: u8,
+}
+
+impl BoolWriter {
+    pub fn new() -> BoolWriter {
+        BoolWriter {buf: Vec::new(), range: 255, bottom: 0, bit_count: 24}
+    }
+
+    pub fn write_bool(&mut self, probability: u8, bit: bool) {
+        let split = 1 + (((self.range - 1) * probability as u32) >> 8);
+
+        if bit {
+            self.bottom += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+
+        while self.range < 128 {
+            self.range <<= 1;
+
+            if self.bottom & (1 << 31) != 0 {
+                self.add_one_to_output();
+            }
+
+            self.bottom <<= 1;
+            self.bit_count -= 1;
+
+            if self.bit_count == 0 {
+                self.buf.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    // Propagates a carry into the bytes that are already written
+    fn add_one_to_output(&mut self) {
+        let mut i = self.buf.len();
+
+        while self.buf[i - 1] == 255 {
+            self.buf[i - 1] = 0;
+            i -= 1;
+        }
+
+        self.buf[i - 1] += 1;
+    }
+
+    pub fn write_literal(&mut self, n: u8, value: u8) {
+        for i in (0..n).rev() {
+            self.write_bool(128, (value >> i as usize) & 1 == 1);
+        }
+    }
+
+    pub fn write_flag(&mut self, flag: bool) {
+        self.write_literal(1, if flag { 1 } else { 0 });
+    }
+
+    pub fn write_with_tree(&mut self, tree: &[i8], probs: &[Prob], start: isize, value: i8) {
+        let mut path = Vec::new();
+        tree_path(tree, start as usize, value, &mut path);
+
+        for &(index, bit) in path.iter() {
+            self.write_bool(probs[index >> 1], bit);
+        }
+    }
+
+    // Writes out the remaining bits and returns the encoded partition
+    pub fn flush(mut self) -> Vec<u8> {
+        let mut c = self.bit_count;
+        let mut v = self.bottom;
+
+        if v & (1 << (32 - c as usize)) != 0 {
+            self.add_one_to_output();
+        }
+
+        v <<= (c & 7) as usize;
+        c >>= 3;
+
+        while c > 0 {
+            v <<= 8;
+            c -= 1;
+        }
+
+        for _ in (0usize..4) {
+            self.buf.push((v >> 24) as u8);
+            v <<= 8;
+        }
+
+        self.buf
+    }
+}
+
+// A DCT token of the residual data and the context it is coded in
+#[derive(Copy)]
+struct Token {
+    plane: u8,
+    band: u8,
+    complexity: u8,
+
+    token: i8,
+
+    // The extra bits of a category token
+    extra: u16,
+    negative: bool,
+
+    // A token following DCT_0 can not be DCT_EOB,
+    // so the first branch of the tree is skipped
+    skip: bool,
+}
+
 #[derive(Copy)]
 struct MacroBlock {
     bpred:       [i8; 16],
@@ -761,6 +903,14 @@ pub struct Frame {
     /// The luma plane of the frame
     pub ybuf: Vec<u8>,
 
+    /// The blue difference chroma plane of the frame.
+    /// It is subsampled by a factor of two in both dimensions.
+    pub ubuf: Vec<u8>,
+
+    /// The red difference chroma plane of the frame.
+    /// It is subsampled by a factor of two in both dimensions.
+    pub vbuf: Vec<u8>,
+
     /// Indicates whether this frame is a keyframe
     pub keyframe: bool,
 
@@ -797,6 +947,57 @@ struct Segment {
     loopfilter_level: i8,
 }
 
+impl Segment {
+    // Section 14.1
+    fn set_quantizers(&mut self,
+                      base: i32,
+                      ydc_delta: i32,
+                      y2dc_delta: i32,
+                      y2ac_delta: i32,
+                      uvdc_delta: i32,
+                      uvac_delta: i32) {
+
+        self.ydc  = DC_QUANT[clamp(base + ydc_delta, 0, 127) as usize];
+        self.yac  = AC_QUANT[clamp(base, 0, 127) as usize];
+
+        self.y2dc = DC_QUANT[clamp(base + y2dc_delta, 0, 127) as usize] * 2;
+        self.y2ac = AC_QUANT[clamp(base + y2ac_delta, 0, 127) as usize] * 155 / 100;
+
+        self.uvdc = DC_QUANT[clamp(base + uvdc_delta, 0, 127) as usize];
+        self.uvac = AC_QUANT[clamp(base + uvac_delta, 0, 127) as usize];
+
+        if self.y2ac < 8 {
+            self.y2ac = 8;
+        }
+
+        if self.uvdc > 132 {
+            self.uvdc = 132;
+        }
+    }
+}
+
+// Section 15.1
+// The filter parameters of a single macroblock
+#[derive(Copy, Default)]
+struct FilterParameters {
+    level: u8,
+    interior_limit: u8,
+    hev_threshold: u8,
+
+    // Whether the edges between the subblocks are filtered
+    inner: bool,
+}
+
+impl FilterParameters {
+    fn mbedge_limit(&self) -> i32 {
+        (self.level as i32 + 2) * 2 + self.interior_limit as i32
+    }
+
+    fn subedge_limit(&self) -> i32 {
+        self.level as i32 * 2 + self.interior_limit as i32
+    }
+}
+
 /// VP8 Decoder
 /// Only decodes keyframes
 pub struct VP8Decoder<R> {
@@ -829,6 +1030,26 @@ pub struct VP8Decoder<R> {
 
     top_border: Vec<u8>,
     left_border: Vec<u8>,
+
+    top_border_u: Vec<u8>,
+    left_border_u: Vec<u8>,
+
+    top_border_v: Vec<u8>,
+    left_border_v: Vec<u8>,
+
+    // Section 9.6
+    loop_filter_adjustments: bool,
+    ref_delta: [i32; 4],
+    mode_delta: [i32; 4],
+
+    // The reconstructed planes padded to whole macroblocks.
+    // The loop filter reads pixels outside of the visible frame,
+    // so the planes are only cropped into the frame once it is filtered.
+    ybuf: Vec<u8>,
+    ubuf: Vec<u8>,
+    vbuf: Vec<u8>,
+
+    mb_filters: Vec<FilterParameters>,
 }
 
 impl<R: Read> VP8Decoder<R> {
@@ -873,8 +1094,24 @@ impl<R: Read> VP8Decoder<R> {
 
             top_border: Vec::new(),
             left_border: Vec::new(),
+
+            top_border_u: Vec::new(),
+            left_border_u: Vec::new(),
+
+            top_border_v: Vec::new(),
+            left_border_v: Vec::new(),
+
+            loop_filter_adjustments: false,
+            ref_delta: [0i32; 4],
+            mode_delta: [0i32; 4],
+
+            ybuf: Vec::new(),
+            ubuf: Vec::new(),
+            vbuf: Vec::new(),
+
+            mb_filters: Vec::new(),
         }
-}
+    }
 
     fn update_token_probabilities(&mut self) {
         for i in (0usize..4) {
@@ -898,7 +1135,7 @@ impl<R: Read> VP8Decoder<R> {
             try!(self.r.by_ref().take(3 * n as u64 - 3).read_to_end(&mut sizes));
 
             for (i, s) in sizes.chunks(3).enumerate() {
-                let size = s[0] as u32 + ((s[1] as u32) << 8) + ((s[2] as u32) << 8);
+                let size = s[0] as u32 + ((s[1] as u32) << 8) + ((s[2] as u32) << 16);
                 let mut buf = Vec::with_capacity(size as usize);
                 try!(self.r.by_ref().take(size as u64).read_to_end(&mut buf));
 
@@ -932,48 +1169,31 @@ impl<R: Read> VP8Decoder<R> {
 
         let n = if self.segments_enabled { MAX_SEGMENTS } else { 1 };
         for i in (0usize..n) {
-            let base = if !self.segment[i].delta_values { self.segment[i].quantizer_level as i16 }
-                    else { self.segment[i].quantizer_level as i16 + yac_abs as i16} as i32;
+            let base = if !self.segments_enabled { yac_abs as i16 }
+                       else if !self.segment[i].delta_values { self.segment[i].quantizer_level as i16 }
+                       else { self.segment[i].quantizer_level as i16 + yac_abs as i16 } as i32;
 
-            self.segment[i].ydc  = DC_QUANT[clamp(base + ydc_delta, 0, 127) as usize];
-            self.segment[i].yac  = AC_QUANT[clamp(base, 0, 127) as usize];
-
-            self.segment[i].y2dc = DC_QUANT[clamp(base + y2dc_delta, 0, 127) as usize] * 2;
-            self.segment[i].y2ac = AC_QUANT[clamp(base + y2ac_delta, 0, 127) as usize] * 155 / 100;
-
-            self.segment[i].uvdc = DC_QUANT[clamp(base + uvdc_delta, 0, 127) as usize];
-            self.segment[i].uvac = AC_QUANT[clamp(base + uvac_delta, 0, 127) as usize];
-
-            if self.segment[i].y2ac < 8 {
-                self.segment[i].y2ac = 8;
-            }
-
-            if self.segment[i].uvdc > 132 {
-                self.segment[i].uvdc = 132;
-            }
+            self.segment[i].set_quantizers(base, ydc_delta, y2dc_delta,
+                                           y2ac_delta, uvdc_delta, uvac_delta);
         }
     }
 
     fn read_loop_filter_adjustments(&mut self) {
         if self.b.read_flag() {
-            for _i in (0usize..4) {
+            for i in (0usize..4) {
                 let ref_frame_delta_update_flag = self.b.read_flag();
 
-                let _delta = if ref_frame_delta_update_flag {
-                    self.b.read_magnitude_and_sign(6)
-                } else {
-                    0i32
-                };
+                if ref_frame_delta_update_flag {
+                    self.ref_delta[i] = self.b.read_magnitude_and_sign(6);
+                }
             }
 
-            for _i in (0usize..4) {
+            for i in (0usize..4) {
                 let mb_mode_delta_update_flag = self.b.read_flag();
 
-                let _delta = if mb_mode_delta_update_flag {
-                    self.b.read_magnitude_and_sign(6)
-                } else {
-                    0i32
-                };
+                if mb_mode_delta_update_flag {
+                    self.mode_delta[i] = self.b.read_magnitude_and_sign(6);
+                }
             }
         }
     }
@@ -1054,8 +1274,30 @@ impl<R: Read> VP8Decoder<R> {
             self.frame.ybuf = repeat(0u8).take(self.frame.width as usize
                 * self.frame.height as usize).collect();
 
+            let chroma_width  = (self.frame.width as usize + 1) / 2;
+            let chroma_height = (self.frame.height as usize + 1) / 2;
+
+            self.frame.ubuf = repeat(0u8).take(chroma_width * chroma_height).collect();
+            self.frame.vbuf = repeat(0u8).take(chroma_width * chroma_height).collect();
+
+            let mbw = self.mbwidth as usize;
+            let mbh = self.mbheight as usize;
+
+            self.ybuf = repeat(0u8).take(mbw * 16 * mbh * 16).collect();
+            self.ubuf = repeat(0u8).take(mbw * 8 * mbh * 8).collect();
+            self.vbuf = repeat(0u8).take(mbw * 8 * mbh * 8).collect();
+
+            self.ref_delta = [0i32; 4];
+            self.mode_delta = [0i32; 4];
+
             self.top_border = repeat(127u8).take(self.frame.width as usize + 4 + 16).collect();
             self.left_border = repeat(129u8).take(1 + 16).collect();
+
+            self.top_border_u = repeat(127u8).take(self.mbwidth as usize * 8).collect();
+            self.left_border_u = repeat(129u8).take(1 + 8).collect();
+
+            self.top_border_v = repeat(127u8).take(self.mbwidth as usize * 8).collect();
+            self.left_border_v = repeat(129u8).take(1 + 8).collect();
         }
 
         let mut buf = Vec::with_capacity(first_partition_size as usize);
@@ -1078,8 +1320,8 @@ impl<R: Read> VP8Decoder<R> {
         self.frame.filter_level    = self.b.read_literal(6);
         self.frame.sharpness_level = self.b.read_literal(3);
 
-        let lf_adjust_enable = self.b.read_flag();
-        if lf_adjust_enable {
+        self.loop_filter_adjustments = self.b.read_flag();
+        if self.loop_filter_adjustments {
             self.read_loop_filter_adjustments();
         }
 
@@ -1189,18 +1431,13 @@ impl<R: Read> VP8Decoder<R> {
 
     fn intra_predict(&mut self, mbx: usize, mby: usize, mb: &MacroBlock, resdata: &[i32]) {
         let stride = 1usize + 16 + 4;
-        let w  = self.frame.width as usize;
         let mw = self.mbwidth as usize;
         let mut ws = create_border(
             mbx, mby, mw, &self.top_border, &self.left_border);
 
         match mb.luma_mode {
-            V_PRED  => predict_vpred(&mut ws, 16, 1, 1, stride),
-            H_PRED  => predict_hpred(&mut ws, 16, 1, 1, stride),
-            TM_PRED => predict_tmpred(&mut ws, 16, 1, 1, stride),
-            DC_PRED => predict_dcpred(&mut ws, 16, stride, mby != 0, mbx != 0),
-            B_PRED  => predict_4x4(&mut ws, stride, &mb.bpred, resdata),
-            _       => panic!("unknown luma intra prediction mode")
+            B_PRED => predict_4x4(&mut ws, stride, &mb.bpred, resdata),
+            mode   => predict_block(&mut ws, 16, stride, mode, mbx, mby),
         }
 
         if mb.luma_mode != B_PRED {
@@ -1223,20 +1460,59 @@ impl<R: Read> VP8Decoder<R> {
             self.left_border[i + 1] = ws[(i + 1) * stride + 16];
         }
 
-        let ylength = if mby < self.mbheight as usize - 1 { 16usize }
-                      else if self.frame.height % 16 == 0 { 16usize }
-                      else { (16 - (self.frame.height as usize & 15)) % 16 };
-
-        let xlength = if mbx < self.mbwidth as usize - 1 { 16usize }
-                      else if self.frame.width % 16 == 0 { 16usize }
-                      else { (16 - (self.frame.width as usize & 15)) % 16 };
+        let w = mw * 16;
 
-        for y in (0usize..ylength) {
-            for x in (0usize..xlength) {
-                self.frame.ybuf[(mby * 16 + y) * w + mbx * 16 + x] =
+        for y in (0usize..16) {
+            for x in (0usize..16) {
+                self.ybuf[(mby * 16 + y) * w + mbx * 16 + x] =
                     ws[(1 + y) * stride + 1 + x];
             }
         }
+
+        self.intra_predict_chroma(mbx, mby, mb, resdata);
+    }
+
+    fn intra_predict_chroma(&mut self, mbx: usize, mby: usize, mb: &MacroBlock, resdata: &[i32]) {
+        let stride = 1usize + 8;
+        let cw = self.mbwidth as usize * 8;
+
+        let mut uws = create_border_chroma(mbx, mby, &self.top_border_u, &self.left_border_u);
+        let mut vws = create_border_chroma(mbx, mby, &self.top_border_v, &self.left_border_v);
+
+        // Section 12.2
+        predict_block(&mut uws, 8, stride, mb.chroma_mode, mbx, mby);
+        predict_block(&mut vws, 8, stride, mb.chroma_mode, mbx, mby);
+
+        for y in (0usize..2) {
+            for x in (0usize..2) {
+                let i  = x + y * 2;
+                let ub = &resdata[16 * 16 + i * 16..16 * 16 + i * 16 + 16];
+                let vb = &resdata[20 * 16 + i * 16..20 * 16 + i * 16 + 16];
+                let y0 = 1 + y * 4;
+                let x0 = 1 + x * 4;
+
+                add_residue(&mut uws, ub, y0, x0, stride);
+                add_residue(&mut vws, vb, y0, x0, stride);
+            }
+        }
+
+        self.left_border_u[0] = uws[8];
+        self.left_border_v[0] = vws[8];
+
+        for i in (0usize..8) {
+            self.top_border_u[mbx * 8 + i] = uws[8 * stride + 1 + i];
+            self.top_border_v[mbx * 8 + i] = vws[8 * stride + 1 + i];
+
+            self.left_border_u[i + 1] = uws[(i + 1) * stride + 8];
+            self.left_border_v[i + 1] = vws[(i + 1) * stride + 8];
+        }
+
+        for y in (0usize..8) {
+            for x in (0usize..8) {
+                self.ubuf[(mby * 8 + y) * cw + mbx * 8 + x] = uws[(1 + y) * stride + 1 + x];
+                self.vbuf[(mby * 8 + y) * cw + mbx * 8 + x] = vws[(1 + y) * stride + 1 + x];
+            }
+        }
     }
 
     fn read_coefficients(&mut self,
@@ -1312,9 +1588,12 @@ impl<R: Read> VP8Decoder<R> {
         has_coefficients
     }
 
-    fn read_residual_data(&mut self, mb: &MacroBlock, mbx: usize, p: usize) -> [i32; 384] {
+    // Returns the residue of the macroblock and whether any of its
+    // coefficients are non-zero
+    fn read_residual_data(&mut self, mb: &MacroBlock, mbx: usize, p: usize) -> ([i32; 384], bool) {
         let sindex     = mb.segmentid as usize;
         let mut blocks = [0i32; 384];
+        let mut non_zero = false;
         let mut plane  = if mb.luma_mode == B_PRED { 3 }
                          else { 1 };
 
@@ -1350,6 +1629,7 @@ impl<R: Read> VP8Decoder<R> {
                 let n = self.read_coefficients(block, p, plane, complexity as usize, dcq, acq);
 
                 if block[0] != 0 || n {
+                    non_zero = true;
                     transform::idct4x4(block);
                 }
 
@@ -1376,6 +1656,7 @@ impl<R: Read> VP8Decoder<R> {
 
                     let n = self.read_coefficients(block, p, plane, complexity as usize, dcq, acq);
                     if block[0] != 0 || n {
+                        non_zero = true;
                         transform::idct4x4(block);
                     }
 
@@ -1387,12 +1668,148 @@ impl<R: Read> VP8Decoder<R> {
             }
         }
 
-        blocks
+        (blocks, non_zero)
+    }
+
+    // Section 9.6 and 15.1
+    fn filter_parameters(&self, mb: &MacroBlock, non_zero: bool) -> FilterParameters {
+        let segment = self.segment[mb.segmentid as usize];
+
+        let mut level = self.frame.filter_level as i32;
+        if self.segments_enabled {
+            level = if segment.delta_values { level + segment.loopfilter_level as i32 }
+                    else { segment.loopfilter_level as i32 };
+        }
+
+        if self.loop_filter_adjustments {
+            // Keyframes only use the intra frame reference
+            level += self.ref_delta[0];
+
+            if mb.luma_mode == B_PRED {
+                level += self.mode_delta[0];
+            }
+        }
+
+        let level = clamp(level, 0, 63) as u8;
+        let sharpness = self.frame.sharpness_level;
+
+        let mut interior_limit = level;
+        if sharpness > 0 {
+            interior_limit >>= if sharpness > 4 { 2 } else { 1 };
+
+            if interior_limit > 9 - sharpness {
+                interior_limit = 9 - sharpness;
+            }
+        }
+
+        if interior_limit == 0 {
+            interior_limit = 1;
+        }
+
+        let hev_threshold = if level >= 40 { 2 }
+                            else if level >= 15 { 1 }
+                            else { 0 };
+
+        FilterParameters {
+            level: level,
+            interior_limit: interior_limit,
+            hev_threshold: hev_threshold,
+            inner: mb.luma_mode == B_PRED || non_zero,
+        }
+    }
+
+    // Section 15
+    // The macroblocks are filtered in raster order, each one
+    // left edge, inner vertical edges, top edge and inner horizontal edges.
+    fn loop_filter(&mut self) {
+        let mbw = self.mbwidth as usize;
+        let mbh = self.mbheight as usize;
+        let ystride = mbw * 16;
+        let cstride = mbw * 8;
+
+        for mby in (0usize..mbh) {
+            for mbx in (0usize..mbw) {
+                let f = self.mb_filters[mby * mbw + mbx];
+
+                if f.level == 0 {
+                    continue
+                }
+
+                let mbedge_limit  = f.mbedge_limit();
+                let subedge_limit = f.subedge_limit();
+
+                let y0 = mby * 16 * ystride + mbx * 16;
+                let c0 = mby * 8 * cstride + mbx * 8;
+
+                if self.frame.filter == 1 {
+                    let ybuf = &mut self.ybuf[..];
+
+                    if mbx > 0 {
+                        for i in (0usize..16) {
+                            loop_filter::simple_segment(mbedge_limit, ybuf, y0 + i * ystride, 1);
+                        }
+                    }
+
+                    if f.inner {
+                        for x in (4usize..16).step_by(4) {
+                            for i in (0usize..16) {
+                                loop_filter::simple_segment(
+                                    subedge_limit, ybuf, y0 + i * ystride + x, 1);
+                            }
+                        }
+                    }
+
+                    if mby > 0 {
+                        for i in (0usize..16) {
+                            loop_filter::simple_segment(mbedge_limit, ybuf, y0 + i, ystride);
+                        }
+                    }
+
+                    if f.inner {
+                        for y in (4usize..16).step_by(4) {
+                            for i in (0usize..16) {
+                                loop_filter::simple_segment(
+                                    subedge_limit, ybuf, y0 + y * ystride + i, ystride);
+                            }
+                        }
+                    }
+                } else {
+                    normal_filter(&mut self.ybuf, y0, ystride, 16, mbx, mby, &f);
+                    normal_filter(&mut self.ubuf, c0, cstride, 8, mbx, mby, &f);
+                    normal_filter(&mut self.vbuf, c0, cstride, 8, mbx, mby, &f);
+                }
+            }
+        }
+    }
+
+    // Copy the visible part of the macroblock aligned planes into the frame
+    fn crop_planes(&mut self) {
+        let w = self.frame.width as usize;
+        let h = self.frame.height as usize;
+        let ystride = self.mbwidth as usize * 16;
+
+        for y in (0usize..h) {
+            for x in (0usize..w) {
+                self.frame.ybuf[y * w + x] = self.ybuf[y * ystride + x];
+            }
+        }
+
+        let cw = (w + 1) / 2;
+        let ch = (h + 1) / 2;
+        let cstride = self.mbwidth as usize * 8;
+
+        for y in (0usize..ch) {
+            for x in (0usize..cw) {
+                self.frame.ubuf[y * cw + x] = self.ubuf[y * cstride + x];
+                self.frame.vbuf[y * cw + x] = self.vbuf[y * cstride + x];
+            }
+        }
     }
 
     /// Decodes the current frame and returns a reference to it
     pub fn decode_frame(&mut self) -> io::Result<&Frame> {
         let _ = try!(self.read_frame_header());
+        self.mb_filters.clear();
 
         for mby in (0..self.mbheight as usize) {
             let p = mby % self.num_partitions as usize;
@@ -1401,9 +1818,12 @@ impl<R: Read> VP8Decoder<R> {
             for mbx in (0..self.mbwidth as usize) {
                 let (skip, mb) = self.read_macroblock_header(mbx);
                 let mut blocks = [0i32; 384];
+                let mut non_zero = false;
 
                 if !skip {
-                    blocks = self.read_residual_data(&mb, mbx, p);
+                    let (b, n) = self.read_residual_data(&mb, mbx, p);
+                    blocks = b;
+                    non_zero = n;
                 } else {
                     if mb.luma_mode != B_PRED {
                         self.left.complexity[0] = 0;
@@ -1417,77 +1837,999 @@ impl<R: Read> VP8Decoder<R> {
                 }
 
                 self.intra_predict(mbx, mby, &mb, &blocks);
+
+                let f = self.filter_parameters(&mb, non_zero);
+                self.mb_filters.push(f);
             }
 
             self.left_border = repeat(129u8).take(1 + 16).collect();
+            self.left_border_u = repeat(129u8).take(1 + 8).collect();
+            self.left_border_v = repeat(129u8).take(1 + 8).collect();
+        }
+
+        // Section 15
+        // Filtering is disabled for the whole frame when its level is zero
+        if self.frame.filter_level != 0 {
+            self.loop_filter();
         }
 
+        self.crop_planes();
+
         Ok(&self.frame)
     }
 }
 
-fn init_top_macroblocks(width: usize) -> Vec<MacroBlock> {
-    let mb_width = (width + 15) / 16;
+/// VP8 Encoder
+/// Encodes a single keyframe using intra prediction
+pub struct VP8Encoder<W> {
+    w: W,
 
-    let mb = MacroBlock {
-        // Section 11.3 #3
-        bpred: [B_DC_PRED; 16],
-        luma_mode: DC_PRED,
-        ..MacroBlock::new()
-    };
+    mbwidth: u16,
+    mbheight: u16,
 
-    (0..mb_width).map(|_| mb).collect()
-}
+    // The quantizer index from 0 to 127
+    qindex: u8,
+    segment: Segment,
+    filter_level: u8,
 
-fn create_border(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) -> [u8; 357] {
-    let stride = 1usize + 16 + 4;
-    let mut ws = [0u8; (1 + 16) * (1 + 16 + 4)];
+    token_probs: Box<TokenProbTables>,
 
-    // A
-    {
-        let above = &mut ws[1..stride];
-        if mby == 0 {
-            for i in (0usize..above.len()) {
-                above[i] = 127;
-            }
-        } else  {
-            for i in (0usize..16) {
-                above[i] = top[mbx * 16 + i];
-            }
+    top: Vec<MacroBlock>,
+    left: MacroBlock,
 
-            if mbx == mbw - 1 {
-                for i in (16usize..above.len()) {
-                    above[i] = top[mbx * 16 + 15];
-                }
-            } else {
-                for i in (16usize..above.len()) {
-                    above[i] = top[mbx * 16 + i];
-                }
-            }
-        }
-    }
+    top_border: Vec<u8>,
+    left_border: Vec<u8>,
 
-    for i in (17usize..stride) {
-        ws[4  * stride + i] = ws[i];
-        ws[8  * stride + i] = ws[i];
-        ws[12 * stride + i] = ws[i];
-    }
+    top_border_u: Vec<u8>,
+    left_border_u: Vec<u8>,
 
-    // L
-    if mbx == 0 {
-        for i in (0usize..16) {
-            ws[(i + 1) * stride] = 129;
-        }
-    } else {
-        for i in (0usize..16) {
-            ws[(i + 1) * stride] = left[i + 1];
-        }
-    }
+    top_border_v: Vec<u8>,
+    left_border_v: Vec<u8>,
 
-    // P
-    ws[0] = if mby == 0 && mbx == 0 {
-        127
-    } else if mby == 0 && mbx != 0 {
+    // The source planes padded to whole macroblocks
+    ybuf: Vec<u8>,
+    ubuf: Vec<u8>,
+    vbuf: Vec<u8>,
+
+    // The coded macroblocks with their skip flag, and the tokens of their residual data.
+    // They are only written once the token probabilities of the frame are known.
+    macroblocks: Vec<(bool, MacroBlock)>,
+    tokens: Vec<Token>,
+}
+
+impl<W: Write> VP8Encoder<W> {
+    /// Create a new encoder that writes a raw vp8 bitstream to ```w```.
+    /// The ```quality``` ranges from 0, the smallest files, to 100, the best quality.
+    pub fn new(w: W, quality: u8) -> VP8Encoder<W> {
+        let quality = cmp::min(quality, 100) as u32;
+        let qindex  = ((100 - quality) * 127 + 50) / 100;
+
+        let mut segment: Segment = Default::default();
+        segment.set_quantizers(qindex as i32, 0, 0, 0, 0, 0);
+
+        VP8Encoder {
+            w: w,
+
+            mbwidth: 0,
+            mbheight: 0,
+
+            qindex: qindex as u8,
+            segment: segment,
+
+            // Coarser quantization needs stronger deblocking
+            filter_level: (qindex * 3 / 8) as u8,
+
+            token_probs: Box::new(COEFF_PROBS),
+
+            top: Vec::new(),
+            left: MacroBlock::new(),
+
+            top_border: Vec::new(),
+            left_border: Vec::new(),
+
+            top_border_u: Vec::new(),
+            left_border_u: Vec::new(),
+
+            top_border_v: Vec::new(),
+            left_border_v: Vec::new(),
+
+            ybuf: Vec::new(),
+            ubuf: Vec::new(),
+            vbuf: Vec::new(),
+
+            macroblocks: Vec::new(),
+            tokens: Vec::new(),
+        }
+    }
+
+    /// Encodes the luma and chroma planes of ```frame``` as a keyframe
+    pub fn encode_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if frame.width == 0 || frame.height == 0 || frame.width > 0x3fff || frame.height > 0x3fff {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid image dimensions.",
+                Some(format!("A VP8 frame can not be {} x {} pixels.", frame.width, frame.height))
+            ))
+        }
+
+        self.init_frame(frame);
+
+        for mby in (0..self.mbheight as usize) {
+            self.left = MacroBlock::new();
+
+            for mbx in (0..self.mbwidth as usize) {
+                self.encode_macroblock(mbx, mby);
+            }
+
+            self.left_border = repeat(129u8).take(1 + 16).collect();
+            self.left_border_u = repeat(129u8).take(1 + 8).collect();
+            self.left_border_v = repeat(129u8).take(1 + 8).collect();
+        }
+
+        self.update_token_probabilities();
+
+        let first_partition = self.write_first_partition();
+        let tokens = self.write_tokens();
+
+        if first_partition.len() >= 1 << 19 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Image too large.",
+                Some("The modes of the macroblocks do not fit into the first partition.".to_string())
+            ))
+        }
+
+        // Section 9.1
+        // A keyframe of version 0 that is shown
+        let tag = (first_partition.len() << 5) | (1 << 4);
+
+        try!(self.w.write_all(&[tag as u8, (tag >> 8) as u8, (tag >> 16) as u8]));
+        try!(self.w.write_all(&[0x9d, 0x01, 0x2a]));
+        try!(self.w.write_u16::<LittleEndian>(frame.width));
+        try!(self.w.write_u16::<LittleEndian>(frame.height));
+
+        try!(self.w.write_all(&first_partition));
+        self.w.write_all(&tokens)
+    }
+
+    fn init_frame(&mut self, frame: &Frame) {
+        let width  = frame.width as usize;
+        let height = frame.height as usize;
+
+        self.mbwidth  = (frame.width + 15) / 16;
+        self.mbheight = (frame.height + 15) / 16;
+
+        let mbw = self.mbwidth as usize;
+        let mbh = self.mbheight as usize;
+
+        let chroma_width  = (width + 1) / 2;
+        let chroma_height = (height + 1) / 2;
+
+        self.ybuf = pad_plane(&frame.ybuf, width, height, mbw * 16, mbh * 16);
+        self.ubuf = pad_plane(&frame.ubuf, chroma_width, chroma_height, mbw * 8, mbh * 8);
+        self.vbuf = pad_plane(&frame.vbuf, chroma_width, chroma_height, mbw * 8, mbh * 8);
+
+        self.top = init_top_macroblocks(width);
+
+        self.top_border = repeat(127u8).take(width + 4 + 16).collect();
+        self.left_border = repeat(129u8).take(1 + 16).collect();
+
+        self.top_border_u = repeat(127u8).take(mbw * 8).collect();
+        self.left_border_u = repeat(129u8).take(1 + 8).collect();
+
+        self.top_border_v = repeat(127u8).take(mbw * 8).collect();
+        self.left_border_v = repeat(129u8).take(1 + 8).collect();
+
+        self.token_probs = Box::new(COEFF_PROBS);
+        self.macroblocks.clear();
+        self.tokens.clear();
+    }
+
+    // The weight of the rate against the distortion when choosing prediction modes
+    fn lambda(&self) -> u64 {
+        let q = self.segment.yac as u64;
+        cmp::max(q * q / 32, 1)
+    }
+
+    // Chooses the prediction modes of the macroblock, and reconstructs it as the decoder would
+    fn encode_macroblock(&mut self, mbx: usize, mby: usize) {
+        let stride = 1usize + 16 + 4;
+        let border = create_border(mbx, mby, self.mbwidth as usize, &self.top_border, &self.left_border);
+
+        let mut mb = MacroBlock::new();
+        let mut levels = [0i32; 400];
+        let mut ws = border;
+        let mut best = u64::MAX;
+
+        for &mode in [DC_PRED, V_PRED, H_PRED, TM_PRED].iter() {
+            let mut l = [0i32; 400];
+            let (score, recon) = self.encode_luma16(mbx, mby, &border, mode, &mut l);
+
+            if score < best {
+                best = score;
+                ws = recon;
+                levels = l;
+                mb.luma_mode = mode;
+            }
+        }
+
+        let mut bpred = [B_DC_PRED; 16];
+        let mut l = [0i32; 400];
+        let (score, recon) = self.encode_luma4(mbx, mby, &border, &mut bpred, &mut l);
+
+        if score < best {
+            ws = recon;
+            levels = l;
+            mb.luma_mode = B_PRED;
+            mb.bpred = bpred;
+        } else {
+            let mode = match mb.luma_mode {
+                DC_PRED => B_DC_PRED,
+                V_PRED  => B_VE_PRED,
+                H_PRED  => B_HE_PRED,
+                _       => B_TM_PRED,
+            };
+
+            mb.bpred = [mode; 16];
+        }
+
+        self.left_border[0] = ws[16];
+
+        for i in (0usize..16) {
+            self.top_border[mbx * 16 + i] = ws[16 * stride + 1 + i];
+            self.left_border[i + 1] = ws[(i + 1) * stride + 16];
+        }
+
+        let stride = 1usize + 8;
+        let mut uws = [0u8; 81];
+        let mut vws = [0u8; 81];
+        let mut best = u64::MAX;
+
+        for &mode in [DC_PRED, V_PRED, H_PRED, TM_PRED].iter() {
+            let mut l = [0i32; 128];
+            let (score, u, v) = self.encode_chroma(mbx, mby, mode, &mut l);
+
+            if score < best {
+                best = score;
+                uws = u;
+                vws = v;
+                slice::bytes::copy_memory(&mut levels[256..384], &l);
+                mb.chroma_mode = mode;
+            }
+        }
+
+        self.left_border_u[0] = uws[8];
+        self.left_border_v[0] = vws[8];
+
+        for i in (0usize..8) {
+            self.top_border_u[mbx * 8 + i] = uws[8 * stride + 1 + i];
+            self.top_border_v[mbx * 8 + i] = vws[8 * stride + 1 + i];
+
+            self.left_border_u[i + 1] = uws[(i + 1) * stride + 8];
+            self.left_border_v[i + 1] = vws[(i + 1) * stride + 8];
+        }
+
+        for i in (0usize..4) {
+            self.left.bpred[i] = mb.bpred[i * 4 + 3];
+        }
+
+        self.top[mbx].bpred = mb.bpred;
+
+        let skip = levels.iter().all(|&l| l == 0);
+
+        if !skip {
+            self.tokenize_residual_data(&mb, mbx, &levels);
+        } else {
+            if mb.luma_mode != B_PRED {
+                self.left.complexity[0] = 0;
+                self.top[mbx].complexity[0] = 0;
+            }
+
+            for i in (1usize..9) {
+                self.left.complexity[i] = 0;
+                self.top[mbx].complexity[i] = 0;
+            }
+        }
+
+        self.macroblocks.push((skip, mb));
+    }
+
+    // Predicts the luma macroblock with one of the 16x16 modes.
+    // Returns the score of the mode and the reconstructed macroblock.
+    fn encode_luma16(&self,
+                     mbx: usize,
+                     mby: usize,
+                     border: &[u8; 357],
+                     mode: i8,
+                     levels: &mut [i32]) -> (u64, [u8; 357]) {
+
+        let stride = 1usize + 16 + 4;
+        let ystride = self.mbwidth as usize * 16;
+        let src0 = mby * 16 * ystride + mbx * 16;
+        let segment = &self.segment;
+
+        let mut ws = *border;
+        predict_block(&mut ws, 16, stride, mode, mbx, mby);
+
+        let mut blocks = [0i32; 256];
+        let mut y2 = [0i32; 16];
+
+        for i in (0usize..16) {
+            let y0 = 1 + (i / 4) * 4;
+            let x0 = 1 + (i % 4) * 4;
+            let block = &mut blocks[i * 16..i * 16 + 16];
+
+            residual(block, &self.ybuf, ystride, src0 + (y0 - 1) * ystride + x0 - 1, &ws, stride, y0 * stride + x0);
+            transform::fdct4x4(block);
+
+            y2[i] = block[0];
+        }
+
+        // The DC coefficients of the subblocks are coded in the Y2 block
+        transform::fwht4x4(&mut y2);
+        let mut rate = quantize_block(&mut y2, &mut levels[384..400], 0, segment.y2dc, segment.y2ac);
+        transform::iwht4x4(&mut y2);
+
+        for i in (0usize..16) {
+            let y0 = 1 + (i / 4) * 4;
+            let x0 = 1 + (i % 4) * 4;
+            let block = &mut blocks[i * 16..i * 16 + 16];
+
+            rate += quantize_block(block, &mut levels[i * 16..i * 16 + 16], 1, segment.ydc, segment.yac);
+            block[0] = y2[i];
+
+            transform::idct4x4(block);
+            add_residue(&mut ws, block, y0, x0, stride);
+        }
+
+        rate += tree_cost(&KEYFRAME_YMODE_TREE, &KEYFRAME_YMODE_PROBS, mode);
+        let distortion = sse(&self.ybuf, ystride, src0, &ws, stride, stride + 1, 16);
+
+        (distortion * 256 + self.lambda() * rate as u64, ws)
+    }
+
+    // Chooses the B_PRED mode of each subblock in turn,
+    // as the subblocks are predicted from the reconstruction of the previous ones.
+    // Returns the score of the modes and the reconstructed macroblock.
+    fn encode_luma4(&self,
+                    mbx: usize,
+                    mby: usize,
+                    border: &[u8; 357],
+                    modes: &mut [i8; 16],
+                    levels: &mut [i32]) -> (u64, [u8; 357]) {
+
+        let stride = 1usize + 16 + 4;
+        let ystride = self.mbwidth as usize * 16;
+        let segment = &self.segment;
+        let lambda = self.lambda();
+
+        let mut ws = *border;
+        let mut score = lambda * tree_cost(&KEYFRAME_YMODE_TREE, &KEYFRAME_YMODE_PROBS, B_PRED) as u64;
+
+        for sby in (0usize..4) {
+            for sbx in (0usize..4) {
+                let i  = sbx + sby * 4;
+                let y0 = 1 + sby * 4;
+                let x0 = 1 + sbx * 4;
+                let src0 = (mby * 16 + sby * 4) * ystride + mbx * 16 + sbx * 4;
+
+                let top  = if sby == 0 { self.top[mbx].bpred[12 + sbx] } else { modes[i - 4] };
+                let left = if sbx == 0 { self.left.bpred[sby] } else { modes[i - 1] };
+                let probs = &KEYFRAME_BPRED_MODE_PROBS[top as usize][left as usize];
+
+                let mut best = (u64::MAX, B_DC_PRED, ws, [0i32; 16]);
+
+                for mode in (B_DC_PRED..B_HU_PRED + 1) {
+                    let mut trial = ws;
+                    let mut block = [0i32; 16];
+                    let mut block_levels = [0i32; 16];
+
+                    predict_subblock(&mut trial, stride, mode, x0, y0);
+                    residual(&mut block, &self.ybuf, ystride, src0, &trial, stride, y0 * stride + x0);
+
+                    transform::fdct4x4(&mut block);
+                    let rate = quantize_block(&mut block, &mut block_levels, 0, segment.ydc, segment.yac) +
+                               tree_cost(&KEYFRAME_BPRED_MODE_TREE, probs, mode);
+                    transform::idct4x4(&mut block);
+
+                    add_residue(&mut trial, &block, y0, x0, stride);
+
+                    let distortion = sse(&self.ybuf, ystride, src0, &trial, stride, y0 * stride + x0, 4);
+                    let s = distortion * 256 + lambda * rate as u64;
+
+                    if s < best.0 {
+                        best = (s, mode, trial, block_levels);
+                    }
+                }
+
+                score += best.0;
+                modes[i] = best.1;
+                ws = best.2;
+                slice::bytes::copy_memory(&mut levels[i * 16..i * 16 + 16], &best.3);
+            }
+        }
+
+        (score, ws)
+    }
+
+    // Predicts both chroma planes with the given mode.
+    // Returns the score of the mode and the reconstructed planes.
+    fn encode_chroma(&self, mbx: usize, mby: usize, mode: i8, levels: &mut [i32]) -> (u64, [u8; 81], [u8; 81]) {
+        let stride = 1usize + 8;
+        let cstride = self.mbwidth as usize * 8;
+        let src0 = mby * 8 * cstride + mbx * 8;
+        let segment = &self.segment;
+
+        let mut uws = create_border_chroma(mbx, mby, &self.top_border_u, &self.left_border_u);
+        let mut vws = create_border_chroma(mbx, mby, &self.top_border_v, &self.left_border_v);
+
+        predict_block(&mut uws, 8, stride, mode, mbx, mby);
+        predict_block(&mut vws, 8, stride, mode, mbx, mby);
+
+        let (urate, udistortion) = encode_chroma_plane(
+            &mut uws, &self.ubuf, cstride, src0, &mut levels[..64], segment.uvdc, segment.uvac);
+
+        let (vrate, vdistortion) = encode_chroma_plane(
+            &mut vws, &self.vbuf, cstride, src0, &mut levels[64..], segment.uvdc, segment.uvac);
+
+        let rate = urate + vrate + tree_cost(&KEYFRAME_UV_MODE_TREE, &KEYFRAME_UV_MODE_PROBS, mode);
+        let score = (udistortion + vdistortion) * 256 + self.lambda() * rate as u64;
+
+        (score, uws, vws)
+    }
+
+    // The counterpart of read_residual_data
+    fn tokenize_residual_data(&mut self, mb: &MacroBlock, mbx: usize, levels: &[i32]) {
+        let mut plane = if mb.luma_mode == B_PRED { 3 }
+                        else { 1 };
+
+        if plane == 1 {
+            let complexity = self.top[mbx].complexity[0] + self.left.complexity[0];
+            let n = self.tokenize_block(&levels[384..400], plane, complexity as usize);
+
+            self.left.complexity[0] = if n { 1 } else { 0 };
+            self.top[mbx].complexity[0] = if n { 1 } else { 0 };
+
+            plane = 0;
+        }
+
+        for y in (0usize..4) {
+            let mut left = self.left.complexity[y + 1];
+            for x in (0usize..4) {
+                let i = x + y * 4;
+                let complexity = self.top[mbx].complexity[x + 1] + left;
+
+                let n = self.tokenize_block(&levels[i * 16..i * 16 + 16], plane, complexity as usize);
+
+                left = if n { 1 } else { 0 };
+                self.top[mbx].complexity[x + 1] = if n { 1 } else { 0 };
+            }
+
+            self.left.complexity[y + 1] = left;
+        }
+
+        plane = 2;
+
+        for &j in [5usize, 7usize].iter() {
+            for y in (0usize..2) {
+                let mut left = self.left.complexity[y + j];
+
+                for x in (0usize..2) {
+                    let i = x + y * 2 + if j == 5 { 16 } else { 20 };
+                    let complexity = self.top[mbx].complexity[x + j] + left;
+
+                    let n = self.tokenize_block(&levels[i * 16..i * 16 + 16], plane, complexity as usize);
+
+                    left = if n { 1 } else { 0 };
+                    self.top[mbx].complexity[x + j] = if n { 1 } else { 0 };
+                }
+
+                self.left.complexity[y + j] = left;
+            }
+        }
+    }
+
+    // The counterpart of read_coefficients.
+    // Returns whether the block has any tokens besides DCT_EOB.
+    fn tokenize_block(&mut self, levels: &[i32], plane: usize, complexity: usize) -> bool {
+        let first = if plane == 0 { 1usize } else { 0usize };
+
+        let end = match (first..16).rev().find(|&i| levels[i] != 0) {
+            Some(last) => last + 1,
+            None       => first
+        };
+
+        let mut complexity = complexity;
+        let mut skip = false;
+
+        for i in (first..16usize) {
+            let mut token = Token {
+                plane: plane as u8,
+                band: COEFF_BANDS[i],
+                complexity: complexity as u8,
+                token: DCT_EOB,
+                extra: 0,
+                negative: false,
+                skip: skip,
+            };
+
+            if i == end {
+                self.tokens.push(token);
+                break
+            }
+
+            let (t, extra) = dct_token(levels[i].abs());
+            token.token = t;
+            token.extra = extra;
+            token.negative = levels[i] < 0;
+
+            self.tokens.push(token);
+
+            skip = levels[i] == 0;
+            complexity = match levels[i].abs() {
+                0 => 0,
+                1 => 1,
+                _ => 2,
+            };
+        }
+
+        end > first
+    }
+
+    // Section 13.4
+    // Updates the probabilities of the tokens to their frequency in this frame,
+    // where this saves more than the cost of the update
+    fn update_token_probabilities(&mut self) {
+        let mut counts = [[[[[0u64; 2]; NUM_DCT_TOKENS - 1]; 3]; 8]; 4];
+        let mut path = Vec::new();
+
+        for t in self.tokens.iter() {
+            path.clear();
+            tree_path(&DCT_TOKEN_TREE, if t.skip { 2 } else { 0 }, t.token, &mut path);
+
+            for &(index, bit) in path.iter() {
+                counts[t.plane as usize][t.band as usize][t.complexity as usize][index >> 1][bit as usize] += 1;
+            }
+        }
+
+        for i in (0usize..4) {
+            for j in (0usize..8) {
+                for k in (0usize..3) {
+                    for t in (0usize..NUM_DCT_TOKENS - 1) {
+                        let zeros = counts[i][j][k][t][0];
+                        let ones  = counts[i][j][k][t][1];
+
+                        if zeros + ones == 0 {
+                            continue
+                        }
+
+                        let old = COEFF_PROBS[i][j][k][t];
+                        let new = clamp(((255 * zeros + (zeros + ones) / 2) / (zeros + ones)) as i32, 1, 255) as u8;
+                        let update = COEFF_UPDATE_PROBS[i][j][k][t];
+
+                        let old_cost = zeros * bit_cost(old, false) as u64 +
+                                       ones * bit_cost(old, true) as u64 +
+                                       bit_cost(update, false) as u64;
+
+                        let new_cost = zeros * bit_cost(new, false) as u64 +
+                                       ones * bit_cost(new, true) as u64 +
+                                       bit_cost(update, true) as u64 + 8 * 256;
+
+                        if new_cost < old_cost {
+                            self.token_probs[i][j][k][t] = new;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Section 9 and 19.2
+    // Writes the frame header and the modes of the macroblocks
+    fn write_first_partition(&self) -> Vec<u8> {
+        let mut b = BoolWriter::new();
+
+        // Color space and clamping type
+        b.write_literal(1, 0);
+        b.write_literal(1, 0);
+
+        // Segmentation is not used
+        b.write_flag(false);
+
+        // The normal loop filter
+        b.write_literal(1, 0);
+        b.write_literal(6, self.filter_level);
+        b.write_literal(3, 0);
+
+        // No loop filter adjustments
+        b.write_flag(false);
+
+        // A single partition of DCT tokens
+        b.write_literal(2, 0);
+
+        // The quantizer index without deltas
+        b.write_literal(7, self.qindex);
+        for _ in (0usize..5) {
+            b.write_flag(false);
+        }
+
+        // Refresh entropy probs
+        b.write_literal(1, 0);
+
+        for i in (0usize..4) {
+            for j in (0usize..8) {
+                for k in (0usize..3) {
+                    for t in (0usize..NUM_DCT_TOKENS - 1) {
+                        let prob = self.token_probs[i][j][k][t];
+                        let update = prob != COEFF_PROBS[i][j][k][t];
+
+                        b.write_bool(COEFF_UPDATE_PROBS[i][j][k][t], update);
+
+                        if update {
+                            b.write_literal(8, prob);
+                        }
+                    }
+                }
+            }
+        }
+
+        let skipped = self.macroblocks.iter().filter(|&&(skip, _)| skip).count();
+        let coded = self.macroblocks.len() - skipped;
+        let prob_skip_false = clamp((coded * 255 / self.macroblocks.len()) as i32, 1, 255) as u8;
+
+        b.write_literal(1, 1);
+        b.write_literal(8, prob_skip_false);
+
+        let mbw = self.mbwidth as usize;
+        let mut top = init_top_macroblocks(mbw * 16);
+        let mut left = MacroBlock::new();
+
+        for (i, &(skip, mb)) in self.macroblocks.iter().enumerate() {
+            let mbx = i % mbw;
+
+            if mbx == 0 {
+                left = MacroBlock::new();
+            }
+
+            b.write_bool(prob_skip_false, skip);
+            b.write_with_tree(&KEYFRAME_YMODE_TREE, &KEYFRAME_YMODE_PROBS, 0, mb.luma_mode);
+
+            if mb.luma_mode == B_PRED {
+                for y in (0usize..4) {
+                    for x in (0usize..4) {
+                        let t = top[mbx].bpred[12 + x];
+                        let l = left.bpred[y];
+                        let bmode = mb.bpred[x + y * 4];
+
+                        b.write_with_tree(&KEYFRAME_BPRED_MODE_TREE,
+                            &KEYFRAME_BPRED_MODE_PROBS[t as usize][l as usize], 0, bmode);
+
+                        top[mbx].bpred[12 + x] = bmode;
+                        left.bpred[y] = bmode;
+                    }
+                }
+            }
+
+            for y in (0usize..4) {
+                left.bpred[y] = mb.bpred[y * 4 + 3];
+            }
+
+            top[mbx].bpred = mb.bpred;
+
+            b.write_with_tree(&KEYFRAME_UV_MODE_TREE, &KEYFRAME_UV_MODE_PROBS, 0, mb.chroma_mode);
+        }
+
+        b.flush()
+    }
+
+    // Section 13
+    fn write_tokens(&self) -> Vec<u8> {
+        let mut b = BoolWriter::new();
+
+        for t in self.tokens.iter() {
+            let probs = &self.token_probs[t.plane as usize][t.band as usize][t.complexity as usize];
+            b.write_with_tree(&DCT_TOKEN_TREE, probs, if t.skip { 2 } else { 0 }, t.token);
+
+            match t.token {
+                DCT_EOB | DCT_0 => continue,
+
+                category @ DCT_CAT1 ... DCT_CAT6 => {
+                    let probs = &PROB_DCT_CAT[(category - DCT_CAT1) as usize];
+                    let n = probs.iter().take_while(|&&p| p > 0).count();
+
+                    for j in (0..n) {
+                        b.write_bool(probs[j], (t.extra >> (n - 1 - j)) & 1 == 1);
+                    }
+                }
+
+                _ => ()
+            }
+
+            b.write_bool(128, t.negative);
+        }
+
+        b.flush()
+    }
+}
+
+// Finds the branches that lead to value, the inverse of read_with_tree
+fn tree_path(tree: &[i8], index: usize, value: i8, path: &mut Vec<(usize, bool)>) -> bool {
+    for bit in (0usize..2) {
+        let next = tree[index + bit];
+        path.push((index, bit == 1));
+
+        if next <= 0 {
+            if -next == value {
+                return true
+            }
+        } else if tree_path(tree, next as usize, value, path) {
+            return true
+        }
+
+        path.pop();
+    }
+
+    false
+}
+
+// The cost of coding a boolean in 1/256 bits
+fn bit_cost(probability: Prob, bit: bool) -> u32 {
+    let p = if bit { 256 - probability as u32 } else { probability as u32 };
+    (-(p as f64 / 256.0).log2() * 256.0) as u32
+}
+
+// The cost of coding value with a tree in 1/256 bits
+fn tree_cost(tree: &[i8], probs: &[Prob], value: i8) -> u32 {
+    let mut path = Vec::new();
+    tree_path(tree, 0, value, &mut path);
+
+    path.iter().fold(0, |cost, &(index, bit)| cost + bit_cost(probs[index >> 1], bit))
+}
+
+// Returns the token and its extra bits for the magnitude of a level
+fn dct_token(value: i32) -> (i8, u16) {
+    if value <= 4 {
+        return (value as i8, 0)
+    }
+
+    let mut category = DCT_CAT_BASE.len() - 1;
+    while value < DCT_CAT_BASE[category] as i32 {
+        category -= 1;
+    }
+
+    (DCT_CAT1 + category as i8, (value - DCT_CAT_BASE[category] as i32) as u16)
+}
+
+// Quantizes a coefficient. Rounding slightly towards zero
+// saves many small levels at a low cost in quality.
+fn quantize(value: i32, q: i32) -> i32 {
+    let level = cmp::min((value.abs() + q * 3 / 8) / q, MAX_LEVEL);
+    if value < 0 { -level } else { level }
+}
+
+// Quantizes the coefficients of a block from position first in zigzag order.
+// The block is replaced by the dequantized coefficients,
+// returns a rough estimate of the cost of the levels in 1/256 bits.
+fn quantize_block(block: &mut [i32], levels: &mut [i32], first: usize, dcq: i16, acq: i16) -> u32 {
+    let mut rate = 0;
+
+    for i in (first..16usize) {
+        let j = ZIGZAG[i] as usize;
+        let q = if j > 0 { acq } else { dcq } as i32;
+
+        let level = quantize(block[j], q);
+        levels[i] = level;
+        block[j] = level * q;
+
+        let mut magnitude = level.abs();
+        if magnitude > 0 {
+            let mut bits = 3;
+            while magnitude > 1 {
+                bits += 2;
+                magnitude >>= 1;
+            }
+
+            rate += bits * 256;
+        }
+    }
+
+    rate
+}
+
+// The difference between a 4x4 block of the source and its prediction
+fn residual(block: &mut [i32],
+            src: &[u8],
+            src_stride: usize,
+            src0: usize,
+            pred: &[u8],
+            stride: usize,
+            pred0: usize) {
+
+    for y in (0usize..4) {
+        for x in (0usize..4) {
+            block[y * 4 + x] = src[src0 + y * src_stride + x] as i32 -
+                               pred[pred0 + y * stride + x] as i32;
+        }
+    }
+}
+
+// The sum of the squared differences between a block of the source and its reconstruction
+fn sse(src: &[u8],
+       src_stride: usize,
+       src0: usize,
+       recon: &[u8],
+       stride: usize,
+       recon0: usize,
+       size: usize) -> u64 {
+
+    let mut sum = 0;
+
+    for y in (0usize..size) {
+        for x in (0usize..size) {
+            let d = src[src0 + y * src_stride + x] as i32 - recon[recon0 + y * stride + x] as i32;
+            sum += (d * d) as u64;
+        }
+    }
+
+    sum
+}
+
+// Codes the four subblocks of a predicted chroma macroblock and reconstructs them.
+// Returns the rate and the distortion.
+fn encode_chroma_plane(ws: &mut [u8],
+                       src: &[u8],
+                       src_stride: usize,
+                       src0: usize,
+                       levels: &mut [i32],
+                       dcq: i16,
+                       acq: i16) -> (u32, u64) {
+
+    let stride = 1usize + 8;
+    let mut rate = 0;
+
+    for i in (0usize..4) {
+        let y0 = 1 + (i / 2) * 4;
+        let x0 = 1 + (i % 2) * 4;
+        let mut block = [0i32; 16];
+
+        residual(&mut block, src, src_stride, src0 + (y0 - 1) * src_stride + x0 - 1, ws, stride, y0 * stride + x0);
+
+        transform::fdct4x4(&mut block);
+        rate += quantize_block(&mut block, &mut levels[i * 16..i * 16 + 16], 0, dcq, acq);
+        transform::idct4x4(&mut block);
+
+        add_residue(ws, &block, y0, x0, stride);
+    }
+
+    (rate, sse(src, src_stride, src0, ws, stride, stride + 1, 8))
+}
+
+// Pads a plane to the given size by repeating its last column and row
+fn pad_plane(plane: &[u8], width: usize, height: usize, padded_width: usize, padded_height: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(padded_width * padded_height);
+
+    for y in (0..padded_height) {
+        let row = cmp::min(y, height - 1) * width;
+
+        for x in (0..padded_width) {
+            buf.push(plane[row + cmp::min(x, width - 1)]);
+        }
+    }
+
+    buf
+}
+
+// Section 15.3
+// Applies the normal loop filter to the edges of a single
+// macroblock in a plane, starting at the top left pixel p0
+fn normal_filter(buf: &mut [u8],
+                 p0: usize,
+                 stride: usize,
+                 size: usize,
+                 mbx: usize,
+                 mby: usize,
+                 f: &FilterParameters) {
+
+    let interior_limit = f.interior_limit as i32;
+    let hev_threshold  = f.hev_threshold as i32;
+    let mbedge_limit   = f.mbedge_limit();
+    let subedge_limit  = f.subedge_limit();
+
+    if mbx > 0 {
+        for i in (0usize..size) {
+            loop_filter::macroblock_filter(
+                hev_threshold, interior_limit, mbedge_limit, buf, p0 + i * stride, 1);
+        }
+    }
+
+    if f.inner {
+        for x in (4usize..size).step_by(4) {
+            for i in (0usize..size) {
+                loop_filter::subblock_filter(
+                    hev_threshold, interior_limit, subedge_limit, buf, p0 + i * stride + x, 1);
+            }
+        }
+    }
+
+    if mby > 0 {
+        for i in (0usize..size) {
+            loop_filter::macroblock_filter(
+                hev_threshold, interior_limit, mbedge_limit, buf, p0 + i, stride);
+        }
+    }
+
+    if f.inner {
+        for y in (4usize..size).step_by(4) {
+            for i in (0usize..size) {
+                loop_filter::subblock_filter(
+                    hev_threshold, interior_limit, subedge_limit, buf, p0 + y * stride + i, stride);
+            }
+        }
+    }
+}
+
+fn init_top_macroblocks(width: usize) -> Vec<MacroBlock> {
+    let mb_width = (width + 15) / 16;
+
+    let mb = MacroBlock {
+        // Section 11.3 #3
+        bpred: [B_DC_PRED; 16],
+        luma_mode: DC_PRED,
+        ..MacroBlock::new()
+    };
+
+    (0..mb_width).map(|_| mb).collect()
+}
+
+fn create_border(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) -> [u8; 357] {
+    let stride = 1usize + 16 + 4;
+    let mut ws = [0u8; (1 + 16) * (1 + 16 + 4)];
+
+    // A
+    {
+        let above = &mut ws[1..stride];
+        if mby == 0 {
+            for i in (0usize..above.len()) {
+                above[i] = 127;
+            }
+        } else  {
+            for i in (0usize..16) {
+                above[i] = top[mbx * 16 + i];
+            }
+
+            if mbx == mbw - 1 {
+                for i in (16usize..above.len()) {
+                    above[i] = top[mbx * 16 + 15];
+                }
+            } else {
+                for i in (16usize..above.len()) {
+                    above[i] = top[mbx * 16 + i];
+                }
+            }
+        }
+    }
+
+    for i in (17usize..stride) {
+        ws[4  * stride + i] = ws[i];
+        ws[8  * stride + i] = ws[i];
+        ws[12 * stride + i] = ws[i];
+    }
+
+    // L
+    if mbx == 0 {
+        for i in (0usize..16) {
+            ws[(i + 1) * stride] = 129;
+        }
+    } else {
+        for i in (0usize..16) {
+            ws[(i + 1) * stride] = left[i + 1];
+        }
+    }
+
+    // P
+    ws[0] = if mby == 0 && mbx == 0 {
+        127
+    } else if mby == 0 && mbx != 0 {
         127
     } else if mbx == 0 && mby != 0 {
         129
@@ -1498,6 +2840,47 @@ fn create_border(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) ->
     ws
 }
 
+fn create_border_chroma(mbx: usize, mby: usize, top: &[u8], left: &[u8]) -> [u8; 81] {
+    let stride = 1usize + 8;
+    let mut ws = [0u8; (1 + 8) * (1 + 8)];
+
+    // A
+    {
+        let above = &mut ws[1..stride];
+        if mby == 0 {
+            for i in (0usize..above.len()) {
+                above[i] = 127;
+            }
+        } else {
+            for i in (0usize..8) {
+                above[i] = top[mbx * 8 + i];
+            }
+        }
+    }
+
+    // L
+    if mbx == 0 {
+        for i in (0usize..8) {
+            ws[(i + 1) * stride] = 129;
+        }
+    } else {
+        for i in (0usize..8) {
+            ws[(i + 1) * stride] = left[i + 1];
+        }
+    }
+
+    // P
+    ws[0] = if mby == 0 {
+        127
+    } else if mbx == 0 {
+        129
+    } else {
+        left[0]
+    };
+
+    ws
+}
+
 fn avg3(left: u8, this: u8, right: u8) -> u8 {
     let avg = (left as u16 + 2 * this as u16 + right as u16 + 2) >> 2;
     avg as u8
@@ -1519,6 +2902,18 @@ fn add_residue(pblock: &mut [u8], rblock: &[i32], y0: usize, x0: usize, stride:
     }
 }
 
+// Section 12.2
+// Predicts a whole luma or chroma macroblock of the given size
+fn predict_block(ws: &mut [u8], size: usize, stride: usize, mode: i8, mbx: usize, mby: usize) {
+    match mode {
+        DC_PRED => predict_dcpred(ws, size, stride, mby != 0, mbx != 0),
+        V_PRED  => predict_vpred(ws, size, 1, 1, stride),
+        H_PRED  => predict_hpred(ws, size, 1, 1, stride),
+        TM_PRED => predict_tmpred(ws, size, 1, 1, stride),
+        _       => panic!("unknown intra prediction mode")
+    }
+}
+
 fn predict_4x4(ws: &mut [u8], stride: usize, modes: &[i8], resdata: &[i32]) {
     for sby in (0usize..4) {
         for sbx in (0usize..4) {
@@ -1527,25 +2922,28 @@ fn predict_4x4(ws: &mut [u8], stride: usize, modes: &[i8], resdata: &[i32]) {
             let x0 = sbx * 4 + 1;
             let rb = &resdata[i * 16..i * 16 + 16];
 
-            match modes[i] {
-                B_TM_PRED => predict_tmpred(ws, 4, x0, y0, stride),
-                B_VE_PRED => predict_bvepred(ws, x0, y0, stride),
-                B_HE_PRED => predict_bhepred(ws, x0, y0, stride),
-                B_DC_PRED => predict_bdcpred(ws, x0, y0, stride),
-                B_LD_PRED => predict_bldpred(ws, x0, y0, stride),
-                B_RD_PRED => predict_brdpred(ws, x0, y0, stride),
-                B_VR_PRED => predict_bvrpred(ws, x0, y0, stride),
-                B_VL_PRED => predict_bvlpred(ws, x0, y0, stride),
-                B_HD_PRED => predict_bhdpred(ws, x0, y0, stride),
-                B_HU_PRED => predict_bhupred(ws, x0, y0, stride),
-                _         => panic!("unknown intra bmode"),
-            }
-
+            predict_subblock(ws, stride, modes[i], x0, y0);
             add_residue(ws, rb, y0, x0, stride);
         }
     }
 }
 
+fn predict_subblock(ws: &mut [u8], stride: usize, mode: i8, x0: usize, y0: usize) {
+    match mode {
+        B_TM_PRED => predict_tmpred(ws, 4, x0, y0, stride),
+        B_VE_PRED => predict_bvepred(ws, x0, y0, stride),
+        B_HE_PRED => predict_bhepred(ws, x0, y0, stride),
+        B_DC_PRED => predict_bdcpred(ws, x0, y0, stride),
+        B_LD_PRED => predict_bldpred(ws, x0, y0, stride),
+        B_RD_PRED => predict_brdpred(ws, x0, y0, stride),
+        B_VR_PRED => predict_bvrpred(ws, x0, y0, stride),
+        B_VL_PRED => predict_bvlpred(ws, x0, y0, stride),
+        B_HD_PRED => predict_bhdpred(ws, x0, y0, stride),
+        B_HU_PRED => predict_bhupred(ws, x0, y0, stride),
+        _         => panic!("unknown intra bmode"),
+    }
+}
+
 fn predict_vpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize) {
     for y in (0usize..size) {
         for x in (0usize..size) {
@@ -1841,3 +3239,249 @@ fn predict_bhupred(a: &mut [u8], x0: usize, y0: usize, stride: usize) {
     a[(y0 + 3) * stride + x0 + 2] = l3;
     a[(y0 + 3) * stride + x0 + 3] = l3;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::{VP8Decoder, VP8Encoder, MacroBlock, BoolReader, BoolWriter, B_PRED, ZIGZAG};
+    use super::{DCT_TOKEN_TREE, KEYFRAME_YMODE_TREE, KEYFRAME_YMODE_PROBS, COEFF_PROBS};
+    use super::{DCT_0, DCT_1, DCT_3, DCT_CAT1, DCT_CAT5, DCT_CAT6, DCT_EOB};
+    use super::super::transform;
+
+    // A deterministic sequence of pseudo random numbers
+    fn noise(n: usize) -> Vec<u32> {
+        let mut x = 1u32;
+        (0..n).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            x >> 16
+        }).collect()
+    }
+
+    // Returns the level, interior limit, hev threshold and whether inner edges are filtered
+    fn parameters(d: &VP8Decoder<io::Cursor<Vec<u8>>>, mb: &MacroBlock, non_zero: bool)
+        -> (u8, u8, u8, bool) {
+
+        let f = d.filter_parameters(mb, non_zero);
+        (f.level, f.interior_limit, f.hev_threshold, f.inner)
+    }
+
+    #[test]
+    /// Test the derivation of the loop filter parameters of a macroblock
+    /// from the frame header, as done by libwebp
+    fn test_filter_parameters() {
+        let mut d = VP8Decoder::new(io::Cursor::new(Vec::new()));
+        let mut mb = MacroBlock::new();
+
+        d.frame.filter_level = 20;
+        assert_eq!(parameters(&d, &mb, false), (20, 20, 1, false));
+        assert_eq!(parameters(&d, &mb, true), (20, 20, 1, true));
+
+        let f = d.filter_parameters(&mb, false);
+        assert_eq!((f.mbedge_limit(), f.subedge_limit()), (64, 60));
+
+        // The sharpness halves or quarters the interior limit and caps it at 9 - sharpness
+        d.frame.filter_level = 32;
+        d.frame.sharpness_level = 3;
+        assert_eq!(parameters(&d, &mb, false), (32, 6, 1, false));
+
+        d.frame.filter_level = 63;
+        d.frame.sharpness_level = 5;
+        assert_eq!(parameters(&d, &mb, false), (63, 4, 2, false));
+
+        // The interior limit is at least 1
+        d.frame.filter_level = 2;
+        d.frame.sharpness_level = 7;
+        assert_eq!(parameters(&d, &mb, false), (2, 1, 0, false));
+
+        // Segment levels are relative to the frame level or absolute
+        d.frame.filter_level = 20;
+        d.frame.sharpness_level = 0;
+        d.segments_enabled = true;
+        d.segment[1].delta_values = true;
+        d.segment[1].loopfilter_level = 10;
+        d.segment[2].delta_values = false;
+        d.segment[2].loopfilter_level = 50;
+
+        mb.segmentid = 1;
+        assert_eq!(parameters(&d, &mb, false), (30, 30, 1, false));
+
+        mb.segmentid = 2;
+        assert_eq!(parameters(&d, &mb, false), (50, 50, 2, false));
+
+        // The intra frame reference delta applies to every macroblock,
+        // the mode delta to macroblocks predicted per subblock
+        d.loop_filter_adjustments = true;
+        d.ref_delta[0] = 15;
+        d.mode_delta[0] = -10;
+
+        mb.segmentid = 1;
+        assert_eq!(parameters(&d, &mb, false), (45, 45, 2, false));
+
+        mb.luma_mode = B_PRED;
+        assert_eq!(parameters(&d, &mb, false), (35, 35, 1, true));
+
+        // The level is clamped to 0 ... 63
+        d.ref_delta[0] = -60;
+        assert_eq!(parameters(&d, &mb, false).0, 0);
+
+        d.ref_delta[0] = 30;
+        mb.segmentid = 2;
+        assert_eq!(parameters(&d, &mb, false).0, 63);
+    }
+
+    #[test]
+    /// Test that the BoolReader reads back what the BoolWriter writes,
+    /// including the long runs of likely bits that propagate carries
+    fn test_bool_coder() {
+        let values = noise(4000);
+        let mut w = BoolWriter::new();
+
+        for &v in values.iter() {
+            w.write_bool((v >> 8) as u8, v & 1 == 1);
+        }
+        for _ in (0usize..500) {
+            w.write_bool(1, true);
+        }
+        for _ in (0usize..500) {
+            w.write_bool(255, false);
+        }
+        for &v in values.iter().take(100) {
+            w.write_literal(7, (v & 0x7f) as u8);
+            w.write_flag(v & 0x80 != 0);
+        }
+        for value in (0i8..5) {
+            w.write_with_tree(&KEYFRAME_YMODE_TREE, &KEYFRAME_YMODE_PROBS, 0, value);
+        }
+        for value in (0i8..12) {
+            w.write_with_tree(&DCT_TOKEN_TREE, &COEFF_PROBS[1][2][0], 0, value);
+        }
+
+        let mut r = BoolReader::new();
+        r.init(w.flush());
+
+        for &v in values.iter() {
+            assert_eq!(r.read_bool((v >> 8) as u8), (v & 1) as u8);
+        }
+        for _ in (0usize..500) {
+            assert_eq!(r.read_bool(1), 1);
+        }
+        for _ in (0usize..500) {
+            assert_eq!(r.read_bool(255), 0);
+        }
+        for &v in values.iter().take(100) {
+            assert_eq!(r.read_literal(7), (v & 0x7f) as u8);
+            assert_eq!(r.read_flag(), v & 0x80 != 0);
+        }
+        for value in (0i8..5) {
+            assert_eq!(r.read_with_tree(&KEYFRAME_YMODE_TREE, &KEYFRAME_YMODE_PROBS, 0), value);
+        }
+        for value in (0i8..12) {
+            assert_eq!(r.read_with_tree(&DCT_TOKEN_TREE, &COEFF_PROBS[1][2][0], 0), value);
+        }
+    }
+
+    #[test]
+    /// Test that the inverse transforms of the decoder reverse
+    /// the forward transforms of the encoder within rounding
+    fn test_forward_transforms() {
+        let values = noise(16 * 500);
+
+        for block in values.chunks(16) {
+            let residue: Vec<i32> = block.iter().map(|&v| (v % 511) as i32 - 255).collect();
+            let mut coeffs = residue.clone();
+
+            transform::fdct4x4(&mut coeffs);
+            transform::idct4x4(&mut coeffs);
+
+            for (&a, &b) in coeffs.iter().zip(residue.iter()) {
+                assert!((a - b).abs() <= 1, "{:?} != {:?}", coeffs, residue);
+            }
+
+            let dc: Vec<i32> = block.iter().map(|&v| (v % 8161) as i32 - 4080).collect();
+            let mut coeffs = dc.clone();
+
+            transform::fwht4x4(&mut coeffs);
+            transform::iwht4x4(&mut coeffs);
+
+            for (&a, &b) in coeffs.iter().zip(dc.iter()) {
+                assert!((a - b).abs() <= 1, "{:?} != {:?}", coeffs, dc);
+            }
+        }
+
+        // A flat block only has a DC coefficient
+        let mut flat = [10i32; 16];
+        transform::fdct4x4(&mut flat);
+        assert_eq!(flat[0], 80);
+
+        let mut flat = [80i32; 16];
+        transform::fwht4x4(&mut flat);
+        assert_eq!(&flat[..], &[640, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0][..]);
+    }
+
+    #[test]
+    /// Test the tokens of a known block, the update of the token probabilities
+    /// to their frequency and that the decoder reads the coded tokens back
+    fn test_token_coding() {
+        // The levels in zigzag order
+        let known = [3, -1, 0, 0, 6, 0, 40, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut full = [0i32; 16];
+        for (i, v) in full.iter_mut().enumerate() {
+            *v = if i % 2 == 0 { i as i32 * 5 } else { -(i as i32) };
+        }
+        full[15] = -2047;
+
+        let mut e = VP8Encoder::new(Vec::new(), 75);
+        assert!(e.tokenize_block(&known, 1, 0));
+
+        let tokens: Vec<_> = e.tokens.iter()
+                              .map(|t| (t.token, t.extra, t.negative, t.complexity, t.skip))
+                              .collect();
+
+        assert_eq!(tokens, vec![
+            (DCT_3,    0, false, 0, false),
+            (DCT_1,    0, true,  2, false),
+            (DCT_0,    0, false, 1, false),
+            (DCT_0,    0, false, 0, true),
+            (DCT_CAT1, 1, false, 0, true),
+            (DCT_0,    0, false, 2, false),
+            (DCT_CAT5, 5, false, 0, true),
+            (DCT_EOB,  0, false, 2, false),
+        ]);
+
+        // An empty block is a single DCT_EOB, the last coefficient needs none
+        assert!(!e.tokenize_block(&[0; 16], 1, 2));
+        assert_eq!(e.tokens.last().unwrap().token, DCT_EOB);
+
+        let n = e.tokens.len();
+        assert!(e.tokenize_block(&full, 1, 1));
+        assert_eq!(e.tokens.len(), n + 16);
+        assert_eq!(e.tokens.last().unwrap().token, DCT_CAT6);
+        assert_eq!(e.tokens.last().unwrap().extra, 2047 - 67);
+
+        for _ in (0usize..200) {
+            e.tokenize_block(&known, 1, 0);
+        }
+
+        // The first token of the known block is never DCT_EOB
+        e.update_token_probabilities();
+        assert!(COEFF_PROBS[1][0][0][0] > 1);
+        assert_eq!(e.token_probs[1][0][0][0], 1);
+
+        let mut d = VP8Decoder::new(io::Cursor::new(Vec::new()));
+        d.token_probs = Box::new(*e.token_probs);
+        d.partitions[0].init(e.write_tokens());
+
+        let expected = [(&known, 0), (&[0; 16], 2), (&full, 1)];
+        let repeated = (0..200).map(|_| (&known, 0));
+
+        for (levels, complexity) in expected.iter().cloned().chain(repeated) {
+            let mut block = [0i32; 16];
+            let non_zero = d.read_coefficients(&mut block, 0, 1, complexity, 1, 1);
+
+            assert_eq!(non_zero, levels.iter().any(|&l| l != 0));
+            for i in (0usize..16) {
+                assert_eq!(block[ZIGZAG[i] as usize], levels[i]);
+            }
+        }
+    }
+}